@@ -0,0 +1,226 @@
+/* src/audit/mod.rs */
+
+pub mod query;
+
+use crate::quic::acl::Identity;
+use crate::rfs::image::ImageMode;
+use crate::setup::config::AuditConfig;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MIB: u64 = 1024 * 1024;
+
+lazy_static! {
+    // Open audit log of the server; `None` until `init`, and for good when auditing is off.
+    static ref AUDIT_LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+}
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    // Unix time, in seconds.
+    pub time: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// How a user proved who they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Token,
+    Certificate,
+    // Took over an earlier session with its resume token.
+    Session,
+}
+
+/// What happened. Paths are virtual `/<dev_name>/...` paths.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connected,
+    Refused {
+        reason: String,
+    },
+    AuthSucceeded {
+        method: AuthMethod,
+    },
+    AuthFailed {
+        reason: String,
+    },
+    Disconnected,
+    UploadStarted {
+        path: String,
+        size: u64,
+        hash: String,
+        resumed: bool,
+    },
+    UploadRejected {
+        path: String,
+        size: u64,
+        hash: String,
+        reason: String,
+    },
+    UploadFinalized {
+        path: String,
+        size: u64,
+        hash: String,
+    },
+    UploadFailed {
+        path: String,
+        size: u64,
+        hash: String,
+        reason: String,
+    },
+    // Partial files of an upload with a different hash, removed to start a new one.
+    StaleUploadDeleted {
+        path: String,
+    },
+    ImageOpened {
+        dev_name: String,
+        mode: ImageMode,
+        size: u64,
+    },
+    ImageRejected {
+        dev_name: String,
+        mode: ImageMode,
+        reason: String,
+    },
+    NbdExported {
+        dev_name: String,
+        size: u64,
+        read_only: bool,
+    },
+    NbdRejected {
+        dev_name: String,
+        reason: String,
+    },
+}
+
+impl AuditEvent {
+    /// The `event` tag this variant is written with.
+    pub fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.get("event").and_then(|e| e.as_str()).map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+struct AuditLog {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+fn open_append(path: &str) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// Where rotation number `n` of the log at `path` lives; 0 is the live log.
+pub fn rotated_path(path: &str, n: u32) -> String {
+    if n == 0 {
+        path.to_string()
+    } else {
+        format!("{}.{}", path, n)
+    }
+}
+
+impl AuditLog {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // `<path>.1` is the newest rotated file; the one past `keep` is dropped.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.keep));
+            for n in (0..self.keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if fs::metadata(&from).is_ok() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// [SERVER-SIDE] Opens the audit log; records are dropped until this succeeded.
+pub fn init(cfg: &AuditConfig) -> Result<(), String> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+    let file = open_append(&cfg.path)
+        .map_err(|e| format!("Cannot open audit log '{}': {}", cfg.path, e))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    *AUDIT_LOG.lock().unwrap() = Some(AuditLog {
+        path: cfg.path.clone(),
+        file,
+        size,
+        max_size: cfg.max_size_mib * MIB,
+        keep: cfg.keep,
+    });
+    Ok(())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// [SERVER-SIDE] Appends an event seen from `remote`, by `user` once they are known.
+pub fn record(remote: Option<SocketAddr>, user: Option<&str>, event: AuditEvent) {
+    let mut log = AUDIT_LOG.lock().unwrap();
+    let Some(log) = log.as_mut() else {
+        return;
+    };
+    let record = AuditRecord {
+        time: now_secs(),
+        remote: remote.map(|addr| addr.to_string()),
+        user: user.map(str::to_string),
+        event,
+    };
+    let line = match serde_json::to_string(&record) {
+        Ok(json) => json + "\n",
+        Err(e) => {
+            eprintln!("! Audit: Cannot serialize record: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = log.write_line(&line) {
+        eprintln!("! Audit: Cannot write to '{}': {}", log.path, e);
+    }
+}
+
+/// [SERVER-SIDE] Appends an event caused by an authenticated user.
+pub fn record_for(identity: &Identity, event: AuditEvent) {
+    record(identity.remote, Some(&identity.name), event);
+}
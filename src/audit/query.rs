@@ -0,0 +1,356 @@
+/* src/audit/query.rs */
+
+use crate::audit::{AuditRecord, rotated_path};
+use crate::setup::config::{AuditConfig, Config};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const AUDIT_USAGE: &str = "Usage: anchr audit [-c <config> | --file <path>] [--user <name>] \
+[--remote <address>] [--event <name>[,<name>...]] [--path <text>] [--since <time>] \
+[--until <time>] [--last <n>] [--json]
+Times are Unix seconds or an age such as 30m, 12h or 7d.";
+
+#[derive(Default)]
+struct Filter {
+    user: Option<String>,
+    remote: Option<String>,
+    events: Vec<String>,
+    path: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, record: &AuditRecord, details: &serde_json::Map<String, Value>) -> bool {
+        if self.user.is_some() && record.user != self.user {
+            return false;
+        }
+        if let Some(remote) = &self.remote {
+            let address = record.remote.as_deref().unwrap_or_default();
+            let same_ip = address_ip(address).is_some_and(|ip| ip == remote);
+            if address != remote && !same_ip {
+                return false;
+            }
+        }
+        if !self.events.is_empty() && !self.events.contains(&record.event.name()) {
+            return false;
+        }
+        if let Some(text) = &self.path {
+            let mentions = ["path", "dev_name"].iter().any(|key| {
+                details
+                    .get(*key)
+                    .and_then(Value::as_str)
+                    .is_some_and(|value| value.contains(text.as_str()))
+            });
+            if !mentions {
+                return false;
+            }
+        }
+        self.since.is_none_or(|since| record.time >= since)
+            && self.until.is_none_or(|until| record.time <= until)
+    }
+}
+
+// `1.2.3.4:5` and `[::1]:5` without the port.
+fn address_ip(address: &str) -> Option<&str> {
+    let (ip, _) = address.rsplit_once(':')?;
+    Some(ip.trim_start_matches('[').trim_end_matches(']'))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Unix seconds, or an age like `90s`, `30m`, `12h` or `7d`.
+fn parse_time(value: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid time '{}'.", value);
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs);
+    }
+    let unit = match value.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86_400,
+        _ => return Err(invalid()),
+    };
+    let amount: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+    Ok(now_secs().saturating_sub(amount * unit))
+}
+
+// `YYYY-MM-DD HH:MM:SS` in UTC.
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil date from days since 1970-01-01 (proleptic Gregorian).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn format_record(record: &AuditRecord, details: &serde_json::Map<String, Value>) -> String {
+    let fields = details
+        .iter()
+        .filter(|(key, _)| key.as_str() != "event")
+        .map(|(key, value)| match value {
+            Value::String(s) if !s.is_empty() && !s.contains(char::is_whitespace) => {
+                format!("{}={}", key, s)
+            }
+            _ => format!("{}={}", key, value),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "{} UTC  {:<22} {:<12} {:<20} {}",
+        format_time(record.time),
+        record.remote.as_deref().unwrap_or("-"),
+        record.user.as_deref().unwrap_or("-"),
+        record.event.name(),
+        fields
+    )
+    .trim_end()
+    .to_string()
+}
+
+// The live log and its rotations, oldest first.
+fn log_files(path: &str) -> Vec<String> {
+    let mut files: Vec<String> = (1..)
+        .map(|n| rotated_path(path, n))
+        .take_while(|file| Path::new(file).exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_string());
+    files
+}
+
+/// `anchr audit`: prints the audit log records matching the given filters, oldest first.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut path = AuditConfig::default().path;
+    let mut filter = Filter::default();
+    let mut last = None;
+    let mut json = false;
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        if flag == "--json" {
+            json = true;
+            continue;
+        }
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("{} needs a value.\n{}", flag, AUDIT_USAGE))?
+            .clone();
+        match flag.as_str() {
+            "-c" => path = Config::from_file(&value)?.audit.path,
+            "--file" => path = value,
+            "--user" => filter.user = Some(value),
+            "--remote" => filter.remote = Some(value),
+            "--event" => filter
+                .events
+                .extend(value.split(',').map(|e| e.trim().to_string())),
+            "--path" => filter.path = Some(value),
+            "--since" => filter.since = Some(parse_time(&value)?),
+            "--until" => filter.until = Some(parse_time(&value)?),
+            "--last" => {
+                last = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| format!("Invalid count '{}'.", value))?,
+                )
+            }
+            _ => return Err(format!("Unknown option '{}'.\n{}", flag, AUDIT_USAGE)),
+        }
+    }
+
+    if !Path::new(&path).exists() {
+        return Err(format!("Audit log '{}' does not exist.", path));
+    }
+    let mut matches = VecDeque::new();
+    let mut malformed = 0;
+    for file in log_files(&path) {
+        let reader = BufReader::new(
+            File::open(&file).map_err(|e| format!("Cannot read '{}': {}", file, e))?,
+        );
+        for line in reader.lines() {
+            let line = line.map_err(|e| format!("Cannot read '{}': {}", file, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Ok(record) = serde_json::from_str::<AuditRecord>(&line) else {
+                malformed += 1;
+                continue;
+            };
+            let details = match serde_json::to_value(&record.event) {
+                Ok(Value::Object(details)) => details,
+                _ => serde_json::Map::new(),
+            };
+            if !filter.matches(&record, &details) {
+                continue;
+            }
+            matches.push_back(if json {
+                line
+            } else {
+                format_record(&record, &details)
+            });
+            if last.is_some_and(|n| matches.len() > n) {
+                matches.pop_front();
+            }
+        }
+    }
+    for line in matches {
+        println!("{}", line);
+    }
+    if malformed > 0 {
+        eprintln!("! Skipped {} malformed line(s).", malformed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPLOAD: &str = concat!(
+        r#"{"time":1700000000,"remote":"192.0.2.7:4433","user":"alice","#,
+        r#""event":"upload_finalized","path":"/v/docs/a.txt","size":3,"hash":"abc"}"#
+    );
+    const IMAGE: &str = concat!(
+        r#"{"time":1700000100,"remote":"[2001:db8::1]:4433","user":"bob","#,
+        r#""event":"image_opened","dev_name":"disk0","mode":"pull","size":1024}"#
+    );
+    const REFUSED: &str =
+        r#"{"time":1700000200,"remote":"192.0.2.8:5000","event":"refused","reason":"deny list"}"#;
+
+    fn matches(filter: &Filter, line: &str) -> bool {
+        let record: AuditRecord = serde_json::from_str(line).unwrap();
+        let Ok(Value::Object(details)) = serde_json::to_value(&record.event) else {
+            panic!("events serialize as objects");
+        };
+        filter.matches(&record, &details)
+    }
+
+    fn matching(filter: &Filter) -> Vec<&'static str> {
+        [UPLOAD, IMAGE, REFUSED]
+            .into_iter()
+            .filter(|line| matches(filter, line))
+            .collect()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert_eq!(matching(&Filter::default()).len(), 3);
+    }
+
+    #[test]
+    fn user_filter_skips_anonymous_records() {
+        let filter = Filter {
+            user: Some("alice".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&filter), vec![UPLOAD]);
+    }
+
+    #[test]
+    fn remote_filter_takes_an_address_or_just_the_ip() {
+        let by_address = Filter {
+            remote: Some("192.0.2.7:4433".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&by_address), vec![UPLOAD]);
+        let by_ip = Filter {
+            remote: Some("2001:db8::1".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&by_ip), vec![IMAGE]);
+        let by_prefix = Filter {
+            remote: Some("192.0.2".to_string()),
+            ..Filter::default()
+        };
+        assert!(matching(&by_prefix).is_empty());
+    }
+
+    #[test]
+    fn event_filter_takes_several_names() {
+        let filter = Filter {
+            events: vec!["refused".to_string(), "image_opened".to_string()],
+            ..Filter::default()
+        };
+        assert_eq!(matching(&filter), vec![IMAGE, REFUSED]);
+    }
+
+    #[test]
+    fn path_filter_looks_at_paths_and_device_names() {
+        let filter = Filter {
+            path: Some("docs/".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&filter), vec![UPLOAD]);
+        let filter = Filter {
+            path: Some("disk".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&filter), vec![IMAGE]);
+    }
+
+    #[test]
+    fn time_range_is_inclusive() {
+        let filter = Filter {
+            since: Some(1_700_000_100),
+            until: Some(1_700_000_200),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&filter), vec![IMAGE, REFUSED]);
+        let filter = Filter {
+            until: Some(1_700_000_099),
+            ..Filter::default()
+        };
+        assert_eq!(matching(&filter), vec![UPLOAD]);
+    }
+
+    #[test]
+    fn filters_combine() {
+        let filter = Filter {
+            user: Some("bob".to_string()),
+            events: vec!["upload_finalized".to_string()],
+            ..Filter::default()
+        };
+        assert!(matching(&filter).is_empty());
+    }
+
+    #[test]
+    fn times_are_seconds_or_ages() {
+        assert_eq!(parse_time("1700000000"), Ok(1_700_000_000));
+        let age = now_secs() - parse_time("2h").unwrap();
+        assert!((7200..7205).contains(&age), "{}", age);
+        assert!(parse_time("2w").is_err());
+        assert!(parse_time("h").is_err());
+    }
+
+    #[test]
+    fn times_are_shown_in_utc() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13:20");
+    }
+}
@@ -0,0 +1,18 @@
+/* src/cli/cert.rs */
+
+use crate::quic::cert_monitor;
+use crate::quic::client::ClientSession;
+use crate::wsm::opcode::Opcode;
+use log::{error, info};
+
+// `cert` shows the certificate the server currently serves and how long it stays valid.
+pub async fn handle_command(_args: Vec<&str>, session: ClientSession) {
+    if !session.caps.supports(Opcode::CertStatusRequest) {
+        error!("The server does not support certificate status queries.");
+        return;
+    }
+    match cert_monitor::fetch_status(&session.requests).await {
+        Ok(status) => info!("{}", cert_monitor::format_status(&status)),
+        Err(e) => error!("! 'cert' failed: {}", e),
+    }
+}
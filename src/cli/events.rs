@@ -0,0 +1,39 @@
+/* src/cli/events.rs */
+
+use crate::events::{self, EventTopic};
+use crate::quic::client::ClientSession;
+use crate::wsm::opcode::Opcode;
+use log::{error, info};
+
+// `events [all|off|<topic>...]` replaces the event subscription of the current connection.
+pub async fn handle_command(args: Vec<&str>, session: ClientSession) {
+    if !session.caps.supports(Opcode::Subscribe) {
+        error!("The server does not support event subscriptions.");
+        return;
+    }
+    let topics: Vec<EventTopic> = match args.as_slice() {
+        [] | ["all"] => EventTopic::ALL.to_vec(),
+        ["off"] => Vec::new(),
+        names => {
+            let mut topics = Vec::new();
+            for name in names {
+                match EventTopic::parse(name) {
+                    Some(topic) => topics.push(topic),
+                    None => {
+                        error!(
+                            "Unknown event topic '{}'. Use: uploads, files, volumes, server, all or off.",
+                            name
+                        );
+                        return;
+                    }
+                }
+            }
+            topics
+        }
+    };
+    match events::subscribe(&session.requests, session.caps.encoding(), &topics).await {
+        Ok(()) if topics.is_empty() => info!("Unsubscribed from server events."),
+        Ok(()) => info!("Subscribed to server events: {:?}", topics),
+        Err(e) => error!("Failed to update event subscription: {}", e),
+    }
+}
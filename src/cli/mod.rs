@@ -1,19 +1,17 @@
 /* src/cli/mod.rs */
 
-mod drop;
+mod cert;
+mod events;
+pub mod oneshot;
 mod ping;
 mod rfs;
 
+use crate::quic::client::{current_requests, SharedSession};
 use crate::rfs::SharedUploadContext;
-use log::info;
-use tokio::sync::mpsc;
+use log::{error, info};
 
-pub async fn dispatch_command(
-    input: &str,
-    tx: mpsc::Sender<Vec<u8>>,
-    context: SharedUploadContext,
-) {
-    let mut parts = input.trim().split_whitespace();
+pub async fn dispatch_command(input: &str, context: SharedUploadContext, session: SharedSession) {
+    let mut parts = input.split_whitespace();
     if let Some(command) = parts.next() {
         let args: Vec<&str> = parts.collect();
         info!("Executing command: '{}' with args: {:?}", command, args);
@@ -21,19 +19,26 @@ pub async fn dispatch_command(
         match command.to_lowercase().as_str() {
             "ping" => {
                 // ping command does not need the context
-                ping::handle_command(args, tx).await;
-            }
-            "drop" => {
-                // drop command does not need the context
-                drop::handle_command(args).await;
+                match current_requests(&session).await {
+                    Some(requests) => ping::handle_command(args, requests).await,
+                    None => error!("Not connected to a server."),
+                }
             }
+            "cert" => match session.lock().await.clone() {
+                Some(current) => cert::handle_command(args, current).await,
+                None => error!("Not connected to a server."),
+            },
+            "events" => match session.lock().await.clone() {
+                Some(current) => events::handle_command(args, current).await,
+                None => error!("Not connected to a server."),
+            },
             "rfs" => {
                 // Only rfs commands might need the stateful context
-                rfs::handle_command(args, tx, context).await;
+                rfs::handle_command(args, context, session).await;
             }
             _ => {
                 info!("Unknown command: {}", command);
             }
         }
     }
-}
\ No newline at end of file
+}
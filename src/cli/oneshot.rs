@@ -0,0 +1,90 @@
+/* src/cli/oneshot.rs */
+
+use super::{cert, rfs};
+use crate::console::app::{Notifications, Stats};
+use crate::console::plain;
+use crate::quic::client::{ClientSession, SharedSession, run_network_tasks};
+use crate::setup::args::{EXIT_FAILURE, EXIT_UNAVAILABLE};
+use crate::setup::config::Config;
+use crate::wsm::codec::WsmMessage;
+use log::error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+// How long a one-shot command waits to be connected and authenticated.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// Lets the close frame leave before the process exits.
+const CLOSE_GRACE: Duration = Duration::from_millis(100);
+
+/// A client command run outside the TUI.
+pub enum OneShot {
+    Rfs(Vec<String>),
+    CertStatus,
+}
+
+// The session once authenticated; None if the network task gave up or time ran out.
+async fn wait_for_session(
+    session: &SharedSession,
+    network: &JoinHandle<()>,
+) -> Option<ClientSession> {
+    let started = Instant::now();
+    loop {
+        if let Some(current) = session.lock().await.clone() {
+            return Some(current);
+        }
+        if network.is_finished() {
+            return None;
+        }
+        if started.elapsed() >= CONNECT_TIMEOUT {
+            error!(
+                "! Not connected to the server after {}s; giving up.",
+                CONNECT_TIMEOUT.as_secs()
+            );
+            return None;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// [CLIENT-SIDE] Connects with the client config, runs one command, disconnects and
+/// returns the exit code: whether the command logged an error decides between 0 and 1.
+pub async fn run(cfg: Config, command: OneShot) -> i32 {
+    if let Err(e) = plain::init(&cfg.setup.log_level) {
+        eprintln!("! {}", e);
+        return EXIT_FAILURE;
+    }
+    let (tx, rx) = mpsc::channel::<WsmMessage>(32);
+    let session: SharedSession = Arc::new(Mutex::new(None));
+    let network = tokio::spawn(run_network_tasks(
+        cfg,
+        Stats::default(),
+        Notifications::default(),
+        tx,
+        rx,
+        session.clone(),
+    ));
+    let Some(current) = wait_for_session(&session, &network).await else {
+        network.abort();
+        return EXIT_UNAVAILABLE;
+    };
+
+    let errors_before = plain::error_count();
+    match command {
+        OneShot::Rfs(args) => {
+            let args = args.iter().map(String::as_str).collect();
+            rfs::handle_command(args, Arc::new(Mutex::new(None)), session).await;
+        }
+        OneShot::CertStatus => cert::handle_command(Vec::new(), current.clone()).await,
+    }
+    let failed = plain::error_count() > errors_before;
+
+    // Stopped first, so the closed connection is not taken for a lost one and retried.
+    network.abort();
+    let _ = network.await;
+    current.connection.close(0u32.into(), b"done");
+    time::sleep(CLOSE_GRACE).await;
+    if failed { EXIT_FAILURE } else { 0 }
+}
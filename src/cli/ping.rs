@@ -1,16 +1,15 @@
 /* src/cli/ping.rs */
 
 use crate::quic::keepalive;
+use crate::wsm::requests::{RequestTracker, REQUEST_TIMEOUT};
 use log::{error, info};
-use tokio::sync::mpsc;
+use std::time::Instant;
 
-pub async fn handle_command(_args: Vec<&str>, tx: mpsc::Sender<Vec<u8>>) {
+pub async fn handle_command(_args: Vec<&str>, requests: RequestTracker) {
     info!("Manual PING command triggered.");
-    if let Some(ping_msg) = keepalive::build_client_ping().await {
-        if tx.send(ping_msg.to_vec()).await.is_err() {
-            error!("Failed to send manual PING: channel closed.");
-        }
-    } else {
-        error!("Failed to create manual PING: message ID pool is full.");
+    let sent_at = Instant::now();
+    match requests.request(keepalive::build_ping, REQUEST_TIMEOUT).await {
+        Ok(_) => info!("PONG received in {:.2?}.", sent_at.elapsed()),
+        Err(e) => error!("Manual PING failed: {}", e),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,40 @@
+/* src/cli/rfs/image.rs */
+
+use crate::quic::client::SharedSession;
+use crate::rfs::image::{self, ImageMode, ImageRequest};
+use log::{error, info};
+use std::path::PathBuf;
+
+const CONFIRM_FLAG: &str = "--confirm-overwrite";
+
+pub async fn execute(args: Vec<&str>, session: SharedSession) {
+    let mode = match args.first() {
+        Some(&"pull") if args.len() == 3 => ImageMode::Pull,
+        Some(&"push") if args.len() == 3 || args.len() == 4 => ImageMode::Push,
+        _ => {
+            error!("Usage: rfs image pull <dev_name> <local.img>");
+            error!("       rfs image push <dev_name> <local.img> {}", CONFIRM_FLAG);
+            return;
+        }
+    };
+    let confirm_overwrite = args.get(3) == Some(&CONFIRM_FLAG);
+    if mode == ImageMode::Push && !confirm_overwrite {
+        error!(
+            "Pushing an image OVERWRITES the whole device '{}'. Re-run with {} to proceed.",
+            args[1], CONFIRM_FLAG
+        );
+        return;
+    }
+
+    let Some(session) = session.lock().await.clone() else {
+        error!("Not connected to a server.");
+        return;
+    };
+    let request = ImageRequest {
+        dev_name: args[1].to_string(),
+        mode,
+        confirm_overwrite,
+    };
+    info!("Starting image {:?} of '{}' <-> '{}'...", mode, args[1], args[2]);
+    image::run_image_transfer(session, request, PathBuf::from(args[2])).await;
+}
@@ -1,28 +1,88 @@
 /* src/cli/rfs/mod.rs */
 
+mod image;
 mod list;
+mod nbd;
+mod scrub;
 mod upload;
+mod watch;
 
-use crate::rfs::SharedUploadContext;
-use log::info;
-use tokio::sync::mpsc;
+use crate::quic::client::SharedSession;
+use crate::rfs::{image as rfs_image, worker, SharedUploadContext};
+use crate::wsm::opcode::Opcode;
+use log::{error, info};
+
+// Opcode and chunk size each subcommand needs from the negotiated feature set.
+fn required_feature(subcommand: &str) -> Option<(Opcode, u64)> {
+    match subcommand {
+        "list" => Some((Opcode::ListRequest, 0)),
+        "upload" => Some((Opcode::UploadInit, worker::CHUNK_SIZE)),
+        "image" => Some((Opcode::ImageHello, rfs_image::IMAGE_CHUNK_SIZE)),
+        "nbd" => Some((Opcode::NbdHello, 0)),
+        "scrub" => Some((Opcode::ScrubStatusRequest, 0)),
+        "watch" => Some((Opcode::Watch, 0)),
+        "unwatch" => Some((Opcode::Unwatch, 0)),
+        _ => None,
+    }
+}
 
 pub async fn handle_command(
     args: Vec<&str>,
-    tx: mpsc::Sender<Vec<u8>>,
     context: SharedUploadContext,
+    session: SharedSession,
 ) {
+    let Some(current) = session.lock().await.clone() else {
+        error!("Not connected to a server.");
+        return;
+    };
+    let caps = &current.caps;
+    if let Some((opcode, chunk_size)) = args.first().and_then(|sub| required_feature(sub)) {
+        if !caps.supports(opcode) {
+            error!("The server does not support 'rfs {}'.", args[0]);
+            return;
+        }
+        if caps.max_chunk_size < chunk_size {
+            error!(
+                "'rfs {}' needs {} byte chunks, but only {} were negotiated.",
+                args[0], chunk_size, caps.max_chunk_size
+            );
+            return;
+        }
+    }
+
     match args.first() {
         Some(&"list") => {
             let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
-            list::execute(sub_args, tx).await;
+            list::execute(sub_args, current.requests).await;
         }
         Some(&"upload") => {
             let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
-            upload::execute(sub_args, tx, context).await;
+            upload::execute(sub_args, context, current).await;
+        }
+        Some(&"image") => {
+            let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
+            image::execute(sub_args, session).await;
+        }
+        Some(&"nbd") => {
+            let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
+            nbd::execute(sub_args, session).await;
+        }
+        Some(&"scrub") => {
+            let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
+            scrub::execute(sub_args, current.requests).await;
+        }
+        Some(&"watch") => {
+            let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
+            watch::execute(sub_args, current.requests).await;
+        }
+        Some(&"unwatch") => {
+            let sub_args = args.get(1..).unwrap_or(&[]).to_vec();
+            watch::execute_unwatch(sub_args, current.requests).await;
         }
         _ => {
-            info!("Unknown rfs command. Available commands: list, upload");
+            info!(
+                "Unknown rfs command. Available commands: list, upload, image, nbd, scrub, watch, unwatch"
+            );
         }
     }
 }
\ No newline at end of file
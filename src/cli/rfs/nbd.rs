@@ -0,0 +1,21 @@
+/* src/cli/rfs/nbd.rs */
+
+use crate::nbd::proxy::{self, ListenAddr};
+use crate::quic::client::SharedSession;
+use log::error;
+
+pub async fn execute(args: Vec<&str>, session: SharedSession) {
+    if args.len() != 2 {
+        error!("Usage: rfs nbd <dev_name> <tcp:host:port | unix:/path/to.sock>");
+        return;
+    }
+    let listen = match ListenAddr::parse(args[1]) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    // Runs for the rest of the session; each local client gets its own tunnel stream.
+    proxy::run_nbd_proxy(session, args[0].to_string(), listen).await;
+}
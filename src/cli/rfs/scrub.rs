@@ -0,0 +1,17 @@
+/* src/cli/rfs/scrub.rs */
+
+use crate::rfs::scrub;
+use crate::wsm::requests::RequestTracker;
+use log::{error, info};
+
+pub async fn execute(_args: Vec<&str>, requests: RequestTracker) {
+    info!("Requesting scrub status from server...");
+
+    match scrub::fetch_status(&requests).await {
+        Ok(reports) if reports.is_empty() => {
+            info!("> No scrub pass has completed since the server started.")
+        }
+        Ok(reports) => info!("{}", scrub::format_status(&reports)),
+        Err(e) => error!("! 'rfs scrub' failed: {}", e),
+    }
+}
@@ -1,21 +1,19 @@
 /* src/cli/rfs/upload.rs */
 
-use crate::rfs::{SharedUploadContext, UploadContext, UploadMetadata, UploadState};
-use crate::wsm::header::{PayloadType, WsmHeader};
-use crate::wsm::msg_id;
-use log::{error, info, warn};
+use crate::quic::client::ClientSession;
+use crate::rfs::{upload, SharedUploadContext, UploadContext, UploadMetadata};
+use log::{error, warn};
 use regex::Regex;
 use sha2::{Digest, Sha256};
 use std::path::{Path};
 use std::time::Instant;
 use tokio::fs as tokio_fs;
 use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc;
 
 pub async fn execute(
     args: Vec<&str>,
-    tx: mpsc::Sender<Vec<u8>>,
     context: SharedUploadContext,
+    session: ClientSession,
 ) {
     if args.len() != 2 {
         error!("Usage: rfs upload <target_dir> <local_path_to_file>");
@@ -72,54 +70,28 @@ pub async fn execute(
     let file_size = metadata.len();
     let upload_meta = UploadMetadata {
         target_dir,
-        file_name: file_name.clone(),
+        file_name,
         file_size,
         file_hash,
     };
 
-    let json_payload = serde_json::to_string(&upload_meta).unwrap();
-
-    if let Some(msg_id) = msg_id::create_new_msg_id().await {
+    {
         let mut ctx_lock = context.lock().await;
-
+        // Checked again under the lock: hashing above gave another upload time to start.
+        if ctx_lock.is_some() {
+            error!("Another upload is already in progress. Please wait for it to complete.");
+            return;
+        }
         *ctx_lock = Some(UploadContext {
-            metadata: upload_meta.clone(),
+            metadata: upload_meta,
             local_file_path: local_path.to_path_buf(),
-            message_id: msg_id,
-            state: UploadState::Initiated,
             chunk_queue: Default::default(),
             total_chunks: 0,
             completed_chunks: Default::default(),
             start_time, // Store start time in context
         });
-
-        let header = WsmHeader::new(
-            0x06,
-            msg_id,
-            PayloadType::Json,
-            json_payload.len() as u32,
-        );
-
-        let mut message = header.to_bytes().to_vec();
-        message.extend_from_slice(json_payload.as_bytes());
-
-        info!(
-            "Initiating upload for '{}'...",
-            upload_meta.file_name
-        );
-        if tx.send(message).await.is_err() {
-            error!("Failed to send upload initiation command.");
-            *ctx_lock = None;
-            msg_id::remove_msg_id(msg_id).await;
-        } else {
-            info!(
-                "Upload initiation for '{}' sent (id: {}). Waiting for server ACK...",
-                upload_meta.file_name, msg_id
-            );
-        }
-    } else {
-        error!("Failed to initiate upload: message ID pool is full.");
     }
+    upload::run_upload(context, session).await;
 }
 
 // calculate file hash asynchronously.
@@ -0,0 +1,36 @@
+/* src/cli/rfs/watch.rs */
+
+use crate::rfs::watch;
+use crate::wsm::requests::RequestTracker;
+use log::{error, info};
+
+// `rfs watch </dev_name/path>` streams changes below a directory; without a path it lists the watches.
+pub async fn execute(args: Vec<&str>, requests: RequestTracker) {
+    let Some(path) = args.first() else {
+        let paths = watch::watched_paths();
+        if paths.is_empty() {
+            info!("> Not watching anything. Usage: rfs watch </dev_name/path>");
+        } else {
+            info!("> Watching: {}", paths.join(", "));
+        }
+        return;
+    };
+    match watch::watch(&requests, path).await {
+        Ok(()) => info!(
+            "Watching {} for changes; they are shown as server events.",
+            path
+        ),
+        Err(e) => error!("! 'rfs watch' failed: {}", e),
+    }
+}
+
+pub async fn execute_unwatch(args: Vec<&str>, requests: RequestTracker) {
+    let Some(path) = args.first() else {
+        error!("Usage: rfs unwatch </dev_name/path>");
+        return;
+    };
+    match watch::unwatch(&requests, path).await {
+        Ok(()) => info!("Stopped watching {}.", path),
+        Err(e) => error!("! 'rfs unwatch' failed: {}", e),
+    }
+}
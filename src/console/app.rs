@@ -1,17 +1,44 @@
 /* src/console/app.rs */
 
-use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tui_logger::TuiWidgetState;
 
 #[derive(Clone, Default)]
 pub struct Stats {
     pub tx_bytes: Arc<AtomicU64>,
     pub rx_bytes: Arc<AtomicU64>,
-    pub last_msg_id: Arc<AtomicU8>,
+    pub last_msg_id: Arc<AtomicU32>,
     pub pool_count: Arc<AtomicUsize>,
 }
 
+// How many notifications are kept, and how long the newest stays in the status bar.
+const MAX_NOTIFICATIONS: usize = 32;
+const NOTIFICATION_DISPLAY: Duration = Duration::from_secs(10);
+
+/// Server-pushed events for the status bar, newest last.
+#[derive(Clone, Default)]
+pub struct Notifications(Arc<Mutex<VecDeque<(Instant, String)>>>);
+
+impl Notifications {
+    pub fn push(&self, text: String) {
+        let mut queue = self.0.lock().unwrap();
+        if queue.len() == MAX_NOTIFICATIONS {
+            queue.pop_front();
+        }
+        queue.push_back((Instant::now(), text));
+    }
+
+    /// The newest notification, while it is recent enough to show.
+    pub fn current(&self) -> Option<String> {
+        let queue = self.0.lock().unwrap();
+        let (received, text) = queue.back()?;
+        (received.elapsed() < NOTIFICATION_DISPLAY).then(|| text.clone())
+    }
+}
+
 pub struct App {
     pub input: String,
     pub status: String,
@@ -19,6 +46,7 @@ pub struct App {
     pub info_log_state: TuiWidgetState,
     pub debug_log_state: TuiWidgetState,
     pub stats: Stats,
+    pub notifications: Notifications,
 }
 
 impl App {
@@ -30,6 +58,7 @@ impl App {
             info_log_state: TuiWidgetState::new(),
             debug_log_state: TuiWidgetState::new(),
             stats: Stats::default(),
+            notifications: Notifications::default(),
         }
     }
 }
\ No newline at end of file
@@ -3,10 +3,10 @@
 use crate::{
     cli as command_cli,
     console::{app::App, ui},
-    quic::client::run_network_tasks,
+    quic::client::{current_requests, run_network_tasks, SharedSession},
     rfs,
     setup::config::Config,
-    wsm::msg_id,
+    wsm::codec::WsmMessage,
 };
 use crossterm::{
     event::{self, Event, KeyCode, KeyModifiers},
@@ -40,16 +40,22 @@ pub async fn run_tui_client(cfg: Config) -> io::Result<()> {
 
     let stats_for_network = app.stats.clone();
     let stats_for_updater = app.stats.clone();
+    let notifications_for_network = app.notifications.clone();
 
-    let (tx, rx) = mpsc::channel::<Vec<u8>>(32);
+    let (tx, rx) = mpsc::channel::<WsmMessage>(32);
 
     // Create the shared context for the entire client session.
     let shared_context: rfs::SharedUploadContext = Arc::new(Mutex::new(None));
+    let shared_session: SharedSession = Arc::new(Mutex::new(None));
 
     // Stats updater task
+    let stats_session = shared_session.clone();
     tokio::spawn(async move {
         loop {
-            let count = msg_id::get_pool_size().await;
+            let count = match current_requests(&stats_session).await {
+                Some(requests) => requests.in_flight(),
+                None => 0,
+            };
             stats_for_updater
                 .pool_count
                 .store(count, Ordering::Relaxed);
@@ -59,45 +65,53 @@ pub async fn run_tui_client(cfg: Config) -> io::Result<()> {
 
     // Network task now gets the context.
     let network_tx = tx.clone();
-    let network_context = shared_context.clone();
+    let network_session = shared_session.clone();
     tokio::spawn(async move {
-        run_network_tasks(cfg, stats_for_network, network_tx, rx, network_context).await;
+        run_network_tasks(
+            cfg,
+            stats_for_network,
+            notifications_for_network,
+            network_tx,
+            rx,
+            network_session,
+        )
+        .await;
     });
 
     // Main UI loop
     while !app.should_quit {
         terminal.draw(|f| ui::draw(f, &app))?;
 
-        if event::poll(Duration::from_millis(250))? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                        app.should_quit = true;
-                    }
-                    KeyCode::Enter => {
-                        let command_tx = tx.clone();
-                        let input_to_process = app.input.clone();
-                        let command_context = shared_context.clone(); // Clone context for the command.
-                        app.input.clear();
+        if event::poll(Duration::from_millis(250))?
+            && let Event::Key(key) = event::read()?
+        {
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    app.should_quit = true;
+                }
+                KeyCode::Enter => {
+                    let input_to_process = app.input.clone();
+                    let command_context = shared_context.clone(); // Clone context for the command.
+                    let command_session = shared_session.clone();
+                    app.input.clear();
 
-                        tokio::spawn(async move {
-                            // Pass the context to the command dispatcher.
-                            command_cli::dispatch_command(
-                                &input_to_process,
-                                command_tx,
-                                command_context,
-                            )
-                            .await;
-                        });
-                    }
-                    KeyCode::Char(c) => {
-                        app.input.push(c);
-                    }
-                    KeyCode::Backspace => {
-                        app.input.pop();
-                    }
-                    _ => {}
+                    tokio::spawn(async move {
+                        // Pass the context to the command dispatcher.
+                        command_cli::dispatch_command(
+                            &input_to_process,
+                            command_context,
+                            command_session,
+                        )
+                        .await;
+                    });
+                }
+                KeyCode::Char(c) => {
+                    app.input.push(c);
+                }
+                KeyCode::Backspace => {
+                    app.input.pop();
                 }
+                _ => {}
             }
         }
     }
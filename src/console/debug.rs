@@ -3,7 +3,7 @@
 use crate::console::app::Stats;
 use std::sync::atomic::Ordering;
 
-fn format_bytes(bytes: u64) -> String {
+pub fn format_bytes(bytes: u64) -> String {
     const KIB: u64 = 1024;
     const MIB: u64 = KIB * 1024;
     const GIB: u64 = MIB * 1024;
@@ -3,4 +3,5 @@
 pub mod app;
 pub mod cli;
 pub mod ui;
-pub mod debug;
\ No newline at end of file
+pub mod debug;
+pub mod plain;
\ No newline at end of file
@@ -0,0 +1,62 @@
+/* src/console/plain.rs */
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Connection, protocol and event chatter; shown below warnings only at debug level.
+const CHATTY_TARGETS: &[&str] = &["anchr::quic", "anchr::wsm", "anchr::events"];
+
+// Errors logged so far, shown or not; a one-shot command failed if it logged any.
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Line-per-record logger for one-shot commands, which run without the TUI:
+/// errors and warnings go to stderr, the rest to stdout.
+struct PlainLogger {
+    level: LevelFilter,
+}
+
+impl Log for PlainLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let chatty =
+            !target.starts_with("anchr::") || CHATTY_TARGETS.iter().any(|t| target.starts_with(t));
+        let level = match chatty && self.level < LevelFilter::Debug {
+            true => self.level.min(LevelFilter::Warn),
+            false => self.level,
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Error {
+            ERRORS.fetch_add(1, Ordering::SeqCst);
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("{}", record.args()),
+            _ => println!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the plain logger at `log_level` (error, warn, info or debug).
+pub fn init(log_level: &str) -> Result<(), String> {
+    let level: LevelFilter = log_level
+        .parse()
+        .map_err(|_| format!("Invalid log level '{}'.", log_level))?;
+    // Errors are counted even when nothing is shown.
+    let level = level.max(LevelFilter::Error);
+    log::set_boxed_logger(Box::new(PlainLogger { level }))
+        .map_err(|e| format!("Cannot install logger: {}", e))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Errors logged since the logger was installed.
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::SeqCst)
+}
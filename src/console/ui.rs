@@ -47,7 +47,10 @@ pub fn draw(f: &mut Frame, app: &App) {
         .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
         .split(main_chunks[2]);
 
-    let status_bar = Paragraph::new(app.status.as_str()).style(Style::default().gray());
+    let status_bar = match app.notifications.current() {
+        Some(text) => Paragraph::new(format!("* {}", text)).style(Style::default().yellow()),
+        None => Paragraph::new(app.status.as_str()).style(Style::default().gray()),
+    };
     f.render_widget(status_bar, status_chunks[0]);
 
     let stats_text = debug::format_stats(&app.stats);
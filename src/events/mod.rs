@@ -0,0 +1,240 @@
+/* src/events/mod.rs */
+
+use crate::quic::acl::SharedIdentity;
+use crate::rfs::volume;
+use crate::setup::config::Permission;
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::error::{self, ErrorCode};
+use crate::wsm::header::PayloadType;
+use crate::wsm::hello::NegotiatedCaps;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::requests::{REQUEST_TIMEOUT, RequestTracker};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::{Mutex, broadcast, mpsc};
+
+// Events a subscriber may fall behind by before it starts missing some.
+const EVENT_BACKLOG: usize = 256;
+
+lazy_static! {
+    // Server-wide bus; any subsystem can publish, every connection forwards what its client subscribed to.
+    static ref EVENT_BUS: broadcast::Sender<ServerEvent> = broadcast::channel(EVENT_BACKLOG).0;
+}
+
+/// Groups of events a client can subscribe to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EventTopic {
+    Uploads,
+    Files,
+    Volumes,
+    Server,
+}
+
+impl EventTopic {
+    pub const ALL: &'static [EventTopic] = &[
+        EventTopic::Uploads,
+        EventTopic::Files,
+        EventTopic::Volumes,
+        EventTopic::Server,
+    ];
+
+    pub fn parse(name: &str) -> Option<EventTopic> {
+        EventTopic::ALL
+            .iter()
+            .copied()
+            .find(|t| format!("{:?}", t).eq_ignore_ascii_case(name))
+    }
+}
+
+/// Something that happened on the server, pushed to subscribers as an event (0x1E).
+/// Paths are virtual `/<dev_name>/...` paths. File changes below a watched directory
+/// (`rfs::watch`) reuse the `Files` variants but go only to the watching connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerEvent {
+    UploadFinalized { path: String, file_size: u64 },
+    FileCreated { path: String },
+    FileModified { path: String },
+    FileDeleted { path: String },
+    FileRenamed { from: String, to: String },
+    VolumeReadOnly { dev_name: String },
+    VolumeFull { dev_name: String, free_bytes: u64 },
+    ServerShutdown { reason: String },
+}
+
+impl ServerEvent {
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            ServerEvent::UploadFinalized { .. } => EventTopic::Uploads,
+            ServerEvent::FileCreated { .. }
+            | ServerEvent::FileModified { .. }
+            | ServerEvent::FileDeleted { .. }
+            | ServerEvent::FileRenamed { .. } => EventTopic::Files,
+            ServerEvent::VolumeReadOnly { .. } | ServerEvent::VolumeFull { .. } => {
+                EventTopic::Volumes
+            }
+            ServerEvent::ServerShutdown { .. } => EventTopic::Server,
+        }
+    }
+
+    /// Volume the event concerns, if any; only users who may list it receive the event.
+    pub fn dev_name(&self) -> Option<&str> {
+        match self {
+            ServerEvent::UploadFinalized { path, .. }
+            | ServerEvent::FileCreated { path }
+            | ServerEvent::FileModified { path }
+            | ServerEvent::FileDeleted { path }
+            | ServerEvent::FileRenamed { to: path, .. } => volume::dev_name_of(path),
+            ServerEvent::VolumeReadOnly { dev_name } | ServerEvent::VolumeFull { dev_name, .. } => {
+                Some(dev_name)
+            }
+            ServerEvent::ServerShutdown { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerEvent::UploadFinalized { path, file_size } => {
+                write!(f, "Upload finalized: {} ({} bytes)", path, file_size)
+            }
+            ServerEvent::FileCreated { path } => write!(f, "File created: {}", path),
+            ServerEvent::FileModified { path } => write!(f, "File modified: {}", path),
+            ServerEvent::FileDeleted { path } => write!(f, "File deleted: {}", path),
+            ServerEvent::FileRenamed { from, to } => write!(f, "File renamed: {} -> {}", from, to),
+            ServerEvent::VolumeReadOnly { dev_name } => {
+                write!(f, "Volume '{}' is now read-only", dev_name)
+            }
+            ServerEvent::VolumeFull {
+                dev_name,
+                free_bytes,
+            } => {
+                write!(
+                    f,
+                    "Volume '{}' is almost full ({} bytes free)",
+                    dev_name, free_bytes
+                )
+            }
+            ServerEvent::ServerShutdown { reason } => write!(f, "Server shutting down: {}", reason),
+        }
+    }
+}
+
+/// Payload of a subscribe request (0x1D). Replaces the previous subscription; no topics unsubscribes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subscription {
+    pub topics: Vec<EventTopic>,
+}
+
+/// Topics the client of one connection subscribed to.
+pub type SubscribedTopics = Arc<Mutex<HashSet<EventTopic>>>;
+
+/// [SERVER-SIDE] Publishes an event to every subscribed connection. Never blocks.
+pub fn publish(event: ServerEvent) {
+    // An error only means nobody is listening right now.
+    let _ = EVENT_BUS.send(event);
+}
+
+// [SERVER-SIDE] Handles the subscribe request (0x1D).
+pub async fn handle_subscribe_request(
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    topics: SubscribedTopics,
+) {
+    match msg.decode::<Subscription>() {
+        Ok(subscription) => {
+            *topics.lock().await = subscription.topics.into_iter().collect();
+            let response = WsmMessage::empty(Opcode::Ack, msg.message_id).with_final();
+            if tx.send(response).await.is_err() {
+                eprintln!("! WSM-Server: Failed to send subscribe 'ACK' response.");
+            }
+        }
+        Err(e) => {
+            error::send_error(
+                &tx,
+                msg.message_id,
+                ErrorCode::MalformedRequest,
+                format!("Invalid subscription: {}", e),
+            )
+            .await;
+        }
+    }
+}
+
+/// [SERVER-SIDE] Receives every event published from now on, until it falls
+/// `EVENT_BACKLOG` events behind.
+pub fn listen() -> broadcast::Receiver<ServerEvent> {
+    EVENT_BUS.subscribe()
+}
+
+/// [SERVER-SIDE] Pushes events from `events` matching the connection's subscription until the
+/// bus or the control channel closes. Events use the reserved message ID 0.
+pub async fn run_forwarder(
+    tx: mpsc::Sender<WsmMessage>,
+    topics: SubscribedTopics,
+    negotiated: NegotiatedCaps,
+    identity: SharedIdentity,
+    mut events: broadcast::Receiver<ServerEvent>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!("! Events: Client fell behind, dropped {} event(s).", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if !topics.lock().await.contains(&event.topic()) {
+            continue;
+        }
+        let visible = match (identity.lock().await.as_ref(), event.dev_name()) {
+            (Some(identity), Some(dev_name)) => identity.allows(dev_name, Permission::List),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if !visible {
+            continue;
+        }
+        let Some(encoding) = negotiated.lock().await.as_ref().map(|caps| caps.encoding()) else {
+            continue;
+        };
+        let message = match WsmMessage::encoded(Opcode::Event, 0, encoding, &event) {
+            Ok(message) => message.with_final(),
+            Err(e) => {
+                eprintln!("! Events: Dropped an event: {}", e);
+                continue;
+            }
+        };
+        if tx.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+// [CLIENT-SIDE] Replaces this connection's subscription (0x1D).
+pub async fn subscribe(
+    requests: &RequestTracker,
+    encoding: PayloadType,
+    topics: &[EventTopic],
+) -> Result<(), String> {
+    let subscription = Subscription {
+        topics: topics.to_vec(),
+    };
+    let request = WsmMessage::encoded(Opcode::Subscribe, 0, encoding, &subscription)?;
+    let reply = requests
+        .request(
+            |id| WsmMessage { message_id: id, ..request },
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    if reply.opcode != Opcode::Ack {
+        return Err(format!("Unexpected reply {} to subscribe.", reply.opcode));
+    }
+    Ok(())
+}
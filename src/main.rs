@@ -1,17 +1,25 @@
 /* src/main.rs */
 
+mod audit;
 mod cli;
 mod console;
+mod events;
+mod nbd;
 mod quic;
 mod setup;
 mod wsm;
 mod rfs;
 
+use crate::cli::oneshot::{self, OneShot};
 use crate::console::cli::run_tui_client;
+use crate::quic::auth::TokenHash;
+use setup::args::{self, Command, Invocation, EXIT_CONFIG, EXIT_FAILURE, EXIT_USAGE};
+use setup::check::{validate_client_config, validate_server_config};
 use setup::config::Config;
 use setup::gen_conf::generate_default_config;
 use std::env;
-use setup::check::validate_server_config;
+use std::path::Path;
+use std::process;
 
 #[tokio::main]
 async fn main() {
@@ -19,33 +27,174 @@ async fn main() {
         .install_default()
         .expect("Failed to install default CryptoProvider");
 
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let invocation = match args::parse(&args) {
+        Ok(invocation) => invocation,
+        Err(e) => {
+            match e.starts_with("Usage:") {
+                true => eprintln!("{}", e),
+                false => eprintln!("! {}", e),
+            }
+            process::exit(EXIT_USAGE);
+        }
+    };
+    process::exit(run(invocation).await);
+}
+
+// Maps a failed command to its exit code after reporting it.
+fn exit_code(result: Result<(), String>, code: i32) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("! {}", e);
+            code
+        }
+    }
+}
+
+// Loads the config with the command-line overrides applied.
+fn load_config(invocation: &Invocation) -> Result<Config, String> {
+    let path = invocation.config.as_deref().unwrap_or(args::DEFAULT_CONFIG);
+    let mut config = Config::from_file(path)?;
+    if let Some(level) = &invocation.log_level {
+        config.setup.log_level = level.clone();
+    }
+    Ok(config)
+}
 
-    if args.len() == 1 {
-        generate_default_config("anchr.toml");
-        println!("> Default config and certificate generated. Use '-c anchr.toml' to run.");
-        return;
+fn require_mode(config: &Config, mode: &str, command: &str) -> Result<(), String> {
+    if config.setup.mode == mode {
+        return Ok(());
     }
+    Err(format!(
+        "'anchr {}' needs a config with mode = \"{}\", not \"{}\".",
+        command, mode, config.setup.mode
+    ))
+}
 
-    if args.len() == 3 && args[1] == "-c" {
-        let config_path = &args[2];
-        let config = Config::from_file(config_path);
+async fn run(mut invocation: Invocation) -> i32 {
+    // First run: set up the config every later command reads by default.
+    let default_config = Path::new(args::DEFAULT_CONFIG);
+    if matches!(invocation.command, Command::Run)
+        && args::is_first_run(invocation.config.as_deref(), default_config)
+    {
+        invocation.command = Command::Init {
+            path: None,
+            force: false,
+        };
+    }
+    match &invocation.command {
+        Command::Help(usage) => {
+            println!("{}", usage);
+            return 0;
+        }
+        Command::Version => {
+            println!("anchr {}", env!("CARGO_PKG_VERSION"));
+            return 0;
+        }
+        Command::Init { path, force } => {
+            let path = path
+                .as_deref()
+                .or(invocation.config.as_deref())
+                .unwrap_or(args::DEFAULT_CONFIG);
+            let result = generate_default_config(path, *force);
+            if result.is_ok() {
+                println!("> Run 'anchr -c {} serve' to start the server.", path);
+            }
+            return exit_code(result, EXIT_FAILURE);
+        }
+        Command::HashToken(token) => {
+            let result = TokenHash::generate(token)
+                .map(|hash| println!("auth_token_hash = \"{}\"", hash));
+            return exit_code(result, EXIT_FAILURE);
+        }
+        Command::GenCert(cert_args) => {
+            let result = setup::cert::CertOptions::from_args(cert_args).and_then(|opts| {
+                setup::cert::generate_certificate(&opts)?;
+                println!(
+                    "+ Certificate '{}' and key '{}' generated.",
+                    opts.cert_path, opts.key_path
+                );
+                Ok(())
+            });
+            return exit_code(result, EXIT_FAILURE);
+        }
+        Command::Ca(ca_args) => return exit_code(setup::ca::run(ca_args), EXIT_FAILURE),
+        Command::Audit(audit_args) => {
+            return exit_code(audit::query::run(audit_args), EXIT_FAILURE);
+        }
+        _ => {}
+    }
 
-        if config.setup.mode == "server" {
-            // Perform validation before starting the server
-            if let Err(e) = validate_server_config(&config) {
+    // Everything else runs from a config.
+    let config = match load_config(&invocation) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("! {}", e);
+            return EXIT_CONFIG;
+        }
+    };
+    let command = match invocation.command {
+        Command::Run => match config.setup.mode.as_str() {
+            "server" => Command::Serve,
+            "client" => Command::Client,
+            mode => {
+                eprintln!("! Unknown mode \"{}\"; expected \"server\" or \"client\".", mode);
+                return EXIT_CONFIG;
+            }
+        },
+        command => command,
+    };
+    match command {
+        Command::Serve => {
+            let checked = require_mode(&config, "server", "serve")
+                .and_then(|_| validate_server_config(&config));
+            if let Err(e) = checked {
                 eprintln!("\n! {}", e);
                 eprintln!("! Server startup aborted due to configuration errors.");
-                std::process::exit(1);
+                return EXIT_CONFIG;
             }
-            quic::bootstrap::start_quic_server(config).await;
-        } else if config.setup.mode == "client" {
-            if let Err(e) = run_tui_client(config).await {
-                eprintln!("\nApplication Error: {}\n", e);
+            exit_code(quic::bootstrap::start_quic_server(config).await, EXIT_FAILURE)
+        }
+        Command::Client => {
+            if let Err(e) = require_mode(&config, "client", "client") {
+                eprintln!("! {}", e);
+                return EXIT_CONFIG;
+            }
+            match run_tui_client(config).await {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("\nApplication Error: {}\n", e);
+                    EXIT_FAILURE
+                }
+            }
+        }
+        Command::CheckConfig => {
+            let result = match config.setup.mode.as_str() {
+                "server" => validate_server_config(&config),
+                "client" => validate_client_config(&config),
+                mode => Err(format!(
+                    "Unknown mode \"{}\"; expected \"server\" or \"client\".",
+                    mode
+                )),
+            };
+            exit_code(result, EXIT_CONFIG)
+        }
+        Command::CertStatus | Command::Rfs(_) => {
+            let name = match &command {
+                Command::CertStatus => "cert status",
+                _ => "rfs",
+            };
+            if let Err(e) = require_mode(&config, "client", name) {
+                eprintln!("! {}", e);
+                return EXIT_CONFIG;
             }
+            let one_shot = match command {
+                Command::Rfs(rfs_args) => OneShot::Rfs(rfs_args),
+                _ => OneShot::CertStatus,
+            };
+            oneshot::run(config, one_shot).await
         }
-        return;
+        _ => unreachable!("handled before loading the config"),
     }
-
-    println!("! Invalid usage. Use '-c <config_path>' to run or no arguments to generate a default config.");
-}
\ No newline at end of file
+}
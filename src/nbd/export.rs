@@ -0,0 +1,254 @@
+/* src/nbd/export.rs */
+
+use crate::audit::{self, AuditEvent};
+use crate::nbd::*;
+use crate::quic::acl::Identity;
+use crate::rfs::volume;
+use crate::setup::config::Config;
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::error::{ErrorCode, ErrorReply};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use quinn::{RecvStream, SendStream};
+use std::io::SeekFrom;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use tokio::fs as tokio_fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::task;
+
+// Largest single READ/WRITE we accept, matching common NBD client limits.
+const MAX_REQUEST_LEN: u32 = 32 * 1024 * 1024;
+
+struct NbdCommand {
+    command: u16,
+    handle: u64,
+    offset: u64,
+    length: u32,
+}
+
+async fn open_export(
+    request: &NbdRequest,
+    cfg: &Config,
+    identity: &Identity,
+) -> Result<(tokio_fs::File, NbdExportInfo), ErrorReply> {
+    let rfs_config = volume::find_block_volume(cfg, &request.dev_name, identity)?;
+    let device_path = PathBuf::from(rfs_config.device.clone().unwrap_or_default());
+    let size_path = device_path.clone();
+    let storage_error = |what: String| ErrorReply::new(ErrorCode::StorageFailure, what);
+    let size = task::spawn_blocking(move || volume::device_size(&size_path))
+        .await
+        .map_err(|e| ErrorReply::new(ErrorCode::Internal, e.to_string()))?
+        .map_err(|e| storage_error(format!("Failed to read device size: {}", e)))?;
+    let file = tokio_fs::OpenOptions::new()
+        .read(true)
+        .write(!rfs_config.read_only)
+        .open(&device_path)
+        .await
+        .map_err(|e| storage_error(format!("Failed to open device: {}", e)))?;
+    Ok((
+        file,
+        NbdExportInfo {
+            size,
+            read_only: rfs_config.read_only,
+        },
+    ))
+}
+
+// Written unframed: once the export info is out, the stream stops carrying WSM messages.
+async fn send_wsm(send: &mut SendStream, message: WsmMessage) {
+    let _ = send.write_all(&message.with_final().to_bytes()).await;
+}
+
+/// [SERVER-SIDE] Serves an NBD export after its Hello (0x16) was read.
+/// Once the export info is sent the stream carries raw NBD transmission-phase traffic.
+pub async fn handle_nbd_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    cfg: Config,
+    request: NbdRequest,
+    encoding: PayloadType,
+    identity: Identity,
+) {
+    let (mut device, info) = match open_export(&request, &cfg, &identity).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("! NBD: Rejected export of '{}': {}", request.dev_name, e);
+            audit::record_for(
+                &identity,
+                AuditEvent::NbdRejected {
+                    dev_name: request.dev_name.clone(),
+                    reason: e.message.clone(),
+                },
+            );
+            send_wsm(&mut send, e.to_message(0)).await;
+            return;
+        }
+    };
+    match WsmMessage::encoded(Opcode::NbdExportInfo, 0, encoding, &info) {
+        Ok(message) => send_wsm(&mut send, message).await,
+        Err(e) => {
+            eprintln!("! NBD: {}", e);
+            send_wsm(&mut send, ErrorReply::new(ErrorCode::Internal, e).to_message(0)).await;
+            return;
+        }
+    }
+    audit::record_for(
+        &identity,
+        AuditEvent::NbdExported {
+            dev_name: request.dev_name.clone(),
+            size: info.size,
+            read_only: info.read_only,
+        },
+    );
+    println!(
+        "+ NBD: Exporting '{}' ({} bytes{}).",
+        request.dev_name,
+        info.size,
+        if info.read_only { ", read-only" } else { "" }
+    );
+
+    let mut request_buf = [0u8; 28];
+    while recv.read_exact(&mut request_buf).await.is_ok() {
+        let magic = u32::from_be_bytes(request_buf[0..4].try_into().unwrap());
+        if magic != NBD_REQUEST_MAGIC {
+            eprintln!("! NBD: Bad request magic {:#010x}. Closing export.", magic);
+            break;
+        }
+        let cmd = NbdCommand {
+            command: u16::from_be_bytes(request_buf[6..8].try_into().unwrap()),
+            handle: u64::from_be_bytes(request_buf[8..16].try_into().unwrap()),
+            offset: u64::from_be_bytes(request_buf[16..24].try_into().unwrap()),
+            length: u32::from_be_bytes(request_buf[24..28].try_into().unwrap()),
+        };
+        let keep_going = match cmd.command {
+            NBD_CMD_READ => handle_read(&cmd, &mut send, &mut device, &info).await,
+            NBD_CMD_WRITE => handle_write(&cmd, &mut recv, &mut send, &mut device, &info).await,
+            NBD_CMD_FLUSH => {
+                let error = if info.read_only || device.sync_data().await.is_ok() {
+                    0
+                } else {
+                    NBD_EIO
+                };
+                send_reply(&mut send, error, cmd.handle, &[]).await
+            }
+            NBD_CMD_TRIM => handle_trim(&cmd, &mut send, &mut device, &info).await,
+            NBD_CMD_DISC => false,
+            _ => send_reply(&mut send, NBD_EINVAL, cmd.handle, &[]).await,
+        };
+        if !keep_going {
+            break;
+        }
+    }
+
+    if !info.read_only {
+        device.sync_all().await.ok();
+    }
+    let _ = send.finish();
+    println!("- NBD: Export of '{}' closed.", request.dev_name);
+}
+
+async fn send_reply(send: &mut SendStream, error: u32, handle: u64, data: &[u8]) -> bool {
+    let mut reply = Vec::with_capacity(16 + data.len());
+    reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&error.to_be_bytes());
+    reply.extend_from_slice(&handle.to_be_bytes());
+    reply.extend_from_slice(data);
+    send.write_all(&reply).await.is_ok()
+}
+
+fn check_range(cmd: &NbdCommand, info: &NbdExportInfo) -> Option<u32> {
+    match cmd.offset.checked_add(cmd.length as u64) {
+        Some(end) if end <= info.size => None,
+        _ => Some(NBD_ENOSPC),
+    }
+}
+
+async fn handle_read(
+    cmd: &NbdCommand,
+    send: &mut SendStream,
+    device: &mut tokio_fs::File,
+    info: &NbdExportInfo,
+) -> bool {
+    if cmd.length > MAX_REQUEST_LEN {
+        return send_reply(send, NBD_EINVAL, cmd.handle, &[]).await;
+    }
+    if let Some(error) = check_range(cmd, info) {
+        return send_reply(send, error, cmd.handle, &[]).await;
+    }
+    let mut data = vec![0; cmd.length as usize];
+    let result = async {
+        device.seek(SeekFrom::Start(cmd.offset)).await?;
+        device.read_exact(&mut data).await
+    }
+    .await;
+    match result {
+        Ok(_) => send_reply(send, 0, cmd.handle, &data).await,
+        Err(e) => {
+            eprintln!("! NBD: Read at {} failed: {}", cmd.offset, e);
+            send_reply(send, NBD_EIO, cmd.handle, &[]).await
+        }
+    }
+}
+
+async fn handle_write(
+    cmd: &NbdCommand,
+    recv: &mut RecvStream,
+    send: &mut SendStream,
+    device: &mut tokio_fs::File,
+    info: &NbdExportInfo,
+) -> bool {
+    if cmd.length > MAX_REQUEST_LEN {
+        // The payload cannot be skipped safely, so the export is torn down.
+        eprintln!(
+            "! NBD: Write of {} bytes exceeds the request limit.",
+            cmd.length
+        );
+        return false;
+    }
+    // The payload must always be consumed to keep the stream in sync.
+    let mut data = vec![0; cmd.length as usize];
+    if recv.read_exact(&mut data).await.is_err() {
+        return false;
+    }
+    if info.read_only {
+        return send_reply(send, NBD_EPERM, cmd.handle, &[]).await;
+    }
+    if let Some(error) = check_range(cmd, info) {
+        return send_reply(send, error, cmd.handle, &[]).await;
+    }
+    let result = async {
+        device.seek(SeekFrom::Start(cmd.offset)).await?;
+        device.write_all(&data).await?;
+        // tokio completes writes in the background; flush surfaces their errors.
+        device.flush().await
+    }
+    .await;
+    match result {
+        Ok(_) => send_reply(send, 0, cmd.handle, &[]).await,
+        Err(e) => {
+            eprintln!("! NBD: Write at {} failed: {}", cmd.offset, e);
+            send_reply(send, NBD_EIO, cmd.handle, &[]).await
+        }
+    }
+}
+
+async fn handle_trim(
+    cmd: &NbdCommand,
+    send: &mut SendStream,
+    device: &mut tokio_fs::File,
+    info: &NbdExportInfo,
+) -> bool {
+    if info.read_only {
+        return send_reply(send, NBD_EPERM, cmd.handle, &[]).await;
+    }
+    if let Some(error) = check_range(cmd, info) {
+        return send_reply(send, error, cmd.handle, &[]).await;
+    }
+    // TRIM is advisory; a device that cannot discard still reports success.
+    if let Err(e) = device.flush().await {
+        eprintln!("! NBD: Flush before trim failed: {}", e);
+    }
+    volume::punch_hole(device.as_raw_fd(), cmd.offset, cmd.length as u64).ok();
+    send_reply(send, 0, cmd.handle, &[]).await
+}
@@ -0,0 +1,77 @@
+/* src/nbd/mod.rs */
+
+use serde::{Deserialize, Serialize};
+
+pub mod export;
+pub mod proxy;
+
+// Handshake magics (newstyle negotiation).
+pub const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+pub const NBD_IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+pub const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+
+// Handshake flags.
+pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+pub const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Transmission flags.
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+
+// Options.
+pub const NBD_OPT_EXPORT_NAME: u32 = 1;
+pub const NBD_OPT_ABORT: u32 = 2;
+pub const NBD_OPT_LIST: u32 = 3;
+pub const NBD_OPT_INFO: u32 = 6;
+pub const NBD_OPT_GO: u32 = 7;
+
+// Option replies.
+pub const NBD_REP_ACK: u32 = 1;
+pub const NBD_REP_SERVER: u32 = 2;
+pub const NBD_REP_INFO: u32 = 3;
+pub const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+pub const NBD_REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+pub const NBD_INFO_EXPORT: u16 = 0;
+
+// Transmission phase.
+pub const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+pub const NBD_CMD_READ: u16 = 0;
+pub const NBD_CMD_WRITE: u16 = 1;
+pub const NBD_CMD_DISC: u16 = 2;
+pub const NBD_CMD_FLUSH: u16 = 3;
+pub const NBD_CMD_TRIM: u16 = 4;
+
+// Error values carried in simple replies (Linux errno numbering).
+pub const NBD_EPERM: u32 = 1;
+pub const NBD_EIO: u32 = 5;
+pub const NBD_EINVAL: u32 = 22;
+pub const NBD_ENOSPC: u32 = 28;
+
+/// Sent in the NBD Hello (0x16) that opens an export stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NbdRequest {
+    pub dev_name: String,
+}
+
+/// Server reply (0x17) describing the export before the stream switches to raw NBD.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NbdExportInfo {
+    pub size: u64,
+    pub read_only: bool,
+}
+
+impl NbdExportInfo {
+    pub fn transmission_flags(&self) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH;
+        if self.read_only {
+            flags |= NBD_FLAG_READ_ONLY;
+        } else {
+            flags |= NBD_FLAG_SEND_TRIM;
+        }
+        flags
+    }
+}
@@ -0,0 +1,255 @@
+/* src/nbd/proxy.rs */
+
+use crate::nbd::*;
+use crate::quic::client::SharedSession;
+use crate::wsm::codec::{WsmCodec, WsmMessage};
+use crate::wsm::error::ErrorReply;
+use crate::wsm::opcode::Opcode;
+use log::{error, info, warn};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+
+// Upper bound for option payloads during the local handshake.
+const MAX_OPTION_LEN: u32 = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// Parses `tcp:<host:port>`, `unix:<path>` or a bare `<host:port>`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        if addr.rsplit_once(':').is_none() {
+            return Err(format!(
+                "Invalid listen address '{}'. Use tcp:<host:port> or unix:<path>.",
+                s
+            ));
+        }
+        Ok(ListenAddr::Tcp(addr.to_string()))
+    }
+}
+
+/// [CLIENT-SIDE] Accepts local NBD clients and tunnels each one over its own QUIC stream.
+pub async fn run_nbd_proxy(session: SharedSession, dev_name: String, listen: ListenAddr) {
+    match listen {
+        ListenAddr::Tcp(addr) => {
+            let listener = match TcpListener::bind(&addr).await {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("! NBD: Failed to listen on {}: {}", addr, e);
+                    return;
+                }
+            };
+            info!("+ NBD: Serving '{}' on tcp:{}", dev_name, addr);
+            while let Ok((stream, peer)) = listener.accept().await {
+                stream.set_nodelay(true).ok();
+                info!("> NBD: Local client {} connected.", peer);
+                spawn_session(stream, session.clone(), dev_name.clone());
+            }
+        }
+        ListenAddr::Unix(path) => {
+            // A stale socket from an earlier run would make bind fail.
+            std::fs::remove_file(&path).ok();
+            let listener = match UnixListener::bind(&path) {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("! NBD: Failed to listen on {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            info!("+ NBD: Serving '{}' on unix:{}", dev_name, path.display());
+            while let Ok((stream, _)) = listener.accept().await {
+                info!("> NBD: Local client connected on {}.", path.display());
+                spawn_session(stream, session.clone(), dev_name.clone());
+            }
+        }
+    }
+}
+
+fn spawn_session<S>(local: S, session: SharedSession, dev_name: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        match serve_local(local, session, &dev_name).await {
+            Ok(()) => info!("- NBD: Local session for '{}' closed.", dev_name),
+            Err(e) => warn!("! NBD: Session for '{}' ended: {}", dev_name, e),
+        }
+    });
+}
+
+async fn serve_local<S>(
+    mut local: S,
+    session: SharedSession,
+    dev_name: &str,
+) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let session = session
+        .lock()
+        .await
+        .clone()
+        .ok_or_else(|| "Not connected to a server.".to_string())?;
+    let (mut send, mut recv) = session
+        .connection
+        .open_bi()
+        .await
+        .map_err(|e| format!("Failed to open NBD stream: {}", e))?;
+
+    let hello = WsmMessage::encoded(
+        Opcode::NbdHello,
+        0,
+        session.caps.encoding(),
+        &NbdRequest {
+            dev_name: dev_name.to_string(),
+        },
+    )?;
+    send.write_all(&hello.to_bytes())
+        .await
+        .map_err(|e| format!("Failed to send NBD Hello: {}", e))?;
+
+    // Read unbuffered: everything after the export info is raw NBD traffic.
+    let reply = WsmCodec::control()
+        .read_one(&mut recv)
+        .await
+        .map_err(|e| format!("Failed to read NBD export info: {}", e))?;
+    let export = match reply.opcode {
+        Opcode::NbdExportInfo => reply
+            .decode::<NbdExportInfo>()
+            .map_err(|e| format!("Invalid NBD export info: {}", e))?,
+        Opcode::Error => {
+            return Err(format!(
+                "Server refused export: {}",
+                ErrorReply::from_message(&reply)
+            ));
+        }
+        op => return Err(format!("Unexpected reply {} to NBD Hello.", op)),
+    };
+
+    if !negotiate(&mut local, &export, dev_name)
+        .await
+        .map_err(|e| format!("Handshake failed: {}", e))?
+    {
+        return Ok(());
+    }
+    info!(
+        "> NBD: Transmission started for '{}' ({} bytes).",
+        dev_name, export.size
+    );
+
+    let mut remote = tokio::io::join(recv, send);
+    tokio::io::copy_bidirectional(&mut local, &mut remote)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn send_option_reply<S>(
+    local: &mut S,
+    option: u32,
+    reply_type: u32,
+    data: &[u8],
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut reply = Vec::with_capacity(20 + data.len());
+    reply.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&option.to_be_bytes());
+    reply.extend_from_slice(&reply_type.to_be_bytes());
+    reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    reply.extend_from_slice(data);
+    local.write_all(&reply).await
+}
+
+// Runs the fixed-newstyle handshake with a local tool.
+// Returns Ok(true) once transmission should begin, Ok(false) if the client aborted.
+async fn negotiate<S>(
+    local: &mut S,
+    export: &NbdExportInfo,
+    dev_name: &str,
+) -> std::io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let mut greeting = Vec::with_capacity(18);
+    greeting.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+    greeting.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    greeting.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+    local.write_all(&greeting).await?;
+
+    let client_flags = local.read_u32().await?;
+    let no_zeroes = client_flags & NBD_FLAG_C_NO_ZEROES != 0;
+    let name_matches = |name: &[u8]| name.is_empty() || name == dev_name.as_bytes();
+
+    loop {
+        if local.read_u64().await? != NBD_IHAVEOPT {
+            return Err(invalid("bad option magic"));
+        }
+        let option = local.read_u32().await?;
+        let len = local.read_u32().await?;
+        if len > MAX_OPTION_LEN {
+            return Err(invalid("option payload too large"));
+        }
+        let mut data = vec![0; len as usize];
+        local.read_exact(&mut data).await?;
+
+        match option {
+            NBD_OPT_EXPORT_NAME => {
+                if !name_matches(&data) {
+                    // This option has no error reply; closing is the only way to refuse.
+                    return Err(invalid("unknown export name"));
+                }
+                let mut reply = Vec::with_capacity(10 + 124);
+                reply.extend_from_slice(&export.size.to_be_bytes());
+                reply.extend_from_slice(&export.transmission_flags().to_be_bytes());
+                if !no_zeroes {
+                    reply.extend_from_slice(&[0; 124]);
+                }
+                local.write_all(&reply).await?;
+                return Ok(true);
+            }
+            NBD_OPT_ABORT => {
+                send_option_reply(local, option, NBD_REP_ACK, &[]).await?;
+                return Ok(false);
+            }
+            NBD_OPT_LIST => {
+                let mut entry = (dev_name.len() as u32).to_be_bytes().to_vec();
+                entry.extend_from_slice(dev_name.as_bytes());
+                send_option_reply(local, option, NBD_REP_SERVER, &entry).await?;
+                send_option_reply(local, option, NBD_REP_ACK, &[]).await?;
+            }
+            NBD_OPT_INFO | NBD_OPT_GO => {
+                let name_len = data
+                    .get(0..4)
+                    .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid("truncated info request"))?;
+                let name = data
+                    .get(4..4 + name_len)
+                    .ok_or_else(|| invalid("truncated export name"))?;
+                if !name_matches(name) {
+                    send_option_reply(local, option, NBD_REP_ERR_UNKNOWN, &[]).await?;
+                    continue;
+                }
+                let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend_from_slice(&export.size.to_be_bytes());
+                info.extend_from_slice(&export.transmission_flags().to_be_bytes());
+                send_option_reply(local, option, NBD_REP_INFO, &info).await?;
+                send_option_reply(local, option, NBD_REP_ACK, &[]).await?;
+                if option == NBD_OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => send_option_reply(local, option, NBD_REP_ERR_UNSUP, &[]).await?,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+/* src/quic/acl.rs */
+
+use crate::setup::config::{Config, Permission, UserConfig};
+use crate::wsm::error::{ErrorCode, ErrorReply};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// User that authenticates with the token in `[setup]`.
+pub const DEFAULT_USER: &str = "default";
+/// Key in a user's `volumes` table that applies to every volume.
+pub const ALL_VOLUMES: &str = "*";
+
+const NON_ADMIN: &[Permission] = &[
+    Permission::List,
+    Permission::Read,
+    Permission::Write,
+    Permission::Delete,
+];
+
+/// Who a connection authenticated as, from where, and what they may do on each volume.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub name: String,
+    // Set once the identity is bound to a connection; the audit log records it.
+    pub remote: Option<SocketAddr>,
+    grants: HashMap<String, HashSet<Permission>>,
+}
+
+impl Identity {
+    /// The setup token's user may do everything, raw access only with `setup.admin`.
+    pub fn default_user(cfg: &Config) -> Identity {
+        let mut permissions: HashSet<Permission> = NON_ADMIN.iter().copied().collect();
+        if cfg.setup.admin {
+            permissions.insert(Permission::Admin);
+        }
+        Identity {
+            name: DEFAULT_USER.to_string(),
+            remote: None,
+            grants: HashMap::from([(ALL_VOLUMES.to_string(), permissions)]),
+        }
+    }
+
+    pub fn from_config(user: &UserConfig) -> Identity {
+        Identity {
+            name: user.name.clone(),
+            remote: None,
+            grants: user
+                .volumes
+                .iter()
+                .map(|(dev_name, permissions)| {
+                    (dev_name.clone(), permissions.iter().copied().collect())
+                })
+                .collect(),
+        }
+    }
+
+    pub fn allows(&self, dev_name: &str, permission: Permission) -> bool {
+        [dev_name, ALL_VOLUMES].iter().any(|key| {
+            self.grants
+                .get(*key)
+                .is_some_and(|granted| includes(granted, permission))
+        })
+    }
+
+    /// Whether any volume grants `permission`.
+    pub fn holds_anywhere(&self, permission: Permission) -> bool {
+        self.grants
+            .values()
+            .any(|granted| includes(granted, permission))
+    }
+
+    pub fn require(&self, dev_name: &str, permission: Permission) -> Result<(), ErrorReply> {
+        if self.allows(dev_name, permission) {
+            return Ok(());
+        }
+        Err(ErrorReply::new(
+            ErrorCode::PermissionDenied,
+            format!(
+                "User '{}' lacks '{}' permission on '{}'.",
+                self.name, permission, dev_name
+            ),
+        ))
+    }
+}
+
+fn includes(granted: &HashSet<Permission>, permission: Permission) -> bool {
+    granted.contains(&permission) || granted.contains(&Permission::Admin)
+}
+
+/// Identity and stored credentials (token, token hash) of a configured user.
+/// An empty name selects the default user; users without a token are never found.
+pub fn find_user<'a>(
+    cfg: &'a Config,
+    name: &str,
+) -> Option<(Identity, &'a str, Option<&'a String>)> {
+    if name.is_empty() || name == DEFAULT_USER {
+        let setup = &cfg.setup;
+        if setup.auth_token.is_empty() && setup.auth_token_hash.is_none() {
+            return None;
+        }
+        return Some((
+            Identity::default_user(cfg),
+            &setup.auth_token,
+            setup.auth_token_hash.as_ref(),
+        ));
+    }
+    // Certificate-only users have no token to prove.
+    let has_token = |user: &&UserConfig| {
+        !user.auth_token.is_empty() || user.auth_token_hash.is_some()
+    };
+    cfg.users.iter().find(|user| user.name == name).filter(has_token).map(|user| {
+        (
+            Identity::from_config(user),
+            user.auth_token.as_str(),
+            user.auth_token_hash.as_ref(),
+        )
+    })
+}
+
+/// User a verified client certificate maps to, by SHA-256 fingerprint or subject common name.
+pub fn find_certificate_user(
+    cfg: &Config,
+    fingerprint: &str,
+    common_name: Option<&str>,
+) -> Option<Identity> {
+    cfg.users
+        .iter()
+        .find(|user| {
+            let by_fingerprint = user
+                .cert_fingerprint
+                .as_deref()
+                .is_some_and(|expected| normalize_fingerprint(expected) == fingerprint);
+            let by_subject =
+                user.cert_subject.is_some() && user.cert_subject.as_deref() == common_name;
+            by_fingerprint || by_subject
+        })
+        .map(Identity::from_config)
+}
+
+/// Accepts fingerprints with or without colons, in either case.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+/// Identity of one connection, set once its client authenticated.
+pub type SharedIdentity = Arc<Mutex<Option<Identity>>>;
@@ -1,81 +1,425 @@
 /* src/quic/auth.rs */
 
+use crate::audit::{self, AuditEvent, AuthMethod};
+use crate::quic::acl::{self, Identity};
+use crate::quic::resume;
+use crate::quic::service::ConnectionState;
 use crate::setup::config::Config;
+use crate::wsm::codec::WsmMessage;
 use crate::wsm::endpoints::AuthState;
-use crate::wsm::header::{PayloadType, WsmHeader, RESERVED_FINAL_FLAG, OPCODE_ERROR_FATAL};
+use crate::wsm::error::{self, ErrorCode, ErrorReply};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use lazy_static::lazy_static;
 use log::info;
-use quinn::RecvStream;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
+use tokio::task;
 
+// Iterations for newly hashed tokens; stored hashes carry their own count.
+pub const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+// Upper bound a client accepts from a challenge, so a server cannot stall it deriving the key.
+const MAX_ITERATIONS: u32 = 10_000_000;
+// TLS exporter label binding each proof to the connection it was made for.
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-anchr-auth";
+const PROOF_CONTEXT: &[u8] = b"anchr-auth-v1";
+// HMAC message turning the salted token into the client key, as in SCRAM.
+const CLIENT_KEY_LABEL: &[u8] = b"Client Key";
+
+lazy_static! {
+    // Keys derived from plaintext tokens on first use, by user, so clients do not re-derive them.
+    static ref DERIVED_TOKEN_HASHES: std::sync::Mutex<HashMap<String, TokenHash>> =
+        std::sync::Mutex::new(HashMap::new());
+    // Salts decoy challenges for unknown users, stable for the lifetime of the server.
+    static ref DECOY_SECRET: [u8; 32] = rand::random();
+}
+
+/// SCRAM-style verifier of the auth token, stored as
+/// `scram-sha256$<iterations>$<salt hex>$<stored key hex>`, where
+/// `ClientKey = HMAC(PBKDF2-SHA256(token, salt, iterations), "Client Key")` and
+/// `StoredKey = SHA-256(ClientKey)`. Only the client can produce ClientKey, so the
+/// stored key alone does not authenticate anyone.
+#[derive(Clone)]
+pub struct TokenHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+}
+
+impl TokenHash {
+    /// ClientKey for a token, the secret a proof shows possession of.
+    pub fn client_key(token: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>, String> {
+        let mut salted = vec![0u8; KEY_LEN];
+        pkcs5::pbkdf2_hmac(
+            token.as_bytes(),
+            salt,
+            iterations as usize,
+            MessageDigest::sha256(),
+            &mut salted,
+        )
+        .map_err(|e| format!("Failed to derive token hash: {}", e))?;
+        hmac_sha256(&salted, &[CLIENT_KEY_LABEL])
+    }
+
+    pub fn derive(token: &str, salt: &[u8], iterations: u32) -> Result<TokenHash, String> {
+        let client_key = TokenHash::client_key(token, salt, iterations)?;
+        Ok(TokenHash {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(&client_key).to_vec(),
+        })
+    }
+
+    /// Hashes a token with a fresh random salt.
+    pub fn generate(token: &str) -> Result<TokenHash, String> {
+        TokenHash::derive(token, &rand::random::<[u8; SALT_LEN]>(), PBKDF2_ITERATIONS)
+    }
+
+    pub fn parse(encoded: &str) -> Result<TokenHash, String> {
+        let parts: Vec<&str> = encoded.trim().split('$').collect();
+        let [scheme, iterations, salt, key] = parts.as_slice() else {
+            return Err("expected 'scram-sha256$<iterations>$<salt>$<stored key>'".to_string());
+        };
+        if *scheme != "scram-sha256" {
+            return Err(format!(
+                "unsupported hash scheme '{}'; run 'anchr hash-token' again",
+                scheme
+            ));
+        }
+        let iterations = iterations
+            .parse::<u32>()
+            .ok()
+            .filter(|i| *i > 0)
+            .ok_or_else(|| format!("invalid iteration count '{}'", iterations))?;
+        let salt = hex::decode(salt).map_err(|e| format!("invalid salt: {}", e))?;
+        let stored_key = hex::decode(key).map_err(|e| format!("invalid stored key: {}", e))?;
+        if stored_key.len() != KEY_LEN {
+            return Err(format!(
+                "stored key must be {} bytes, got {}",
+                KEY_LEN,
+                stored_key.len()
+            ));
+        }
+        Ok(TokenHash {
+            iterations,
+            salt,
+            stored_key,
+        })
+    }
+}
+
+impl fmt::Display for TokenHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "scram-sha256${}${}${}",
+            self.iterations,
+            hex::encode(&self.salt),
+            hex::encode(&self.stored_key)
+        )
+    }
+}
+
+/// Payload of the auth challenge (0x21): how to derive the key from the token, and the
+/// nonce to prove it with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthChallenge {
+    pub nonce: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+}
+
+/// Challenge the server issued on a connection and still waits to see answered.
+/// Decoy challenges for unknown users carry no identity and can never be answered.
+pub struct PendingChallenge {
+    // Name the client asked to authenticate as, for the audit log.
+    user: String,
+    nonce: Vec<u8>,
+    stored_key: Vec<u8>,
+    identity: Option<Identity>,
+}
+
+/// Keying material both peers derive from the TLS session; a proof made for one
+/// connection is useless on any other.
+pub fn channel_binding(conn: &Connection) -> Result<[u8; 32], String> {
+    let mut binding = [0u8; 32];
+    conn.export_keying_material(&mut binding, EXPORTER_LABEL, &[])
+        .map_err(|e| format!("TLS exporter unavailable: {:?}", e))?;
+    Ok(binding)
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, String> {
+    let pkey = PKey::hmac(key).map_err(|e| e.to_string())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).map_err(|e| e.to_string())?;
+    for part in parts {
+        signer.update(part).map_err(|e| e.to_string())?;
+    }
+    signer.sign_to_vec().map_err(|e| e.to_string())
+}
+
+// HMAC(StoredKey, auth-message); the auth message ties the proof to the nonce and connection.
+fn client_signature(stored_key: &[u8], nonce: &[u8], binding: &[u8]) -> Result<Vec<u8>, String> {
+    hmac_sha256(stored_key, &[PROOF_CONTEXT, nonce, binding])
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(x, y)| x ^ y).collect()
+}
+
+// The proof is ClientKey XOR ClientSignature: recover ClientKey and check it hashes to StoredKey.
+fn verify_proof(stored_key: &[u8], nonce: &[u8], binding: &[u8], proof: &[u8]) -> bool {
+    if proof.len() != KEY_LEN {
+        return false;
+    }
+    client_signature(stored_key, nonce, binding).is_ok_and(|signature| {
+        let client_key = xor(proof, &signature);
+        memcmp::eq(&Sha256::digest(&client_key), stored_key)
+    })
+}
+
+// The user's configured hash, or one derived once from their plaintext token.
+async fn user_token_hash(
+    user: &str,
+    token: &str,
+    hash: Option<&String>,
+) -> Result<TokenHash, String> {
+    if let Some(encoded) = hash {
+        return TokenHash::parse(encoded)
+            .map_err(|e| format!("Invalid auth_token_hash for '{}': {}", user, e));
+    }
+    if let Some(hash) = DERIVED_TOKEN_HASHES.lock().unwrap().get(user) {
+        return Ok(hash.clone());
+    }
+    // Derived off the runtime with the cache unlocked; if two first logins race, the
+    // hash stored first wins and the other challenge still carries its own.
+    let token = token.to_string();
+    let hash = task::spawn_blocking(move || TokenHash::generate(&token))
+        .await
+        .map_err(|e| format!("Token hash derivation failed: {}", e))??;
+    let mut cached = DERIVED_TOKEN_HASHES.lock().unwrap();
+    Ok(cached.entry(user.to_string()).or_insert(hash).clone())
+}
+
+// Iteration counts real challenges carry: one per user who can authenticate with a token.
+fn configured_iterations(cfg: &Config) -> Vec<u32> {
+    let names = std::iter::once(acl::DEFAULT_USER).chain(cfg.users.iter().map(|u| u.name.as_str()));
+    names
+        .filter_map(|name| acl::find_user(cfg, name))
+        .map(|(_, _, hash)| match hash {
+            Some(encoded) => TokenHash::parse(encoded).map_or(PBKDF2_ITERATIONS, |h| h.iterations),
+            None => PBKDF2_ITERATIONS,
+        })
+        .collect()
+}
+
+// Unknown users get a challenge that looks like a real one, so names cannot be probed:
+// a salt and an iteration count taken from a configured user, both stable per name.
+fn decoy_challenge(cfg: &Config, user: &str) -> (TokenHash, Option<Identity>) {
+    let mut hasher = Sha256::new();
+    hasher.update(&DECOY_SECRET[..]);
+    hasher.update(user.as_bytes());
+    let digest = hasher.finalize();
+    let counts = configured_iterations(cfg);
+    let iterations = match counts.is_empty() {
+        true => PBKDF2_ITERATIONS,
+        false => counts[digest[SALT_LEN] as usize % counts.len()],
+    };
+    let hash = TokenHash {
+        iterations,
+        salt: digest[..SALT_LEN].to_vec(),
+        stored_key: rand::random::<[u8; KEY_LEN]>().to_vec(),
+    };
+    (hash, None)
+}
+
+// [SERVER-SIDE] Handles the auth request (0x03). Without a pending challenge the payload
+// names the user (empty for the default user) and asks for a challenge; with one it is
+// the proof answering it. Returns false if the connection must be closed.
 pub async fn handle_auth_request(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
-    tx: mpsc::Sender<Vec<u8>>,
-    auth_state: Arc<Mutex<AuthState>>,
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    conn: &ConnectionState,
     cfg: &Config,
+    encoding: PayloadType,
 ) -> bool {
-    let mut token_buf = vec![0; header.payload_len as usize];
-    if recv.read_exact(&mut token_buf).await.is_err() {
-        return false;
-    }
+    // Each challenge answers exactly one proof.
+    let Some(challenge) = conn.challenge.lock().await.take() else {
+        let user = msg.text();
+        if let Some(identity) = &conn.cert_identity
+            && (user.is_empty() || user == identity.name)
+        {
+            println!("  -> WSM: Client authenticated by certificate as '{}'.", identity.name);
+            accept(msg, &tx, conn, cfg, identity.clone(), AuthMethod::Certificate).await;
+            return true;
+        }
+        if !cfg.setup.token_auth {
+            println!("  -> WSM: Client authentication failed (no client certificate).");
+            reject(conn, &user, "no client certificate");
+            error::send_error(
+                &tx,
+                msg.message_id,
+                ErrorCode::AuthFailed,
+                "Token authentication is disabled; a client certificate is required",
+            )
+            .await;
+            return false;
+        }
+        let (hash, identity) = match acl::find_user(cfg, &user) {
+            Some((identity, token, hash)) => match user_token_hash(&identity.name, token, hash)
+                .await
+            {
+                Ok(hash) => (hash, Some(identity)),
+                Err(e) => {
+                    eprintln!("! WSM: {}", e);
+                    reject(conn, &user, "authentication is misconfigured");
+                    error::send_error(
+                        &tx,
+                        msg.message_id,
+                        ErrorCode::Internal,
+                        "Authentication is misconfigured",
+                    )
+                    .await;
+                    return false;
+                }
+            },
+            None => decoy_challenge(cfg, &user),
+        };
+        let nonce = rand::random::<[u8; NONCE_LEN]>().to_vec();
+        let challenge = AuthChallenge {
+            nonce: nonce.clone(),
+            salt: hash.salt.clone(),
+            iterations: hash.iterations,
+        };
+        let response =
+            match WsmMessage::encoded(Opcode::AuthChallenge, msg.message_id, encoding, &challenge) {
+                Ok(response) => response.with_final(),
+                Err(e) => {
+                    eprintln!("! WSM: {}", e);
+                    error::send_error(&tx, msg.message_id, ErrorCode::Internal, e).await;
+                    return false;
+                }
+            };
+        *conn.challenge.lock().await = Some(PendingChallenge {
+            user,
+            nonce,
+            stored_key: hash.stored_key,
+            identity,
+        });
+        return tx.send(response).await.is_ok();
+    };
 
-    let received_token = String::from_utf8_lossy(&token_buf);
-    let expected_token = &cfg.setup.auth_token;
-
-    if received_token == *expected_token {
-        println!("  -> WSM: Client authenticated successfully.");
-        let mut state = auth_state.lock().await;
-        *state = AuthState::Authenticated;
-        let response_header = WsmHeader::with_reserved(
-            0x00,
-            header.message_id,
-            PayloadType::Raw,
-            0,
-            RESERVED_FINAL_FLAG,
-        );
-        let _ = tx.send(response_header.to_bytes().to_vec()).await;
+    let verified = verify_proof(
+        &challenge.stored_key,
+        &challenge.nonce,
+        &conn.auth_binding,
+        &msg.payload,
+    );
+
+    if let (true, Some(identity)) = (verified, challenge.identity) {
+        println!("  -> WSM: Client authenticated successfully as '{}'.", identity.name);
+        accept(msg, &tx, conn, cfg, identity, AuthMethod::Token).await;
         true
     } else {
-        println!("  -> WSM: Client authentication failed (token mismatch).");
-        let reason = "Invalid authentication token".as_bytes();
-        let response_header = WsmHeader::with_reserved(
-            0x00,
-            header.message_id,
-            PayloadType::Raw,
-            reason.len() as u32,
-            RESERVED_FINAL_FLAG,
-        );
-        let mut response = response_header.to_bytes().to_vec();
-        response.extend_from_slice(reason);
-        let _ = tx.send(response).await;
+        println!("  -> WSM: Client authentication failed (proof mismatch).");
+        reject(conn, &challenge.user, "invalid token");
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::AuthFailed,
+            "Invalid authentication token",
+        )
+        .await;
         false
     }
 }
 
+/// [SERVER-SIDE] Binds `identity` to the connection, acknowledges the request that proved it,
+/// and offers a token to resume the session with later.
+pub async fn accept(
+    msg: &WsmMessage,
+    tx: &mpsc::Sender<WsmMessage>,
+    conn: &ConnectionState,
+    cfg: &Config,
+    mut identity: Identity,
+    method: AuthMethod,
+) {
+    identity.remote = Some(conn.remote);
+    audit::record_for(&identity, AuditEvent::AuthSucceeded { method });
+    *conn.identity.lock().await = Some(identity.clone());
+    *conn.auth_state.lock().await = AuthState::Authenticated;
+    let response = WsmMessage::empty(Opcode::Ack, msg.message_id).with_final();
+    let _ = tx.send(response).await;
+    resume::offer(tx, conn, cfg, &identity).await;
+}
+
+// Audits a failed attempt; `user` is whoever the client claimed to be, if anyone.
+fn reject(conn: &ConnectionState, user: &str, reason: &str) {
+    let user = if user.is_empty() { acl::DEFAULT_USER } else { user };
+    let reason = reason.to_string();
+    audit::record(Some(conn.remote), Some(user), AuditEvent::AuthFailed { reason });
+}
+
+/// [CLIENT-SIDE] Asks the server for an auth challenge (0x03) for `user`, or for the
+/// default user when none is configured.
+pub fn build_challenge_request(user: Option<&str>) -> WsmMessage {
+    let name = user.unwrap_or_default().as_bytes().to_vec();
+    WsmMessage::new(Opcode::Auth, 0, PayloadType::Raw, name)
+}
+
+/// [CLIENT-SIDE] Answers an auth challenge (0x21) with a proof of the token; the token
+/// itself never leaves the client.
+pub fn answer_challenge(
+    msg: &WsmMessage,
+    token: &str,
+    binding: &[u8],
+) -> Result<WsmMessage, String> {
+    let challenge = msg
+        .decode::<AuthChallenge>()
+        .map_err(|e| format!("Malformed auth challenge: {}", e))?;
+    if challenge.nonce.len() != NONCE_LEN || !(1..=MAX_ITERATIONS).contains(&challenge.iterations) {
+        return Err("Auth challenge has invalid parameters.".to_string());
+    }
+    let client_key = TokenHash::client_key(token, &challenge.salt, challenge.iterations)?;
+    let stored_key = Sha256::digest(&client_key);
+    let signature = client_signature(&stored_key, &challenge.nonce, binding)?;
+    let proof = xor(&client_key, &signature);
+    Ok(WsmMessage::new(Opcode::Auth, msg.message_id, PayloadType::Raw, proof))
+}
+
 pub async fn handle_auth_response(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
+    msg: &WsmMessage,
     auth_state: Arc<Mutex<AuthState>>,
     stop_reconnecting: Arc<AtomicBool>,
 ) -> bool {
-    if header.is_final() {
-        if header.payload_len == 0 {
+    if msg.opcode == Opcode::Error {
+        info!("! WSM: Authentication failed. {}", ErrorReply::from_message(msg));
+        stop_reconnecting.store(true, Ordering::SeqCst);
+        return false;
+    }
+    if msg.is_final() {
+        if msg.payload.is_empty() {
             // FIX: Use info! macro to log to the client's TUI
             info!("WSM: Authentication successful.");
             let mut state = auth_state.lock().await;
             *state = AuthState::Authenticated;
             return true;
         } else {
-            let mut reason_buf = vec![0; header.payload_len as usize];
-            if recv.read_exact(&mut reason_buf).await.is_ok() {
-                let reason = String::from_utf8_lossy(&reason_buf);
-                // This is also a client-side print, so it should be a log macro
-                info!("! WSM: Authentication failed. Reason: {}", reason);
-            } else {
-                info!("! WSM: Authentication failed. Could not read reason.");
-            }
+            // This is also a client-side print, so it should be a log macro
+            info!("! WSM: Authentication failed. Reason: {}", msg.text());
             stop_reconnecting.store(true, Ordering::SeqCst);
             return false;
         }
@@ -83,16 +427,10 @@ pub async fn handle_auth_response(
     true
 }
 
-pub async fn send_unauthorized_response(msg_id: u8, tx: mpsc::Sender<Vec<u8>>) {
+pub async fn send_unauthorized_response(msg_id: u32, tx: mpsc::Sender<WsmMessage>) {
     let reason = "Unauthenticated".as_bytes();
-    let header = WsmHeader::with_reserved(
-        OPCODE_ERROR_FATAL,
-        msg_id,
-        PayloadType::Raw,
-        reason.len() as u32,
-        RESERVED_FINAL_FLAG,
-    );
-    let mut response = header.to_bytes().to_vec();
-    response.extend_from_slice(reason);
+    let response = WsmMessage::new(Opcode::ErrorFatal, msg_id, PayloadType::Raw, reason.to_vec())
+        .with_final()
+        .v1(); // The peer may not have negotiated v2 framing yet.
     let _ = tx.send(response).await;
-}
\ No newline at end of file
+}
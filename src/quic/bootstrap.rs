@@ -1,47 +1,109 @@
 /* src/quic/bootstrap.rs */
 
-use crate::{quic::service, setup::config::Config};
+use crate::{
+    audit::{self, AuditEvent},
+    events::{self, ServerEvent},
+    quic::{cert_monitor, guard, service, tls},
+    rfs::{scrub, volume},
+    setup::config::Config,
+};
 use quinn::{Endpoint, ServerConfig, TransportConfig};
-use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-pub async fn start_quic_server(cfg: Config) {
-    let certs = rustls_pemfile::certs(&mut BufReader::new(
-        File::open(&cfg.setup.certificate).unwrap(),
-    ))
-    .map(|res| res.unwrap())
-    .collect();
-
-    let key = rustls_pemfile::private_key(&mut BufReader::new(
-        File::open(&cfg.setup.private_key).unwrap(),
-    ))
-    .unwrap()
-    .expect("Failed to find private key");
+// Time between announcing a shutdown and closing every connection.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
 
+/// [SERVER-SIDE] TLS and transport config for new connections; rebuilt when the
+/// certificates change on disk.
+pub fn build_server_config(cfg: &Config) -> Result<ServerConfig, String> {
     let mut transport = TransportConfig::default();
     // MODIFIED: Increased stream limit to support 1 control stream + 16 worker streams
     transport.max_concurrent_bidi_streams(20u32.into());
     transport.keep_alive_interval(Some(Duration::from_secs(5)));
 
-    let mut server_config = ServerConfig::with_single_cert(certs, key).unwrap();
+    let mut server_config = tls::build_server_config(cfg)?;
     server_config.transport = Arc::new(transport);
+    Ok(server_config)
+}
 
+/// [SERVER-SIDE] Serves until interrupted; fails only if the server cannot start.
+pub async fn start_quic_server(cfg: Config) -> Result<(), String> {
+    let server_config =
+        build_server_config(&cfg).map_err(|e| format!("TLS setup failed: {}", e))?;
+    audit::init(&cfg.audit).map_err(|e| format!("Audit setup failed: {}", e))?;
+    if let Some(status) = cert_monitor::refresh_status(&cfg) {
+        // Clients can pin this with server_cert_sha256.
+        println!("> Server certificate SHA-256: {}", status.fingerprint);
+        println!("> Server certificate valid until {}.", status.not_after);
+    }
+    if cfg.setup.client_ca.is_some() {
+        println!(
+            "> Client certificates are {}.",
+            if cfg.setup.require_client_cert { "required" } else { "verified when presented" }
+        );
+    }
     let addr: SocketAddr = format!("{}:{}", cfg.network.listen, cfg.network.port)
         .parse()
-        .unwrap();
+        .map_err(|e| format!("Invalid listen address '{}': {}", cfg.network.listen, e))?;
 
-    let endpoint = Endpoint::server(server_config, addr).unwrap();
+    let endpoint = Endpoint::server(server_config, addr)
+        .map_err(|e| format!("Cannot listen on {}: {}", addr, e))?;
     println!("> QUIC server running on {}", addr);
 
-    while let Some(connecting) = endpoint.accept().await {
+    tokio::spawn(volume::run_binding_monitor(cfg.clone()));
+    tokio::spawn(volume::run_health_monitor(cfg.clone()));
+    tokio::spawn(scrub::run_scrubber(cfg.clone()));
+    tokio::spawn(cert_monitor::run_cert_monitor(cfg.clone(), endpoint.clone()));
+
+    let server_state = service::ServerState::default();
+    loop {
+        let connecting = tokio::select! {
+            connecting = endpoint.accept() => match connecting {
+                Some(c) => c,
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                println!("> Shutting down; notifying connected clients...");
+                events::publish(ServerEvent::ServerShutdown {
+                    reason: "interrupted by operator".to_string(),
+                });
+                // Give the forwarders a moment to push the notice before connections close.
+                tokio::time::sleep(SHUTDOWN_GRACE).await;
+                endpoint.close(0u32.into(), b"server shutdown");
+                endpoint.wait_idle().await;
+                break;
+            }
+        };
+        // Refused before the handshake, so rejected clients cost no crypto.
+        let source = guard::source_of(connecting.remote_address().ip());
+        if let Err(reason) = guard::admit(&cfg.network, source) {
+            println!("! Guard: Refusing {}: {}.", source, reason);
+            audit::record(Some(connecting.remote_address()), None, AuditEvent::Refused { reason });
+            connecting.refuse();
+            continue;
+        }
+        let Some(slot) = guard::UnauthenticatedSlot::acquire(cfg.network.max_unauthenticated)
+        else {
+            println!(
+                "! Guard: Refusing {}: {} connections are already authenticating.",
+                source, cfg.network.max_unauthenticated
+            );
+            let reason = "too many connections authenticating".to_string();
+            audit::record(Some(connecting.remote_address()), None, AuditEvent::Refused { reason });
+            connecting.refuse();
+            continue;
+        };
         let server_cfg = cfg.clone();
+        let state = server_state.clone();
         tokio::spawn(async move {
             match connecting.await {
                 Ok(conn) => {
                     println!("+ New connection from {}", conn.remote_address());
-                    service::handle_connection(conn, server_cfg).await;
+                    service::handle_connection(conn, server_cfg, state, slot).await;
                 }
                 Err(e) => println!("! Connection failed: {}", e),
             }
         });
     }
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,263 @@
+/* src/quic/cert_monitor.rs */
+
+use crate::quic::acl::Identity;
+use crate::quic::{bootstrap, tls};
+use crate::setup::config::{Config, Permission};
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::error::{self, ErrorCode};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::requests::{REQUEST_TIMEOUT, RequestTracker};
+use lazy_static::lazy_static;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::X509;
+use quinn::Endpoint;
+use rustls::pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time;
+
+// Time between checks of the certificate files and the remaining validity.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Renewal tools may write the certificate and key one after the other.
+const RELOAD_SETTLE: Duration = Duration::from_secs(2);
+// Days before expiry at which a warning is logged, each once.
+const EXPIRY_WARNING_DAYS: &[i64] = &[30, 14, 7, 3, 1];
+const SECS_PER_DAY: i64 = 86_400;
+
+lazy_static! {
+    // Certificate currently offered to new connections.
+    static ref CERT_STATUS: RwLock<Option<CertStatus>> = RwLock::new(None);
+}
+
+/// Reply to the certificate status query (0x23).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CertStatus {
+    pub subject: String,
+    pub fingerprint: String,
+    pub not_after: String,
+    // Unix time the certificate expires at.
+    pub expires_at: i64,
+    // Unix time the server started serving it.
+    pub loaded_at: u64,
+}
+
+impl CertStatus {
+    /// Seconds of validity left; negative once expired.
+    pub fn remaining_secs(&self) -> i64 {
+        self.expires_at - now_secs() as i64
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn unix_time(time: &Asn1TimeRef) -> Result<i64, String> {
+    let epoch = Asn1Time::from_unix(0).map_err(|e| e.to_string())?;
+    let diff = epoch.diff(time).map_err(|e| e.to_string())?;
+    Ok(diff.days as i64 * SECS_PER_DAY + diff.secs as i64)
+}
+
+fn read_status(path: &str) -> Result<CertStatus, String> {
+    let pem = fs::read(path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+    let cert = X509::from_pem(&pem).map_err(|e| format!("Invalid certificate: {}", e))?;
+    let der = cert.to_der().map_err(|e| e.to_string())?;
+    let subject = cert
+        .subject_name()
+        .entries()
+        .map(|entry| {
+            let field = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            format!("{}={}", field, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(CertStatus {
+        subject,
+        fingerprint: tls::fingerprint(&CertificateDer::from(der)),
+        not_after: cert.not_after().to_string(),
+        expires_at: unix_time(cert.not_after())?,
+        loaded_at: now_secs(),
+    })
+}
+
+/// [SERVER-SIDE] Records the certificate new connections are served with.
+pub fn refresh_status(cfg: &Config) -> Option<CertStatus> {
+    match read_status(&cfg.setup.certificate) {
+        Ok(status) => {
+            *CERT_STATUS.write().unwrap() = Some(status.clone());
+            Some(status)
+        }
+        Err(e) => {
+            eprintln!("! TLS: Cannot read server certificate validity: {}", e);
+            None
+        }
+    }
+}
+
+// Modification times of every file the TLS config is built from.
+fn file_stamps(cfg: &Config) -> Vec<Option<SystemTime>> {
+    let setup = &cfg.setup;
+    [
+        Some(&setup.certificate),
+        Some(&setup.private_key),
+        setup.client_ca.as_ref(),
+        setup.client_crl.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+// Logs once per threshold as expiry approaches, and on every check once expired.
+fn check_expiry(warned: &mut Option<i64>) {
+    let Some(status) = CERT_STATUS.read().unwrap().clone() else {
+        return;
+    };
+    let remaining = status.remaining_secs();
+    if remaining <= 0 {
+        eprintln!(
+            "! TLS: Server certificate EXPIRED on {}; clients will refuse to connect.",
+            status.not_after
+        );
+        return;
+    }
+    let threshold = EXPIRY_WARNING_DAYS
+        .iter()
+        .copied()
+        .filter(|days| remaining <= days * SECS_PER_DAY)
+        .min();
+    if threshold.is_some() && threshold != *warned {
+        eprintln!(
+            "! TLS: Server certificate expires in {} day(s), on {}. Renew it; the server reloads it without a restart.",
+            remaining / SECS_PER_DAY,
+            status.not_after
+        );
+        *warned = threshold;
+    }
+}
+
+/// [SERVER-SIDE] Swaps in a fresh TLS config whenever the certificate, key, client CA or CRL
+/// changes on disk. Only new connections use it; established ones keep their session.
+pub async fn run_cert_monitor(cfg: Config, endpoint: Endpoint) {
+    let mut stamps = file_stamps(&cfg);
+    let mut warned = None;
+    loop {
+        check_expiry(&mut warned);
+        time::sleep(CERT_CHECK_INTERVAL).await;
+        if file_stamps(&cfg) == stamps {
+            continue;
+        }
+        time::sleep(RELOAD_SETTLE).await;
+        match bootstrap::build_server_config(&cfg) {
+            Ok(server_config) => {
+                endpoint.set_server_config(Some(server_config));
+                stamps = file_stamps(&cfg);
+                warned = None;
+                if let Some(status) = refresh_status(&cfg) {
+                    println!(
+                        "+ TLS: Reloaded certificates; new connections get {} (valid until {}).",
+                        status.fingerprint, status.not_after
+                    );
+                }
+            }
+            // The stamps stay stale, so the next check retries.
+            Err(e) => eprintln!(
+                "! TLS: Reload failed, still serving the previous certificate: {}",
+                e
+            ),
+        }
+    }
+}
+
+// [SERVER-SIDE] Handles the certificate status query (0x22).
+pub async fn handle_status_request(
+    message_id: u32,
+    tx: mpsc::Sender<WsmMessage>,
+    encoding: PayloadType,
+    identity: &Identity,
+) {
+    if !identity.holds_anywhere(Permission::Admin) {
+        eprintln!(
+            "! WSM-Server: Refusing certificate status to non-admin user '{}'.",
+            identity.name
+        );
+        error::send_error(
+            &tx,
+            message_id,
+            ErrorCode::PermissionDenied,
+            "Certificate status requires admin privileges.",
+        )
+        .await;
+        return;
+    }
+    let Some(status) = CERT_STATUS.read().unwrap().clone() else {
+        error::send_error(
+            &tx,
+            message_id,
+            ErrorCode::Internal,
+            "Certificate status unavailable",
+        )
+        .await;
+        return;
+    };
+    let encoded = WsmMessage::encoded(Opcode::CertStatusResponse, message_id, encoding, &status);
+    let response = match encoded {
+        Ok(response) => response.with_final(),
+        Err(e) => {
+            eprintln!("! Cert: {}", e);
+            error::send_error(&tx, message_id, ErrorCode::Internal, e).await;
+            return;
+        }
+    };
+    if tx.send(response).await.is_err() {
+        eprintln!("! WSM-Server: Failed to send certificate status to channel.");
+    }
+}
+
+// [CLIENT-SIDE] Requests the certificate status (0x22) and waits for the response (0x23).
+pub async fn fetch_status(requests: &RequestTracker) -> Result<CertStatus, String> {
+    let reply = requests
+        .request(
+            |id| WsmMessage::empty(Opcode::CertStatusRequest, id),
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    if reply.opcode != Opcode::CertStatusResponse {
+        return Err(format!("Unexpected reply {} to cert.", reply.opcode));
+    }
+    reply
+        .decode::<CertStatus>()
+        .map_err(|e| format!("Failed to deserialize certificate status: {}", e))
+}
+
+/// Renders the certificate status for the log panel.
+pub fn format_status(status: &CertStatus) -> String {
+    let remaining = status.remaining_secs();
+    let validity = if remaining > 0 {
+        format!(
+            "{} day(s) {} hour(s) left",
+            remaining / SECS_PER_DAY,
+            remaining % SECS_PER_DAY / 3600
+        )
+    } else {
+        "EXPIRED".to_string()
+    };
+    format!(
+        "Server Certificate:\n  Subject: {}\n  SHA-256: {}\n  Valid until: {} ({})",
+        status.subject, status.fingerprint, status.not_after, validity
+    )
+}
@@ -1,19 +1,23 @@
 /* src/quic/client.rs */
 
-use crate::console::app::Stats;
-use crate::quic::keepalive;
-use crate::rfs::{SharedUploadContext};
+use crate::console::app::{Notifications, Stats};
+use crate::events::{self, EventTopic};
+use crate::quic::resume::{self, ResumeToken};
+use crate::quic::{auth, keepalive, tls};
+use crate::rfs::watch;
 use crate::setup::config::Config;
-use crate::wsm::endpoints::{self, AuthState, InFlightPings};
-use crate::wsm::header::{PayloadType, WsmHeader};
-use crate::wsm::msg_id;
+use crate::wsm::codec::{self, WsmCodec, WsmMessage, WsmReader};
+use crate::wsm::endpoints::{self, AuthState};
+use crate::wsm::error::ErrorReply;
+use crate::wsm::header::PayloadType;
+use crate::wsm::hello::{self, Capabilities, HelloOutcome};
+use crate::wsm::opcode::Opcode;
+use crate::wsm::payload;
+use crate::wsm::requests::{RequestError, RequestTracker};
+use futures::{SinkExt, StreamExt};
 use log::{debug, error, info, warn};
-use quinn::{ClientConfig, Endpoint};
-use rustls::{ClientConfig as RustlsClientConfig, RootCertStore};
-use std::collections::HashMap;
+use quinn::{Connection, Endpoint};
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::ops::ControlFlow;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -23,19 +27,45 @@ use tokio::sync::{mpsc, Mutex};
 use tokio::task::JoinHandle;
 use tokio::time;
 
+/// Per-connection client state: the connection, what was negotiated on it,
+/// and the requests outstanding on it.
+#[derive(Clone)]
+pub struct ClientSession {
+    pub connection: Connection,
+    pub caps: Capabilities,
+    pub requests: RequestTracker,
+}
+
+// Set once authenticated, for commands that open their own streams or gate on features.
+pub type SharedSession = Arc<Mutex<Option<ClientSession>>>;
+
 pub async fn run_network_tasks(
     cfg: Config,
     stats: Stats,
-    tx: mpsc::Sender<Vec<u8>>,
-    rx: mpsc::Receiver<Vec<u8>>,
-    context: SharedUploadContext, // Receive context from the TUI entry point
+    notifications: Notifications,
+    tx: mpsc::Sender<WsmMessage>,
+    rx: mpsc::Receiver<WsmMessage>,
+    shared_session: SharedSession,
 ) {
     info!("Network task starting...");
-    let mut first_failure_time: Option<Instant> = None;
     let stop_reconnecting = Arc::new(AtomicBool::new(false));
 
     let rx_arc = Arc::new(Mutex::new(rx));
 
+    // One endpoint for every attempt: its TLS config keeps the session tickets reconnects
+    // resume with.
+    let reconnect = match client_endpoint(&cfg) {
+        Ok(endpoint) => Reconnect {
+            endpoint,
+            stop: stop_reconnecting.clone(),
+            resume_token: ResumeToken::default(),
+        },
+        Err(e) => {
+            error!("! Cannot set up the client endpoint: {}", e);
+            return;
+        }
+    };
+
     loop {
         if stop_reconnecting.load(Ordering::SeqCst) {
             error!("Halting reconnection attempts due to fatal error.");
@@ -43,16 +73,18 @@ pub async fn run_network_tasks(
         }
 
         info!("Attempting to connect to the server...");
-        match connect_and_run(
+        let result = connect_and_run(
+            &reconnect,
             &cfg,
-            stop_reconnecting.clone(),
             stats.clone(),
+            &notifications,
             tx.clone(),
             Arc::clone(&rx_arc),
-            context.clone(), // Pass the context down to the connection handler
+            shared_session.clone(),
         )
-        .await
-        {
+        .await;
+        *shared_session.lock().await = None;
+        match result {
             Ok(_) => {
                 info!("Connection closed gracefully. Exiting network task.");
                 break;
@@ -63,210 +95,354 @@ pub async fn run_network_tasks(
                     break;
                 }
                 warn!("Connection error: {}. Retrying in 3 seconds...", e);
-                let now = Instant::now();
-                let first_fail = first_failure_time.get_or_insert(now);
-                if now.duration_since(*first_fail) > Duration::from_secs(30) {
-                    msg_id::clear_msg_id_pool().await;
-                    first_failure_time = None;
-                }
                 time::sleep(Duration::from_secs(3)).await;
             }
         }
     }
 }
 
-async fn connect_and_run(
-    cfg: &Config,
-    stop_reconnecting: Arc<AtomicBool>,
-    stats: Stats,
-    tx: mpsc::Sender<Vec<u8>>,
-    rx: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
-    context: SharedUploadContext, // Receive context from run_network_tasks
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut roots = RootCertStore::empty();
-    let cert_file = File::open(&cfg.setup.certificate)?;
-    let mut reader = BufReader::new(cert_file);
-    for cert_result in rustls_pemfile::certs(&mut reader) {
-        let cert = cert_result?;
-        roots.add(cert)?;
-    }
-    let tls_config = RustlsClientConfig::builder()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-    let client_config = ClientConfig::new(Arc::new(
-        quinn::crypto::rustls::QuicClientConfig::try_from(tls_config)?,
-    ));
+/// What outlives a single connection attempt.
+struct Reconnect {
+    endpoint: Endpoint,
+    // Set on fatal errors, where retrying would fail the same way.
+    stop: Arc<AtomicBool>,
+    // Latest session token, to resume the session with on the next connection.
+    resume_token: ResumeToken,
+}
+
+fn client_endpoint(cfg: &Config) -> Result<Endpoint, Box<dyn Error + Send + Sync>> {
+    let client_config = tls::build_client_config(cfg)?;
     let mut endpoint = Endpoint::client("[::]:0".parse()?)?;
     endpoint.set_default_client_config(client_config);
+    Ok(endpoint)
+}
 
-    let addr_str = format!("{}:{}", cfg.network.address, cfg.network.port);
-    let remote_addr: SocketAddr = addr_str.to_socket_addrs()?.next().ok_or("Invalid address")?;
+// None until the handshake completes, then whether 0-RTT data (if any) was accepted.
+type Handshake = tokio::sync::watch::Receiver<Option<bool>>;
 
-    let connection = endpoint.connect(remote_addr, "localhost")?.await?;
-    info!("Connection established with {}", connection.remote_address());
+// Resolves to whether the handshake completed with the 0-RTT data accepted.
+async fn early_data_accepted(handshake: &mut Handshake) -> bool {
+    matches!(handshake.wait_for(Option::is_some).await.as_deref(), Ok(Some(true)))
+}
 
-    let (mut control_send, mut control_recv) = connection.open_bi().await?;
+// Opens the control stream and negotiates on it. Until the handshake completes, only
+// idempotent messages leave the sender: 0-RTT data can be replayed by anyone who recorded it.
+async fn open_control_stream(
+    connection: &Connection,
+    rx: Arc<Mutex<mpsc::Receiver<WsmMessage>>>,
+    tx: &mpsc::Sender<WsmMessage>,
+    stats: &Stats,
+    handshake: &Handshake,
+    preferred: PayloadType,
+) -> Result<(JoinHandle<()>, WsmReader, HelloOutcome), Box<dyn Error + Send + Sync>> {
+    let (control_send, control_recv) = connection.open_bi().await?;
     info!("Control stream opened for bidirectional communication.");
-
-    let in_flight_pings: InFlightPings = Arc::new(Mutex::new(HashMap::new()));
-    let auth_state = Arc::new(Mutex::new(AuthState::Unauthenticated));
+    let (mut control_send, mut control_recv) =
+        codec::framed(control_send, control_recv, WsmCodec::control());
 
     let stats_for_sender = stats.clone();
-    tokio::spawn(async move {
-        while let Some(msg_bytes) = rx.lock().await.recv().await {
-            // The temporary hack is now removed. All messages are sent directly.
-            if let Err(e) = control_send.write_all(&msg_bytes).await {
+    let mut handshake = handshake.clone();
+    let sender_task = tokio::spawn(async move {
+        while let Some(msg) = rx.lock().await.recv().await {
+            if !msg.opcode.is_idempotent() && handshake.wait_for(Option::is_some).await.is_err() {
+                break;
+            }
+            let wire_len = msg.wire_len() as u64;
+            if let Err(e) = control_send.send(msg).await {
                 error!("Client failed to send message: {}", e);
                 break;
             }
             stats_for_sender
                 .tx_bytes
-                .fetch_add(msg_bytes.len() as u64, Ordering::Relaxed);
+                .fetch_add(wire_len, Ordering::Relaxed);
         }
     });
 
-    // --- Authentication Phase ---
-    let auth_token = cfg.setup.auth_token.as_bytes();
-    let auth_header = WsmHeader::new(
-        0x03,
-        msg_id::create_new_msg_id().await.unwrap_or(0),
-        PayloadType::Raw,
-        auth_token.len() as u32,
-    );
-    let mut auth_request = auth_header.to_bytes().to_vec();
-    auth_request.extend_from_slice(auth_token);
-    info!("Sending authentication request...");
-    let _ = tx.send(auth_request).await;
+    info!("Negotiating protocol v{}...", hello::PROTOCOL_VERSION);
+    match hello::client_handshake(0, tx, &mut control_recv, preferred).await {
+        Ok(outcome) => Ok((sender_task, control_recv, outcome)),
+        Err(e) => {
+            sender_task.abort();
+            Err(e.into())
+        }
+    }
+}
 
-    let mut header_buf = [0u8; 8];
-    match control_recv.read_exact(&mut header_buf).await {
-        Ok(()) => {
-            stats.rx_bytes.fetch_add(8, Ordering::Relaxed);
-            let header = WsmHeader::from_bytes(&header_buf);
-            stats
-                .last_msg_id
-                .store(header.message_id, Ordering::Relaxed);
+async fn connect_and_run(
+    reconnect: &Reconnect,
+    cfg: &Config,
+    stats: Stats,
+    notifications: &Notifications,
+    tx: mpsc::Sender<WsmMessage>,
+    rx: Arc<Mutex<mpsc::Receiver<WsmMessage>>>,
+    shared_session: SharedSession,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let stop_reconnecting = reconnect.stop.clone();
+    let resume_token = &reconnect.resume_token;
+    let addr_str = format!("{}:{}", cfg.network.address, cfg.network.port);
+    let remote_addr: SocketAddr = addr_str.to_socket_addrs()?.next().ok_or("Invalid address")?;
 
-            // Pass the context to the dispatcher
-            if let ControlFlow::Break(_) = endpoints::dispatch_client(
-                &header,
-                &mut control_recv,
-                in_flight_pings.clone(),
-                auth_state.clone(),
-                stop_reconnecting.clone(),
-                cfg,
-                stats.clone(),
-                context.clone(),
-                tx.clone(),
-                Arc::new(connection.clone()),
-            )
-            .await
-            {
-                error!("Dispatcher requested termination (auth failure).");
-                return Err("Authentication failed".into());
+    let server_name = cfg.setup.server_name.as_deref().unwrap_or("localhost");
+    let connecting = reconnect.endpoint.connect(remote_addr, server_name)?;
+    let (handshake_tx, mut handshake) = tokio::sync::watch::channel(None);
+    let connection = match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+            info!("Resuming TLS session; the Hello goes out as 0-RTT data.");
+            tokio::spawn(async move {
+                let _ = handshake_tx.send(Some(accepted.await));
+            });
+            connection
+        }
+        Err(connecting) => match connecting.await {
+            Ok(connection) => {
+                let _ = handshake_tx.send(Some(true));
+                connection
+            }
+            Err(e) => {
+                if tls::server_cert_changed() {
+                    stop_reconnecting.store(true, Ordering::SeqCst);
+                }
+                return Err(e.into());
             }
+        },
+    };
+    info!("Connection established with {}", connection.remote_address());
+
+    let auth_state = Arc::new(Mutex::new(AuthState::Unauthenticated));
+
+    // --- Negotiation Phase ---
+    let preferred = match cfg.setup.encoding.as_deref() {
+        None => PayloadType::Json,
+        Some(name) => payload::parse_name(name).unwrap_or_else(|| {
+            warn!("Unknown payload encoding '{}' in config, using json.", name);
+            PayloadType::Json
+        }),
+    };
+    let first = open_control_stream(&connection, rx.clone(), &tx, &stats, &handshake, preferred);
+    let opened = match first.await {
+        // Streams opened in rejected 0-RTT data are gone; start over on the same connection.
+        Err(e) if !early_data_accepted(&mut handshake).await => {
+            warn!("Server rejected the 0-RTT data ({}). Negotiating again...", e);
+            open_control_stream(&connection, rx, &tx, &stats, &handshake, preferred).await
         }
-        Err(e) => {
-            warn!(
-                "Client connection lost during auth: {}. Triggering reconnect...",
-                e
-            );
-            return Err(Box::new(e));
+        opened => opened,
+    };
+    let (sender_task, mut control_recv, outcome) = opened?;
+    let caps = match outcome {
+        HelloOutcome::Accepted(caps) => caps,
+        HelloOutcome::Rejected(reason) => {
+            error!("! WSM: Server rejected this client: {}", reason);
+            stop_reconnecting.store(true, Ordering::SeqCst);
+            sender_task.abort();
+            return Err(reason.into());
         }
     };
+    info!(
+        "Negotiated protocol v{} ({} opcodes, {:?} payloads, compression: {}, max chunk: {} bytes).",
+        caps.protocol_version,
+        caps.opcodes.len(),
+        caps.encoding(),
+        caps.compression,
+        caps.max_chunk_size
+    );
+
+    let session = ClientSession {
+        connection: connection.clone(),
+        caps,
+        requests: RequestTracker::new(tx.clone()),
+    };
+
+    // --- Authentication Phase ---
+    // Like the handshake, auth runs before the dispatcher and uses the reserved ID 0.
+    // A token from the previous connection takes over its session in one round trip.
+    // Otherwise the server sends a challenge; only a proof derived from the token goes back.
+    let token = match session.caps.supports(Opcode::Resume) {
+        true => resume_token.lock().await.take(),
+        false => None,
+    };
+    let mut resuming = token.is_some();
+    match token {
+        Some(token) => {
+            info!("Resuming the previous session...");
+            let _ = tx.send(resume::build_resume_request(token)).await;
+        }
+        None => {
+            info!("Requesting authentication challenge...");
+            let _ = tx.send(auth::build_challenge_request(cfg.setup.user.as_deref())).await;
+        }
+    }
+
+    loop {
+        let Some(received) = control_recv.next().await else {
+            warn!("Server closed the control stream during auth. Triggering reconnect...");
+            sender_task.abort();
+            return Err("Control stream closed".into());
+        };
+        let msg = match received {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!(
+                    "Client connection lost during auth: {}. Triggering reconnect...",
+                    e
+                );
+                sender_task.abort();
+                return Err(Box::new(e));
+            }
+        };
+        stats
+            .rx_bytes
+            .fetch_add(msg.wire_len() as u64, Ordering::Relaxed);
+        stats
+            .last_msg_id
+            .store(msg.message_id, Ordering::Relaxed);
+
+        let accepted = match msg.opcode {
+            Opcode::AuthChallenge => {
+                let answer = auth::channel_binding(&connection).and_then(|binding| {
+                    auth::answer_challenge(&msg, &cfg.setup.auth_token, &binding)
+                });
+                match answer {
+                    Ok(proof) => {
+                        info!("Sending authentication proof...");
+                        let _ = tx.send(proof).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("! WSM: {}", e);
+                        false
+                    }
+                }
+            }
+            Opcode::Error if resuming => {
+                info!(
+                    "Session could not be resumed: {}. Requesting authentication challenge...",
+                    ErrorReply::from_message(&msg)
+                );
+                resuming = false;
+                let _ = tx.send(auth::build_challenge_request(cfg.setup.user.as_deref())).await;
+                continue;
+            }
+            Opcode::Ack | Opcode::Error => {
+                auth::handle_auth_response(&msg, auth_state.clone(), stop_reconnecting.clone())
+                    .await
+            }
+            _ => {
+                let _ = endpoints::dispatch_client(
+                    msg,
+                    stop_reconnecting.clone(),
+                    &session,
+                    notifications,
+                )
+                .await;
+                false
+            }
+        };
+        if !accepted {
+            error!("Dispatcher requested termination (auth failure).");
+            sender_task.abort();
+            return Err("Authentication failed".into());
+        }
+        break;
+    }
 
     if *auth_state.lock().await != AuthState::Authenticated {
+        sender_task.abort();
         return Err("Authentication was not successful.".into());
     }
+    if resuming {
+        info!("Session resumed; subscriptions, watches and missed events carried over.");
+    }
 
     // --- Post-Authentication Phase ---
-    info!("Clearing message pool for new session...");
-    msg_id::clear_msg_id_pool().await;
+    *shared_session.lock().await = Some(session.clone());
 
-    info!("Spawning keep-alive tasks...");
-    let ping_tx = tx.clone();
-    let pings_to_track = in_flight_pings.clone();
-    let log_cfg = cfg.clone();
-    let pinger_handle: JoinHandle<()> = tokio::spawn(async move {
-        loop {
-            time::sleep(Duration::from_secs(1)).await;
-            if let Some(ping_msg) = keepalive::build_client_ping().await {
-                let msg_id = ping_msg[1];
-                if log_cfg.setup.log_level == "debug" {
-                    debug!("Queueing keep-alive PING (id: {})", msg_id);
-                }
-                pings_to_track.lock().await.insert(msg_id, Instant::now());
-                if let Err(e) = ping_tx.send(ping_msg.to_vec()).await {
-                    error!("Failed to queue PING: {}", e);
-                    break;
-                }
-            } else {
-                error!("Failed to create PING: message ID pool is full.");
+    // Subscribe to every event so the TUI can show notifications; `events` narrows it down.
+    // A resumed session keeps the subscription it had.
+    if session.caps.supports(Opcode::Subscribe) && !resuming {
+        let requests = session.requests.clone();
+        let encoding = session.caps.encoding();
+        tokio::spawn(async move {
+            if let Err(e) = events::subscribe(&requests, encoding, EventTopic::ALL).await {
+                warn!("Failed to subscribe to server events: {}", e);
             }
-        }
-    });
+        });
+    }
+    if session.caps.supports(Opcode::Watch) && !resuming {
+        let requests = session.requests.clone();
+        tokio::spawn(async move { watch::restore(&requests).await });
+    }
 
-    let pings_to_watch = in_flight_pings.clone();
+    info!("Spawning keep-alive task...");
+    let ping_requests = session.requests.clone();
+    let log_cfg = cfg.clone();
     let conn_for_timeout = connection.clone();
-    let watcher_handle: JoinHandle<()> = tokio::spawn(async move {
+    let pinger_handle: JoinHandle<()> = tokio::spawn(async move {
         loop {
-            time::sleep(Duration::from_millis(100)).await;
-            let mut timed_out = None;
-            let mut pings = pings_to_watch.lock().await;
-            for (msg_id, sent_at) in pings.iter() {
-                if sent_at.elapsed() > Duration::from_millis(500) {
-                    timed_out = Some(*msg_id);
-                    break;
+            time::sleep(Duration::from_secs(1)).await;
+            let requests = ping_requests.clone();
+            let log_cfg = log_cfg.clone();
+            let conn = conn_for_timeout.clone();
+            tokio::spawn(async move {
+                let sent_at = Instant::now();
+                match requests
+                    .request(keepalive::build_ping, keepalive::PING_TIMEOUT)
+                    .await
+                {
+                    Ok(_) => {
+                        if log_cfg.setup.log_level == "debug" {
+                            debug!("PONG received after {:?}", sent_at.elapsed());
+                        }
+                    }
+                    Err(RequestError::TimedOut(after)) => {
+                        warn!("PONG not received in {:?}. Closing connection.", after);
+                        conn.close(1u32.into(), b"PONG timeout");
+                    }
+                    Err(e) => error!("Failed to send PING: {}", e),
                 }
-            }
-            if let Some(msg_id) = timed_out {
-                warn!(
-                    "PONG for msg_id {} not received in 500ms. Closing connection.",
-                    msg_id
-                );
-                conn_for_timeout.close(1u32.into(), b"PONG timeout");
-                pings.clear();
-                break;
-            }
+            });
         }
     });
 
     info!("Client is now in main loop, handling messages...");
     let loop_result: Result<(), Box<dyn Error + Send + Sync>> = loop {
-        match control_recv.read_exact(&mut header_buf).await {
-            Ok(()) => {
-                stats.rx_bytes.fetch_add(8, Ordering::Relaxed);
-                let header = WsmHeader::from_bytes(&header_buf);
+        match control_recv.next().await {
+            Some(Ok(msg)) => {
+                stats
+                    .rx_bytes
+                    .fetch_add(msg.wire_len() as u64, Ordering::Relaxed);
                 stats
                     .last_msg_id
-                    .store(header.message_id, Ordering::Relaxed);
-                // Pass context to the dispatcher in the main loop as well
-                if let ControlFlow::Break(_) = endpoints::dispatch_client(
-                    &header,
-                    &mut control_recv,
-                    in_flight_pings.clone(),
-                    auth_state.clone(),
-                    stop_reconnecting.clone(),
-                    cfg,
-                    stats.clone(),
-                    context.clone(),
-                    tx.clone(),
-                    Arc::new(connection.clone()),
-                )
-                .await
+                    .store(msg.message_id, Ordering::Relaxed);
+                if msg.opcode == Opcode::SessionToken {
+                    *resume_token.lock().await = Some(msg.payload);
+                    continue;
+                }
+                if let ControlFlow::Break(_) =
+                    endpoints::dispatch_client(msg, stop_reconnecting.clone(), &session, notifications)
+                        .await
                 {
                     error!("Dispatcher requested termination post-auth.");
                     break Err("Connection terminated by dispatcher".into());
                 }
             }
-            Err(e) => {
+            Some(Err(e)) => {
                 warn!("Client connection lost: {}. Triggering reconnect...", e);
                 break Err(Box::new(e));
             }
+            None => {
+                warn!("Server closed the control stream. Triggering reconnect...");
+                break Err("Control stream closed".into());
+            }
         }
     };
 
     pinger_handle.abort();
-    watcher_handle.abort();
+    sender_task.abort();
+    session.requests.fail_all().await;
     loop_result
-}
\ No newline at end of file
+}
+
+/// Request tracker of the current connection, if there is one.
+pub async fn current_requests(session: &SharedSession) -> Option<RequestTracker> {
+    session.lock().await.as_ref().map(|s| s.requests.clone())
+}
@@ -0,0 +1,288 @@
+/* src/quic/guard.rs */
+
+use crate::setup::config::NetworkConfig;
+use ipnetwork::IpNetwork;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Failed attempts an address gets before it is locked out.
+const FREE_FAILURES: u32 = 3;
+// First lockout; each further failure doubles it.
+const BASE_LOCKOUT: Duration = Duration::from_secs(5);
+const MAX_LOCKOUT: Duration = Duration::from_secs(3600);
+// An address that stays quiet this long starts over.
+const FAILURE_MEMORY: Duration = Duration::from_secs(24 * 3600);
+// Tracked addresses before quiet ones are pruned, and how many pruning keeps at most.
+const MAX_TRACKED: usize = 10_000;
+const PRUNED_TO: usize = MAX_TRACKED * 9 / 10;
+
+lazy_static! {
+    // Recent authentication failures by source address.
+    static ref FAILURES: Mutex<HashMap<IpAddr, FailureRecord>> = Mutex::new(HashMap::new());
+}
+
+// Connections between accept and successful authentication.
+static UNAUTHENTICATED: AtomicUsize = AtomicUsize::new(0);
+
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Seat among the connections still authenticating; dropping it frees the seat.
+pub struct UnauthenticatedSlot(());
+
+impl UnauthenticatedSlot {
+    /// Takes a seat unless `limit` connections are already authenticating.
+    pub fn acquire(limit: usize) -> Option<UnauthenticatedSlot> {
+        UNAUTHENTICATED
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < limit).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| UnauthenticatedSlot(()))
+    }
+}
+
+impl Drop for UnauthenticatedSlot {
+    fn drop(&mut self) {
+        UNAUTHENTICATED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses.
+pub fn source_of(addr: IpAddr) -> IpAddr {
+    addr.to_canonical()
+}
+
+fn listed(networks: &[String], ip: IpAddr) -> bool {
+    networks
+        .iter()
+        .filter_map(|network| network.parse::<IpNetwork>().ok())
+        .any(|network| network.contains(ip))
+}
+
+/// [SERVER-SIDE] Whether `ip` may start a handshake: not denied, allowed if an allow list
+/// exists, and not locked out after failed authentication.
+pub fn admit(network: &NetworkConfig, ip: IpAddr) -> Result<(), String> {
+    if listed(&network.deny, ip) {
+        return Err("address is on the deny list".to_string());
+    }
+    if !network.allow.is_empty() && !listed(&network.allow, ip) {
+        return Err("address is not on the allow list".to_string());
+    }
+    let failures = FAILURES.lock().unwrap();
+    if let Some(until) = failures.get(&ip).and_then(|record| record.locked_until) {
+        let now = Instant::now();
+        if until > now {
+            return Err(format!(
+                "locked out for another {}s after failed authentication",
+                (until - now).as_secs() + 1
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Forgets quiet addresses, then, if a flood from many addresses keeps the map full, the
+// ones that failed longest ago, even if still locked out.
+fn prune(failures: &mut HashMap<IpAddr, FailureRecord>, now: Instant) {
+    failures.retain(|_, record| now - record.last_failure < FAILURE_MEMORY);
+    if failures.len() <= PRUNED_TO {
+        return;
+    }
+    let mut by_age: Vec<(Instant, IpAddr)> = failures
+        .iter()
+        .map(|(ip, record)| (record.last_failure, *ip))
+        .collect();
+    by_age.sort_unstable();
+    let excess = failures.len() - PRUNED_TO;
+    for (_, ip) in by_age.into_iter().take(excess) {
+        failures.remove(&ip);
+    }
+}
+
+/// [SERVER-SIDE] Counts a failed authentication; from the fourth on, the address is
+/// locked out for a period that doubles with each failure.
+pub fn record_failure(ip: IpAddr) {
+    let now = Instant::now();
+    let mut failures = FAILURES.lock().unwrap();
+    if failures.len() >= MAX_TRACKED && !failures.contains_key(&ip) {
+        prune(&mut failures, now);
+    }
+    let record = failures.entry(ip).or_insert(FailureRecord {
+        failures: 0,
+        last_failure: now,
+        locked_until: None,
+    });
+    if now - record.last_failure >= FAILURE_MEMORY {
+        record.failures = 0;
+    }
+    record.failures += 1;
+    record.last_failure = now;
+    if record.failures > FREE_FAILURES {
+        let doublings = (record.failures - FREE_FAILURES - 1).min(16);
+        let lockout = (BASE_LOCKOUT * 2u32.pow(doublings)).min(MAX_LOCKOUT);
+        record.locked_until = Some(now + lockout);
+        println!(
+            "! Guard: {} failed authentication {} times; locked out for {}s.",
+            ip,
+            record.failures,
+            lockout.as_secs()
+        );
+    } else {
+        println!(
+            "! Guard: {} failed authentication ({} of {} before lockout).",
+            ip, record.failures, FREE_FAILURES
+        );
+    }
+}
+
+/// [SERVER-SIDE] Forgets the failures of an address that authenticated.
+pub fn record_success(ip: IpAddr) {
+    FAILURES.lock().unwrap().remove(&ip);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(allow: &[&str], deny: &[&str]) -> NetworkConfig {
+        let list = |entries: &[&str]| {
+            let quoted: Vec<String> = entries.iter().map(|e| format!("\"{}\"", e)).collect();
+            quoted.join(", ")
+        };
+        toml::from_str(&format!(
+            "listen = \"::\"\naddress = \"localhost\"\nport = 1\nallow = [{}]\ndeny = [{}]",
+            list(allow),
+            list(deny)
+        ))
+        .unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    // The lockout the last failure of `ip` started, if any.
+    fn lockout(ip: IpAddr) -> Option<Duration> {
+        let failures = FAILURES.lock().unwrap();
+        let record = failures.get(&ip)?;
+        record.locked_until.map(|until| until - record.last_failure)
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_ranges() {
+        let mapped = ip("::ffff:192.0.2.7");
+        assert_eq!(source_of(mapped), ip("192.0.2.7"));
+        assert!(listed(&["192.0.2.0/24".to_string()], source_of(mapped)));
+        assert!(!listed(&["198.51.100.0/24".to_string()], source_of(mapped)));
+        // Plain IPv6 addresses are left alone.
+        assert_eq!(source_of(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn unparsable_entries_are_skipped() {
+        let networks = ["not-a-network".to_string(), "192.0.2.7".to_string()];
+        assert!(listed(&networks, ip("192.0.2.7")));
+        assert!(!listed(&networks, ip("192.0.2.8")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let cfg = network(&["192.0.2.0/24"], &["192.0.2.66"]);
+        assert!(admit(&cfg, source_of(ip("::ffff:192.0.2.1"))).is_ok());
+        assert!(admit(&cfg, source_of(ip("::ffff:192.0.2.66"))).is_err());
+        assert!(admit(&cfg, ip("198.51.100.1")).is_err());
+        assert!(admit(&network(&[], &[]), ip("198.51.100.1")).is_ok());
+    }
+
+    #[test]
+    fn lockout_doubles_after_the_free_failures() {
+        let source = ip("203.0.113.10");
+        for _ in 0..FREE_FAILURES {
+            record_failure(source);
+            assert_eq!(lockout(source), None);
+        }
+        assert!(admit(&network(&[], &[]), source).is_ok());
+        for expected in [5, 10, 20, 40] {
+            record_failure(source);
+            assert_eq!(lockout(source), Some(Duration::from_secs(expected)));
+        }
+        assert!(admit(&network(&[], &[]), source).is_err());
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        let source = ip("203.0.113.11");
+        for _ in 0..FREE_FAILURES + 30 {
+            record_failure(source);
+        }
+        assert_eq!(lockout(source), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let source = ip("203.0.113.12");
+        for _ in 0..=FREE_FAILURES {
+            record_failure(source);
+        }
+        assert!(admit(&network(&[], &[]), source).is_err());
+        record_success(source);
+        assert!(admit(&network(&[], &[]), source).is_ok());
+        assert!(FAILURES.lock().unwrap().get(&source).is_none());
+    }
+
+    #[test]
+    fn pruning_keeps_the_map_bounded_under_a_flood() {
+        let start = Instant::now();
+        let mut failures: HashMap<IpAddr, FailureRecord> = (0..MAX_TRACKED as u32)
+            .map(|n| {
+                let record = FailureRecord {
+                    failures: FREE_FAILURES + 1,
+                    last_failure: start + Duration::from_millis(n.into()),
+                    locked_until: Some(start + MAX_LOCKOUT),
+                };
+                (IpAddr::from(n.to_be_bytes()), record)
+            })
+            .collect();
+        prune(&mut failures, start + Duration::from_secs(60));
+        assert_eq!(failures.len(), PRUNED_TO);
+        // The most recent failures are the ones kept.
+        let oldest_kept = (MAX_TRACKED - PRUNED_TO) as u32;
+        assert!(failures.contains_key(&IpAddr::from(oldest_kept.to_be_bytes())));
+        assert!(!failures.contains_key(&IpAddr::from((oldest_kept - 1).to_be_bytes())));
+    }
+
+    #[test]
+    fn pruning_forgets_quiet_addresses_first() {
+        let start = Instant::now();
+        let record = |last_failure| FailureRecord {
+            failures: 1,
+            last_failure,
+            locked_until: None,
+        };
+        let mut failures = HashMap::from([
+            (ip("203.0.113.20"), record(start)),
+            (ip("203.0.113.21"), record(start + FAILURE_MEMORY)),
+        ]);
+        prune(
+            &mut failures,
+            start + FAILURE_MEMORY + Duration::from_secs(1),
+        );
+        assert_eq!(failures.keys().collect::<Vec<_>>(), [&ip("203.0.113.21")]);
+    }
+
+    #[test]
+    fn unauthenticated_slots_are_freed_on_drop() {
+        let first = UnauthenticatedSlot::acquire(1).unwrap();
+        assert!(UnauthenticatedSlot::acquire(1).is_none());
+        drop(first);
+        assert!(UnauthenticatedSlot::acquire(1).is_some());
+    }
+}
@@ -1,50 +1,28 @@
 /* src/quic/keepalive.rs */
 
 use crate::setup::config::Config;
-use crate::wsm::header::{PayloadType, WsmHeader, RESERVED_FINAL_FLAG};
-use crate::wsm::msg_id;
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::{mpsc, Mutex};
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::opcode::Opcode;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
-pub type InFlightPings = Arc<Mutex<HashMap<u8, Instant>>>;
+/// A PING unanswered for this long means the connection is dead.
+pub const PING_TIMEOUT: Duration = Duration::from_millis(500);
 
-pub fn build_ping_header(message_id: u8) -> [u8; 8] {
-    WsmHeader::new(0x01, message_id, PayloadType::Raw, 0).to_bytes()
+pub fn build_ping(message_id: u32) -> WsmMessage {
+    WsmMessage::empty(Opcode::Ping, message_id)
 }
 
-pub fn build_pong_header(message_id: u8) -> [u8; 8] {
-    WsmHeader::with_reserved(0x02, message_id, PayloadType::Raw, 0, RESERVED_FINAL_FLAG).to_bytes()
-}
-
-pub async fn build_client_ping() -> Option<[u8; 8]> {
-    msg_id::create_new_msg_id().await.map(build_ping_header)
+pub fn build_pong(message_id: u32) -> WsmMessage {
+    WsmMessage::empty(Opcode::Pong, message_id).with_final()
 }
 
 // (SERVER) Handles a received PING message by sending a PONG back.
-pub async fn handle_ping_request(msg_id: u8, tx: mpsc::Sender<Vec<u8>>, cfg: &Config) {
+pub async fn handle_ping_request(msg_id: u32, tx: mpsc::Sender<WsmMessage>, cfg: &Config) {
     if cfg.setup.log_level == "debug" {
         println!("  -> WSM: Handling PING with msg_id: {}. Responding with PONG.", msg_id);
     }
-    let pong_header = build_pong_header(msg_id);
-    if let Err(e) = tx.send(pong_header.to_vec()).await {
+    if let Err(e) = tx.send(build_pong(msg_id)).await {
         println!("! WSM: Failed to queue PONG response: {}", e);
     }
 }
-
-// (CLIENT) Handles a received PONG message by removing the msg_id from the in-flight map.
-pub async fn handle_pong_response(msg_id: u8, in_flight_pings: InFlightPings, cfg: &Config) {
-    if cfg.setup.log_level == "debug" {
-        println!("  -> WSM: Handling PONG for msg_id: {}", msg_id);
-    }
-    if in_flight_pings.lock().await.remove(&msg_id).is_some() {
-        if msg_id::remove_msg_id(msg_id).await {
-            if cfg.setup.log_level == "debug" {
-                println!("  -- Correctly cleared msg_id {} from pool.", msg_id);
-            }
-        }
-    } else {
-        println!("  -- Warning: Received PONG for an untracked or timed-out msg_id: {}", msg_id);
-    }
-}
\ No newline at end of file
@@ -1,7 +1,12 @@
 /* src/quic/mod.rs */
 
+pub mod acl;
 pub mod bootstrap;
+pub mod cert_monitor;
 pub mod client;
+pub mod guard;
 pub mod auth;
 pub mod service;
-pub mod keepalive;
\ No newline at end of file
+pub mod keepalive;
+pub mod resume;
+pub mod tls;
\ No newline at end of file
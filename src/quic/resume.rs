@@ -0,0 +1,206 @@
+/* src/quic/resume.rs */
+
+use crate::audit::{self, AuditEvent, AuthMethod};
+use crate::events::{self, EventTopic, ServerEvent, SubscribedTopics};
+use crate::quic::acl::Identity;
+use crate::quic::auth;
+use crate::quic::service::ConnectionState;
+use crate::rfs::watch::{self, SessionWatches};
+use crate::setup::config::Config;
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::endpoints::AuthState;
+use crate::wsm::error::{self, ErrorCode};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use lazy_static::lazy_static;
+use quinn::Connection;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, broadcast, mpsc};
+use tokio::time;
+
+pub const TOKEN_LEN: usize = 32;
+// Resumable sessions kept at once; beyond this no new tokens are handed out.
+const MAX_SESSIONS: usize = 10_000;
+
+lazy_static! {
+    // Sessions a reconnecting client may take over, by token. Each token works once.
+    static ref SESSIONS: std::sync::Mutex<HashMap<[u8; TOKEN_LEN], ResumableSession>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+struct ResumableSession {
+    identity: Identity,
+    topics: SubscribedTopics,
+    // Set while the connection is open; resuming elsewhere closes it.
+    connection: Option<Connection>,
+    // Set once it closed: the events published since, and when the offer ends.
+    missed: Option<broadcast::Receiver<ServerEvent>>,
+    // Watches of the closed connection, still running and holding their events.
+    watches: Option<SessionWatches>,
+    expires: Option<Instant>,
+}
+
+/// What a resuming connection takes over from the one it replaces.
+pub struct ResumedSession {
+    pub identity: Identity,
+    pub topics: HashSet<EventTopic>,
+    pub missed: Option<broadcast::Receiver<ServerEvent>>,
+    pub watches: Option<SessionWatches>,
+}
+
+/// [SERVER-SIDE] Hands a freshly authenticated connection a token (0x25) to resume its session
+/// with after a reconnect, unless resumption is disabled or the client does not support it.
+pub async fn offer(
+    tx: &mpsc::Sender<WsmMessage>,
+    conn: &ConnectionState,
+    cfg: &Config,
+    identity: &Identity,
+) {
+    let supported = conn
+        .negotiated
+        .lock()
+        .await
+        .as_ref()
+        .is_some_and(|caps| caps.supports(Opcode::SessionToken));
+    if cfg.network.resume_secs == 0 || !supported {
+        return;
+    }
+    let token: [u8; TOKEN_LEN] = rand::random();
+    let previous = conn.resume_token.lock().await.replace(token);
+    {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if let Some(previous) = previous {
+            sessions.remove(&previous);
+        }
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires.is_none_or(|expires| expires > now));
+        if sessions.len() >= MAX_SESSIONS {
+            eprintln!(
+                "! Resume: {} sessions are resumable already; not offering another.",
+                MAX_SESSIONS
+            );
+            return;
+        }
+        sessions.insert(
+            token,
+            ResumableSession {
+                identity: identity.clone(),
+                topics: conn.topics.clone(),
+                connection: Some(conn.connection.clone()),
+                missed: None,
+                watches: None,
+                expires: None,
+            },
+        );
+    }
+    let message =
+        WsmMessage::new(Opcode::SessionToken, 0, PayloadType::Raw, token.to_vec()).with_final();
+    let _ = tx.send(message).await;
+}
+
+/// [SERVER-SIDE] Keeps the session of a closed connection resumable for `resume_secs`,
+/// collecting the events it misses meanwhile and keeping its watches running.
+pub async fn park(conn: &ConnectionState, cfg: &Config) {
+    let Some(token) = conn.resume_token.lock().await.take() else {
+        return;
+    };
+    if !SESSIONS.lock().unwrap().contains_key(&token) {
+        return;
+    }
+    let watches = watch::detach(&conn.watches).await;
+    let resume_for = Duration::from_secs(cfg.network.resume_secs);
+    if let Some(session) = SESSIONS.lock().unwrap().get_mut(&token) {
+        session.connection = None;
+        session.missed = Some(events::listen());
+        session.watches = Some(watches);
+        session.expires = Some(Instant::now() + resume_for);
+    }
+    // Parked watches hold inotify instances; drop them as soon as the offer ends.
+    tokio::spawn(async move {
+        time::sleep(resume_for).await;
+        let now = Instant::now();
+        let mut sessions = SESSIONS.lock().unwrap();
+        if sessions
+            .get(&token)
+            .is_some_and(|session| session.expires.is_some_and(|expires| expires <= now))
+        {
+            sessions.remove(&token);
+        }
+    });
+}
+
+// Removes the session so the token cannot be used again.
+async fn take(token: &[u8]) -> Option<ResumedSession> {
+    let token: [u8; TOKEN_LEN] = token.try_into().ok()?;
+    let session = SESSIONS.lock().unwrap().remove(&token)?;
+    if session
+        .expires
+        .is_some_and(|expires| expires <= Instant::now())
+    {
+        return None;
+    }
+    if let Some(connection) = session.connection {
+        // The client reconnected before the server noticed its old connection was gone.
+        connection.close(4u32.into(), b"session resumed elsewhere");
+    }
+    let topics = session.topics.lock().await.clone();
+    Some(ResumedSession {
+        identity: session.identity,
+        topics,
+        missed: session.missed,
+        watches: session.watches,
+    })
+}
+
+// [SERVER-SIDE] Handles the resume request (0x24), whose payload is a token (0x25) from an
+// earlier connection. Failing is not fatal: the client falls back to authenticating.
+pub async fn handle_resume_request(
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    conn: &ConnectionState,
+    cfg: &Config,
+) {
+    if *conn.auth_state.lock().await == AuthState::Authenticated {
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::MalformedRequest,
+            "Connection is already authenticated",
+        )
+        .await;
+        return;
+    }
+    let Some(resumed) = take(&msg.payload).await else {
+        println!("  -> WSM: Client presented an unknown or expired session token.");
+        let reason = "unknown or expired session token".to_string();
+        audit::record(Some(conn.remote), None, AuditEvent::AuthFailed { reason });
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::AuthFailed,
+            "Session cannot be resumed",
+        )
+        .await;
+        return;
+    };
+    println!(
+        "  -> WSM: Client resumed its session as '{}'.",
+        resumed.identity.name
+    );
+    *conn.topics.lock().await = resumed.topics;
+    *conn.missed_events.lock().await = resumed.missed;
+    if let Some(watches) = resumed.watches {
+        watch::adopt(&conn.watches, watches, tx.clone()).await;
+    }
+    auth::accept(msg, &tx, conn, cfg, resumed.identity, AuthMethod::Session).await;
+}
+
+/// [CLIENT-SIDE] Latest session token from the server, kept across reconnects.
+pub type ResumeToken = Arc<Mutex<Option<Vec<u8>>>>;
+
+/// [CLIENT-SIDE] Asks to take over the session `token` was issued for (0x24).
+pub fn build_resume_request(token: Vec<u8>) -> WsmMessage {
+    WsmMessage::new(Opcode::Resume, 0, PayloadType::Raw, token)
+}
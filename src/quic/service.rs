@@ -1,48 +1,109 @@
 /* src/quic/service.rs */
 
+use crate::audit::{self, AuditEvent};
+use crate::events::{self, ServerEvent, SubscribedTopics};
+use crate::nbd::NbdRequest;
+use crate::quic::acl::{self, Identity, SharedIdentity};
+use crate::quic::auth::{self, PendingChallenge};
+use crate::quic::guard::{self, UnauthenticatedSlot};
+use crate::quic::resume::{self, TOKEN_LEN};
+use crate::quic::tls;
+use crate::rfs::image::ImageRequest;
 use crate::rfs::UploadMetadata;
+use crate::rfs::watch::{self, ActiveWatches, SessionWatches};
 use crate::setup::config::Config;
+use crate::wsm::codec::{self, WsmCodec, WsmMessage};
 use crate::wsm::endpoints::{self, AuthState};
-use crate::wsm::header::WsmHeader;
+use crate::wsm::error::{ErrorCode, ErrorReply};
+use crate::wsm::hello::NegotiatedCaps;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::payload;
+use futures::{SinkExt, StreamExt};
 use quinn::{Connection, RecvStream, SendStream};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::ops::ControlFlow;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-use tokio::time::{self, Duration};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::time::{self, Duration, Instant};
 
 pub type OngoingUploads = Arc<Mutex<HashMap<String, Arc<UploadMetadata>>>>;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ServerState {
     pub ongoing_uploads: OngoingUploads,
 }
 
-pub async fn handle_connection(conn: Connection, cfg: Config) {
+/// State of one client connection, shared by the handlers of its control stream.
+#[derive(Clone)]
+pub struct ConnectionState {
+    pub connection: Connection,
+    pub remote: SocketAddr,
+    pub auth_state: Arc<Mutex<AuthState>>,
+    pub identity: SharedIdentity,
+    pub negotiated: NegotiatedCaps,
+    pub topics: SubscribedTopics,
+    pub watches: ActiveWatches,
+    pub challenge: Arc<Mutex<Option<PendingChallenge>>>,
+    // User the verified client certificate maps to; they need no token.
+    pub cert_identity: Option<Identity>,
+    // TLS exporter secret the client's auth proof must be bound to.
+    pub auth_binding: [u8; 32],
+    // Token the client may resume this session with once the connection is gone.
+    pub resume_token: Arc<Mutex<Option<[u8; TOKEN_LEN]>>>,
+    // Events a resumed session missed while disconnected; the forwarder starts with them.
+    pub missed_events: Arc<Mutex<Option<broadcast::Receiver<ServerEvent>>>>,
+}
+
+// Uploads are tracked server-wide so `rfs list` can report them per volume.
+// `slot` is held until the client authenticates, which it must do before `auth_timeout_secs`.
+pub async fn handle_connection(
+    conn: Connection,
+    cfg: Config,
+    server_state: ServerState,
+    slot: UnauthenticatedSlot,
+) {
     println!("-> Handing connection from {} to service.", conn.remote_address());
+    let remote = conn.remote_address();
+    audit::record(Some(remote), None, AuditEvent::Connected);
+    let remote_ip = guard::source_of(remote.ip());
+    let auth_deadline = Instant::now() + Duration::from_secs(cfg.network.auth_timeout_secs);
+    let mut unauthenticated = Some(slot);
     let auth_state = Arc::new(Mutex::new(AuthState::Unauthenticated));
-
-    let server_state = ServerState {
-        ongoing_uploads: Arc::new(Mutex::new(HashMap::new())),
-    };
+    let identity = SharedIdentity::default();
+    let negotiated: NegotiatedCaps = Arc::new(Mutex::new(None));
 
     // --- Step 1: Accept the main control stream FIRST ---
-    let control_stream = match conn.accept_bi().await {
-        Ok(stream) => stream,
-        Err(e) => {
+    let control_stream = match time::timeout_at(auth_deadline, conn.accept_bi()).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
             println!("! Failed to accept the initial control stream: {}", e);
+            audit::record(Some(remote), None, AuditEvent::Disconnected);
+            return;
+        }
+        Err(_) => {
+            println!("! {} opened no control stream in time. Closing.", remote_ip);
+            let reason = "no control stream opened in time".to_string();
+            audit::record(Some(remote), None, AuditEvent::AuthFailed { reason });
+            audit::record(Some(remote), None, AuditEvent::Disconnected);
+            guard::record_failure(remote_ip);
+            conn.close(2u32.into(), b"auth timeout");
             return;
         }
     };
-    let (mut control_send, mut control_recv) = control_stream;
+    let (control_send, control_recv) = control_stream;
     if cfg.setup.log_level == "debug" {
         println!("  -- Control stream {} established.", control_send.id());
     }
+    let (mut control_send, mut control_recv) =
+        codec::framed(control_send, control_recv, WsmCodec::control());
 
     // --- Step 2: NOW, spawn a task to handle all SUBSEQUENT worker streams ---
     let conn_clone = conn.clone();
     let cfg_clone = cfg.clone();
     let state_clone = server_state.clone();
+    let worker_identity = identity.clone();
+    let worker_caps = negotiated.clone();
     tokio::spawn(async move {
         loop {
             match conn_clone.accept_bi().await {
@@ -52,8 +113,22 @@ pub async fn handle_connection(conn: Connection, cfg: Config) {
                     }
                     let worker_cfg = cfg_clone.clone();
                     let worker_state = state_clone.clone();
+                    let identity = worker_identity.clone();
+                    let caps = worker_caps.clone();
                     tokio::spawn(async move {
-                        associate_and_run_worker(send, recv, worker_cfg, worker_state).await;
+                        let Some(identity) = identity.lock().await.clone() else {
+                            eprintln!("! Worker: Rejecting stream on an unauthenticated connection.");
+                            return;
+                        };
+                        associate_and_run_worker(
+                            send,
+                            recv,
+                            worker_cfg,
+                            worker_state,
+                            caps,
+                            identity,
+                        )
+                        .await;
                     });
                 }
                 Err(e) => {
@@ -67,41 +142,126 @@ pub async fn handle_connection(conn: Connection, cfg: Config) {
     });
 
     // --- Step 3: Proceed with handling the main control stream logic ---
-    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
-    let sender_task = tokio::spawn(async move {
-        while let Some(msg_bytes) = rx.recv().await {
-            if control_send.write_all(&msg_bytes).await.is_err() {
-                break;
+    let auth_binding = match auth::channel_binding(&conn) {
+        Ok(binding) => binding,
+        Err(e) => {
+            println!("! Cannot authenticate {}: {}", conn.remote_address(), e);
+            conn.close(3u32.into(), b"protocol error");
+            audit::record(Some(remote), None, AuditEvent::Disconnected);
+            return;
+        }
+    };
+    let cert_identity = tls::peer_certificate(&conn).and_then(|cert| {
+        let fingerprint = tls::fingerprint(&cert);
+        let common_name = tls::common_name(&cert);
+        let identity = acl::find_certificate_user(&cfg, &fingerprint, common_name.as_deref());
+        match &identity {
+            Some(identity) => {
+                println!("  -> Client certificate belongs to user '{}'.", identity.name)
             }
+            None => println!(
+                "! Client certificate {} (CN {:?}) belongs to no user.",
+                fingerprint, common_name
+            ),
         }
+        identity
+    });
+    let (tx, mut rx) = mpsc::channel::<WsmMessage>(32);
+    let conn_state = ConnectionState {
+        connection: conn.clone(),
+        remote,
+        auth_state,
+        identity,
+        negotiated,
+        topics: SubscribedTopics::default(),
+        watches: Arc::new(Mutex::new(SessionWatches::new(tx.clone()))),
+        challenge: Arc::new(Mutex::new(None)),
+        cert_identity,
+        auth_binding,
+        resume_token: Arc::new(Mutex::new(None)),
+        missed_events: Arc::new(Mutex::new(None)),
+    };
+    // Started on authentication; nothing is forwarded to an unknown client anyway.
+    let mut forwarder_task = None;
+    let mut sender_task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = control_send.send(msg).await {
+                eprintln!("! Failed to write to control stream: {}", e);
+                return;
+            }
+        }
+        // The channel only closes when rejecting a client; wait until the reason is read.
+        let send = control_send.get_mut();
+        let _ = send.finish();
+        let _ = send.stopped().await;
     });
 
-    let mut header_buf = [0u8; 8];
     loop {
-        match time::timeout(
-            Duration::from_secs(15),
-            control_recv.read_exact(&mut header_buf),
-        )
-        .await
-        {
-            Ok(Ok(())) => {
-                let header = WsmHeader::from_bytes(&header_buf);
-                if let ControlFlow::Break(_) = endpoints::dispatch_server(
-                    &header,
-                    &mut control_recv,
+        let idle_timeout = Duration::from_secs(15);
+        let wait = match unauthenticated {
+            Some(_) => idle_timeout.min(auth_deadline.saturating_duration_since(Instant::now())),
+            None => idle_timeout,
+        };
+        match time::timeout(wait, control_recv.next()).await {
+            Ok(Some(Ok(msg))) => {
+                let flow = endpoints::dispatch_server(
+                    &msg,
                     tx.clone(),
-                    auth_state.clone(),
+                    &conn_state,
                     &cfg,
                     server_state.ongoing_uploads.clone(),
                 )
-                .await
+                .await;
+                if unauthenticated.is_some()
+                    && *conn_state.auth_state.lock().await == AuthState::Authenticated
                 {
+                    unauthenticated = None;
+                    guard::record_success(remote_ip);
+                    let events = match conn_state.missed_events.lock().await.take() {
+                        Some(missed) => missed,
+                        None => events::listen(),
+                    };
+                    forwarder_task = Some(tokio::spawn(events::run_forwarder(
+                        tx.clone(),
+                        conn_state.topics.clone(),
+                        conn_state.negotiated.clone(),
+                        conn_state.identity.clone(),
+                        events,
+                    )));
+                }
+                if let ControlFlow::Break(_) = flow {
+                    // Only unauthenticated connections are ever dropped by the dispatcher.
+                    if unauthenticated.is_some() {
+                        guard::record_failure(remote_ip);
+                    }
+                    if let Some(task) = &forwarder_task {
+                        task.abort();
+                    }
+                    watch::stop_all(&conn_state.watches).await;
+                    drop(tx);
+                    let _ = time::timeout(Duration::from_secs(2), &mut sender_task).await;
                     conn.close(2u32.into(), b"auth failure");
                     break;
                 }
             }
-            Ok(Err(e)) => {
+            Ok(Some(Err(e))) => {
                 println!("! Read error on control stream: {}. Closing.", e);
+                conn.close(3u32.into(), b"protocol error");
+                break;
+            }
+            Ok(None) => {
+                println!("! Control stream closed by peer.");
+                break;
+            }
+            Err(_) if unauthenticated.is_some() && Instant::now() >= auth_deadline => {
+                println!(
+                    "! {} did not authenticate within {}s. Closing connection.",
+                    remote_ip, cfg.network.auth_timeout_secs
+                );
+                let reason = format!("no authentication within {}s", cfg.network.auth_timeout_secs);
+                audit::record(Some(remote), None, AuditEvent::AuthFailed { reason });
+                guard::record_failure(remote_ip);
+                conn.close(2u32.into(), b"auth timeout");
                 break;
             }
             Err(_) => {
@@ -111,46 +271,144 @@ pub async fn handle_connection(conn: Connection, cfg: Config) {
             }
         }
     }
+    if let Some(task) = &forwarder_task {
+        task.abort();
+    }
     sender_task.abort();
+    // A resumable session takes its watches along; the rest stop with the connection.
+    resume::park(&conn_state, &cfg).await;
+    watch::stop_all(&conn_state.watches).await;
 
+    let user = conn_state.identity.lock().await.as_ref().map(|i| i.name.clone());
+    audit::record(Some(remote), user.as_deref(), AuditEvent::Disconnected);
     println!("- Connection from {} closed.", conn.remote_address());
 }
 
+// Refuses a stream before it is framed; the client reads the reply as its first message.
+async fn reject_stream(send: &mut SendStream, code: ErrorCode, message: String) {
+    let reply = ErrorReply::new(code, message);
+    let _ = send.write_all(&reply.to_message(0).to_bytes()).await;
+    let _ = send.finish();
+}
+
 async fn associate_and_run_worker(
-    send: SendStream,
+    mut send: SendStream,
     mut recv: RecvStream,
     cfg: Config,
     state: ServerState,
+    negotiated: NegotiatedCaps,
+    identity: Identity,
 ) {
-    let mut header_buf = [0u8; 8];
-    if time::timeout(Duration::from_secs(2), recv.read_exact(&mut header_buf))
-        .await
-        .is_err()
+    // Read unbuffered: NBD streams leave WSM framing right after their Hello.
+    let hello = match time::timeout(
+        Duration::from_secs(2),
+        WsmCodec::transfer().read_one(&mut recv),
+    )
+    .await
     {
-        eprintln!("! Worker: Did not receive Hello in time.");
-        return;
-    }
-    let header = WsmHeader::from_bytes(&header_buf);
-    if header.opcode == 0x11 {
-        // Worker Hello
-        let mut payload = vec![0; header.payload_len as usize];
-        if recv.read_exact(&mut payload).await.is_err() {
+        Ok(Ok(msg)) => msg,
+        Ok(Err(e)) => {
+            eprintln!("! Worker: Invalid Hello: {}", e);
+            reject_stream(&mut send, ErrorCode::MalformedRequest, format!("Invalid Hello: {}", e))
+                .await;
             return;
         }
-        let file_hash = String::from_utf8_lossy(&payload).to_string();
-        let uploads = state.ongoing_uploads.lock().await;
-        if let Some(metadata) = uploads.get(&file_hash) {
-            let metadata_clone = metadata.clone();
-            drop(uploads);
-            crate::rfs::worker::handle_worker_stream(send, recv, cfg, (*metadata_clone).clone())
-                .await;
-        } else {
-            eprintln!("! Worker stream for unknown file hash: {}", file_hash);
+        Err(_) => {
+            eprintln!("! Worker: Did not receive Hello in time.");
+            return;
         }
-    } else {
+    };
+    let supported = negotiated
+        .lock()
+        .await
+        .as_ref()
+        .is_some_and(|caps| caps.supports(hello.opcode));
+    if !supported {
         eprintln!(
-            "! Worker stream's first message was not a Hello (0x11), but {:#02x}",
-            header.opcode
+            "! Worker: Stream opened with opcode {}, which was not negotiated.",
+            hello.opcode
         );
+        let reason = format!("Opcode {} was not negotiated", hello.opcode);
+        reject_stream(&mut send, ErrorCode::Unsupported, reason).await;
+        return;
+    }
+    if !payload::accepts(hello.opcode, hello.payload_type) {
+        eprintln!(
+            "! Worker: {} Hello with undecodable payload type {:?}.",
+            hello.opcode, hello.payload_type
+        );
+        let reason = format!("Opcode {} cannot carry a {:?} payload", hello.opcode, hello.payload_type);
+        reject_stream(&mut send, ErrorCode::MalformedRequest, reason).await;
+        return;
     }
-}
\ No newline at end of file
+    // Replies on this stream use the encoding the client opened it with.
+    match hello.opcode {
+        Opcode::WorkerHello => {
+            let file_hash = hello.text();
+            let uploads = state.ongoing_uploads.lock().await;
+            if let Some(metadata) = uploads.get(&file_hash) {
+                let metadata_clone = metadata.clone();
+                drop(uploads);
+                let (send, recv) = codec::framed(send, recv, WsmCodec::transfer());
+                crate::rfs::worker::handle_worker_stream(
+                    send,
+                    recv,
+                    cfg,
+                    (*metadata_clone).clone(),
+                    identity,
+                )
+                .await;
+            } else {
+                eprintln!("! Worker stream for unknown file hash: {}", file_hash);
+                drop(uploads);
+                let reason = format!("No upload in progress for hash {}", file_hash);
+                reject_stream(&mut send, ErrorCode::NotFound, reason).await;
+            }
+        }
+        Opcode::ImageHello => match hello.decode::<ImageRequest>() {
+            Ok(request) => {
+                let (send, recv) = codec::framed(send, recv, WsmCodec::transfer());
+                crate::rfs::image::handle_image_stream(
+                    send,
+                    recv,
+                    cfg,
+                    request,
+                    hello.payload_type,
+                    identity,
+                )
+                .await;
+            }
+            Err(e) => {
+                eprintln!("! Worker: Invalid image Hello: {}", e);
+                let reason = format!("Invalid image Hello: {}", e);
+                reject_stream(&mut send, ErrorCode::MalformedRequest, reason).await;
+            }
+        },
+        Opcode::NbdHello => match hello.decode::<NbdRequest>() {
+            Ok(request) => {
+                crate::nbd::export::handle_nbd_stream(
+                    send,
+                    recv,
+                    cfg,
+                    request,
+                    hello.payload_type,
+                    identity,
+                )
+                .await
+            }
+            Err(e) => {
+                eprintln!("! Worker: Invalid NBD Hello: {}", e);
+                let reason = format!("Invalid NBD Hello: {}", e);
+                reject_stream(&mut send, ErrorCode::MalformedRequest, reason).await;
+            }
+        },
+        op => {
+            eprintln!(
+                "! Worker stream's first message was not a Hello (0x11/0x12/0x16), but {}",
+                op
+            );
+            let reason = format!("Expected a stream Hello, got {}", op);
+            reject_stream(&mut send, ErrorCode::MalformedRequest, reason).await;
+        }
+    }
+}
@@ -0,0 +1,298 @@
+/* src/quic/tls.rs */
+
+use crate::quic::acl;
+use crate::setup::config::Config;
+use log::{error, warn};
+use openssl::nid::Nid;
+use openssl::x509::X509;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{ClientConfig, Connection, ServerConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{
+    CertificateDer, CertificateRevocationListDer, PrivateKeyDer, ServerName, UnixTime,
+};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    CertificateError, ClientConfig as RustlsClientConfig, DigitallySignedStruct, RootCertStore,
+    ServerConfig as RustlsServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Where clients record server fingerprints trusted on first use, unless `known_hosts` is set.
+pub const DEFAULT_KNOWN_HOSTS: &str = "anchr_known_hosts";
+
+// Set when a server presents a certificate other than the pinned or recorded one.
+static SERVER_CERT_CHANGED: AtomicBool = AtomicBool::new(false);
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open '{}': {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Cannot read certificates from '{}': {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in '{}'.", path));
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open '{}': {}", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("Cannot read private key from '{}': {}", path, e))?
+        .ok_or_else(|| format!("No private key found in '{}'.", path))
+}
+
+pub fn load_crls(path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open '{}': {}", path, e))?;
+    let crls = rustls_pemfile::crls(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Cannot read CRLs from '{}': {}", path, e))?;
+    if crls.is_empty() {
+        return Err(format!("No CRLs found in '{}'.", path));
+    }
+    Ok(crls)
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("Invalid CA certificate in '{}': {}", path, e))?;
+    }
+    Ok(roots)
+}
+
+/// [SERVER-SIDE] TLS config of the QUIC endpoint. With `client_ca` set, client certificates
+/// signed by it are verified against `client_crl`, and required if `require_client_cert` is on.
+pub fn build_server_config(cfg: &Config) -> Result<ServerConfig, String> {
+    let certs = load_certs(&cfg.setup.certificate)?;
+    let key = load_key(&cfg.setup.private_key)?;
+    let builder = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13]);
+    let builder = match &cfg.setup.client_ca {
+        Some(ca_path) => {
+            let mut verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca_path)?));
+            if let Some(crl_path) = &cfg.setup.client_crl {
+                verifier = verifier
+                    .with_crls(load_crls(crl_path)?)
+                    .only_check_end_entity_revocation();
+            }
+            let verifier = if cfg.setup.require_client_cert {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            let verifier = verifier
+                .build()
+                .map_err(|e| format!("Invalid client CA '{}': {}", ca_path, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid server certificate or key: {}", e))?;
+    // QUIC only allows 0 or u32::MAX here. Early data is only acted on once the handshake
+    // completes, so a replayed first flight never reaches the dispatcher. Each rebuilt config
+    // gets its own session cache: resuming across a CA or CRL change would skip the new checks.
+    tls.max_early_data_size = if cfg.network.zero_rtt { u32::MAX } else { 0 };
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| e.to_string())?;
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// [CLIENT-SIDE] TLS config verifying the server by `server_cert_sha256` if pinned, else by
+/// the `certificate` file as trust root, else by the fingerprint trusted on first use.
+/// Presents the client certificate if one is configured.
+pub fn build_client_config(cfg: &Config) -> Result<ClientConfig, Box<dyn Error + Send + Sync>> {
+    let setup = &cfg.setup;
+    let pin = match (&setup.server_cert_sha256, setup.certificate.is_empty()) {
+        (Some(expected), _) => {
+            let expected = acl::normalize_fingerprint(expected);
+            if expected.len() != 64 || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("server_cert_sha256 must be a SHA-256 hex digest.".into());
+            }
+            Some(Pin::Fixed(expected))
+        }
+        (None, true) => Some(Pin::FirstUse {
+            host: format!("{}:{}", cfg.network.address, cfg.network.port),
+            path: setup
+                .known_hosts
+                .clone()
+                .unwrap_or_else(|| DEFAULT_KNOWN_HOSTS.to_string()),
+        }),
+        (None, false) => None,
+    };
+    let builder = match pin {
+        Some(pin) => {
+            let provider = CryptoProvider::get_default()
+                .ok_or("No TLS crypto provider installed.")?
+                .clone();
+            let verifier = FingerprintVerifier { pin, provider };
+            RustlsClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+        }
+        None => {
+            let roots = load_roots(&setup.certificate)?;
+            RustlsClientConfig::builder().with_root_certificates(roots)
+        }
+    };
+    let mut tls = match (&cfg.setup.client_certificate, &cfg.setup.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            builder.with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err("client_certificate and client_key must be set together.".into()),
+    };
+    // Session tickets are kept in the config, so reconnects through the same endpoint resume.
+    tls.enable_early_data = cfg.network.zero_rtt;
+    Ok(ClientConfig::new(Arc::new(QuicClientConfig::try_from(
+        tls,
+    )?)))
+}
+
+/// [CLIENT-SIDE] Whether a handshake failed because the server certificate changed;
+/// reconnecting cannot fix that.
+pub fn server_cert_changed() -> bool {
+    SERVER_CERT_CHANGED.load(Ordering::SeqCst)
+}
+
+#[derive(Debug)]
+enum Pin {
+    // server_cert_sha256, normalized.
+    Fixed(String),
+    // `<address>:<port>` and the known hosts file recording it.
+    FirstUse { host: String, path: String },
+}
+
+// Trusts exactly one leaf certificate, whoever signed it and whatever names it carries.
+// Handshake signatures are still checked, so the server must hold the matching key.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    pin: Pin,
+    provider: Arc<CryptoProvider>,
+}
+
+impl FingerprintVerifier {
+    fn check(&self, actual: &str) -> Result<(), rustls::Error> {
+        let (host, expected, source) = match &self.pin {
+            Pin::Fixed(expected) => ("the server", expected.clone(), "server_cert_sha256".into()),
+            Pin::FirstUse { host, path } => match known_fingerprint(path, host) {
+                Some(expected) => (host.as_str(), expected, format!("'{}'", path)),
+                None => {
+                    record_fingerprint(path, host, actual).map_err(rustls::Error::General)?;
+                    warn!(
+                        "Trusting {} on first use; fingerprint {} recorded in '{}'.",
+                        host, actual, path
+                    );
+                    return Ok(());
+                }
+            },
+        };
+        if expected == actual {
+            return Ok(());
+        }
+        SERVER_CERT_CHANGED.store(true, Ordering::SeqCst);
+        error!("!!! THE CERTIFICATE OF {} HAS CHANGED !!!", host.to_uppercase());
+        error!("Expected fingerprint {} (from {}).", expected, source);
+        error!("Server presented     {}.", actual);
+        error!("Someone may be intercepting the connection. If the server was re-keyed,");
+        error!("update {} with the new fingerprint.", source);
+        Err(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        ))
+    }
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(&fingerprint(end_entity))
+            .map(|_| ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// Known hosts lines are `<address>:<port> <sha256 hex>`.
+fn known_fingerprint(path: &str, host: &str) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    content.lines().find_map(|line| {
+        let (name, fingerprint) = line.trim().split_once(' ')?;
+        (name == host).then(|| acl::normalize_fingerprint(fingerprint.trim()))
+    })
+}
+
+fn record_fingerprint(path: &str, host: &str, fingerprint: &str) -> Result<(), String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{} {}", host, fingerprint))
+        .map_err(|e| format!("Cannot record server fingerprint in '{}': {}", path, e))
+}
+
+/// [SERVER-SIDE] The verified leaf certificate the client presented, if any.
+pub fn peer_certificate(conn: &Connection) -> Option<CertificateDer<'static>> {
+    conn.peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?
+        .into_iter()
+        .next()
+}
+
+/// SHA-256 over the DER encoding, as lowercase hex.
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    hex::encode(Sha256::digest(cert.as_ref()))
+}
+
+/// Common name of the certificate subject.
+pub fn common_name(cert: &CertificateDer) -> Option<String> {
+    let x509 = X509::from_der(cert.as_ref()).ok()?;
+    let entry = x509.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    entry.data().as_utf8().ok().map(|name| name.to_string())
+}
@@ -0,0 +1,845 @@
+/* src/rfs/image.rs */
+
+use crate::audit::{self, AuditEvent};
+use crate::quic::acl::Identity;
+use crate::quic::client::ClientSession;
+use crate::rfs::{stats, volume};
+use crate::setup::config::Config;
+use crate::wsm::codec::{self, WsmCodec, WsmMessage, WsmReader, WsmWriter};
+use crate::wsm::error::{ErrorCode, ErrorReply};
+use crate::wsm::header::{PayloadType, RESERVED_FINAL_FLAG};
+use crate::wsm::opcode::Opcode;
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::SeekFrom;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::fs as tokio_fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+pub const IMAGE_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const IMAGE_WORKERS: u8 = 4;
+// A chunk that still fails to verify after this many tries fails the whole transfer.
+const MAX_CHUNK_ATTEMPTS: u32 = 5;
+
+// Chunk inquiry reply codes (opcode 0x00, 1-byte payload).
+const CODE_LOAD: u8 = 1;
+const CODE_SKIP: u8 = 2;
+const CODE_ZERO: u8 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageMode {
+    Pull,
+    Push,
+}
+
+/// Sent in the image Hello (0x12) that opens every image worker stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageRequest {
+    pub dev_name: String,
+    pub mode: ImageMode,
+    pub confirm_overwrite: bool,
+}
+
+/// Server reply (0x13) describing the device behind an image stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageInfo {
+    pub size: u64,
+    pub chunk_size: u64,
+}
+
+#[derive(Default)]
+struct TransferCounters {
+    transferred: AtomicU64,
+    skipped: AtomicU64,
+    zero: AtomicU64,
+    // Failed tries per chunk, and whether one of them ran out and the job was given up.
+    attempts: Mutex<HashMap<u64, u32>>,
+    abandoned: AtomicBool,
+}
+
+fn is_all_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+fn chunk_len(chunk_id: u64, chunk_size: u64, total_size: u64) -> usize {
+    std::cmp::min(chunk_size, total_size - chunk_id * chunk_size) as usize
+}
+
+async fn read_at(file: &mut tokio_fs::File, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buffer = vec![0; len];
+    file.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+async fn write_at(file: &mut tokio_fs::File, offset: u64, data: &[u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
+
+// Turns a region of the local image back into a hole, falling back to writing zeros.
+async fn zero_range(file: &mut tokio_fs::File, offset: u64, len: usize) -> std::io::Result<()> {
+    file.flush().await?;
+    if volume::punch_hole(file.as_raw_fd(), offset, len as u64).is_ok() {
+        return Ok(());
+    }
+    write_at(file, offset, &vec![0; len]).await
+}
+
+// Image streams are QUIC streams; the helpers take any byte stream so the server loop can be
+// exercised in memory.
+async fn send_message<W: AsyncWrite + Unpin>(
+    send: &mut FramedWrite<W, WsmCodec>,
+    opcode: Opcode,
+    reserved: u8,
+    payload: Vec<u8>,
+) -> bool {
+    let message = WsmMessage::new(opcode, 0, PayloadType::Raw, payload).with_reserved(reserved);
+    send.send(message).await.is_ok()
+}
+
+async fn read_message<R: AsyncRead + Unpin>(
+    recv: &mut FramedRead<R, WsmCodec>,
+) -> Option<WsmMessage> {
+    recv.next().await?.ok()
+}
+
+// Reports a failed image request; image streams always use message ID 0.
+async fn send_error<W: AsyncWrite + Unpin>(
+    send: &mut FramedWrite<W, WsmCodec>,
+    reply: ErrorReply,
+) -> bool {
+    send.send(reply.to_message(0)).await.is_ok()
+}
+
+// --- SERVER-SIDE IMAGE LOGIC ---
+
+async fn open_device(
+    request: &ImageRequest,
+    cfg: &Config,
+    identity: &Identity,
+) -> Result<(tokio_fs::File, u64), ErrorReply> {
+    let rfs_config = volume::find_block_volume(cfg, &request.dev_name, identity)?;
+    if request.mode == ImageMode::Push && rfs_config.read_only {
+        return Err(ErrorReply::new(
+            ErrorCode::ReadOnly,
+            format!("Device '{}' is read-only.", request.dev_name),
+        ));
+    }
+    if request.mode == ImageMode::Push && !request.confirm_overwrite {
+        return Err(ErrorReply::new(
+            ErrorCode::MalformedRequest,
+            "Refusing to overwrite a device without explicit confirmation.",
+        ));
+    }
+    let device_path = PathBuf::from(rfs_config.device.clone().unwrap_or_default());
+    let size_path = device_path.clone();
+    let storage_error = |what: String| ErrorReply::new(ErrorCode::StorageFailure, what);
+    let size = task::spawn_blocking(move || volume::device_size(&size_path))
+        .await
+        .map_err(|e| ErrorReply::new(ErrorCode::Internal, e.to_string()))?
+        .map_err(|e| storage_error(format!("Failed to read device size: {}", e)))?;
+    let file = tokio_fs::OpenOptions::new()
+        .read(true)
+        .write(request.mode == ImageMode::Push)
+        .open(&device_path)
+        .await
+        .map_err(|e| storage_error(format!("Failed to open device: {}", e)))?;
+    Ok((file, size))
+}
+
+/// [SERVER-SIDE] Serves one image worker stream after its Hello (0x12) was read.
+pub async fn handle_image_stream(
+    mut send: WsmWriter,
+    mut recv: WsmReader,
+    cfg: Config,
+    request: ImageRequest,
+    encoding: PayloadType,
+    identity: Identity,
+) {
+    let (mut device, size) = match open_device(&request, &cfg, &identity).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("! Image: Rejected {:?} of '{}': {}", request.mode, request.dev_name, e);
+            audit::record_for(
+                &identity,
+                AuditEvent::ImageRejected {
+                    dev_name: request.dev_name.clone(),
+                    mode: request.mode,
+                    reason: e.message.clone(),
+                },
+            );
+            send_error(&mut send, e).await;
+            return;
+        }
+    };
+    audit::record_for(
+        &identity,
+        AuditEvent::ImageOpened {
+            dev_name: request.dev_name.clone(),
+            mode: request.mode,
+            size,
+        },
+    );
+    if cfg.setup.log_level == "debug" {
+        println!(
+            "-> Image: {:?} stream opened for '{}' ({} bytes).",
+            request.mode, request.dev_name, size
+        );
+    }
+
+    let info = ImageInfo {
+        size,
+        chunk_size: IMAGE_CHUNK_SIZE,
+    };
+    let reply = match WsmMessage::encoded(Opcode::ImageInfo, 0, encoding, &info) {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("! Image: {}", e);
+            send_error(&mut send, ErrorReply::new(ErrorCode::Internal, e)).await;
+            return;
+        }
+    };
+    if send.send(reply).await.is_err() {
+        return;
+    }
+
+    serve_chunks(&mut send, &mut recv, &mut device, size, request.mode).await;
+
+    if request.mode == ImageMode::Push && device.sync_all().await.is_err() {
+        eprintln!("! Image: Failed to sync device for '{}'.", request.dev_name);
+    }
+    if cfg.setup.log_level == "debug" {
+        println!("-> Image stream finished for '{}'.", request.dev_name);
+    }
+}
+
+// Answers chunk requests until the client closes the stream or a request fails.
+async fn serve_chunks<W, R>(
+    send: &mut FramedWrite<W, WsmCodec>,
+    recv: &mut FramedRead<R, WsmCodec>,
+    device: &mut tokio_fs::File,
+    size: u64,
+    mode: ImageMode,
+) where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    while let Some(msg) = read_message(recv).await {
+        let ok = match msg.opcode {
+            Opcode::ImageChunkInquiry => {
+                handle_chunk_inquiry(&msg.payload, send, device, size, mode).await
+            }
+            Opcode::ImageChunkData if mode == ImageMode::Push => {
+                handle_chunk_data(&msg.payload, send, device, size).await
+            }
+            op => {
+                eprintln!("! Image: Unexpected opcode {}", op);
+                let reply = ErrorReply::new(
+                    ErrorCode::Unsupported,
+                    format!("Unexpected opcode {} on image stream", op),
+                );
+                send_error(send, reply).await;
+                false
+            }
+        };
+        if !ok {
+            break;
+        }
+    }
+}
+
+async fn handle_chunk_inquiry<W: AsyncWrite + Unpin>(
+    payload: &[u8],
+    send: &mut FramedWrite<W, WsmCodec>,
+    device: &mut tokio_fs::File,
+    size: u64,
+    mode: ImageMode,
+) -> bool {
+    if payload.len() != 40 {
+        let reply = ErrorReply::new(
+            ErrorCode::MalformedRequest,
+            "Chunk inquiry payload must be 40 bytes",
+        );
+        send_error(send, reply).await;
+        return false;
+    }
+    let chunk_id = u64::from_le_bytes(payload[0..8].try_into().unwrap());
+    let client_hash: [u8; 32] = payload[8..40].try_into().unwrap();
+    let Some(offset) = chunk_id.checked_mul(IMAGE_CHUNK_SIZE).filter(|&offset| offset < size)
+    else {
+        let reply = ErrorReply::new(
+            ErrorCode::MalformedRequest,
+            format!("Chunk #{} is beyond the end of the device", chunk_id),
+        );
+        send_error(send, reply).await;
+        return false;
+    };
+    let data = match read_at(device, offset, chunk_len(chunk_id, IMAGE_CHUNK_SIZE, size)).await {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("! Image: Failed to read chunk #{}: {}", chunk_id, e);
+            let reply = ErrorReply::new(
+                ErrorCode::StorageFailure,
+                format!("Failed to read chunk #{}: {}", chunk_id, e),
+            );
+            send_error(send, reply).await;
+            return false;
+        }
+    };
+    let device_hash: [u8; 32] = Sha256::digest(&data).into();
+
+    if device_hash == client_hash {
+        return send_message(send, Opcode::Ack, RESERVED_FINAL_FLAG, vec![CODE_SKIP]).await;
+    }
+    match mode {
+        ImageMode::Push => send_message(send, Opcode::Ack, 0, vec![CODE_LOAD]).await,
+        ImageMode::Pull if is_all_zero(&data) => {
+            send_message(send, Opcode::Ack, RESERVED_FINAL_FLAG, vec![CODE_ZERO]).await
+        }
+        ImageMode::Pull => {
+            let mut reply = Vec::with_capacity(40 + data.len());
+            reply.extend_from_slice(&chunk_id.to_le_bytes());
+            reply.extend_from_slice(&device_hash);
+            reply.extend_from_slice(&data);
+            send_message(send, Opcode::ImageChunkData, RESERVED_FINAL_FLAG, reply).await
+        }
+    }
+}
+
+async fn handle_chunk_data<W: AsyncWrite + Unpin>(
+    payload: &[u8],
+    send: &mut FramedWrite<W, WsmCodec>,
+    device: &mut tokio_fs::File,
+    size: u64,
+) -> bool {
+    if payload.len() <= 40 {
+        let reply = ErrorReply::new(ErrorCode::MalformedRequest, "Chunk data payload is empty");
+        send_error(send, reply).await;
+        return false;
+    }
+    let chunk_id = u64::from_le_bytes(payload[0..8].try_into().unwrap());
+    let expected_hash: [u8; 32] = payload[8..40].try_into().unwrap();
+    let data = &payload[40..];
+    // The chunk id comes from the peer; an overflowing offset is as malformed as a large one.
+    let fits = |offset: &u64| offset.checked_add(data.len() as u64).is_some_and(|end| end <= size);
+    let Some(offset) = chunk_id.checked_mul(IMAGE_CHUNK_SIZE).filter(fits) else {
+        eprintln!("! Image: Chunk #{} exceeds the device size.", chunk_id);
+        let reply = ErrorReply::new(
+            ErrorCode::MalformedRequest,
+            format!("Chunk #{} exceeds the device size", chunk_id),
+        );
+        send_error(send, reply).await;
+        return false;
+    };
+    let mut is_final = false;
+    if <[u8; 32]>::from(Sha256::digest(data)) != expected_hash {
+        eprintln!(
+            "! Image: Received chunk #{} with mismatched hash. Requesting reload.",
+            chunk_id
+        );
+    } else if let Err(e) = write_at(device, offset, data).await {
+        eprintln!("! Image: Failed to write chunk #{} to device: {}", chunk_id, e);
+        let reply = ErrorReply::new(
+            ErrorCode::StorageFailure,
+            format!("Failed to write chunk #{}: {}", chunk_id, e),
+        );
+        send_error(send, reply).await;
+        return false;
+    } else {
+        is_final = true;
+    }
+    send_message(send, Opcode::Ack, if is_final { RESERVED_FINAL_FLAG } else { 0 }, Vec::new()).await
+}
+
+// --- CLIENT-SIDE IMAGE LOGIC ---
+
+async fn open_image_stream(
+    connection: &Connection,
+    request: &ImageRequest,
+    encoding: PayloadType,
+) -> Result<(WsmWriter, WsmReader, ImageInfo), String> {
+    let (send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(|e| format!("Failed to open image stream: {}", e))?;
+    let (mut send, mut recv) = codec::framed(send, recv, WsmCodec::transfer());
+    send.send(WsmMessage::encoded(Opcode::ImageHello, 0, encoding, request)?)
+        .await
+        .map_err(|e| format!("Failed to send image Hello: {}", e))?;
+
+    let reply = read_message(&mut recv)
+        .await
+        .ok_or_else(|| "Server closed the image stream.".to_string())?;
+    match reply.opcode {
+        Opcode::ImageInfo => reply
+            .decode::<ImageInfo>()
+            .map(|info| (send, recv, info))
+            .map_err(|e| format!("Invalid image info from server: {}", e)),
+        Opcode::Error => Err(format!(
+            "Server refused image transfer: {}",
+            ErrorReply::from_message(&reply)
+        )),
+        op => Err(format!("Unexpected reply {} to image Hello.", op)),
+    }
+}
+
+async fn prepare_local_image(
+    local_path: &Path,
+    mode: ImageMode,
+    device_size: u64,
+) -> Result<u64, String> {
+    match mode {
+        ImageMode::Pull => {
+            let file = tokio_fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(local_path)
+                .await
+                .map_err(|e| format!("Failed to open '{}': {}", local_path.display(), e))?;
+            // Extending with set_len leaves holes, so untouched zero chunks stay sparse.
+            file.set_len(device_size)
+                .await
+                .map_err(|e| format!("Failed to size '{}': {}", local_path.display(), e))?;
+            Ok(device_size)
+        }
+        ImageMode::Push => {
+            let local_size = tokio_fs::metadata(local_path)
+                .await
+                .map_err(|e| format!("Failed to access '{}': {}", local_path.display(), e))?
+                .len();
+            if local_size > device_size {
+                return Err(format!(
+                    "Image is {} bytes but the device only holds {} bytes.",
+                    local_size, device_size
+                ));
+            }
+            if local_size < device_size {
+                warn!(
+                    "> Image is smaller than the device; only the first {} bytes will be written.",
+                    local_size
+                );
+            }
+            Ok(local_size)
+        }
+    }
+}
+
+/// [CLIENT-SIDE] Pulls or pushes a whole block volume over parallel image streams.
+pub async fn run_image_transfer(session: ClientSession, request: ImageRequest, local_path: PathBuf) {
+    let start_time = Instant::now();
+    let connection = session.connection;
+    let encoding = session.caps.encoding();
+    let (send, recv, info) = match open_image_stream(&connection, &request, encoding).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("! Image: {}", e);
+            return;
+        }
+    };
+    let total_size = match prepare_local_image(&local_path, request.mode, info.size).await {
+        Ok(size) => size,
+        Err(e) => {
+            error!("! Image: {}", e);
+            return;
+        }
+    };
+    let total_chunks = total_size.div_ceil(info.chunk_size);
+    let queue: Arc<Mutex<VecDeque<u64>>> = Arc::new(Mutex::new((0..total_chunks).collect()));
+    let counters = Arc::new(TransferCounters::default());
+    info!(
+        "> Image {:?} of '{}': {} bytes in {} chunks over {} streams.",
+        request.mode, request.dev_name, total_size, total_chunks, IMAGE_WORKERS
+    );
+
+    let mut handles = Vec::new();
+    let mut first_stream = Some((send, recv));
+    for worker_id in 1..=IMAGE_WORKERS {
+        let streams = first_stream.take();
+        let connection = connection.clone();
+        let request = request.clone();
+        let local_path = local_path.clone();
+        let queue = queue.clone();
+        let counters = counters.clone();
+        let chunk_size = info.chunk_size;
+        handles.push(tokio::spawn(async move {
+            let (send, recv) = match streams {
+                Some(s) => s,
+                None => match open_image_stream(&connection, &request, encoding).await {
+                    Ok((send, recv, _)) => (send, recv),
+                    Err(e) => {
+                        error!("! Image worker {}: {}", worker_id, e);
+                        return;
+                    }
+                },
+            };
+            let worker = ImageWorker {
+                worker_id,
+                mode: request.mode,
+                chunk_size,
+                total_size,
+                total_chunks,
+                queue,
+                counters,
+            };
+            worker.run(send, recv, &local_path).await;
+        }));
+    }
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+    let transferred = counters.transferred.load(Ordering::Relaxed);
+    let skipped = counters.skipped.load(Ordering::Relaxed);
+    let zero = counters.zero.load(Ordering::Relaxed);
+    if transferred + skipped + zero < total_chunks {
+        error!(
+            "! Image {:?} of '{}' incomplete: {}/{} chunks done. Re-run the command to resume.",
+            request.mode,
+            request.dev_name,
+            transferred + skipped + zero,
+            total_chunks
+        );
+        return;
+    }
+    info!(
+        "+ Image {:?} of '{}' complete: {} transferred, {} unchanged, {} zero chunks.",
+        request.mode, request.dev_name, transferred, skipped, zero
+    );
+    stats::log_transfer_stats(total_size, start_time.elapsed());
+}
+
+struct ImageWorker {
+    worker_id: u8,
+    mode: ImageMode,
+    chunk_size: u64,
+    total_size: u64,
+    total_chunks: u64,
+    queue: Arc<Mutex<VecDeque<u64>>>,
+    counters: Arc<TransferCounters>,
+}
+
+impl ImageWorker {
+    async fn run(&self, mut send: WsmWriter, mut recv: WsmReader, local_path: &Path) {
+        let mut local = match tokio_fs::OpenOptions::new()
+            .read(true)
+            .write(self.mode == ImageMode::Pull)
+            .open(local_path)
+            .await
+        {
+            Ok(f) => f,
+            Err(e) => {
+                error!("! Image worker {}: Failed to open local image: {}", self.worker_id, e);
+                return;
+            }
+        };
+
+        loop {
+            if self.counters.abandoned.load(Ordering::Relaxed) {
+                break;
+            }
+            let Some(chunk_id) = self.queue.lock().await.pop_front() else {
+                break;
+            };
+            let offset = chunk_id * self.chunk_size;
+            let len = chunk_len(chunk_id, self.chunk_size, self.total_size);
+            let local_data = match read_at(&mut local, offset, len).await {
+                Ok(d) => d,
+                Err(e) => {
+                    error!(
+                        "! Image worker {}: Failed to read local chunk {}: {}",
+                        self.worker_id, chunk_id, e
+                    );
+                    break;
+                }
+            };
+            let local_hash: [u8; 32] = Sha256::digest(&local_data).into();
+            let mut inquiry = chunk_id.to_le_bytes().to_vec();
+            inquiry.extend_from_slice(&local_hash);
+            if !send_message(&mut send, Opcode::ImageChunkInquiry, 0, inquiry).await {
+                self.queue.lock().await.push_back(chunk_id);
+                break;
+            }
+
+            let done = match self.mode {
+                ImageMode::Pull => self.pull_chunk(&mut recv, &mut local, chunk_id, offset, len).await,
+                ImageMode::Push => {
+                    self.push_chunk(&mut send, &mut recv, chunk_id, &local_hash, &local_data)
+                        .await
+                }
+            };
+            match done {
+                Some(true) => {}
+                Some(false) => self.retry(chunk_id).await,
+                None => {
+                    // Stream is unusable; leave the chunk for the remaining workers.
+                    self.queue.lock().await.push_back(chunk_id);
+                    break;
+                }
+            }
+        }
+        let _ = send.get_mut().finish();
+        info!("> Image worker {} finished.", self.worker_id);
+    }
+
+    // Requeues a chunk that failed to verify, or gives up the job once it has run out of tries.
+    async fn retry(&self, chunk_id: u64) {
+        let attempts = {
+            let mut attempts = self.counters.attempts.lock().await;
+            let count = attempts.entry(chunk_id).or_default();
+            *count += 1;
+            *count
+        };
+        if attempts < MAX_CHUNK_ATTEMPTS {
+            self.queue.lock().await.push_back(chunk_id);
+            return;
+        }
+        error!(
+            "! Image worker {}: Chunk #{} failed to verify {} times; giving up.",
+            self.worker_id, chunk_id, attempts
+        );
+        self.counters.abandoned.store(true, Ordering::Relaxed);
+    }
+
+    fn log_progress(&self) {
+        let done = self.counters.transferred.load(Ordering::Relaxed)
+            + self.counters.skipped.load(Ordering::Relaxed)
+            + self.counters.zero.load(Ordering::Relaxed);
+        info!("> {}/{} image chunks completed.", done, self.total_chunks);
+    }
+
+    // Returns Some(true) when the chunk is settled, Some(false) to retry it, None on stream failure.
+    async fn pull_chunk(
+        &self,
+        recv: &mut WsmReader,
+        local: &mut tokio_fs::File,
+        chunk_id: u64,
+        offset: u64,
+        len: usize,
+    ) -> Option<bool> {
+        let reply = read_message(recv).await?;
+        let payload = &reply.payload;
+        match (reply.opcode, payload.first()) {
+            (Opcode::Ack, Some(&CODE_SKIP)) => {
+                self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            (Opcode::Ack, Some(&CODE_ZERO)) => {
+                if let Err(e) = zero_range(local, offset, len).await {
+                    error!("! Image: Failed to zero chunk {}: {}", chunk_id, e);
+                    return None;
+                }
+                self.counters.zero.fetch_add(1, Ordering::Relaxed);
+            }
+            (Opcode::ImageChunkData, _) if payload.len() == 40 + len => {
+                let data = &payload[40..];
+                let expected: [u8; 32] = payload[8..40].try_into().unwrap();
+                if <[u8; 32]>::from(Sha256::digest(data)) != expected {
+                    warn!("! Image: Chunk {} failed verification. Retrying.", chunk_id);
+                    return Some(false);
+                }
+                if let Err(e) = write_at(local, offset, data).await {
+                    error!("! Image: Failed to write chunk {}: {}", chunk_id, e);
+                    return None;
+                }
+                self.counters.transferred.fetch_add(1, Ordering::Relaxed);
+            }
+            (Opcode::Error, _) => {
+                error!(
+                    "! Image: Server failed chunk {}: {}",
+                    chunk_id,
+                    ErrorReply::from_message(&reply)
+                );
+                return None;
+            }
+            (op, _) => {
+                warn!("! Image: Unexpected reply {} for chunk {}.", op, chunk_id);
+                return None;
+            }
+        }
+        self.log_progress();
+        Some(true)
+    }
+
+    async fn push_chunk(
+        &self,
+        send: &mut WsmWriter,
+        recv: &mut WsmReader,
+        chunk_id: u64,
+        local_hash: &[u8; 32],
+        local_data: &[u8],
+    ) -> Option<bool> {
+        let reply = read_message(recv).await?;
+        match (reply.opcode, reply.payload.first()) {
+            (Opcode::Ack, Some(&CODE_SKIP)) => {
+                self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            (Opcode::Ack, Some(&CODE_LOAD)) => {
+                let mut message = chunk_id.to_le_bytes().to_vec();
+                message.extend_from_slice(local_hash);
+                message.extend_from_slice(local_data);
+                if !send_message(send, Opcode::ImageChunkData, 0, message).await {
+                    return None;
+                }
+                let ack = read_message(recv).await?;
+                if ack.opcode == Opcode::Error {
+                    error!(
+                        "! Image: Server failed to store chunk {}: {}",
+                        chunk_id,
+                        ErrorReply::from_message(&ack)
+                    );
+                    return None;
+                }
+                if !ack.is_final() {
+                    warn!("! Image: Server requested reload for chunk {}.", chunk_id);
+                    return Some(false);
+                }
+                self.counters.transferred.fetch_add(1, Ordering::Relaxed);
+            }
+            (Opcode::Error, _) => {
+                error!(
+                    "! Image: Server failed chunk {}: {}",
+                    chunk_id,
+                    ErrorReply::from_message(&reply)
+                );
+                return None;
+            }
+            (op, _) => {
+                warn!("! Image: Unexpected reply {} for chunk {}.", op, chunk_id);
+                return None;
+            }
+        }
+        self.log_progress();
+        Some(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+    use tokio::time;
+
+    const DEVICE_SIZE: u64 = IMAGE_CHUNK_SIZE + 4096;
+
+    struct Client {
+        send: FramedWrite<DuplexStream, WsmCodec>,
+        recv: FramedRead<DuplexStream, WsmCodec>,
+        server: JoinHandle<()>,
+    }
+
+    // Serves a push session on a sparse file standing in for the device.
+    async fn push_session(dir: &TempDir, writable: bool) -> Client {
+        let path = dir.path().join("device.img");
+        std::fs::File::create(&path)
+            .unwrap()
+            .set_len(DEVICE_SIZE)
+            .unwrap();
+        let mut device = tokio_fs::OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(&path)
+            .await
+            .unwrap();
+        let (client_out, server_in) = tokio::io::duplex(1 << 16);
+        let (server_out, client_in) = tokio::io::duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            let mut send = FramedWrite::new(server_out, WsmCodec::transfer());
+            let mut recv = FramedRead::new(server_in, WsmCodec::transfer());
+            serve_chunks(
+                &mut send,
+                &mut recv,
+                &mut device,
+                DEVICE_SIZE,
+                ImageMode::Push,
+            )
+            .await;
+        });
+        Client {
+            send: FramedWrite::new(client_out, WsmCodec::transfer()),
+            recv: FramedRead::new(client_in, WsmCodec::transfer()),
+            server,
+        }
+    }
+
+    fn chunk(chunk_id: u64, data: &[u8]) -> WsmMessage {
+        let mut payload = chunk_id.to_le_bytes().to_vec();
+        payload.extend_from_slice(&Sha256::digest(data));
+        payload.extend_from_slice(data);
+        WsmMessage::new(Opcode::ImageChunkData, 0, PayloadType::Raw, payload).with_final()
+    }
+
+    // Sends `msg` and returns the server's reply.
+    async fn exchange(client: &mut Client, msg: WsmMessage) -> WsmMessage {
+        client.send.send(msg).await.unwrap();
+        read_message(&mut client.recv)
+            .await
+            .expect("server replies")
+    }
+
+    // The session must end on its own while the client still holds its stream open.
+    async fn assert_session_ends(client: Client) {
+        let ended = time::timeout(Duration::from_secs(5), client.server).await;
+        assert!(ended.is_ok(), "server kept the image session open");
+        drop(client.send);
+    }
+
+    #[tokio::test]
+    async fn written_chunks_keep_the_session_open() {
+        let dir = TempDir::new().unwrap();
+        let mut client = push_session(&dir, true).await;
+        let reply = exchange(&mut client, chunk(1, &[7; 4096])).await;
+        assert_eq!(reply.opcode, Opcode::Ack);
+        assert!(reply.is_final());
+        assert!(!client.server.is_finished());
+    }
+
+    #[tokio::test]
+    async fn out_of_range_chunks_end_the_session() {
+        for chunk_id in [2, u64::MAX / IMAGE_CHUNK_SIZE + 1] {
+            let dir = TempDir::new().unwrap();
+            let mut client = push_session(&dir, true).await;
+            let reply = exchange(&mut client, chunk(chunk_id, &[7; 16])).await;
+            assert_eq!(reply.opcode, Opcode::Error);
+            let error = ErrorReply::from_message(&reply);
+            assert_eq!(error.code, ErrorCode::MalformedRequest as u16);
+            assert_session_ends(client).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_overrunning_the_device_end_the_session() {
+        let dir = TempDir::new().unwrap();
+        let mut client = push_session(&dir, true).await;
+        let reply = exchange(&mut client, chunk(1, &[7; 8192])).await;
+        assert_eq!(reply.opcode, Opcode::Error);
+        assert_session_ends(client).await;
+    }
+
+    #[tokio::test]
+    async fn failed_writes_end_the_session() {
+        let dir = TempDir::new().unwrap();
+        let mut client = push_session(&dir, false).await;
+        let reply = exchange(&mut client, chunk(0, &[7; 4096])).await;
+        assert_eq!(reply.opcode, Opcode::Error);
+        let error = ErrorReply::from_message(&reply);
+        assert_eq!(error.code, ErrorCode::StorageFailure as u16);
+        assert_session_ends(client).await;
+    }
+}
@@ -1,59 +1,147 @@
 /* src/rfs/list.rs */
 
-use crate::setup::config::{Config, RfsConfig};
-use crate::wsm::header::{PayloadType, WsmHeader, RESERVED_FINAL_FLAG};
-use quinn::RecvStream;
+use crate::console::debug::format_bytes;
+use crate::quic::acl::Identity;
+use crate::quic::service::OngoingUploads;
+use crate::rfs::volume::{self, VolumeInfo};
+use crate::setup::config::{Config, Permission, RfsConfig};
+use crate::wsm::codec::{self, WsmMessage};
+use crate::wsm::error::{self, ErrorCode};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::payload;
+use crate::wsm::requests::{RequestTracker, REQUEST_TIMEOUT};
+use std::collections::HashMap;
 use tokio::sync::mpsc;
+use tokio::task;
 
 // [SERVER-SIDE] Handles the `rfs list` (0x05) request.
-pub async fn handle_request(message_id: u8, tx: mpsc::Sender<Vec<u8>>, cfg: &Config) {
-    let rfs_list = cfg.rfs.as_ref().unwrap();
-    match serde_json::to_string(rfs_list) {
-        Ok(json_payload) => {
-            let payload_bytes = json_payload.as_bytes();
-            let response_header = WsmHeader::with_reserved(
-                0x04, // Opcode for list response
-                message_id,
-                PayloadType::Json,
-                payload_bytes.len() as u32,
-                RESERVED_FINAL_FLAG,
-            );
-            let mut response = response_header.to_bytes().to_vec();
-            response.extend_from_slice(payload_bytes);
-            if tx.send(response).await.is_err() {
+pub async fn handle_request(
+    message_id: u32,
+    tx: mpsc::Sender<WsmMessage>,
+    cfg: &Config,
+    ongoing_uploads: OngoingUploads,
+    encoding: PayloadType,
+    identity: &Identity,
+) {
+    let mut active_per_volume: HashMap<String, usize> = HashMap::new();
+    for metadata in ongoing_uploads.lock().await.values() {
+        if let Some(dev_name) = volume::dev_name_of(&metadata.target_dir) {
+            *active_per_volume.entry(dev_name.to_string()).or_default() += 1;
+        }
+    }
+
+    // Volumes the user may not list are left out entirely.
+    let rfs_list: Vec<RfsConfig> = cfg
+        .rfs
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|rfs| identity.allows(&rfs.dev_name, Permission::List))
+        .collect();
+    let identity = identity.clone();
+    let probed = task::spawn_blocking(move || {
+        let mounts = volume::read_mountinfo().unwrap_or_else(|e| {
+            eprintln!("! WSM-Server: {}", e);
+            Vec::new()
+        });
+        rfs_list
+            .iter()
+            .map(|rfs| {
+                let active = active_per_volume.get(&rfs.dev_name).copied().unwrap_or(0);
+                let expose_bind_path = identity.allows(&rfs.dev_name, Permission::Admin);
+                volume::probe_volume(rfs, &mounts, active, expose_bind_path)
+            })
+            .collect::<Vec<VolumeInfo>>()
+    })
+    .await;
+    let volumes = match probed {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("! WSM-Server: Volume probe task failed: {}", e);
+            error::send_error(&tx, message_id, ErrorCode::Internal, "Volume probe failed").await;
+            return;
+        }
+    };
+
+    match payload::encode(encoding, &volumes) {
+        Ok(list_payload) => {
+            let response = WsmMessage::new(Opcode::ListResponse, message_id, encoding, list_payload)
+                .with_final();
+            if !codec::send_streamed(&tx, response).await {
                 eprintln!("! WSM-Server: Failed to send rfs list response to channel.");
             }
         }
         Err(e) => {
             eprintln!("! WSM-Server: Failed to serialize rfs list: {}", e);
+            error::send_error(
+                &tx,
+                message_id,
+                ErrorCode::Internal,
+                format!("Failed to serialize rfs list: {}", e),
+            )
+            .await;
         }
     }
 }
 
-// [CLIENT-SIDE] Handles the `rfs list` (0x04) response.
-pub async fn handle_response(header: &WsmHeader, recv: &mut RecvStream) {
-    if header.payload_len == 0 {
-        log::info!("> Received empty volume list from server.");
-        return;
-    }
-    let mut payload_buf = vec![0; header.payload_len as usize];
-    if recv.read_exact(&mut payload_buf).await.is_err() {
-        log::error!("! WSM-Client: Failed to read rfs list payload.");
-        return;
-    }
-    match serde_json::from_slice::<Vec<RfsConfig>>(&payload_buf) {
-        Ok(rfs_list) => {
-            let mut display_text = String::from("Volume List Received:\n");
-            for (i, rfs) in rfs_list.iter().enumerate() {
-                display_text.push_str(&format!(
-                    "  [{}] dev_name: '{}', bind_path: '{}'\n",
-                    i, rfs.dev_name, rfs.bind_path
-                ));
-            }
-            log::info!("{}", display_text.trim_end());
-        }
-        Err(e) => {
-            log::error!("! WSM-Client: Failed to deserialize rfs list: {}", e);
-        }
+// [CLIENT-SIDE] Requests the volume list (0x05) and waits for the response (0x04).
+pub async fn fetch(requests: &RequestTracker) -> Result<Vec<VolumeInfo>, String> {
+    let reply = requests
+        .request(|id| WsmMessage::empty(Opcode::ListRequest, id), REQUEST_TIMEOUT)
+        .await
+        .map_err(|e| e.to_string())?;
+    if reply.opcode != Opcode::ListResponse {
+        return Err(format!("Unexpected reply {} to rfs list.", reply.opcode));
+    }
+    if reply.payload.is_empty() {
+        return Ok(Vec::new());
+    }
+    reply
+        .decode::<Vec<VolumeInfo>>()
+        .map_err(|e| format!("Failed to deserialize rfs list: {}", e))
+}
+
+/// Renders a volume list for the log panel.
+pub fn format_list(volumes: &[VolumeInfo]) -> String {
+    let mut display_text = String::from("Volume List Received:\n");
+    for (i, v) in volumes.iter().enumerate() {
+        display_text.push_str(&format_volume(i, v));
     }
-}
\ No newline at end of file
+    display_text.trim_end().to_string()
+}
+
+fn format_volume(index: usize, v: &VolumeInfo) -> String {
+    let mut line = format!("  [{}] dev_name: '{}'", index, v.dev_name);
+    if let Some(bind_path) = &v.bind_path {
+        line.push_str(&format!(", bind_path: '{}'", bind_path));
+    }
+    if let Some(err) = &v.error {
+        line.push_str(&format!(" - UNAVAILABLE: {}\n", err));
+        return line;
+    }
+    let used_pct = if v.total_bytes > 0 {
+        v.total_bytes.saturating_sub(v.free_bytes) as f64 / v.total_bytes as f64 * 100.0
+    } else {
+        0.0
+    };
+    line.push_str(&format!(
+        "\n      fs: {}{}{}, free: {} / {} ({:.1}% used), inodes: {} / {} free, uploads: {}\n",
+        v.fs_type.as_deref().unwrap_or("unknown"),
+        if v.is_mountpoint { "" } else { " (not a mountpoint)" },
+        if v.read_only { ", READ-ONLY" } else { "" },
+        format_bytes(v.free_bytes),
+        format_bytes(v.total_bytes),
+        used_pct,
+        v.free_inodes,
+        v.total_inodes,
+        v.active_uploads
+    ));
+    if v.corrupted_files > 0 {
+        line.push_str(&format!(
+            "      WARNING: {} file(s) failed integrity checks\n",
+            v.corrupted_files
+        ));
+    }
+    line
+}
@@ -8,10 +8,14 @@ use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::Mutex;
 
+pub mod image;
 pub mod list;
+pub mod scrub;
 pub mod stats;
 pub mod upload;
 pub mod verify;
+pub mod volume;
+pub mod watch;
 pub mod worker;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,20 +26,17 @@ pub struct UploadMetadata {
     pub file_hash: String,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum UploadState {
-    Initiated,
-    WorkersOpening,
-    Streaming,
-    Finishing,
+impl UploadMetadata {
+    /// Virtual `/<dev_name>/...` path of the uploaded file.
+    pub fn virtual_path(&self) -> String {
+        format!("{}/{}", self.target_dir.trim_end_matches('/'), self.file_name)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct UploadContext {
     pub metadata: UploadMetadata,
     pub local_file_path: PathBuf,
-    pub message_id: u8,
-    pub state: UploadState,
     pub chunk_queue: Arc<Mutex<VecDeque<u64>>>,
     pub total_chunks: u64,
     pub completed_chunks: Arc<AtomicU64>,
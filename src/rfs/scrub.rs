@@ -0,0 +1,733 @@
+/* src/rfs/scrub.rs */
+
+use crate::quic::acl::Identity;
+use crate::rfs::volume;
+use crate::setup::config::{Config, Permission, RfsConfig, ScrubConfig, VolumeKind};
+use crate::wsm::codec::{self, WsmMessage};
+use crate::wsm::header::PayloadType;
+use crate::wsm::error::{self, ErrorCode};
+use crate::wsm::opcode::Opcode;
+use crate::wsm::requests::{RequestTracker, REQUEST_TIMEOUT};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+// Per-volume checksum index, stored at the root of the bind path.
+pub const INDEX_FILE_NAME: &str = ".anchr-checksums.json";
+// Entries recorded since the index was last written, one JSON line each. The scrubber folds
+// them into the index; a journal past this size is folded in by the finalize that grew it.
+const JOURNAL_EXTENSION: &str = "json.journal";
+const JOURNAL_COMPACT_BYTES: u64 = 4 * 1024 * 1024;
+// How often the scheduler looks for volumes that are due.
+const SCRUB_TICK: Duration = Duration::from_secs(60);
+const READ_BUF_SIZE: usize = 1024 * 1024;
+
+lazy_static! {
+    // bind_path -> lock serializing journal appends and load-modify-save cycles on its index.
+    static ref INDEX_LOCKS: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+    // dev_name -> outcome of the most recent scrub pass.
+    static ref SCRUB_REPORTS: RwLock<HashMap<String, ScrubReport>> = RwLock::new(HashMap::new());
+    // dev_name -> indexed paths flagged as corrupted, so `rfs list` need not read the index.
+    static ref CORRUPTED_FILES: RwLock<HashMap<String, BTreeSet<String>>> =
+        RwLock::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChecksumEntry {
+    pub hash: String,
+    pub size: u64,
+    // Modification time when the entry was recorded; a newer mtime means the
+    // file was legitimately rewritten outside anchr, not corrupted.
+    pub mtime: i64,
+    pub recorded_at: u64,
+    pub last_verified: u64,
+    pub corrupted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChecksumIndex {
+    pub last_scrub: u64,
+    /// Paths relative to the volume's bind path.
+    pub files: BTreeMap<String, ChecksumEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CorruptedFile {
+    pub path: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Result of the latest scrub pass over one volume.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScrubReport {
+    pub dev_name: String,
+    pub finished_at: u64,
+    pub files_checked: u64,
+    pub bytes_checked: u64,
+    pub missing: u64,
+    pub modified: u64,
+    pub corrupted: Vec<CorruptedFile>,
+    pub error: Option<String>,
+}
+
+/// Reply to the scrub status query (0x19).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScrubStatus {
+    pub reports: Vec<ScrubReport>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn index_path(bind_path: &Path) -> PathBuf {
+    bind_path.join(INDEX_FILE_NAME)
+}
+
+fn journal_path(bind_path: &Path) -> PathBuf {
+    index_path(bind_path).with_extension(JOURNAL_EXTENSION)
+}
+
+fn index_lock(bind_path: &Path) -> Arc<Mutex<()>> {
+    INDEX_LOCKS
+        .lock()
+        .unwrap()
+        .entry(bind_path.to_path_buf())
+        .or_default()
+        .clone()
+}
+
+fn cache_corrupted(dev_name: &str, index: &ChecksumIndex) {
+    let flagged = index
+        .files
+        .iter()
+        .filter(|(_, entry)| entry.corrupted)
+        .map(|(path, _)| path.clone())
+        .collect();
+    CORRUPTED_FILES
+        .write()
+        .unwrap()
+        .insert(dev_name.to_string(), flagged);
+}
+
+/// One finalized file, as appended to the journal.
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    path: String,
+    entry: ChecksumEntry,
+}
+
+// The index with the journal replayed over it; later lines win.
+fn load_index(bind_path: &Path) -> ChecksumIndex {
+    let mut index = match fs::read(index_path(bind_path)) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            eprintln!(
+                "! Scrub: Checksum index in '{}' is unreadable, starting fresh: {}",
+                bind_path.display(),
+                e
+            );
+            ChecksumIndex::default()
+        }),
+        Err(_) => ChecksumIndex::default(),
+    };
+    if let Ok(journal) = fs::read(journal_path(bind_path)) {
+        // A crash mid-append leaves a partial last line, which is skipped.
+        for line in journal.split(|b| *b == b'\n') {
+            if let Ok(JournalEntry { path, entry }) = serde_json::from_slice(line) {
+                index.files.insert(path, entry);
+            }
+        }
+    }
+    index
+}
+
+fn append_journal(bind_path: &Path, entry: &JournalEntry) -> std::io::Result<u64> {
+    let mut line = serde_json::to_vec(entry).map_err(std::io::Error::other)?;
+    line.push(b'\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(journal_path(bind_path))?;
+    // Start a fresh line after a torn one, or this entry would be skipped along with it.
+    let len = file.metadata()?.len();
+    let mut last = [b'\n'];
+    if len > 0 {
+        file.read_exact_at(&mut last, len - 1)?;
+    }
+    if last[0] != b'\n' {
+        line.insert(0, b'\n');
+    }
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(file.metadata()?.len())
+}
+
+// Journals one entry, folding the journal into the index once it outgrows
+// `JOURNAL_COMPACT_BYTES`.
+fn record_entry(bind_path: &Path, entry: &JournalEntry) -> std::io::Result<()> {
+    match append_journal(bind_path, entry)? {
+        len if len >= JOURNAL_COMPACT_BYTES => compact_index(bind_path, &load_index(bind_path)),
+        _ => Ok(()),
+    }
+}
+
+// Writes the index and drops the journal it now contains. Replaying a journal that
+// survived a crash in between is harmless.
+fn compact_index(bind_path: &Path, index: &ChecksumIndex) -> std::io::Result<()> {
+    save_index(bind_path, index)?;
+    match fs::remove_file(journal_path(bind_path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Writes through a temporary file so a crash never leaves a truncated index.
+fn save_index(bind_path: &Path, index: &ChecksumIndex) -> std::io::Result<()> {
+    let path = index_path(bind_path);
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(index).map_err(std::io::Error::other)?)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+// Maps `/dev_name/sub/dir` + file name to the volume and a bind-path-relative key.
+fn locate<'a>(
+    cfg: &'a Config,
+    target_dir: &str,
+    file_name: &str,
+) -> Option<(&'a RfsConfig, String)> {
+    let dev_name = volume::dev_name_of(target_dir)?;
+    let rfs = cfg
+        .rfs
+        .as_ref()?
+        .iter()
+        .find(|v| v.dev_name == dev_name && v.kind == VolumeKind::Directory)?;
+    let mut rel = PathBuf::new();
+    for component in Path::new(target_dir).components().skip(2) {
+        match component {
+            Component::Normal(name) => rel.push(name),
+            _ => return None,
+        }
+    }
+    rel.push(file_name);
+    Some((rfs, rel.to_string_lossy().into_owned()))
+}
+
+/// [SERVER-SIDE] Records a freshly finalized file in its volume's checksum journal, an
+/// append that costs the same however many files the volume holds.
+/// NOTE: This is a BLOCKING function and should be run via `tokio::task::spawn_blocking`.
+pub fn record_checksum_blocking(
+    cfg: &Config,
+    target_dir: &str,
+    file_name: &str,
+    file_path: &Path,
+    hash: &str,
+) {
+    let Some((rfs, rel_path)) = locate(cfg, target_dir, file_name) else {
+        return;
+    };
+    let meta = match fs::metadata(file_path) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!(
+                "! Scrub: Cannot stat '{}' for indexing: {}",
+                file_path.display(),
+                e
+            );
+            return;
+        }
+    };
+    let now = now_secs();
+    let bind_path = Path::new(&rfs.bind_path);
+    let journal_entry = JournalEntry {
+        path: rel_path,
+        entry: ChecksumEntry {
+            hash: hash.to_string(),
+            size: meta.len(),
+            mtime: mtime_of(&meta),
+            recorded_at: now,
+            last_verified: now,
+            corrupted: false,
+        },
+    };
+    let lock = index_lock(bind_path);
+    let _guard = lock.lock().unwrap();
+    if let Err(e) = record_entry(bind_path, &journal_entry) {
+        eprintln!(
+            "! Scrub: Failed to update checksum index for '{}': {}",
+            rfs.dev_name, e
+        );
+        return;
+    }
+    // The new contents have not been found corrupted.
+    if let Some(flagged) = CORRUPTED_FILES.write().unwrap().get_mut(&rfs.dev_name) {
+        flagged.remove(&journal_entry.path);
+    }
+}
+
+/// Number of files flagged as corrupted in a volume's index, as of the last scrub pass or
+/// finalize; 0 until the scrubber has read the index at startup.
+pub fn corrupted_count(rfs: &RfsConfig) -> usize {
+    CORRUPTED_FILES
+        .read()
+        .unwrap()
+        .get(&rfs.dev_name)
+        .map_or(0, BTreeSet::len)
+}
+
+fn mtime_of(meta: &fs::Metadata) -> i64 {
+    meta.mtime()
+}
+
+// Caps read throughput by sleeping whenever we get ahead of the configured rate.
+struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate_mib: u64) -> Self {
+        Self {
+            bytes_per_sec: rate_mib.max(1) * 1024 * 1024,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.bytes += n as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
+
+fn hash_file_throttled(path: &Path, throttle: &mut Throttle) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; READ_BUF_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        throttle.consume(n);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+enum Verdict {
+    Ok,
+    Missing,
+    Modified,
+    Corrupted(String),
+}
+
+/// [SERVER-SIDE] Re-hashes every indexed file of one volume and merges the results.
+/// NOTE: This is a BLOCKING function and should be run via `tokio::task::spawn_blocking`.
+pub fn scrub_volume_blocking(rfs: &RfsConfig, scrub_cfg: &ScrubConfig) -> ScrubReport {
+    let mut report = ScrubReport {
+        dev_name: rfs.dev_name.clone(),
+        ..Default::default()
+    };
+    let bind_path = Path::new(&rfs.bind_path);
+    let lock = index_lock(bind_path);
+    let snapshot = {
+        let _guard = lock.lock().unwrap();
+        load_index(bind_path)
+    };
+    println!(
+        "> Scrub: Checking {} file(s) on '{}' at {} MiB/s...",
+        snapshot.files.len(),
+        rfs.dev_name,
+        scrub_cfg.rate_mib.max(1)
+    );
+
+    let mut throttle = Throttle::new(scrub_cfg.rate_mib);
+    let mut verdicts = Vec::with_capacity(snapshot.files.len());
+    for (rel_path, entry) in &snapshot.files {
+        // The volume may drop offline mid-pass; stop rather than report everything missing.
+        if let Some(reason) = volume::offline_reason(&rfs.dev_name) {
+            report.error = Some(format!("volume went offline: {}", reason));
+            break;
+        }
+        let path = bind_path.join(rel_path);
+        let verdict = match fs::metadata(&path) {
+            Err(_) => Verdict::Missing,
+            Ok(meta) if meta.len() != entry.size || mtime_of(&meta) != entry.mtime => {
+                Verdict::Modified
+            }
+            Ok(_) => match hash_file_throttled(&path, &mut throttle) {
+                Ok(actual) => {
+                    report.files_checked += 1;
+                    report.bytes_checked += entry.size;
+                    if actual == entry.hash {
+                        Verdict::Ok
+                    } else {
+                        Verdict::Corrupted(actual)
+                    }
+                }
+                Err(e) => {
+                    eprintln!("! Scrub: Failed to read '{}': {}", path.display(), e);
+                    continue;
+                }
+            },
+        };
+        match &verdict {
+            Verdict::Ok => {}
+            Verdict::Missing => {
+                report.missing += 1;
+                // Index bookkeeping only; watches already reported the deletion when it happened.
+                println!(
+                    "   - '{}' on '{}' was removed; dropping from index.",
+                    rel_path, rfs.dev_name
+                );
+            }
+            Verdict::Modified => {
+                report.modified += 1;
+                println!(
+                    "   - '{}' on '{}' was modified outside anchr; dropping from index.",
+                    rel_path, rfs.dev_name
+                );
+            }
+            Verdict::Corrupted(actual) => {
+                eprintln!(
+                    "! Scrub: CHECKSUM MISMATCH on '{}' in '{}'",
+                    rel_path, rfs.dev_name
+                );
+                eprintln!("   - Expected: {}", entry.hash);
+                eprintln!("   - Got:      {}", actual);
+                report.corrupted.push(CorruptedFile {
+                    path: rel_path.clone(),
+                    expected: entry.hash.clone(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+        verdicts.push((rel_path.clone(), entry.clone(), verdict));
+    }
+
+    // Merge against a fresh copy so entries re-recorded during the pass are kept.
+    let now = now_secs();
+    let _guard = lock.lock().unwrap();
+    let mut index = load_index(bind_path);
+    for (rel_path, seen, verdict) in verdicts {
+        let Some(current) = index.files.get_mut(&rel_path) else {
+            continue;
+        };
+        if current.hash != seen.hash || current.mtime != seen.mtime {
+            continue;
+        }
+        match verdict {
+            Verdict::Ok => {
+                current.last_verified = now;
+                current.corrupted = false;
+            }
+            Verdict::Missing | Verdict::Modified => {
+                index.files.remove(&rel_path);
+            }
+            Verdict::Corrupted(_) => current.corrupted = scrub_cfg.mark_corrupted,
+        }
+    }
+    if report.error.is_none() {
+        index.last_scrub = now;
+    }
+    if let Err(e) = compact_index(bind_path, &index) {
+        eprintln!(
+            "! Scrub: Failed to save checksum index for '{}': {}",
+            rfs.dev_name, e
+        );
+    }
+    cache_corrupted(&rfs.dev_name, &index);
+
+    report.finished_at = now;
+    println!(
+        "+ Scrub: '{}' done. {} file(s) verified, {} corrupted, {} missing, {} modified.",
+        rfs.dev_name,
+        report.files_checked,
+        report.corrupted.len(),
+        report.missing,
+        report.modified
+    );
+    report
+}
+
+/// [SERVER-SIDE] Periodically scrubs every directory volume whose last pass is older than the interval.
+/// Reads every index once at startup, even with scrubbing disabled, for `corrupted_count`.
+pub async fn run_scrubber(cfg: Config) {
+    let scrub_cfg = cfg.scrub.clone();
+    let rfs_list: Vec<RfsConfig> = cfg
+        .rfs
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|r| r.kind == VolumeKind::Directory)
+        .collect();
+    if rfs_list.is_empty() {
+        return;
+    }
+    let interval = scrub_cfg.interval_secs.max(60);
+
+    // Pick up where the previous server run left off, and what it found.
+    let list = rfs_list.clone();
+    let mut last_scrub: HashMap<String, u64> = task::spawn_blocking(move || {
+        list.iter()
+            .map(|r| {
+                let bind_path = Path::new(&r.bind_path);
+                let lock = index_lock(bind_path);
+                let _guard = lock.lock().unwrap();
+                let index = load_index(bind_path);
+                cache_corrupted(&r.dev_name, &index);
+                (r.dev_name.clone(), index.last_scrub)
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default();
+    if !scrub_cfg.enabled {
+        return;
+    }
+
+    loop {
+        for rfs in &rfs_list {
+            let last = last_scrub.get(&rfs.dev_name).copied().unwrap_or(0);
+            if now_secs().saturating_sub(last) < interval
+                || volume::offline_reason(&rfs.dev_name).is_some()
+            {
+                continue;
+            }
+            let (rfs_clone, scrub_clone) = (rfs.clone(), scrub_cfg.clone());
+            match task::spawn_blocking(move || scrub_volume_blocking(&rfs_clone, &scrub_clone))
+                .await
+            {
+                Ok(report) => {
+                    // A pass cut short by the volume going offline is retried once it is back.
+                    if report.error.is_none() {
+                        last_scrub.insert(rfs.dev_name.clone(), report.finished_at);
+                    }
+                    SCRUB_REPORTS
+                        .write()
+                        .unwrap()
+                        .insert(rfs.dev_name.clone(), report);
+                }
+                Err(e) => eprintln!("! Scrub: Task for '{}' failed: {}", rfs.dev_name, e),
+            }
+        }
+        time::sleep(SCRUB_TICK).await;
+    }
+}
+
+// [SERVER-SIDE] Handles the scrub status query (0x18).
+pub async fn handle_status_request(
+    message_id: u32,
+    tx: mpsc::Sender<WsmMessage>,
+    encoding: PayloadType,
+    identity: &Identity,
+) {
+    if !identity.holds_anywhere(Permission::Admin) {
+        eprintln!("! WSM-Server: Refusing scrub status to non-admin user '{}'.", identity.name);
+        error::send_error(
+            &tx,
+            message_id,
+            ErrorCode::PermissionDenied,
+            "Scrub status requires admin privileges.",
+        )
+        .await;
+        return;
+    }
+    // Each user only sees the volumes they administer.
+    let mut reports: Vec<ScrubReport> = SCRUB_REPORTS
+        .read()
+        .unwrap()
+        .values()
+        .filter(|report| identity.allows(&report.dev_name, Permission::Admin))
+        .cloned()
+        .collect();
+    reports.sort_by(|a, b| a.dev_name.cmp(&b.dev_name));
+    let status = ScrubStatus { reports };
+    let encoded = WsmMessage::encoded(Opcode::ScrubStatusResponse, message_id, encoding, &status);
+    let response = match encoded {
+        Ok(response) => response.with_final(),
+        Err(e) => {
+            eprintln!("! Scrub: {}", e);
+            error::send_error(&tx, message_id, ErrorCode::Internal, e).await;
+            return;
+        }
+    };
+    if !codec::send_streamed(&tx, response).await {
+        eprintln!("! WSM-Server: Failed to send scrub status to channel.");
+    }
+}
+
+// [CLIENT-SIDE] Requests the scrub status (0x18) and waits for the response (0x19).
+pub async fn fetch_status(requests: &RequestTracker) -> Result<Vec<ScrubReport>, String> {
+    let reply = requests
+        .request(
+            |id| WsmMessage::empty(Opcode::ScrubStatusRequest, id),
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    if reply.opcode != Opcode::ScrubStatusResponse {
+        return Err(format!("Unexpected reply {} to rfs scrub.", reply.opcode));
+    }
+    let status = reply
+        .decode::<ScrubStatus>()
+        .map_err(|e| format!("Failed to deserialize scrub status: {}", e))?;
+    Ok(status.reports)
+}
+
+/// Renders scrub reports for the log panel.
+pub fn format_status(reports: &[ScrubReport]) -> String {
+    let mut display_text = String::from("Scrub Status:\n");
+    for r in reports {
+        display_text.push_str(&format!(
+            "  '{}': {} file(s), {} checked, {} corrupted, {} missing, {} modified{}\n",
+            r.dev_name,
+            r.files_checked,
+            crate::console::debug::format_bytes(r.bytes_checked),
+            r.corrupted.len(),
+            r.missing,
+            r.modified,
+            r.error
+                .as_ref()
+                .map(|e| format!(" - INCOMPLETE: {}", e))
+                .unwrap_or_default()
+        ));
+        for c in &r.corrupted {
+            display_text.push_str(&format!("      CORRUPTED: {}\n", c.path));
+        }
+    }
+    display_text.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(path: &str, hash: &str) -> JournalEntry {
+        JournalEntry {
+            path: path.to_string(),
+            entry: ChecksumEntry {
+                hash: hash.to_string(),
+                size: 3,
+                mtime: 0,
+                recorded_at: 0,
+                last_verified: 0,
+                corrupted: false,
+            },
+        }
+    }
+
+    fn hash_of(index: &ChecksumIndex, path: &str) -> Option<String> {
+        index.files.get(path).map(|entry| entry.hash.clone())
+    }
+
+    #[test]
+    fn journal_replays_over_the_index() {
+        let dir = TempDir::new().unwrap();
+        let mut index = ChecksumIndex::default();
+        for JournalEntry { path, entry } in [entry("a", "old"), entry("b", "kept")] {
+            index.files.insert(path, entry);
+        }
+        save_index(dir.path(), &index).unwrap();
+        append_journal(dir.path(), &entry("a", "new")).unwrap();
+        append_journal(dir.path(), &entry("c", "added")).unwrap();
+        append_journal(dir.path(), &entry("a", "newest")).unwrap();
+
+        let index = load_index(dir.path());
+        assert_eq!(hash_of(&index, "a").as_deref(), Some("newest"));
+        assert_eq!(hash_of(&index, "b").as_deref(), Some("kept"));
+        assert_eq!(hash_of(&index, "c").as_deref(), Some("added"));
+    }
+
+    #[test]
+    fn journal_alone_is_an_index() {
+        let dir = TempDir::new().unwrap();
+        append_journal(dir.path(), &entry("a", "h")).unwrap();
+        assert_eq!(hash_of(&load_index(dir.path()), "a").as_deref(), Some("h"));
+    }
+
+    #[test]
+    fn compaction_folds_the_journal_into_the_index() {
+        let dir = TempDir::new().unwrap();
+        append_journal(dir.path(), &entry("a", "h")).unwrap();
+        compact_index(dir.path(), &load_index(dir.path())).unwrap();
+        assert!(!journal_path(dir.path()).exists());
+        let saved: ChecksumIndex =
+            serde_json::from_slice(&fs::read(index_path(dir.path())).unwrap()).unwrap();
+        assert_eq!(hash_of(&saved, "a").as_deref(), Some("h"));
+    }
+
+    #[test]
+    fn journals_past_the_threshold_are_compacted() {
+        let dir = TempDir::new().unwrap();
+        record_entry(dir.path(), &entry("a", "h")).unwrap();
+        assert!(journal_path(dir.path()).exists());
+
+        // Pad the journal with blank lines, which replay skips.
+        let padding = vec![b'\n'; JOURNAL_COMPACT_BYTES as usize];
+        fs::OpenOptions::new()
+            .append(true)
+            .open(journal_path(dir.path()))
+            .unwrap()
+            .write_all(&padding)
+            .unwrap();
+        record_entry(dir.path(), &entry("b", "h")).unwrap();
+        assert!(!journal_path(dir.path()).exists());
+        let index = load_index(dir.path());
+        assert_eq!(index.files.len(), 2);
+    }
+
+    #[test]
+    fn torn_and_corrupt_lines_are_skipped() {
+        let dir = TempDir::new().unwrap();
+        append_journal(dir.path(), &entry("a", "h")).unwrap();
+        let mut journal = fs::OpenOptions::new()
+            .append(true)
+            .open(journal_path(dir.path()))
+            .unwrap();
+        journal
+            .write_all(b"not json\n{\"path\":\"b\",\"entry\":{\"ha")
+            .unwrap();
+
+        let index = load_index(dir.path());
+        assert_eq!(index.files.len(), 1);
+        assert_eq!(hash_of(&index, "a").as_deref(), Some("h"));
+
+        // An entry appended after a torn line still counts.
+        append_journal(dir.path(), &entry("c", "h")).unwrap();
+        let index = load_index(dir.path());
+        assert_eq!(index.files.len(), 2);
+        assert_eq!(hash_of(&index, "c").as_deref(), Some("h"));
+    }
+
+    #[test]
+    fn unreadable_index_starts_fresh_with_the_journal() {
+        let dir = TempDir::new().unwrap();
+        fs::write(index_path(dir.path()), b"{\"last_scrub\":").unwrap();
+        append_journal(dir.path(), &entry("a", "h")).unwrap();
+        let index = load_index(dir.path());
+        assert_eq!(index.files.len(), 1);
+    }
+}
@@ -1,6 +1,5 @@
 /* src/rfs/stats.rs */
 
-use crate::rfs::UploadContext;
 use log::info;
 
 /// Formats file size and speed with appropriate units (KB, MB, GB, etc.).
@@ -38,11 +37,9 @@ fn format_speed_and_size(bytes: u64, duration: std::time::Duration) -> (String,
     (size_str, speed_str)
 }
 
-/// Calculates and logs the final upload statistics.
-pub fn log_completion_stats(ctx: &UploadContext) {
-    let duration = ctx.start_time.elapsed();
-    let (size_str, speed_str) = format_speed_and_size(ctx.metadata.file_size, duration);
-    // The success check happens in the calling function, so we just log the stats here.
+/// Logs duration, size and average speed of a finished transfer.
+pub fn log_transfer_stats(bytes: u64, duration: std::time::Duration) {
+    let (size_str, speed_str) = format_speed_and_size(bytes, duration);
     info!(
         "   Total time: {:.2?}, File size: {}, Average speed: {}",
         duration, size_str, speed_str
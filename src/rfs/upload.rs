@@ -1,16 +1,24 @@
 /* src/rfs/upload.rs */
 
+use crate::audit::{self, AuditEvent};
+use crate::events::{self, ServerEvent};
 use crate::rfs::{
-    worker, PreparationResult, SharedUploadContext, UploadMetadata, UploadState,
-    verify,
+    worker, PreparationResult, SharedUploadContext, UploadMetadata,
+    scrub, stats, verify, volume,
 };
+use crate::quic::acl::Identity;
+use crate::quic::client::ClientSession;
 use crate::quic::service::OngoingUploads;
-use crate::setup::config::Config;
-use crate::wsm::header::{PayloadType, WsmHeader, RESERVED_FINAL_FLAG};
-use crate::wsm::msg_id;
+use crate::setup::config::{Config, Permission, VolumeKind};
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::error::{self, ErrorCode, ErrorReply};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::requests::REQUEST_TIMEOUT;
 use log::{error, info};
-use quinn::{Connection, RecvStream};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs as tokio_fs;
 use tokio::sync::mpsc;
 use tokio::task;
@@ -23,107 +31,150 @@ pub fn calculate_workers(file_size: u64) -> u8 {
         return 1;
     }
     let workers = (file_size as f64 / worker::CHUNK_SIZE as f64).ceil() as u8;
-    workers.max(1).min(MAX_WORKERS)
+    workers.clamp(1, MAX_WORKERS)
 }
 
-// --- CLIENT-SIDE HANDLERS ---
+// --- CLIENT-SIDE UPLOAD ---
 
-pub async fn handle_init_ack(context: SharedUploadContext, tx: mpsc::Sender<Vec<u8>>) {
-    let mut context_lock = context.lock().await;
-    if let Some(ctx) = context_lock.as_mut() {
-        if ctx.state != UploadState::Initiated {
-            return;
-        }
-        let file_size = ctx.metadata.file_size;
-        let num_workers = calculate_workers(file_size);
-        info!(
-            "> Upload acknowledged by server. Requesting {} worker stream(s)...",
-            num_workers
-        );
-        if let Some(msg_id) = msg_id::create_new_msg_id().await {
-            ctx.state = UploadState::WorkersOpening;
-            ctx.message_id = msg_id;
-            let header = WsmHeader::new(0x07, msg_id, PayloadType::Raw, 1);
-            let mut message = header.to_bytes().to_vec();
-            message.push(num_workers);
-            if tx.send(message).await.is_err() {
-                error!("! Failed to send worker request command.");
-                msg_id::remove_msg_id(msg_id).await;
-                *context_lock = None;
-            }
-        } else {
-            error!("! Failed to get message ID for worker request.");
-            *context_lock = None;
-        }
+// Assembling and hashing a large file on the server can take a while.
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// [CLIENT-SIDE] Drives an upload registered in `context` to completion, then clears it.
+pub async fn run_upload(context: SharedUploadContext, session: ClientSession) {
+    if let Err(e) = drive_upload(&context, &session).await {
+        error!("! Upload failed: {}", e);
     }
+    *context.lock().await = None;
 }
 
-pub async fn handle_worker_ack(
-    context: SharedUploadContext,
-    connection: Arc<Connection>,
-    main_tx: mpsc::Sender<Vec<u8>>,
-) {
-    let mut context_lock = context.lock().await;
-    if let Some(ctx) = context_lock.as_mut() {
-        if ctx.state != UploadState::WorkersOpening {
-            return;
-        }
-        let num_workers = calculate_workers(ctx.metadata.file_size);
-        let total_chunks =
-            (ctx.metadata.file_size as f64 / worker::CHUNK_SIZE as f64).ceil() as u64;
-        ctx.state = UploadState::Streaming;
+async fn drive_upload(context: &SharedUploadContext, session: &ClientSession) -> Result<(), String> {
+    let (metadata, start_time) = match context.lock().await.as_ref() {
+        Some(ctx) => (ctx.metadata.clone(), ctx.start_time),
+        None => return Ok(()),
+    };
+
+    info!("Initiating upload for '{}'...", metadata.file_name);
+    let request = WsmMessage::encoded(Opcode::UploadInit, 0, session.caps.encoding(), &metadata)?;
+    let reply = session
+        .requests
+        .request(|id| WsmMessage { message_id: id, ..request }, REQUEST_TIMEOUT)
+        .await
+        .map_err(|e| format!("Upload initiation failed: {}", e))?;
+    match (reply.opcode, reply.payload.as_slice()) {
+        (Opcode::Ack, [1]) => info!("> Server acknowledged NEW upload request."),
+        (Opcode::Ack, [2]) => info!("> Server acknowledged RESUMABLE upload."),
+        _ => return Err("Server sent invalid ACK for upload initiation.".to_string()),
+    }
+
+    let num_workers = calculate_workers(metadata.file_size);
+    info!(
+        "> Upload acknowledged by server. Requesting {} worker stream(s)...",
+        num_workers
+    );
+    let reply = session
+        .requests
+        .request(
+            |id| WsmMessage::new(Opcode::WorkerRequest, id, PayloadType::Raw, vec![num_workers]),
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map_err(|e| format!("Worker request failed: {}", e))?;
+    if reply.opcode != Opcode::Ack {
+        return Err(format!("Unexpected reply {} to worker request.", reply.opcode));
+    }
+
+    let total_chunks = (metadata.file_size as f64 / worker::CHUNK_SIZE as f64).ceil() as u64;
+    let completed_chunks = {
+        let mut context_lock = context.lock().await;
+        let Some(ctx) = context_lock.as_mut() else {
+            return Ok(());
+        };
         ctx.total_chunks = total_chunks;
-        let mut queue = ctx.chunk_queue.lock().await;
-        *queue = (0..total_chunks).collect();
-        drop(queue);
-        log::info!(
-            "> Worker request approved. Spawning {} worker(s) for {} chunks...",
-            num_workers,
-            total_chunks
-        );
-        for i in 0..num_workers {
-            let conn_clone = connection.clone();
-            let upload_context = context.clone();
-            let tx_clone = main_tx.clone();
-            tokio::spawn(async move {
-                match conn_clone.open_bi().await {
-                    Ok((send, recv)) => {
-                        log::info!("  - Worker stream {} opened successfully.", i + 1);
-                        worker::run_worker_task(i + 1, upload_context, send, recv, tx_clone).await;
-                    }
-                    Err(e) => log::error!("! Failed to open worker stream {}: {}", i + 1, e),
+        *ctx.chunk_queue.lock().await = (0..total_chunks).collect();
+        ctx.completed_chunks.clone()
+    };
+    info!(
+        "> Worker request approved. Spawning {} worker(s) for {} chunks...",
+        num_workers, total_chunks
+    );
+    let mut handles = Vec::new();
+    for i in 0..num_workers {
+        let conn_clone = session.connection.clone();
+        let upload_context = context.clone();
+        handles.push(tokio::spawn(async move {
+            match conn_clone.open_bi().await {
+                Ok((send, recv)) => {
+                    info!("  - Worker stream {} opened successfully.", i + 1);
+                    worker::run_worker_task(i + 1, upload_context, send, recv).await;
                 }
-            });
-        }
+                Err(e) => error!("! Failed to open worker stream {}: {}", i + 1, e),
+            }
+        }));
+    }
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+    let completed = completed_chunks.load(Ordering::SeqCst);
+    if completed < total_chunks {
+        return Err(format!(
+            "{}/{} chunks transferred. Re-run the upload to resume.",
+            completed, total_chunks
+        ));
     }
+
+    info!("> All chunks transferred. Sending finalize request...");
+    let request = WsmMessage::encoded(Opcode::Finalize, 0, session.caps.encoding(), &metadata)?;
+    let reply = session
+        .requests
+        .request(|id| WsmMessage { message_id: id, ..request }, FINALIZE_TIMEOUT)
+        .await
+        .map_err(|e| format!("Finalize request failed: {}", e))?;
+    if reply.payload.as_slice() != [1] {
+        return Err("Upload failed during server-side finalization.".to_string());
+    }
+    info!("+ Upload of '{}' verified and complete.", metadata.file_name);
+    stats::log_transfer_stats(metadata.file_size, start_time.elapsed());
+    Ok(())
 }
 
 // --- SERVER-SIDE HANDLERS ---
 
 pub async fn handle_init_request(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
-    tx: mpsc::Sender<Vec<u8>>,
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
     cfg: &Config,
     ongoing_uploads: OngoingUploads,
+    identity: &Identity,
 ) {
-    if header.payload_len == 0 {
+    if msg.payload.is_empty() {
         eprintln!("! WSM-Server: Received upload request with no payload.");
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::MalformedRequest,
+            "Upload request has no payload",
+        )
+        .await;
         return;
     }
-    let mut payload_buf = vec![0; header.payload_len as usize];
-    if recv.read_exact(&mut payload_buf).await.is_err() {
-        eprintln!("! WSM-Server: Failed to read upload metadata payload.");
-        return;
-    }
-    match serde_json::from_slice::<UploadMetadata>(&payload_buf) {
+    match msg.decode::<UploadMetadata>() {
         Ok(metadata) => {
             println!(
                 "-> Received upload initiation for '{}'.",
                 metadata.file_name
             );
-            match prepare_upload_directory(&metadata, cfg).await {
+            match prepare_upload_directory(&metadata, cfg, identity).await {
                 Ok(prep_result) => {
+                    audit::record_for(
+                        identity,
+                        AuditEvent::UploadStarted {
+                            path: metadata.virtual_path(),
+                            size: metadata.file_size,
+                            hash: metadata.file_hash.clone(),
+                            resumed: matches!(prep_result, PreparationResult::Resumable),
+                        },
+                    );
                     ongoing_uploads
                         .lock()
                         .await
@@ -132,10 +183,8 @@ pub async fn handle_init_request(
                         PreparationResult::New => 1,
                         PreparationResult::Resumable => 2,
                     };
-                    let response_header =
-                        WsmHeader::new(0x00, header.message_id, PayloadType::Raw, 1);
-                    let mut response = response_header.to_bytes().to_vec();
-                    response.push(ack_code);
+                    let response =
+                        WsmMessage::new(Opcode::Ack, msg.message_id, PayloadType::Raw, vec![ack_code]);
                     if tx.send(response).await.is_err() {
                         eprintln!("! WSM-Server: Failed to send upload 'ACK' response.");
                     }
@@ -145,6 +194,16 @@ pub async fn handle_init_request(
                         "! WSM-Server: Failed to prepare upload for '{}': {}",
                         metadata.file_name, e
                     );
+                    audit::record_for(
+                        identity,
+                        AuditEvent::UploadRejected {
+                            path: metadata.virtual_path(),
+                            size: metadata.file_size,
+                            hash: metadata.file_hash.clone(),
+                            reason: e.message.clone(),
+                        },
+                    );
+                    let _ = tx.send(e.to_message(msg.message_id)).await;
                 }
             }
         }
@@ -153,55 +212,65 @@ pub async fn handle_init_request(
                 "! WSM-Server: Failed to deserialize upload metadata: {}",
                 e
             );
+            error::send_error(
+                &tx,
+                msg.message_id,
+                ErrorCode::MalformedRequest,
+                format!("Invalid upload metadata: {}", e),
+            )
+            .await;
         }
     }
 }
 
 pub async fn handle_worker_request(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
-    tx: mpsc::Sender<Vec<u8>>,
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
     cfg: &Config,
 ) {
-    if header.payload_len != 1 {
+    if msg.payload.len() != 1 {
         eprintln!("! WSM-Server: Received worker request with invalid payload size.");
-        return;
-    }
-    let mut payload_buf = [0; 1];
-    if recv.read_exact(&mut payload_buf).await.is_err() {
-        eprintln!("! WSM-Server: Failed to read worker request payload.");
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::MalformedRequest,
+            "Worker request payload must be a single byte",
+        )
+        .await;
         return;
     }
     if cfg.setup.log_level == "debug" {
-        let num_workers = payload_buf[0];
+        let num_workers = msg.payload[0];
         println!(
             "-> Received request to open {} worker stream(s).",
             num_workers
         );
     }
-    let response_header = WsmHeader::new(0x00, header.message_id, PayloadType::Raw, 0);
-    let response = response_header.to_bytes().to_vec();
+    let response = WsmMessage::empty(Opcode::Ack, msg.message_id);
     if tx.send(response).await.is_err() {
         eprintln!("! WSM-Server: Failed to send worker 'ACK' response.");
     }
 }
 
 pub async fn handle_finalize_request(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
-    tx: mpsc::Sender<Vec<u8>>,
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
     cfg: &Config,
     ongoing_uploads: OngoingUploads,
+    identity: &Identity,
 ) {
-    if header.payload_len == 0 {
-        return;
-    }
-    let mut payload_buf = vec![0; header.payload_len as usize];
-    if recv.read_exact(&mut payload_buf).await.is_err() {
+    if msg.payload.is_empty() {
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::MalformedRequest,
+            "Finalize request has no payload",
+        )
+        .await;
         return;
     }
 
-    match serde_json::from_slice::<UploadMetadata>(&payload_buf) {
+    match msg.decode::<UploadMetadata>() {
         Ok(metadata) => {
             println!(
                 "-> Received finalize request for '{}'. Spawning blocking task for assembly...",
@@ -210,27 +279,60 @@ pub async fn handle_finalize_request(
 
             let meta_clone = metadata.clone();
             let cfg_clone = cfg.clone();
-            let message_id = header.message_id;
+            let identity = identity.clone();
+            let message_id = msg.message_id;
 
             tokio::spawn(async move {
-                let success = task::spawn_blocking(move || {
-                    verify::assemble_and_verify_blocking(&meta_clone, &cfg_clone)
+                let worker_identity = identity.clone();
+                let result = task::spawn_blocking(move || {
+                    verify::assemble_and_verify_blocking(&meta_clone, &cfg_clone, &worker_identity)
                 })
                 .await
-                .unwrap_or(false);
+                .unwrap_or_else(|e| {
+                    Err(ErrorReply::new(
+                        ErrorCode::Internal,
+                        format!("Assembly task failed: {}", e),
+                    ))
+                });
 
                 ongoing_uploads.lock().await.remove(&metadata.file_hash);
 
-                let ack_code: u8 = if success { 1 } else { 0 };
-                let response_header = WsmHeader::with_reserved(
-                    0x00,
-                    message_id,
-                    PayloadType::Raw,
-                    1,
-                    RESERVED_FINAL_FLAG,
-                );
-                let mut response = response_header.to_bytes().to_vec();
-                response.push(ack_code);
+                let path = metadata.virtual_path();
+                let size = metadata.file_size;
+                let hash = metadata.file_hash.clone();
+                let response = match result {
+                    Ok(()) => {
+                        audit::record_for(
+                            &identity,
+                            AuditEvent::UploadFinalized {
+                                path: path.clone(),
+                                size,
+                                hash,
+                            },
+                        );
+                        events::publish(ServerEvent::UploadFinalized {
+                            path: path.clone(),
+                            file_size: metadata.file_size,
+                        });
+                        events::publish(ServerEvent::FileCreated { path });
+                        WsmMessage::new(Opcode::Ack, message_id, PayloadType::Raw, vec![1])
+                            .with_final()
+                    }
+                    Err(e) => {
+                        eprintln!("! Finalize Error: {}", e);
+                        let reason = e.message.clone();
+                        audit::record_for(
+                            &identity,
+                            AuditEvent::UploadFailed {
+                                path,
+                                size,
+                                hash,
+                                reason,
+                            },
+                        );
+                        e.to_message(message_id)
+                    }
+                };
                 if tx.send(response).await.is_err() {
                     eprintln!("! WSM-Server: Failed to send finalize 'ACK' response.");
                 }
@@ -241,6 +343,13 @@ pub async fn handle_finalize_request(
                 "! WSM-Server: Failed to deserialize finalize metadata: {}",
                 e
             );
+            error::send_error(
+                &tx,
+                msg.message_id,
+                ErrorCode::MalformedRequest,
+                format!("Invalid finalize metadata: {}", e),
+            )
+            .await;
         }
     }
 }
@@ -248,8 +357,17 @@ pub async fn handle_finalize_request(
 pub async fn prepare_upload_directory(
     metadata: &UploadMetadata,
     cfg: &Config,
-) -> Result<PreparationResult, String> {
-    let final_path = resolve_and_validate_path(&metadata.target_dir, cfg)?;
+    identity: &Identity,
+) -> Result<PreparationResult, ErrorReply> {
+    let final_path =
+        resolve_and_validate_path(&metadata.target_dir, cfg, identity, Permission::Write)?;
+    if metadata.file_name.starts_with(scrub::INDEX_FILE_NAME) {
+        return Err(ErrorReply::new(
+            ErrorCode::InvalidPath,
+            format!("'{}' is reserved by the server.", metadata.file_name),
+        ));
+    }
+    let storage_error = |what: String| ErrorReply::new(ErrorCode::StorageFailure, what);
     let final_file_path = final_path.join(&metadata.file_name);
     let lock_filename = format!("{}.lock", metadata.file_name);
     let hash_filename = format!("{}.hash", metadata.file_name);
@@ -262,9 +380,12 @@ pub async fn prepare_upload_directory(
         .await
         .unwrap_or(false)
     {
-        return Err(format!(
-            "File '{}' already exists at the target location.",
-            metadata.file_name
+        return Err(ErrorReply::new(
+            ErrorCode::AlreadyExists,
+            format!(
+                "File '{}' already exists at the target location.",
+                metadata.file_name
+            ),
         ));
     }
     if tokio_fs::try_exists(&lock_file_path)
@@ -276,13 +397,17 @@ pub async fn prepare_upload_directory(
         }
         let existing_hash = tokio_fs::read_to_string(&hash_file_path)
             .await
-            .map_err(|e| format!("Failed to read existing hash file: {}", e))?;
+            .map_err(|e| storage_error(format!("Failed to read existing hash file: {}", e)))?;
         if existing_hash.trim() == metadata.file_hash {
             if cfg.setup.log_level == "debug" {
                 println!("   - Hashes match. This is a resumable upload.");
             }
             return Ok(PreparationResult::Resumable);
         } else {
+            // Discarding someone else's partial upload is a delete.
+            if let Some(dev_name) = volume::dev_name_of(&metadata.target_dir) {
+                identity.require(dev_name, Permission::Delete)?;
+            }
             if cfg.setup.log_level == "debug" {
                 println!("   - Hashes do not match. Cleaning up stale upload files...");
             }
@@ -291,6 +416,12 @@ pub async fn prepare_upload_directory(
             if tokio_fs::try_exists(&tmp_dir_path).await.unwrap_or(false) {
                 tokio_fs::remove_dir_all(&tmp_dir_path).await.ok();
             }
+            audit::record_for(
+                identity,
+                AuditEvent::StaleUploadDeleted {
+                    path: metadata.virtual_path(),
+                },
+            );
             if cfg.setup.log_level == "debug" {
                 println!("   - Stale files cleaned up.");
             }
@@ -305,54 +436,83 @@ pub async fn prepare_upload_directory(
     }
     tokio_fs::create_dir_all(&final_path)
         .await
-        .map_err(|e| format!("Failed to create target directory: {}", e))?;
+        .map_err(|e| storage_error(format!("Failed to create target directory: {}", e)))?;
     tokio_fs::File::create(&lock_file_path)
         .await
-        .map_err(|e| format!("Failed to create lock file: {}", e))?;
+        .map_err(|e| storage_error(format!("Failed to create lock file: {}", e)))?;
     tokio_fs::write(&hash_file_path, &metadata.file_hash)
         .await
-        .map_err(|e| format!("Failed to create hash file: {}", e))?;
+        .map_err(|e| storage_error(format!("Failed to create hash file: {}", e)))?;
     tokio_fs::create_dir_all(&tmp_dir_path)
         .await
-        .map_err(|e| format!("Failed to create .tmp directory: {}", e))?;
+        .map_err(|e| storage_error(format!("Failed to create .tmp directory: {}", e)))?;
     if cfg.setup.log_level == "debug" {
         println!("   - Lock, hash, and tmp directory created successfully.");
     }
     Ok(PreparationResult::New)
 }
 
-pub fn resolve_and_validate_path(target_dir: &str, cfg: &Config) -> Result<PathBuf, String> {
+pub fn resolve_and_validate_path(
+    target_dir: &str,
+    cfg: &Config,
+    identity: &Identity,
+    permission: Permission,
+) -> Result<PathBuf, ErrorReply> {
+    let invalid = |what: String| ErrorReply::new(ErrorCode::InvalidPath, what);
     let virtual_path = Path::new(target_dir);
     let mut components = virtual_path.components();
     if components.next() != Some(Component::RootDir) {
-        return Err(format!(
+        return Err(invalid(format!(
             "Invalid target_dir format: '{}'. Must start with /.",
             target_dir
-        ));
+        )));
     }
     let dev_name_comp = components
         .next()
-        .ok_or_else(|| "target_dir is missing <dev_name>.".to_string())?;
+        .ok_or_else(|| invalid("target_dir is missing <dev_name>.".to_string()))?;
     if !matches!(dev_name_comp, Component::Normal(_)) {
-        return Err("Invalid <dev_name> in target_dir.".to_string());
+        return Err(invalid("Invalid <dev_name> in target_dir.".to_string()));
     }
     let dev_name = dev_name_comp.as_os_str().to_string_lossy();
+    // Checked first, so users cannot probe for volumes they have no access to.
+    identity.require(&dev_name, permission)?;
     let rfs_config = cfg
         .rfs
         .as_ref()
         .unwrap()
         .iter()
         .find(|v| v.dev_name == dev_name)
-        .ok_or_else(|| format!("Device '{}' not found on server.", dev_name))?;
+        .ok_or_else(|| {
+            ErrorReply::new(
+                ErrorCode::NotFound,
+                format!("Device '{}' not found on server.", dev_name),
+            )
+        })?;
+    if rfs_config.kind != VolumeKind::Directory {
+        return Err(ErrorReply::new(
+            ErrorCode::Unsupported,
+            format!(
+                "Device '{}' is a raw block volume; use 'rfs image' instead.",
+                dev_name
+            ),
+        ));
+    }
+    let show_paths = identity.allows(&dev_name, Permission::Admin);
+    if let Some(reason) = volume::offline_reason_for(&dev_name, show_paths) {
+        return Err(ErrorReply::new(
+            ErrorCode::Unavailable,
+            format!("Device '{}' is offline: {}", dev_name, reason),
+        ));
+    }
     let mut final_path = PathBuf::from(&rfs_config.bind_path);
     for component in components {
         match component {
             Component::Normal(name) => final_path.push(name),
             _ => {
-                return Err(format!(
+                return Err(invalid(format!(
                     "Invalid path component in target_dir: '{}'.",
                     target_dir
-                ));
+                )));
             }
         }
     }
@@ -1,21 +1,22 @@
 /* src/rfs/verify.rs */
 
-use crate::rfs::{upload, worker, UploadMetadata};
-use crate::setup::config::Config;
+use crate::rfs::{scrub, upload, worker, UploadMetadata};
+use crate::quic::acl::Identity;
+use crate::setup::config::{Config, Permission};
+use crate::wsm::error::{ErrorCode, ErrorReply};
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::io::{Read, Write};
 
 /// [SERVER-SIDE] Assembles all chunks, verifies the final hash, and cleans up.
 /// NOTE: This is a BLOCKING function and should be run via `tokio::task::spawn_blocking`.
-pub fn assemble_and_verify_blocking(metadata: &UploadMetadata, cfg: &Config) -> bool {
-    let final_path = match upload::resolve_and_validate_path(&metadata.target_dir, cfg) {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("! Finalize Error: {}", e);
-            return false;
-        }
-    };
+pub fn assemble_and_verify_blocking(
+    metadata: &UploadMetadata,
+    cfg: &Config,
+    identity: &Identity,
+) -> Result<(), ErrorReply> {
+    let final_path =
+        upload::resolve_and_validate_path(&metadata.target_dir, cfg, identity, Permission::Write)?;
     let final_file_path = final_path.join(&metadata.file_name);
     let tmp_dir_path = final_path.join(format!("{}.tmp", metadata.file_name));
     let total_chunks = (metadata.file_size as f64 / worker::CHUNK_SIZE as f64).ceil() as u64;
@@ -24,59 +25,60 @@ pub fn assemble_and_verify_blocking(metadata: &UploadMetadata, cfg: &Config) ->
     for i in 0..total_chunks {
         let chunk_path = tmp_dir_path.join(format!("chunk_{}", i));
         if !chunk_path.exists() {
-            eprintln!("! Finalize Error: Missing chunk #{}", i);
-            return false;
+            return Err(ErrorReply::new(
+                ErrorCode::VerificationFailed,
+                format!("Missing chunk #{}", i),
+            ));
         }
     }
     println!("   - All {} chunks verified.", total_chunks);
 
     // Assemble file
-    let mut final_file = match fs::File::create(&final_file_path) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("! Finalize Error: Could not create final file: {}", e);
-            return false;
-        }
-    };
+    let storage_error = |what: String| ErrorReply::new(ErrorCode::StorageFailure, what);
+    let mut final_file = fs::File::create(&final_file_path)
+        .map_err(|e| storage_error(format!("Could not create final file: {}", e)))?;
 
     for i in 0..total_chunks {
         let chunk_path = tmp_dir_path.join(format!("chunk_{}", i));
-        match fs::read(&chunk_path) {
-            Ok(data) => {
-                if final_file.write_all(&data).is_err() {
-                    eprintln!("! Finalize Error: Failed to write chunk #{}", i);
-                    return false;
-                }
-            }
-            Err(_) => return false,
-        }
+        let data = fs::read(&chunk_path)
+            .map_err(|e| storage_error(format!("Failed to read chunk #{}: {}", i, e)))?;
+        final_file
+            .write_all(&data)
+            .map_err(|e| storage_error(format!("Failed to write chunk #{}: {}", i, e)))?;
     }
     println!("   - File assembled successfully.");
     final_file.sync_all().ok();
 
     // Verify final hash efficiently
-    let mut final_file_reader = match fs::File::open(&final_file_path) {
-        Ok(f) => f,
-        Err(_) => return false,
-    };
+    let mut final_file_reader = fs::File::open(&final_file_path)
+        .map_err(|e| storage_error(format!("Could not reopen final file: {}", e)))?;
     let mut hasher = Sha256::new();
     let mut buf = [0; 8192];
     loop {
         match final_file_reader.read(&mut buf) {
             Ok(0) => break,
             Ok(n) => hasher.update(&buf[..n]),
-            Err(_) => return false,
+            Err(e) => return Err(storage_error(format!("Failed to hash final file: {}", e))),
         }
     }
     let final_hash = hex::encode(hasher.finalize());
 
     if final_hash != metadata.file_hash {
-        eprintln!("! Finalize Error: Final file hash mismatch!");
         eprintln!("   - Expected: {}", metadata.file_hash);
         eprintln!("   - Got:      {}", final_hash);
-        return false;
+        return Err(ErrorReply::new(
+            ErrorCode::VerificationFailed,
+            "Final file hash mismatch",
+        ));
     }
     println!("   - Final hash verified successfully.");
+    scrub::record_checksum_blocking(
+        cfg,
+        &metadata.target_dir,
+        &metadata.file_name,
+        &final_file_path,
+        &final_hash,
+    );
 
     // Cleanup
     let lock_file_path = final_path.join(format!("{}.lock", metadata.file_name));
@@ -86,5 +88,5 @@ pub fn assemble_and_verify_blocking(metadata: &UploadMetadata, cfg: &Config) ->
     fs::remove_dir_all(tmp_dir_path).ok();
     println!("   - Cleanup complete.");
 
-    true
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,429 @@
+/* src/rfs/volume.rs */
+
+use crate::events::{self, ServerEvent};
+use crate::quic::acl::Identity;
+use crate::rfs::scrub;
+use crate::setup::config::{Config, Permission, RfsConfig, VolumeKind};
+use crate::wsm::error::{ErrorCode, ErrorReply};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+// How often bound volumes are re-checked against the mount table.
+const BINDING_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// How often directory volumes are checked for going read-only or full.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// A volume counts as full below this share of free space, in percent.
+const FULL_THRESHOLD_PERCENT: u64 = 1;
+
+lazy_static! {
+    // dev_name -> reason, for volumes whose expected device is not mounted.
+    static ref OFFLINE_VOLUMES: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+}
+
+/// Per-volume health and capacity snapshot returned by `rfs list`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VolumeInfo {
+    pub dev_name: String,
+    /// Only populated for users with admin permission on the volume; `None` hides the server-side path.
+    pub bind_path: Option<String>,
+    pub fs_type: Option<String>,
+    pub is_mountpoint: bool,
+    pub read_only: bool,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub active_uploads: usize,
+    /// Files flagged by the integrity scrubber (only with `scrub.mark_corrupted`).
+    pub corrupted_files: usize,
+    /// Set when the volume could not be probed at all.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MountEntry {
+    pub device: (u32, u32),
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+}
+
+struct FsStats {
+    total_bytes: u64,
+    free_bytes: u64,
+    total_inodes: u64,
+    free_inodes: u64,
+    read_only: bool,
+}
+
+// Decodes the octal escapes (`\040` for space etc.) used in mountinfo paths.
+fn unescape_mount_path(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 3 < bytes.len()
+            && let Ok(digits) = std::str::from_utf8(&bytes[i + 1..i + 4])
+            && let Ok(v) = u8::from_str_radix(digits, 8)
+        {
+            out.push(v);
+            i += 4;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses `/proc/self/mountinfo` into a list of mount entries.
+pub fn read_mountinfo() -> Result<Vec<MountEntry>, String> {
+    let content = fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|e| format!("Failed to read /proc/self/mountinfo: {}", e))?;
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        // Optional fields end with a single "-" separator.
+        let Some(sep) = fields.iter().position(|f| *f == "-") else {
+            continue;
+        };
+        if fields.len() < sep + 3 || sep < 6 {
+            continue;
+        }
+        let Some((major, minor)) = fields[2].split_once(':') else {
+            continue;
+        };
+        let (Ok(major), Ok(minor)) = (major.parse(), minor.parse()) else {
+            continue;
+        };
+        entries.push(MountEntry {
+            device: (major, minor),
+            mount_point: PathBuf::from(unescape_mount_path(fields[4])),
+            fs_type: fields[sep + 1].to_string(),
+            source: unescape_mount_path(fields[sep + 2]),
+        });
+    }
+    Ok(entries)
+}
+
+/// Returns the mount that contains `path` (longest mount point prefix wins).
+pub fn find_mount<'a>(mounts: &'a [MountEntry], path: &Path) -> Option<&'a MountEntry> {
+    // `max_by_key` keeps the last maximum, so later mounts shadow earlier ones on the same point.
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.components().count())
+}
+
+fn statvfs(path: &Path) -> Result<FsStats, String> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| "bind_path contains a NUL byte".to_string())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is a valid out-pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!("statvfs failed: {}", std::io::Error::last_os_error()));
+    }
+    let frsize = stat.f_frsize as u64;
+    Ok(FsStats {
+        total_bytes: stat.f_blocks as u64 * frsize,
+        free_bytes: stat.f_bavail as u64 * frsize,
+        total_inodes: stat.f_files as u64,
+        free_inodes: stat.f_favail as u64,
+        read_only: stat.f_flag & libc::ST_RDONLY != 0,
+    })
+}
+
+/// Probes a configured volume. NOTE: This is BLOCKING; run it via `spawn_blocking`.
+pub fn probe_volume(
+    rfs: &RfsConfig,
+    mounts: &[MountEntry],
+    active_uploads: usize,
+    expose_bind_path: bool,
+) -> VolumeInfo {
+    let mut info = VolumeInfo {
+        dev_name: rfs.dev_name.clone(),
+        bind_path: expose_bind_path.then(|| rfs.bind_path.clone()),
+        fs_type: None,
+        is_mountpoint: false,
+        read_only: false,
+        total_bytes: 0,
+        free_bytes: 0,
+        total_inodes: 0,
+        free_inodes: 0,
+        active_uploads,
+        corrupted_files: 0,
+        error: None,
+    };
+
+    if rfs.kind == VolumeKind::Block {
+        let device = rfs.device.as_deref().unwrap_or_default();
+        info.bind_path = expose_bind_path.then(|| device.to_string());
+        info.fs_type = Some("block".to_string());
+        match device_size(Path::new(device)) {
+            Ok(size) => info.total_bytes = size,
+            Err(e) => info.error = Some(format!("device is not accessible: {}", e)),
+        }
+        return info;
+    }
+    if let Some(reason) = offline_reason_for(&rfs.dev_name, expose_bind_path) {
+        info.error = Some(reason);
+        return info;
+    }
+    let path = match fs::canonicalize(&rfs.bind_path) {
+        Ok(p) => p,
+        Err(e) => {
+            info.error = Some(format!("bind_path is not accessible: {}", e));
+            return info;
+        }
+    };
+    if let Some(mount) = find_mount(mounts, &path) {
+        info.fs_type = Some(mount.fs_type.clone());
+        info.is_mountpoint = mount.mount_point == path;
+    }
+    match statvfs(&path) {
+        Ok(stats) => {
+            info.total_bytes = stats.total_bytes;
+            info.free_bytes = stats.free_bytes;
+            info.total_inodes = stats.total_inodes;
+            info.free_inodes = stats.free_inodes;
+            info.read_only = stats.read_only;
+        }
+        Err(e) => info.error = Some(e),
+    }
+    info.corrupted_files = scrub::corrupted_count(rfs);
+    info
+}
+
+/// Looks up a `block` volume for privileged raw access (image transfers, NBD).
+pub fn find_block_volume<'a>(
+    cfg: &'a Config,
+    dev_name: &str,
+    identity: &Identity,
+) -> Result<&'a RfsConfig, ErrorReply> {
+    identity.require(dev_name, Permission::Admin)?;
+    let rfs_config = cfg
+        .rfs
+        .as_ref()
+        .and_then(|list| list.iter().find(|v| v.dev_name == dev_name))
+        .ok_or_else(|| {
+            ErrorReply::new(
+                ErrorCode::NotFound,
+                format!("Device '{}' not found on server.", dev_name),
+            )
+        })?;
+    if rfs_config.kind != VolumeKind::Block {
+        return Err(ErrorReply::new(
+            ErrorCode::Unsupported,
+            format!("Device '{}' is not a block volume.", dev_name),
+        ));
+    }
+    Ok(rfs_config)
+}
+
+/// Deallocates a byte range of a file or block device, reading back as zeros.
+pub fn punch_hole(fd: RawFd, offset: u64, len: u64) -> std::io::Result<()> {
+    // SAFETY: fallocate only operates on the caller-provided descriptor.
+    let rc = unsafe {
+        libc::fallocate(
+            fd,
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Returns the size of a block device or image file. NOTE: This is BLOCKING.
+pub fn device_size(path: &Path) -> std::io::Result<u64> {
+    // Block devices report a zero length in metadata, so seek to the end instead.
+    fs::File::open(path)?.seek(SeekFrom::End(0))
+}
+
+/// Extracts `<dev_name>` from a virtual `/<dev_name>/...` target path.
+pub fn dev_name_of(target_dir: &str) -> Option<&str> {
+    target_dir
+        .strip_prefix('/')
+        .and_then(|rest| rest.split('/').next())
+        .filter(|name| !name.is_empty())
+}
+
+/// Resolves the block device a volume is bound to via `/dev/disk/by-uuid` or `by-label`.
+pub fn resolve_bound_device(rfs: &RfsConfig) -> Option<Result<PathBuf, String>> {
+    let (dir, key) = match (&rfs.uuid, &rfs.label) {
+        (Some(uuid), _) => ("/dev/disk/by-uuid", uuid),
+        (None, Some(label)) => ("/dev/disk/by-label", label),
+        (None, None) => return None,
+    };
+    let link = Path::new(dir).join(key);
+    Some(fs::canonicalize(&link).map_err(|e| {
+        format!(
+            "Device '{}' for dev_name '{}' not found: {}",
+            link.display(),
+            rfs.dev_name,
+            e
+        )
+    }))
+}
+
+/// Checks that a UUID/label-bound volume has its expected device mounted exactly at bind_path.
+/// Volumes declared by path only always pass. NOTE: This is BLOCKING.
+pub fn verify_binding(rfs: &RfsConfig, mounts: &[MountEntry]) -> Result<(), String> {
+    let device = match resolve_bound_device(rfs) {
+        None => return Ok(()),
+        Some(res) => res?,
+    };
+    let meta = fs::metadata(&device)
+        .map_err(|e| format!("Cannot stat device '{}': {}", device.display(), e))?;
+    if !meta.file_type().is_block_device() {
+        return Err(format!("'{}' is not a block device.", device.display()));
+    }
+    let rdev = meta.rdev();
+    let expected = (libc::major(rdev), libc::minor(rdev));
+
+    let bind_path = fs::canonicalize(&rfs.bind_path)
+        .map_err(|e| format!("bind_path '{}' is not accessible: {}", rfs.bind_path, e))?;
+    let mounted = mounts
+        .iter()
+        .rfind(|m| m.mount_point == bind_path)
+        .ok_or_else(|| {
+            format!(
+                "Nothing is mounted at bind_path '{}' (expected '{}').",
+                rfs.bind_path,
+                device.display()
+            )
+        })?;
+    // btrfs, overlay and other filesystems report an anonymous `0:N` device in mountinfo,
+    // so fall back to the mount source, which names the device they were mounted from.
+    let same_source = || fs::canonicalize(&mounted.source).is_ok_and(|source| source == device);
+    if mounted.device != expected && !same_source() {
+        return Err(format!(
+            "bind_path '{}' has '{}' mounted, expected '{}'.",
+            rfs.bind_path,
+            mounted.source,
+            device.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns why a volume is currently refused, if it is.
+pub fn offline_reason(dev_name: &str) -> Option<String> {
+    OFFLINE_VOLUMES.read().unwrap().get(dev_name).cloned()
+}
+
+/// Like `offline_reason`, worded for a client: the reason names host paths and devices,
+/// so only identities that may see the bind path get it.
+pub fn offline_reason_for(dev_name: &str, show_paths: bool) -> Option<String> {
+    offline_reason(dev_name).map(|reason| match show_paths {
+        true => reason,
+        false => "its device is not mounted where expected".to_string(),
+    })
+}
+
+fn is_bound(rfs: &RfsConfig) -> bool {
+    rfs.kind == VolumeKind::Directory && (rfs.uuid.is_some() || rfs.label.is_some())
+}
+
+// Re-verifies every bound volume, logging and recording state transitions.
+fn refresh_bindings_blocking(rfs_list: &[RfsConfig]) {
+    let mounts = match read_mountinfo() {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("! Volume check: {}", e);
+            return;
+        }
+    };
+    let mut offline = OFFLINE_VOLUMES.write().unwrap();
+    for rfs in rfs_list.iter().filter(|r| is_bound(r)) {
+        match verify_binding(rfs, &mounts) {
+            Ok(()) => {
+                if offline.remove(&rfs.dev_name).is_some() {
+                    println!("+ Volume '{}' is mounted again and back online.", rfs.dev_name);
+                }
+            }
+            Err(reason) => {
+                if offline.insert(rfs.dev_name.clone(), reason.clone()).is_none() {
+                    eprintln!("! Volume '{}' taken offline: {}", rfs.dev_name, reason);
+                }
+            }
+        }
+    }
+}
+
+/// [SERVER-SIDE] Periodically re-checks UUID/label-bound volumes against the mount table.
+pub async fn run_binding_monitor(cfg: Config) {
+    let rfs_list = cfg.rfs.unwrap_or_default();
+    if !rfs_list.iter().any(is_bound) {
+        return;
+    }
+    loop {
+        time::sleep(BINDING_CHECK_INTERVAL).await;
+        let list = rfs_list.clone();
+        let _ = task::spawn_blocking(move || refresh_bindings_blocking(&list)).await;
+    }
+}
+
+// Last seen (read_only, full) state per volume; only transitions are published.
+fn check_health_blocking(rfs_list: &[RfsConfig], last: &mut HashMap<String, (bool, bool)>) {
+    for rfs in rfs_list.iter().filter(|r| r.kind == VolumeKind::Directory) {
+        if offline_reason(&rfs.dev_name).is_some() {
+            continue;
+        }
+        let Ok(stats) = statvfs(Path::new(&rfs.bind_path)) else {
+            continue;
+        };
+        let full = stats.free_bytes * 100 < stats.total_bytes * FULL_THRESHOLD_PERCENT;
+        let (was_read_only, was_full) = last
+            .insert(rfs.dev_name.clone(), (stats.read_only, full))
+            .unwrap_or_default();
+        if stats.read_only && !was_read_only {
+            eprintln!("! Volume '{}' is now mounted read-only.", rfs.dev_name);
+            events::publish(ServerEvent::VolumeReadOnly {
+                dev_name: rfs.dev_name.clone(),
+            });
+        }
+        if full && !was_full {
+            eprintln!(
+                "! Volume '{}' is almost full ({} bytes free).",
+                rfs.dev_name, stats.free_bytes
+            );
+            events::publish(ServerEvent::VolumeFull {
+                dev_name: rfs.dev_name.clone(),
+                free_bytes: stats.free_bytes,
+            });
+        }
+    }
+}
+
+/// [SERVER-SIDE] Periodically checks directory volumes and publishes read-only/full events.
+pub async fn run_health_monitor(cfg: Config) {
+    let rfs_list = cfg.rfs.unwrap_or_default();
+    let mut last = HashMap::new();
+    loop {
+        let list = rfs_list.clone();
+        last = task::spawn_blocking(move || {
+            check_health_blocking(&list, &mut last);
+            last
+        })
+        .await
+        .unwrap_or_default();
+        time::sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
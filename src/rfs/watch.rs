@@ -0,0 +1,632 @@
+/* src/rfs/watch.rs */
+
+use crate::events::ServerEvent;
+use crate::rfs::{scrub, upload};
+use crate::quic::acl::Identity;
+use crate::setup::config::{Config, Permission};
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::error::{self, ErrorCode, ErrorReply};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::requests::{REQUEST_TIMEOUT, RequestTracker};
+use futures::StreamExt;
+use inotify::{EventMask, EventOwned, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Duration, Instant};
+
+// Watches one connection may hold at the same time.
+pub const MAX_WATCHES: usize = 16;
+// Directories one recursive watch may cover; each costs a kernel inotify watch.
+const MAX_WATCHED_DIRS: usize = 4096;
+// Changes arriving within this window after the first one are merged and sent together.
+const COALESCE_WINDOW: Duration = Duration::from_millis(500);
+const EVENT_BUFFER_SIZE: usize = 4096;
+// Events one watch holds while its session is parked; older ones are dropped beyond this.
+const MAX_PARKED_EVENTS: usize = 1024;
+
+lazy_static! {
+    // [CLIENT-SIDE] Paths to watch again after a reconnect.
+    static ref WATCHED_PATHS: std::sync::Mutex<BTreeSet<String>> =
+        std::sync::Mutex::new(BTreeSet::new());
+}
+
+/// Where the watches of a session send their events: the connection holding the session,
+/// or None while it is parked for resumption.
+type WatchSink = tokio::sync::watch::Sender<Option<mpsc::Sender<WsmMessage>>>;
+
+/// Watch tasks of one session, keyed by the virtual path they cover, and the sink they send
+/// through. A resumed session brings both along to its new connection.
+pub struct SessionWatches {
+    tasks: HashMap<String, JoinHandle<()>>,
+    sink: WatchSink,
+}
+
+pub type ActiveWatches = Arc<Mutex<SessionWatches>>;
+
+impl SessionWatches {
+    pub fn new(tx: mpsc::Sender<WsmMessage>) -> Self {
+        SessionWatches {
+            tasks: HashMap::new(),
+            sink: tokio::sync::watch::Sender::new(Some(tx)),
+        }
+    }
+}
+
+// Watches outliving their session, parked or not, would run forever.
+impl Drop for SessionWatches {
+    fn drop(&mut self) {
+        for (_, handle) in self.tasks.drain() {
+            handle.abort();
+        }
+    }
+}
+
+/// [SERVER-SIDE] Stops every watch of a closing connection.
+pub async fn stop_all(watches: &ActiveWatches) {
+    let mut active = watches.lock().await;
+    for (_, handle) in active.tasks.drain() {
+        handle.abort();
+    }
+    active.sink.send_replace(None);
+}
+
+/// [SERVER-SIDE] Takes the watches off a closing connection whose session can be resumed.
+/// They keep running and hold their events until `adopt` hands them a connection again.
+pub async fn detach(watches: &ActiveWatches) -> SessionWatches {
+    let (tx, _) = mpsc::channel(1);
+    let parked = std::mem::replace(&mut *watches.lock().await, SessionWatches::new(tx));
+    parked.sink.send_replace(None);
+    parked
+}
+
+/// [SERVER-SIDE] Moves the watches of a resumed session onto the connection taking it over.
+pub async fn adopt(watches: &ActiveWatches, parked: SessionWatches, tx: mpsc::Sender<WsmMessage>) {
+    parked.sink.send_replace(Some(tx));
+    *watches.lock().await = parked;
+}
+
+// [SERVER-SIDE] Handles the watch request (0x1F). The payload is a `/<dev_name>/path` directory.
+pub async fn handle_watch_request(
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    cfg: &Config,
+    watches: ActiveWatches,
+    encoding: PayloadType,
+    identity: &Identity,
+) {
+    let virtual_root = normalize(&msg.text());
+    let resolved =
+        upload::resolve_and_validate_path(&virtual_root, cfg, identity, Permission::List);
+    let dir = match resolved {
+        Ok(dir) => dir,
+        Err(e) => {
+            let _ = tx.send(e.to_message(msg.message_id)).await;
+            return;
+        }
+    };
+    if !dir.is_dir() {
+        let reason = format!("'{}' is not a directory on the server.", virtual_root);
+        error::send_error(&tx, msg.message_id, ErrorCode::NotFound, reason).await;
+        return;
+    }
+
+    let mut active = watches.lock().await;
+    // Watches end on their own when the directory goes away.
+    active.tasks.retain(|_, handle| !handle.is_finished());
+    if !active.tasks.contains_key(&virtual_root) {
+        if active.tasks.len() >= MAX_WATCHES {
+            let reason = format!(
+                "At most {} paths can be watched per connection.",
+                MAX_WATCHES
+            );
+            error::send_error(&tx, msg.message_id, ErrorCode::Unavailable, reason).await;
+            return;
+        }
+        let root = virtual_root.clone();
+        let setup = match task::spawn_blocking(move || Watcher::new(root, &dir)).await {
+            Ok(setup) => setup,
+            Err(e) => Err(ErrorReply::new(
+                ErrorCode::Internal,
+                format!("Watch setup task failed: {}", e),
+            )),
+        };
+        let (inotify, watcher) = match setup {
+            Ok(setup) => setup,
+            Err(e) => {
+                eprintln!("! Watch: Cannot watch {}: {}", virtual_root, e.message);
+                let _ = tx.send(e.to_message(msg.message_id)).await;
+                return;
+            }
+        };
+        let stream = match inotify.into_event_stream([0u8; EVENT_BUFFER_SIZE]) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let reason = format!("Failed to read inotify events: {}", e);
+                error::send_error(&tx, msg.message_id, ErrorCode::Internal, reason).await;
+                return;
+            }
+        };
+        println!(
+            "-> Watching {} ({} directories).",
+            virtual_root,
+            watcher.dirs.len()
+        );
+        let outlet = Outlet::new(active.sink.subscribe());
+        let handle = tokio::spawn(watcher.run(stream, outlet, encoding));
+        active.tasks.insert(virtual_root, handle);
+    }
+    drop(active);
+
+    let response = WsmMessage::empty(Opcode::Ack, msg.message_id).with_final();
+    if tx.send(response).await.is_err() {
+        eprintln!("! WSM-Server: Failed to send watch 'ACK' response.");
+    }
+}
+
+// [SERVER-SIDE] Handles the unwatch request (0x20).
+pub async fn handle_unwatch_request(
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    watches: ActiveWatches,
+) {
+    let virtual_root = normalize(&msg.text());
+    let removed = watches.lock().await.tasks.remove(&virtual_root);
+    match removed {
+        Some(handle) => {
+            handle.abort();
+            println!("-> Stopped watching {}.", virtual_root);
+        }
+        None => {
+            let reason = format!("'{}' is not being watched.", virtual_root);
+            error::send_error(&tx, msg.message_id, ErrorCode::NotFound, reason).await;
+            return;
+        }
+    }
+    let response = WsmMessage::empty(Opcode::Ack, msg.message_id).with_final();
+    if tx.send(response).await.is_err() {
+        eprintln!("! WSM-Server: Failed to send unwatch 'ACK' response.");
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.trim().trim_end_matches('/').to_string()
+}
+
+// Upload bookkeeping and the scrub index change constantly and are not user files.
+fn is_internal(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with(scrub::INDEX_FILE_NAME)
+        || name.ends_with(".tmp")
+        || name.ends_with(".lock")
+        || name.ends_with(".hash")
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVE_SELF
+        | WatchMask::ONLYDIR
+        | WatchMask::DONT_FOLLOW
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Created,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug)]
+enum Pending {
+    Change(String, Change),
+    Renamed(String, String),
+}
+
+// Changes collected during one coalescing window, in the order they happened.
+#[derive(Default)]
+struct Batch {
+    pending: Vec<Option<Pending>>,
+    // Position of the latest plain change per path, merged with the next one.
+    latest: HashMap<String, usize>,
+    // MOVED_FROM halves waiting for their MOVED_TO, by inotify cookie.
+    moved_from: HashMap<u32, (PathBuf, bool)>,
+}
+
+impl Batch {
+    fn change(&mut self, path: String, change: Change) {
+        if let Some(&index) = self.latest.get(&path) {
+            let Some(Pending::Change(_, previous)) = &self.pending[index] else {
+                unreachable!("latest only indexes plain changes");
+            };
+            let merged = match (*previous, change) {
+                (Change::Created, Change::Modified) => Some(Change::Created),
+                // Came and went within one window; the client never needs to know.
+                (Change::Created, Change::Deleted) => None,
+                (Change::Deleted, Change::Created) => Some(Change::Modified),
+                (_, change) => Some(change),
+            };
+            match merged {
+                Some(merged) => self.pending[index] = Some(Pending::Change(path, merged)),
+                None => {
+                    self.pending[index] = None;
+                    self.latest.remove(&path);
+                }
+            }
+            return;
+        }
+        self.latest.insert(path.clone(), self.pending.len());
+        self.pending.push(Some(Pending::Change(path, change)));
+    }
+
+    fn renamed(&mut self, from: String, to: String) {
+        self.latest.remove(&from);
+        self.latest.remove(&to);
+        self.pending.push(Some(Pending::Renamed(from, to)));
+    }
+}
+
+// Sends a watch's events to the current connection of its session, holding them while
+// there is none.
+struct Outlet {
+    sink: tokio::sync::watch::Receiver<Option<mpsc::Sender<WsmMessage>>>,
+    backlog: VecDeque<WsmMessage>,
+    overflowed: bool,
+}
+
+impl Outlet {
+    fn new(sink: tokio::sync::watch::Receiver<Option<mpsc::Sender<WsmMessage>>>) -> Self {
+        Outlet {
+            sink,
+            backlog: VecDeque::new(),
+            overflowed: false,
+        }
+    }
+
+    async fn send(&mut self, message: WsmMessage) {
+        if self.backlog.len() >= MAX_PARKED_EVENTS {
+            self.backlog.pop_front();
+            self.overflowed = true;
+        }
+        self.backlog.push_back(message);
+        self.flush().await;
+    }
+
+    // Sends what is held once a connection is there; a closed one keeps it for the next.
+    async fn flush(&mut self) {
+        let Some(tx) = self.sink.borrow_and_update().clone() else {
+            return;
+        };
+        if std::mem::take(&mut self.overflowed) {
+            eprintln!(
+                "! Watch: More than {} changes while disconnected; older ones were dropped.",
+                MAX_PARKED_EVENTS
+            );
+        }
+        while let Some(message) = self.backlog.pop_front() {
+            if let Err(mpsc::error::SendError(message)) = tx.send(message).await {
+                self.backlog.push_front(message);
+                return;
+            }
+        }
+    }
+}
+
+struct Watcher {
+    virtual_root: String,
+    bind_root: PathBuf,
+    watches: Watches,
+    root_wd: WatchDescriptor,
+    // Every watched directory, relative to the watched root.
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watcher {
+    // Blocking: walks the whole tree below `dir` to add a watch per directory.
+    fn new(virtual_root: String, dir: &Path) -> Result<(Inotify, Watcher), ErrorReply> {
+        let unavailable = |e: std::io::Error| {
+            ErrorReply::new(
+                ErrorCode::Unavailable,
+                format!("Failed to set up inotify: {}", e),
+            )
+        };
+        let inotify = Inotify::init().map_err(unavailable)?;
+        let mut watches = inotify.watches();
+        let root_wd = watches.add(dir, watch_mask()).map_err(unavailable)?;
+        let mut watcher = Watcher {
+            virtual_root,
+            bind_root: dir.to_path_buf(),
+            watches,
+            root_wd: root_wd.clone(),
+            dirs: HashMap::from([(root_wd, PathBuf::new())]),
+        };
+        watcher.add_subtree(Path::new(""))?;
+        Ok((inotify, watcher))
+    }
+
+    fn add_subtree(&mut self, rel: &Path) -> Result<(), ErrorReply> {
+        let mut stack = vec![rel.to_path_buf()];
+        while let Some(rel_dir) = stack.pop() {
+            let Ok(entries) = fs::read_dir(self.bind_root.join(&rel_dir)) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+                if !is_dir || is_internal(&entry.file_name()) {
+                    continue;
+                }
+                if self.dirs.len() >= MAX_WATCHED_DIRS {
+                    return Err(ErrorReply::new(
+                        ErrorCode::Unavailable,
+                        format!("More than {} directories to watch.", MAX_WATCHED_DIRS),
+                    ));
+                }
+                let child = rel_dir.join(entry.file_name());
+                match self.watches.add(entry.path(), watch_mask()) {
+                    Ok(wd) => {
+                        self.dirs.insert(wd, child.clone());
+                        stack.push(child);
+                    }
+                    Err(e) => eprintln!("! Watch: Cannot watch {}: {}", entry.path().display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Directories created or moved in while watching are added as they appear.
+    fn watch_new_dir(&mut self, rel: &Path) {
+        if self.dirs.len() >= MAX_WATCHED_DIRS {
+            eprintln!(
+                "! Watch: {} covers too many directories, not descending further.",
+                self.virtual_root
+            );
+            return;
+        }
+        match self.watches.add(self.bind_root.join(rel), watch_mask()) {
+            Ok(wd) => {
+                self.dirs.insert(wd, rel.to_path_buf());
+                if let Err(e) = self.add_subtree(rel) {
+                    eprintln!("! Watch: {}: {}", self.virtual_root, e.message);
+                }
+            }
+            Err(e) => eprintln!(
+                "! Watch: Cannot watch new directory {}: {}",
+                rel.display(),
+                e
+            ),
+        }
+    }
+
+    fn virtual_path(&self, rel: &Path) -> String {
+        if rel.as_os_str().is_empty() {
+            self.virtual_root.clone()
+        } else {
+            format!("{}/{}", self.virtual_root, rel.to_string_lossy())
+        }
+    }
+
+    // Records one inotify event. Returns false once the watched directory itself is gone.
+    fn apply(&mut self, event: EventOwned, batch: &mut Batch) -> bool {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            eprintln!(
+                "! Watch: Event queue overflowed for {}; some changes were lost.",
+                self.virtual_root
+            );
+            return true;
+        }
+        if event.wd == self.root_wd
+            && event
+                .mask
+                .intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF)
+        {
+            batch.change(self.virtual_root.clone(), Change::Deleted);
+            return false;
+        }
+        if event.mask.contains(EventMask::IGNORED) {
+            self.dirs.remove(&event.wd);
+            return event.wd != self.root_wd;
+        }
+        let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) else {
+            return true;
+        };
+        if is_internal(&name) {
+            return true;
+        }
+        let rel = dir.join(&name);
+        let is_dir = event.mask.contains(EventMask::ISDIR);
+
+        if event.mask.contains(EventMask::CREATE) {
+            if is_dir {
+                self.watch_new_dir(&rel);
+            }
+            batch.change(self.virtual_path(&rel), Change::Created);
+        } else if event.mask.contains(EventMask::CLOSE_WRITE) {
+            batch.change(self.virtual_path(&rel), Change::Modified);
+        } else if event.mask.contains(EventMask::DELETE) {
+            batch.change(self.virtual_path(&rel), Change::Deleted);
+        } else if event.mask.contains(EventMask::MOVED_FROM) {
+            batch.moved_from.insert(event.cookie, (rel, is_dir));
+        } else if event.mask.contains(EventMask::MOVED_TO) {
+            match batch.moved_from.remove(&event.cookie) {
+                Some((from, _)) => {
+                    if is_dir {
+                        self.rebase_dirs(&from, &rel);
+                    }
+                    batch.renamed(self.virtual_path(&from), self.virtual_path(&rel));
+                }
+                None => {
+                    // Moved in from outside the watched tree.
+                    if is_dir {
+                        self.watch_new_dir(&rel);
+                    }
+                    batch.change(self.virtual_path(&rel), Change::Created);
+                }
+            }
+        }
+        true
+    }
+
+    // A renamed directory keeps its watches; only the paths they report change.
+    fn rebase_dirs(&mut self, from: &Path, to: &Path) {
+        for rel in self.dirs.values_mut() {
+            if let Ok(rest) = rel.strip_prefix(from) {
+                *rel = to.join(rest);
+            }
+        }
+    }
+
+    // Turns a finished window into events. Unpaired MOVED_FROMs left the watched tree.
+    fn finish(&mut self, mut batch: Batch) -> Vec<ServerEvent> {
+        for (_, (rel, is_dir)) in std::mem::take(&mut batch.moved_from) {
+            if is_dir {
+                let gone: Vec<WatchDescriptor> = self
+                    .dirs
+                    .iter()
+                    .filter(|(_, dir)| dir.starts_with(&rel))
+                    .map(|(wd, _)| wd.clone())
+                    .collect();
+                for wd in gone {
+                    self.dirs.remove(&wd);
+                    let _ = self.watches.remove(wd);
+                }
+            }
+            batch.change(self.virtual_path(&rel), Change::Deleted);
+        }
+        batch
+            .pending
+            .into_iter()
+            .flatten()
+            .map(|pending| match pending {
+                Pending::Change(path, Change::Created) => ServerEvent::FileCreated { path },
+                Pending::Change(path, Change::Modified) => ServerEvent::FileModified { path },
+                Pending::Change(path, Change::Deleted) => ServerEvent::FileDeleted { path },
+                Pending::Renamed(from, to) => ServerEvent::FileRenamed { from, to },
+            })
+            .collect()
+    }
+
+    // Sends coalesced changes as events (0x1E) with the reserved message ID 0 until the
+    // watched directory disappears or the session ends.
+    async fn run(
+        mut self,
+        mut stream: EventStream<[u8; EVENT_BUFFER_SIZE]>,
+        mut outlet: Outlet,
+        encoding: PayloadType,
+    ) {
+        let mut running = true;
+        while running {
+            let next = tokio::select! {
+                next = stream.next() => next,
+                // A resumed session: send what was held while it was parked.
+                changed = outlet.sink.changed(), if !outlet.backlog.is_empty() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    outlet.flush().await;
+                    continue;
+                }
+            };
+            let first = match next {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => {
+                    eprintln!(
+                        "! Watch: Failed to read events for {}: {}",
+                        self.virtual_root, e
+                    );
+                    break;
+                }
+                None => break,
+            };
+            let mut batch = Batch::default();
+            running = self.apply(first, &mut batch);
+            let deadline = Instant::now() + COALESCE_WINDOW;
+            while running {
+                match time::timeout_at(deadline, stream.next()).await {
+                    Ok(Some(Ok(event))) => running = self.apply(event, &mut batch),
+                    Ok(Some(Err(e))) => {
+                        eprintln!(
+                            "! Watch: Failed to read events for {}: {}",
+                            self.virtual_root, e
+                        );
+                        running = false;
+                    }
+                    Ok(None) => running = false,
+                    Err(_) => break,
+                }
+            }
+            for event in self.finish(batch) {
+                match WsmMessage::encoded(Opcode::Event, 0, encoding, &event) {
+                    Ok(message) => outlet.send(message.with_final()).await,
+                    Err(e) => {
+                        eprintln!("! Watch: Dropped a change in {}: {}", self.virtual_root, e)
+                    }
+                }
+            }
+        }
+        println!("- Watch on {} ended.", self.virtual_root);
+    }
+}
+
+// [CLIENT-SIDE] Starts watching a directory (0x1F). Changes arrive as server events.
+pub async fn watch(requests: &RequestTracker, path: &str) -> Result<(), String> {
+    let path = normalize(path);
+    send_path_request(requests, Opcode::Watch, &path).await?;
+    WATCHED_PATHS.lock().unwrap().insert(path);
+    Ok(())
+}
+
+// [CLIENT-SIDE] Stops watching a directory (0x20).
+pub async fn unwatch(requests: &RequestTracker, path: &str) -> Result<(), String> {
+    let path = normalize(path);
+    WATCHED_PATHS.lock().unwrap().remove(&path);
+    send_path_request(requests, Opcode::Unwatch, &path).await
+}
+
+/// [CLIENT-SIDE] Paths watched on this or the previous connection.
+pub fn watched_paths() -> Vec<String> {
+    WATCHED_PATHS.lock().unwrap().iter().cloned().collect()
+}
+
+// [CLIENT-SIDE] Watches live with the session; re-establish them after a reconnect that
+// could not resume it.
+pub async fn restore(requests: &RequestTracker) {
+    for path in watched_paths() {
+        match send_path_request(requests, Opcode::Watch, &path).await {
+            Ok(()) => info!("Watching {} again.", path),
+            Err(e) => {
+                warn!("Failed to watch {} again: {}", path, e);
+                WATCHED_PATHS.lock().unwrap().remove(&path);
+            }
+        }
+    }
+}
+
+async fn send_path_request(
+    requests: &RequestTracker,
+    opcode: Opcode,
+    path: &str,
+) -> Result<(), String> {
+    let reply = requests
+        .request(
+            |id| WsmMessage::new(opcode, id, PayloadType::Raw, path.as_bytes().to_vec()),
+            REQUEST_TIMEOUT,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    if reply.opcode != Opcode::Ack {
+        return Err(format!("Unexpected reply {} to {}.", reply.opcode, opcode));
+    }
+    Ok(())
+}
@@ -0,0 +1,410 @@
+/* src/setup/args.rs */
+
+use super::ca::CA_USAGE;
+use super::cert::GEN_CERT_USAGE;
+use crate::audit::query::AUDIT_USAGE;
+use std::path::Path;
+
+/// Config used when no --config is given; `anchr init` writes it.
+pub const DEFAULT_CONFIG: &str = "anchr.toml";
+
+// Exit codes besides 0 for success.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_CONFIG: i32 = 3;
+// The server could not be reached, or refused the client.
+pub const EXIT_UNAVAILABLE: i32 = 4;
+
+const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug"];
+
+// rfs subcommands that make sense for a single connection; watches end with it.
+const ONE_SHOT_RFS: &[&str] = &["list", "scrub", "upload", "image", "nbd"];
+
+pub const USAGE: &str = "Usage: anchr [options] <command> [arguments]
+
+Without a command, anchr runs what the config's mode says. On first use, when there is
+no anchr.toml yet, it generates one as 'anchr init' does.
+
+Commands:
+  init [path] [--force]    Generate a server config, certificate and key
+  serve                    Run the server
+  client                   Run the interactive client
+  check-config             Validate the config and exit
+  rfs <command> ...        Run one rfs command against the server: list, scrub, upload,
+                           image or nbd
+  ca <command> ...         Run a certificate authority for mutual TLS
+  cert gen|status ...      Generate a certificate and key, show the server certificate
+  hash-token <token>       Print the auth_token_hash line for a token
+  audit [filters]          Query the server's audit log
+
+Options:
+  -c, --config <path>      Config file (default: anchr.toml)
+      --log-level <level>  Override log_level: error, warn, info or debug
+  -h, --help               Show this help; 'anchr <command> --help' shows a command's
+  -V, --version            Show the version
+
+Exit codes: 0 success, 1 the command failed, 2 invalid usage, 3 configuration error,
+4 the server was unreachable or refused the client.";
+
+const INIT_USAGE: &str = "Usage: anchr init [path] [--force]
+Generates a server config (default anchr.toml) with a certificate and key for a chosen
+address. --force replaces existing files.";
+
+const SERVE_USAGE: &str = "Usage: anchr [--config <path>] [--log-level <level>] serve
+Runs the server; the config must have mode = \"server\" and pass 'anchr check-config'.";
+
+const CLIENT_USAGE: &str = "Usage: anchr [--config <path>] [--log-level <level>] client
+Runs the interactive client; the config must have mode = \"client\".";
+
+const CHECK_CONFIG_USAGE: &str = "Usage: anchr [--config <path>] check-config
+Validates the config for its mode and exits with 0 if it is usable, 3 if not.";
+
+const RFS_USAGE: &str = "Usage: anchr [--config <path>] rfs <command> [arguments]
+Connects with a client config, runs one command and exits:
+  rfs list
+  rfs scrub
+  rfs upload <target_dir> <local_path_to_file>
+  rfs image pull <dev_name> <local.img>
+  rfs image push <dev_name> <local.img> --confirm-overwrite
+  rfs nbd <dev_name> <tcp:host:port | unix:/path/to.sock>    (until interrupted)
+'rfs watch' and 'rfs unwatch' last only as long as a session; use 'anchr client'.";
+
+const CERT_USAGE: &str =
+    "Usage: anchr cert gen [options]    Generate a self-signed certificate and key
+       anchr [--config <path>] cert status
+                                     Show the certificate the server presents
+The certificate authority is 'anchr ca'.";
+
+const HASH_TOKEN_USAGE: &str = "Usage: anchr hash-token <token>
+Prints a salted auth_token_hash line to store in the server config instead of auth_token.";
+
+/// What to run, with the global overrides given before it.
+pub struct Invocation {
+    pub config: Option<String>,
+    pub log_level: Option<String>,
+    pub command: Command,
+}
+
+pub enum Command {
+    Help(&'static str),
+    Version,
+    Init { path: Option<String>, force: bool },
+    // No command: run what the config's mode says, or set it up on first use.
+    Run,
+    Serve,
+    Client,
+    CheckConfig,
+    HashToken(String),
+    GenCert(Vec<String>),
+    Ca(Vec<String>),
+    CertStatus,
+    Audit(Vec<String>),
+    Rfs(Vec<String>),
+}
+
+fn is_help(arg: &str) -> bool {
+    arg == "-h" || arg == "--help"
+}
+
+fn usage_error(message: String, usage: &str) -> String {
+    format!("{}\n{}", message, usage)
+}
+
+impl Invocation {
+    // Takes a global option off the front of `args`; false if it does not start with one.
+    fn take_option(&mut self, args: &mut &[String]) -> Result<bool, String> {
+        let Some(flag) = args.first() else {
+            return Ok(false);
+        };
+        if !matches!(flag.as_str(), "-c" | "--config" | "--log-level") {
+            return Ok(false);
+        }
+        let value = args
+            .get(1)
+            .ok_or_else(|| usage_error(format!("{} needs a value.", flag), USAGE))?
+            .clone();
+        if flag == "--log-level" {
+            if !LOG_LEVELS.contains(&value.as_str()) {
+                return Err(format!(
+                    "Invalid log level '{}'; expected error, warn, info or debug.",
+                    value
+                ));
+            }
+            self.log_level = Some(value);
+        } else {
+            self.config = Some(value);
+        }
+        *args = &args[2..];
+        Ok(true)
+    }
+
+    // Commands without arguments still take the global options after their name.
+    fn no_arguments(
+        &mut self,
+        mut args: &[String],
+        command: Command,
+        usage: &'static str,
+    ) -> Result<Command, String> {
+        while self.take_option(&mut args)? {}
+        match args.first() {
+            None => Ok(command),
+            Some(arg) if is_help(arg) => Ok(Command::Help(usage)),
+            Some(arg) => Err(usage_error(
+                format!("Unexpected argument '{}'.", arg),
+                usage,
+            )),
+        }
+    }
+}
+
+/// Parses the command line, without the program name. Errors carry the usage to print.
+pub fn parse(args: &[String]) -> Result<Invocation, String> {
+    let mut invocation = Invocation {
+        config: None,
+        log_level: None,
+        command: Command::Help(USAGE),
+    };
+    let mut args = args;
+    while invocation.take_option(&mut args)? {}
+
+    let Some((name, rest)) = args.split_first() else {
+        invocation.command = Command::Run;
+        return Ok(invocation);
+    };
+    let wants_help = rest.first().is_some_and(|arg| is_help(arg));
+    invocation.command = match name.as_str() {
+        "-h" | "--help" | "help" => Command::Help(USAGE),
+        "-V" | "--version" => Command::Version,
+        "init" => parse_init(rest)?,
+        "serve" => invocation.no_arguments(rest, Command::Serve, SERVE_USAGE)?,
+        "client" => invocation.no_arguments(rest, Command::Client, CLIENT_USAGE)?,
+        "check-config" => {
+            invocation.no_arguments(rest, Command::CheckConfig, CHECK_CONFIG_USAGE)?
+        }
+        "hash-token" | "--hash-token" => match rest {
+            [arg] if is_help(arg) => Command::Help(HASH_TOKEN_USAGE),
+            [token] => Command::HashToken(token.clone()),
+            _ => return Err(HASH_TOKEN_USAGE.to_string()),
+        },
+        "cert" => match rest.split_first() {
+            Some((sub, args)) if sub == "gen" => match args.first() {
+                Some(arg) if is_help(arg) => Command::Help(GEN_CERT_USAGE),
+                _ => Command::GenCert(args.to_vec()),
+            },
+            // `ca` under `cert` as well, where it is easy to look for it.
+            Some((sub, args)) if sub == "ca" => match args.first() {
+                Some(arg) if is_help(arg) => Command::Help(CA_USAGE),
+                _ => Command::Ca(args.to_vec()),
+            },
+            Some((sub, args)) if sub == "status" => {
+                invocation.no_arguments(args, Command::CertStatus, CERT_USAGE)?
+            }
+            _ if wants_help => Command::Help(CERT_USAGE),
+            _ => return Err(CERT_USAGE.to_string()),
+        },
+        "ca" if wants_help => Command::Help(CA_USAGE),
+        "ca" => Command::Ca(rest.to_vec()),
+        // Older spelling of `cert gen`.
+        "gen-cert" if wants_help => Command::Help(GEN_CERT_USAGE),
+        "gen-cert" => Command::GenCert(rest.to_vec()),
+        "audit" if wants_help => Command::Help(AUDIT_USAGE),
+        "audit" => Command::Audit(audit_args(rest, invocation.config.as_deref())),
+        "rfs" => parse_rfs(rest)?,
+        _ => {
+            return Err(format!(
+                "Unknown command '{}'. Run 'anchr --help' for usage.",
+                name
+            ));
+        }
+    };
+    Ok(invocation)
+}
+
+/// Whether `anchr` without a command should generate `default_config` rather than run it:
+/// only when no config was named and the default one does not exist yet.
+pub fn is_first_run(config: Option<&str>, default_config: &Path) -> bool {
+    config.is_none() && !default_config.exists()
+}
+
+fn parse_init(args: &[String]) -> Result<Command, String> {
+    let mut path = None;
+    let mut force = false;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => force = true,
+            _ if is_help(arg) => return Ok(Command::Help(INIT_USAGE)),
+            _ if arg.starts_with('-') || path.is_some() => {
+                return Err(usage_error(
+                    format!("Unexpected argument '{}'.", arg),
+                    INIT_USAGE,
+                ));
+            }
+            _ => path = Some(arg.clone()),
+        }
+    }
+    Ok(Command::Init { path, force })
+}
+
+fn parse_rfs(args: &[String]) -> Result<Command, String> {
+    match args.first().map(String::as_str) {
+        Some(arg) if is_help(arg) => Ok(Command::Help(RFS_USAGE)),
+        Some(sub) if ONE_SHOT_RFS.contains(&sub) => Ok(Command::Rfs(args.to_vec())),
+        Some(sub @ ("watch" | "unwatch")) => Err(usage_error(
+            format!("'rfs {}' needs a lasting session.", sub),
+            RFS_USAGE,
+        )),
+        Some(sub) => Err(usage_error(
+            format!("Unknown rfs command '{}'.", sub),
+            RFS_USAGE,
+        )),
+        None => Err(RFS_USAGE.to_string()),
+    }
+}
+
+// `anchr --config <path> audit` reads the log that config writes, unless told otherwise.
+fn audit_args(args: &[String], config: Option<&str>) -> Vec<String> {
+    let chosen = args.iter().any(|arg| arg == "-c" || arg == "--file");
+    match config {
+        Some(config) if !chosen => ["-c".to_string(), config.to_string()]
+            .into_iter()
+            .chain(args.iter().cloned())
+            .collect(),
+        _ => args.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn parse_line(line: &str) -> Result<Invocation, String> {
+        let args: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        parse(&args)
+    }
+
+    fn error_for(line: &str) -> String {
+        match parse_line(line) {
+            Err(e) => e,
+            Ok(_) => panic!("'{}' should be refused", line),
+        }
+    }
+
+    fn help_for(line: &str) -> &'static str {
+        match parse_line(line).map(|invocation| invocation.command) {
+            Ok(Command::Help(usage)) => usage,
+            _ => panic!("'{}' should show help", line),
+        }
+    }
+
+    #[test]
+    fn global_options_go_before_the_command() {
+        let invocation = parse_line("-c s.toml --log-level debug serve").unwrap();
+        assert_eq!(invocation.config.as_deref(), Some("s.toml"));
+        assert_eq!(invocation.log_level.as_deref(), Some("debug"));
+        assert!(matches!(invocation.command, Command::Serve));
+    }
+
+    #[test]
+    fn global_options_go_after_commands_without_arguments() {
+        let invocation = parse_line("client --config c.toml --log-level warn").unwrap();
+        assert_eq!(invocation.config.as_deref(), Some("c.toml"));
+        assert_eq!(invocation.log_level.as_deref(), Some("warn"));
+        assert!(matches!(invocation.command, Command::Client));
+
+        let invocation = parse_line("--log-level info check-config -c s.toml").unwrap();
+        assert_eq!(invocation.config.as_deref(), Some("s.toml"));
+        assert_eq!(invocation.log_level.as_deref(), Some("info"));
+        assert!(matches!(invocation.command, Command::CheckConfig));
+
+        let invocation = parse_line("cert status -c c.toml").unwrap();
+        assert_eq!(invocation.config.as_deref(), Some("c.toml"));
+        assert!(matches!(invocation.command, Command::CertStatus));
+    }
+
+    #[test]
+    fn the_last_config_wins() {
+        let invocation = parse_line("-c a.toml serve -c b.toml").unwrap();
+        assert_eq!(invocation.config.as_deref(), Some("b.toml"));
+    }
+
+    #[test]
+    fn config_without_a_command_runs_its_mode() {
+        let invocation = parse_line("-c s.toml").unwrap();
+        assert!(matches!(invocation.command, Command::Run));
+    }
+
+    #[test]
+    fn no_arguments_run_the_default_config() {
+        let invocation = parse_line("").unwrap();
+        assert!(invocation.config.is_none());
+        assert!(matches!(invocation.command, Command::Run));
+    }
+
+    #[test]
+    fn the_first_run_generates_the_default_config() {
+        let dir = TempDir::new().unwrap();
+        let default_config = dir.path().join(DEFAULT_CONFIG);
+        assert!(is_first_run(None, &default_config));
+        assert!(!is_first_run(Some("s.toml"), &default_config));
+
+        std::fs::write(&default_config, "").unwrap();
+        assert!(!is_first_run(None, &default_config));
+    }
+
+    #[test]
+    fn option_errors_are_reported() {
+        assert!(error_for("serve -c").starts_with("-c needs a value."));
+        assert!(error_for("--log-level trace serve").starts_with("Invalid log level 'trace'"));
+        assert!(error_for("serve --verbose").starts_with("Unexpected argument '--verbose'."));
+        assert!(error_for("frobnicate").starts_with("Unknown command 'frobnicate'."));
+    }
+
+    #[test]
+    fn help_is_shown_per_command() {
+        assert_eq!(help_for("--help"), USAGE);
+        assert_eq!(help_for("-c s.toml help"), USAGE);
+        assert_eq!(help_for("serve --help"), SERVE_USAGE);
+        assert_eq!(help_for("init -h"), INIT_USAGE);
+        assert_eq!(help_for("rfs --help"), RFS_USAGE);
+        assert_eq!(help_for("ca --help"), CA_USAGE);
+        assert_eq!(help_for("cert ca --help"), CA_USAGE);
+        assert_eq!(help_for("cert --help"), CERT_USAGE);
+        assert_eq!(help_for("audit -h"), AUDIT_USAGE);
+    }
+
+    #[test]
+    fn init_takes_a_path_and_force() {
+        let invocation = parse_line("init --force custom.toml").unwrap();
+        assert!(matches!(
+            invocation.command,
+            Command::Init { path: Some(path), force: true } if path == "custom.toml"
+        ));
+        assert!(parse_line("init a.toml b.toml").is_err());
+    }
+
+    #[test]
+    fn audit_reads_the_log_of_the_given_config() {
+        let invocation = parse_line("-c s.toml audit --user alice").unwrap();
+        assert!(matches!(
+            invocation.command,
+            Command::Audit(args) if args == ["-c", "s.toml", "--user", "alice"]
+        ));
+        let invocation = parse_line("-c s.toml audit --file other.log").unwrap();
+        assert!(matches!(
+            invocation.command,
+            Command::Audit(args) if args == ["--file", "other.log"]
+        ));
+    }
+
+    #[test]
+    fn rfs_takes_one_shot_commands_only() {
+        let invocation = parse_line("rfs upload /v ./a.txt").unwrap();
+        assert!(matches!(
+            invocation.command,
+            Command::Rfs(args) if args == ["upload", "/v", "./a.txt"]
+        ));
+        assert!(error_for("rfs watch /v").contains("lasting session"));
+        assert!(parse_line("rfs").is_err());
+    }
+}
@@ -0,0 +1,631 @@
+/* src/setup/ca.rs */
+
+use super::cert::{KeyType, signing_digest, write_private_key};
+use foreign_types::ForeignTypeRef;
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{X509, X509Builder, X509Crl, X509NameBuilder};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::ffi::c_void;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory the `anchr ca` commands work in unless `--dir` is given.
+pub const DEFAULT_CA_DIR: &str = "anchr-ca";
+
+pub const CA_USAGE: &str = "Usage: anchr ca <init | issue-server <address> | \
+issue-client <name> | revoke <serial> | list> [--dir <path>]";
+
+const CA_VALIDITY_DAYS: u32 = 3650;
+const CERT_VALIDITY_DAYS: u32 = 365;
+// Revocations re-sign the CRL, so this only bounds how stale an untouched CRL may look.
+const CRL_VALIDITY_DAYS: u32 = 365;
+
+const CA_CERT: &str = "ca.pem";
+const CA_KEY: &str = "ca.key";
+const INDEX: &str = "index.json";
+const CRL: &str = "crl.pem";
+// Below `servers/` and `clients/`: revoked pairs, moved aside so the name can be reissued.
+const REVOKED_DIR: &str = "revoked";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum CertKind {
+    Server,
+    Client,
+}
+
+/// One certificate the CA signed, as recorded in `index.json`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct IssuedCert {
+    // Uppercase hex, as printed by `openssl x509 -serial`.
+    serial: String,
+    kind: CertKind,
+    name: String,
+    fingerprint: String,
+    not_after: String,
+    // Unix time of the revocation.
+    #[serde(default)]
+    revoked_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CaIndex {
+    crl_number: u64,
+    certs: Vec<IssuedCert>,
+}
+
+struct Ca {
+    dir: PathBuf,
+    cert: X509,
+    key: PKey<Private>,
+    index: CaIndex,
+}
+
+/// Runs `anchr ca <command> [argument] [--dir <path>]`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut dir = DEFAULT_CA_DIR.to_string();
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--dir" {
+            dir = iter.next().ok_or("--dir needs a path.")?.clone();
+        } else {
+            positional.push(arg.as_str());
+        }
+    }
+    let dir = Path::new(&dir);
+    match positional.as_slice() {
+        ["init"] => init(dir),
+        ["issue-server", address] => issue_server(dir, address),
+        ["issue-client", name] => issue_client(dir, name),
+        ["revoke", serial] => revoke(dir, serial),
+        ["list"] => list(dir),
+        _ => Err(CA_USAGE.to_string()),
+    }
+}
+
+// Creates the CA key, its self-signed certificate, an empty index and an empty CRL.
+fn init(dir: &Path) -> Result<(), String> {
+    if dir.join(CA_KEY).exists() {
+        return Err(format!("A CA already exists in '{}'.", dir.display()));
+    }
+    fs::create_dir_all(dir).map_err(|e| format!("Cannot create '{}': {}", dir.display(), e))?;
+
+    let key = KeyType::default().generate().map_err(ssl_error)?;
+    let mut builder = new_builder(&key, "anchr CA", CA_VALIDITY_DAYS).map_err(ssl_error)?;
+    let name = subject_name("anchr CA").map_err(ssl_error)?;
+    builder.set_issuer_name(&name).map_err(ssl_error)?;
+    builder
+        .append_extension(
+            BasicConstraints::new()
+                .critical()
+                .ca()
+                .build()
+                .map_err(ssl_error)?,
+        )
+        .map_err(ssl_error)?;
+    builder
+        .append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()
+                .map_err(ssl_error)?,
+        )
+        .map_err(ssl_error)?;
+    let key_id = SubjectKeyIdentifier::new()
+        .build(&builder.x509v3_context(None, None))
+        .map_err(ssl_error)?;
+    builder.append_extension(key_id).map_err(ssl_error)?;
+    builder
+        .sign(&key, signing_digest(&key))
+        .map_err(ssl_error)?;
+
+    let mut ca = Ca {
+        dir: dir.to_path_buf(),
+        cert: builder.build(),
+        key,
+        index: CaIndex::default(),
+    };
+    write_private_key(&dir.join(CA_KEY), &ca.key, false)?;
+    write_file(&dir.join(CA_CERT), &ca.cert.to_pem().map_err(ssl_error)?)?;
+    ca.save()?;
+
+    println!("+ CA created in '{}'.", dir.display());
+    println!("> Server config ([setup]):");
+    println!("  client_ca = \"{}\"", dir.join(CA_CERT).display());
+    println!("  client_crl = \"{}\"", dir.join(CRL).display());
+    println!("> Clients trust servers issued by this CA with:");
+    println!("  certificate = \"{}\"", dir.join(CA_CERT).display());
+    Ok(())
+}
+
+// Server certificate for `address` (an IP address or DNS name), plus localhost.
+fn issue_server(dir: &Path, address: &str) -> Result<(), String> {
+    let dns_name = Regex::new(r"^[a-zA-Z0-9.-]+$").unwrap();
+    let is_ip = address.parse::<IpAddr>().is_ok();
+    if !is_ip && !dns_name.is_match(address) {
+        return Err(format!(
+            "'{}' is neither an IP address nor a DNS name.",
+            address
+        ));
+    }
+    let mut ca = Ca::load(dir)?;
+    let key = KeyType::default().generate().map_err(ssl_error)?;
+    let mut builder = ca.leaf_builder(&key, address)?;
+
+    let mut san = SubjectAlternativeName::new();
+    san.dns("localhost").ip("127.0.0.1").ip("::1");
+    if is_ip {
+        san.ip(address);
+    } else {
+        san.dns(address);
+    }
+    let san = san
+        .build(&builder.x509v3_context(Some(&ca.cert), None))
+        .map_err(ssl_error)?;
+    builder.append_extension(san).map_err(ssl_error)?;
+    builder
+        .append_extension(
+            ExtendedKeyUsage::new()
+                .server_auth()
+                .build()
+                .map_err(ssl_error)?,
+        )
+        .map_err(ssl_error)?;
+
+    let (cert_path, key_path) = ca.sign_and_write(builder, &key, CertKind::Server, address)?;
+    println!("+ Server certificate issued for '{}'.", address);
+    println!("> Server config ([setup]):");
+    println!("  certificate = \"{}\"", cert_path.display());
+    println!("  private_key = \"{}\"", key_path.display());
+    Ok(())
+}
+
+// Client certificate with common name `name`, which the server maps to a [[users]] entry.
+fn issue_client(dir: &Path, name: &str) -> Result<(), String> {
+    let re = Regex::new(r"^[a-zA-Z0-9_.-]+$").unwrap();
+    if !re.is_match(name) {
+        return Err(format!(
+            "Client name '{}' contains invalid characters. Only a-z, A-Z, 0-9, _, ., - are allowed.",
+            name
+        ));
+    }
+    let mut ca = Ca::load(dir)?;
+    let key = KeyType::default().generate().map_err(ssl_error)?;
+    let mut builder = ca.leaf_builder(&key, name)?;
+    builder
+        .append_extension(
+            ExtendedKeyUsage::new()
+                .client_auth()
+                .build()
+                .map_err(ssl_error)?,
+        )
+        .map_err(ssl_error)?;
+
+    let (cert_path, key_path) = ca.sign_and_write(builder, &key, CertKind::Client, name)?;
+    println!("+ Client certificate issued for '{}'.", name);
+    println!("> Client config ([setup]):");
+    println!("  client_certificate = \"{}\"", cert_path.display());
+    println!("  client_key = \"{}\"", key_path.display());
+    println!("> Server config:");
+    println!("  [[users]]");
+    println!("  name = \"{}\"", name);
+    println!("  cert_subject = \"{}\"", name);
+    Ok(())
+}
+
+// Marks a certificate revoked and re-signs the CRL; running servers reload it.
+fn revoke(dir: &Path, serial: &str) -> Result<(), String> {
+    let mut ca = Ca::load(dir)?;
+    let serial = serial.trim_start_matches("0x").to_ascii_uppercase();
+    let entry = ca
+        .index
+        .certs
+        .iter_mut()
+        .find(|c| c.serial == serial)
+        .ok_or_else(|| format!("No certificate with serial {} in the index.", serial))?;
+    if entry.revoked_at.is_some() {
+        return Err(format!("Certificate {} is already revoked.", serial));
+    }
+    entry.revoked_at = Some(unix_now());
+    let entry = entry.clone();
+    println!(
+        "+ Revoked {} certificate '{}' ({}).",
+        if entry.kind == CertKind::Server {
+            "server"
+        } else {
+            "client"
+        },
+        entry.name,
+        serial
+    );
+    ca.save()?;
+    println!("> CRL updated: {}", dir.join(CRL).display());
+    move_aside(dir, &entry)
+}
+
+// Where the certificate and key issued under `name` are written.
+fn issued_paths(dir: &Path, kind: CertKind, name: &str) -> (PathBuf, PathBuf) {
+    let subdir = dir.join(match kind {
+        CertKind::Server => "servers",
+        CertKind::Client => "clients",
+    });
+    (
+        subdir.join(format!("{}.pem", name)),
+        subdir.join(format!("{}.key", name)),
+    )
+}
+
+// Moves a revoked pair to `revoked/<name>-<serial>.*` beside it, if it is still the one
+// issued under its name.
+fn move_aside(dir: &Path, entry: &IssuedCert) -> Result<(), String> {
+    let (cert_path, key_path) = issued_paths(dir, entry.kind, &entry.name);
+    let fingerprint = fs::read(&cert_path)
+        .ok()
+        .and_then(|pem| X509::from_pem(&pem).ok())
+        .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+        .map(hex::encode);
+    if fingerprint.as_deref() != Some(entry.fingerprint.as_str()) {
+        return Ok(());
+    }
+    let revoked_dir = cert_path.with_file_name(REVOKED_DIR);
+    fs::create_dir_all(&revoked_dir)
+        .map_err(|e| format!("Cannot create '{}': {}", revoked_dir.display(), e))?;
+    let stem = format!("{}-{}", entry.name, entry.serial);
+    for (from, extension) in [(&cert_path, "pem"), (&key_path, "key")] {
+        let to = revoked_dir.join(format!("{}.{}", stem, extension));
+        match fs::rename(from, &to) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!("Cannot move '{}' aside: {}", from.display(), e));
+            }
+            _ => {}
+        }
+    }
+    println!(
+        "> Moved to '{}'; '{}' can be issued again.",
+        revoked_dir.join(&stem).display(),
+        entry.name
+    );
+    Ok(())
+}
+
+fn list(dir: &Path) -> Result<(), String> {
+    let ca = Ca::load(dir)?;
+    for cert in &ca.index.certs {
+        println!(
+            "{:<42} {:<6} {:<24} until {} {}",
+            cert.serial,
+            format!("{:?}", cert.kind).to_lowercase(),
+            cert.name,
+            cert.not_after,
+            if cert.revoked_at.is_some() {
+                "(revoked)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+impl Ca {
+    fn load(dir: &Path) -> Result<Ca, String> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            fs::read(&path).map_err(|e| format!("Cannot read '{}': {}", path.display(), e))
+        };
+        let cert = X509::from_pem(&read(CA_CERT)?).map_err(ssl_error)?;
+        let key = PKey::private_key_from_pem(&read(CA_KEY)?).map_err(ssl_error)?;
+        let index = serde_json::from_slice(&read(INDEX)?)
+            .map_err(|e| format!("Invalid CA index: {}", e))?;
+        Ok(Ca {
+            dir: dir.to_path_buf(),
+            cert,
+            key,
+            index,
+        })
+    }
+
+    fn leaf_builder(&self, key: &PKey<Private>, common_name: &str) -> Result<X509Builder, String> {
+        let mut builder = new_builder(key, common_name, CERT_VALIDITY_DAYS).map_err(ssl_error)?;
+        builder
+            .set_issuer_name(self.cert.subject_name())
+            .map_err(ssl_error)?;
+        builder
+            .append_extension(
+                BasicConstraints::new()
+                    .critical()
+                    .build()
+                    .map_err(ssl_error)?,
+            )
+            .map_err(ssl_error)?;
+        let mut key_usage = KeyUsage::new();
+        key_usage.critical().digital_signature();
+        // Only RSA keys encrypt key material; EC and Ed25519 keys just sign.
+        if key.id() == Id::RSA {
+            key_usage.key_encipherment();
+        }
+        builder
+            .append_extension(key_usage.build().map_err(ssl_error)?)
+            .map_err(ssl_error)?;
+        let authority_key_id = AuthorityKeyIdentifier::new()
+            .keyid(false)
+            .build(&builder.x509v3_context(Some(&self.cert), None))
+            .map_err(ssl_error)?;
+        builder
+            .append_extension(authority_key_id)
+            .map_err(ssl_error)?;
+        Ok(builder)
+    }
+
+    // Signs the certificate, writes it with its key below `servers/` or `clients/`,
+    // and records it in the index.
+    fn sign_and_write(
+        &mut self,
+        mut builder: X509Builder,
+        key: &PKey<Private>,
+        kind: CertKind,
+        name: &str,
+    ) -> Result<(PathBuf, PathBuf), String> {
+        builder
+            .sign(&self.key, signing_digest(&self.key))
+            .map_err(ssl_error)?;
+        let cert = builder.build();
+
+        let (cert_path, key_path) = issued_paths(&self.dir, kind, name);
+        let subdir = cert_path.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(subdir)
+            .map_err(|e| format!("Cannot create '{}': {}", subdir.display(), e))?;
+        if key_path.exists() {
+            return Err(format!(
+                "'{}' already exists; revoke its certificate to issue a new one.",
+                key_path.display()
+            ));
+        }
+        write_private_key(&key_path, key, false)?;
+        write_file(&cert_path, &cert.to_pem().map_err(ssl_error)?)?;
+
+        let serial = cert
+            .serial_number()
+            .to_bn()
+            .and_then(|bn| bn.to_hex_str().map(|hex| hex.to_string()))
+            .map_err(ssl_error)?;
+        let fingerprint = cert.digest(MessageDigest::sha256()).map_err(ssl_error)?;
+        self.index.certs.push(IssuedCert {
+            serial: serial.clone(),
+            kind,
+            name: name.to_string(),
+            fingerprint: hex::encode(fingerprint),
+            not_after: cert.not_after().to_string(),
+            revoked_at: None,
+        });
+        self.save()?;
+        println!(
+            "> Serial {}, fingerprint {}",
+            serial,
+            hex::encode(fingerprint)
+        );
+        Ok((cert_path, key_path))
+    }
+
+    // Writes the index and a freshly signed CRL listing every revoked certificate.
+    fn save(&mut self) -> Result<(), String> {
+        self.index.crl_number += 1;
+        let crl = self.build_crl()?;
+        write_file(&self.dir.join(CRL), &crl.to_pem().map_err(ssl_error)?)?;
+        let index = serde_json::to_vec_pretty(&self.index).map_err(|e| e.to_string())?;
+        write_file(&self.dir.join(INDEX), &index)
+    }
+
+    // The openssl crate can parse CRLs but not build them, hence the raw calls.
+    fn build_crl(&self) -> Result<X509Crl, String> {
+        let this_update = Asn1Time::days_from_now(0).map_err(ssl_error)?;
+        let next_update = Asn1Time::days_from_now(CRL_VALIDITY_DAYS).map_err(ssl_error)?;
+        let crl_number = asn1_integer(&self.index.crl_number.to_string(), false)?;
+        let mut revoked = Vec::new();
+        for cert in &self.index.certs {
+            if let Some(at) = cert.revoked_at {
+                revoked.push((
+                    asn1_integer(&cert.serial, true)?,
+                    Asn1Time::from_unix(at as libc::time_t).map_err(ssl_error)?,
+                ));
+            }
+        }
+
+        let der = unsafe {
+            let crl = openssl_sys::X509_CRL_new();
+            if crl.is_null() {
+                return Err("Failed to allocate CRL.".to_string());
+            }
+            let mut ok = openssl_sys::X509_CRL_set_version(crl, 1) == 1
+                && openssl_sys::X509_CRL_set_issuer_name(crl, self.cert.subject_name().as_ptr())
+                    == 1
+                && openssl_sys::X509_CRL_set1_lastUpdate(crl, this_update.as_ptr()) == 1
+                && openssl_sys::X509_CRL_set1_nextUpdate(crl, next_update.as_ptr()) == 1;
+            for (serial, date) in &revoked {
+                if !ok {
+                    break;
+                }
+                let entry = openssl_sys::X509_REVOKED_new();
+                ok = !entry.is_null()
+                    && openssl_sys::X509_REVOKED_set_serialNumber(entry, serial.as_ptr()) == 1
+                    && openssl_sys::X509_REVOKED_set_revocationDate(entry, date.as_ptr()) == 1;
+                // The CRL owns the entry once added.
+                if !ok || openssl_sys::X509_CRL_add0_revoked(crl, entry) != 1 {
+                    openssl_sys::X509_REVOKED_free(entry);
+                    ok = false;
+                }
+            }
+            ok = ok
+                && openssl_sys::X509_CRL_add1_ext_i2d(
+                    crl,
+                    openssl_sys::NID_crl_number,
+                    crl_number.as_ptr() as *mut c_void,
+                    0,
+                    0,
+                ) == 1
+                && openssl_sys::X509_CRL_sort(crl) == 1
+                && openssl_sys::X509_CRL_sign(
+                    crl,
+                    self.key.as_ptr(),
+                    signing_digest(&self.key).as_ptr(),
+                ) > 0;
+            let mut der = Vec::new();
+            if ok {
+                let len = openssl_sys::i2d_X509_CRL(crl, std::ptr::null_mut());
+                if len > 0 {
+                    der.resize(len as usize, 0);
+                    let mut out = der.as_mut_ptr();
+                    openssl_sys::i2d_X509_CRL(crl, &mut out);
+                }
+            }
+            openssl_sys::X509_CRL_free(crl);
+            der
+        };
+        if der.is_empty() {
+            return Err(format!(
+                "Failed to sign CRL: {}",
+                openssl::error::ErrorStack::get()
+            ));
+        }
+        X509Crl::from_der(&der).map_err(ssl_error)
+    }
+}
+
+fn subject_name(common_name: &str) -> Result<openssl::x509::X509Name, openssl::error::ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("O", "anchr")?;
+    name.append_entry_by_text("CN", common_name)?;
+    Ok(name.build())
+}
+
+// Builder with subject, key, validity and a random serial; the caller adds the rest.
+fn new_builder(
+    key: &PKey<Private>,
+    common_name: &str,
+    days: u32,
+) -> Result<X509Builder, openssl::error::ErrorStack> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let name = subject_name(common_name)?;
+    builder.set_subject_name(&name)?;
+    builder.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    builder.set_not_before(&not_before)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+    builder.set_not_after(&not_after)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    Ok(builder)
+}
+
+fn asn1_integer(value: &str, hex: bool) -> Result<Asn1Integer, String> {
+    let bn = if hex {
+        BigNum::from_hex_str(value)
+    } else {
+        BigNum::from_dec_str(value)
+    };
+    bn.and_then(|bn| bn.to_asn1_integer()).map_err(ssl_error)
+}
+
+fn write_file(path: &Path, content: &[u8]) -> Result<(), String> {
+    fs::write(path, content).map_err(|e| format!("Cannot write '{}': {}", path.display(), e))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn ssl_error(e: openssl::error::ErrorStack) -> String {
+    format!("OpenSSL error: {}", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn issued(dir: &Path, name: &str) -> Vec<IssuedCert> {
+        let ca = Ca::load(dir).unwrap();
+        ca.index
+            .certs
+            .into_iter()
+            .filter(|cert| cert.name == name)
+            .collect()
+    }
+
+    #[test]
+    fn names_are_issued_once_until_revoked() {
+        let dir = TempDir::new().unwrap();
+        init(dir.path()).unwrap();
+        issue_client(dir.path(), "alice").unwrap();
+        let error = issue_client(dir.path(), "alice").unwrap_err();
+        assert!(error.contains("already exists"), "{}", error);
+        assert_eq!(issued(dir.path(), "alice").len(), 1);
+    }
+
+    #[test]
+    fn revoked_names_can_be_issued_again() {
+        let dir = TempDir::new().unwrap();
+        init(dir.path()).unwrap();
+        issue_client(dir.path(), "alice").unwrap();
+        let first = issued(dir.path(), "alice").remove(0);
+        revoke(dir.path(), &first.serial).unwrap();
+
+        let revoked_dir = dir.path().join("clients").join(REVOKED_DIR);
+        let stem = format!("alice-{}", first.serial);
+        assert!(revoked_dir.join(format!("{}.pem", stem)).exists());
+        assert!(revoked_dir.join(format!("{}.key", stem)).exists());
+
+        issue_client(dir.path(), "alice").unwrap();
+        let certs = issued(dir.path(), "alice");
+        assert_eq!(certs.len(), 2);
+        assert!(certs[0].revoked_at.is_some());
+        assert!(certs[1].revoked_at.is_none());
+        let (cert_path, _) = issued_paths(dir.path(), CertKind::Client, "alice");
+        let cert = X509::from_pem(&fs::read(cert_path).unwrap()).unwrap();
+        let fingerprint = hex::encode(cert.digest(MessageDigest::sha256()).unwrap());
+        assert_eq!(fingerprint, certs[1].fingerprint);
+
+        // The CRL still lists the first certificate.
+        let crl = X509Crl::from_pem(&fs::read(dir.path().join(CRL)).unwrap()).unwrap();
+        let listed = crl.get_revoked().map_or(0, |revoked| revoked.len());
+        assert_eq!(listed, 1);
+    }
+
+    #[test]
+    fn revoking_an_older_certificate_leaves_the_current_pair() {
+        let dir = TempDir::new().unwrap();
+        init(dir.path()).unwrap();
+        issue_server(dir.path(), "192.0.2.1").unwrap();
+        let (cert_path, key_path) = issued_paths(dir.path(), CertKind::Server, "192.0.2.1");
+        // Removed by hand rather than revoked, then issued again.
+        fs::remove_file(&cert_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        issue_server(dir.path(), "192.0.2.1").unwrap();
+        let current = fs::read(&cert_path).unwrap();
+
+        let first = issued(dir.path(), "192.0.2.1").remove(0);
+        revoke(dir.path(), &first.serial).unwrap();
+        assert_eq!(fs::read(&cert_path).unwrap(), current);
+        assert!(key_path.exists());
+        assert!(!cert_path.with_file_name(REVOKED_DIR).exists());
+    }
+}
@@ -2,66 +2,245 @@
 
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private};
 use openssl::rsa::Rsa;
 use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
 use openssl::x509::{X509Builder, X509NameBuilder};
-use std::fs::File;
+use std::fs::{self, OpenOptions};
 use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 
-pub fn generate_certificate(cert_path: &str, key_path: &str, ip_address: &str) {
-    let rsa = Rsa::generate(2048).unwrap();
-    let pkey = PKey::from_rsa(rsa).unwrap();
+/// Key algorithm of generated certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyType {
+    Rsa,
+    #[default]
+    EcdsaP256,
+    Ed25519,
+}
+
+impl KeyType {
+    pub fn parse(name: &str) -> Option<KeyType> {
+        match name.to_ascii_lowercase().as_str() {
+            "rsa" | "rsa2048" => Some(KeyType::Rsa),
+            "ecdsa" | "ecdsa-p256" | "p256" => Some(KeyType::EcdsaP256),
+            "ed25519" => Some(KeyType::Ed25519),
+            _ => None,
+        }
+    }
+
+    pub fn generate(self) -> Result<PKey<Private>, ErrorStack> {
+        match self {
+            KeyType::Rsa => PKey::from_rsa(Rsa::generate(2048)?),
+            KeyType::EcdsaP256 => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)
+            }
+            KeyType::Ed25519 => PKey::generate_ed25519(),
+        }
+    }
+}
+
+/// Digest to sign with `key`; Ed25519 hashes internally and takes none.
+pub fn signing_digest(key: &PKey<Private>) -> MessageDigest {
+    if key.id() == Id::ED25519 {
+        MessageDigest::null()
+    } else {
+        MessageDigest::sha256()
+    }
+}
 
-    let mut name = X509NameBuilder::new().unwrap();
-    name.append_entry_by_text("C", "CN").unwrap();
-    name.append_entry_by_text("ST", "GD").unwrap();
-    name.append_entry_by_text("L", "SZ").unwrap();
-    name.append_entry_by_text("O", "Acme, Inc.").unwrap();
-    name.append_entry_by_text("CN", "localhost").unwrap();
+/// What `generate_certificate` produces; `anchr cert gen` fills it from its flags.
+#[derive(Debug, Clone)]
+pub struct CertOptions {
+    pub key_type: KeyType,
+    pub days: u32,
+    pub dns_names: Vec<String>,
+    pub ip_addresses: Vec<IpAddr>,
+    pub common_name: String,
+    pub organization: Option<String>,
+    pub country: Option<String>,
+    pub state: Option<String>,
+    pub locality: Option<String>,
+    pub cert_path: String,
+    pub key_path: String,
+    // Replace an existing key instead of refusing.
+    pub force: bool,
+}
+
+impl Default for CertOptions {
+    fn default() -> Self {
+        CertOptions {
+            key_type: KeyType::default(),
+            days: 365,
+            dns_names: vec!["localhost".to_string(), "*.localhost".to_string()],
+            ip_addresses: vec![[127, 0, 0, 1].into(), std::net::Ipv6Addr::LOCALHOST.into()],
+            common_name: "localhost".to_string(),
+            organization: None,
+            country: None,
+            state: None,
+            locality: None,
+            cert_path: "cert.crt".to_string(),
+            key_path: "cert.key".to_string(),
+            force: false,
+        }
+    }
+}
+
+pub const GEN_CERT_USAGE: &str = "Usage: anchr cert gen [--key-type rsa|ecdsa-p256|ed25519] \
+[--days <n>] [--dns <name>]... [--ip <address>]... [--cn <name>] [--org <name>] \
+[--country <code>] [--state <name>] [--locality <name>] [--cert <path>] [--key <path>] [--force]";
+
+impl CertOptions {
+    /// Parses the flags of `anchr cert gen`. Any --dns or --ip replaces the default
+    /// localhost names; the common name defaults to the first DNS name.
+    pub fn from_args(args: &[String]) -> Result<CertOptions, String> {
+        let mut opts = CertOptions::default();
+        let mut dns_names = Vec::new();
+        let mut ip_addresses = Vec::new();
+        let mut common_name = None;
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            if flag == "--force" {
+                opts.force = true;
+                continue;
+            }
+            let value = iter
+                .next()
+                .ok_or_else(|| format!("{} needs a value.\n{}", flag, GEN_CERT_USAGE))?
+                .clone();
+            match flag.as_str() {
+                "--key-type" => {
+                    opts.key_type = KeyType::parse(&value)
+                        .ok_or_else(|| format!("Unknown key type '{}'.", value))?;
+                }
+                "--days" => {
+                    opts.days = value
+                        .parse()
+                        .ok()
+                        .filter(|days| *days > 0)
+                        .ok_or_else(|| format!("Invalid validity '{}'.", value))?;
+                }
+                "--dns" => dns_names.push(value),
+                "--ip" => ip_addresses.push(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid IP address '{}'.", value))?,
+                ),
+                "--cn" => common_name = Some(value),
+                "--org" => opts.organization = Some(value),
+                "--country" => opts.country = Some(value),
+                "--state" => opts.state = Some(value),
+                "--locality" => opts.locality = Some(value),
+                "--cert" => opts.cert_path = value,
+                "--key" => opts.key_path = value,
+                _ => return Err(format!("Unknown option '{}'.\n{}", flag, GEN_CERT_USAGE)),
+            }
+        }
+        if !dns_names.is_empty() || !ip_addresses.is_empty() {
+            opts.dns_names = dns_names;
+            opts.ip_addresses = ip_addresses;
+        }
+        opts.common_name = common_name
+            .or_else(|| opts.dns_names.first().cloned())
+            .or_else(|| opts.ip_addresses.first().map(|ip| ip.to_string()))
+            .unwrap_or(opts.common_name);
+        Ok(opts)
+    }
+}
+
+pub fn generate_certificate(opts: &CertOptions) -> Result<(), String> {
+    // Check up front so a refused key does not leave a certificate for another key behind.
+    if !opts.force && Path::new(&opts.key_path).exists() {
+        return Err(format!(
+            "'{}' already exists; use --force to replace it.",
+            opts.key_path
+        ));
+    }
+    let pkey = opts.key_type.generate().map_err(ssl_error)?;
+    let cert = build_certificate(opts, &pkey).map_err(ssl_error)?;
+
+    write_private_key(Path::new(&opts.key_path), &pkey, opts.force)?;
+    fs::write(&opts.cert_path, cert)
+        .map_err(|e| format!("Cannot write '{}': {}", opts.cert_path, e))
+}
+
+fn build_certificate(opts: &CertOptions, pkey: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    let fields = [
+        ("C", &opts.country),
+        ("ST", &opts.state),
+        ("L", &opts.locality),
+        ("O", &opts.organization),
+    ];
+    for (field, value) in fields {
+        if let Some(value) = value {
+            name.append_entry_by_text(field, value)?;
+        }
+    }
+    name.append_entry_by_text("CN", &opts.common_name)?;
     let name = name.build();
 
-    let mut builder = X509Builder::new().unwrap();
-    builder.set_version(2).unwrap();
-    builder.set_subject_name(&name).unwrap();
-    builder.set_issuer_name(&name).unwrap();
-    builder.set_pubkey(&pkey).unwrap();
-    builder
-        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
-        .unwrap();
-    builder
-        .set_not_after(&Asn1Time::days_from_now(365).unwrap())
-        .unwrap();
-
-    let mut serial = BigNum::new().unwrap();
-    serial.rand(159, MsbOption::MAYBE_ZERO, false).unwrap();
-    builder
-        .set_serial_number(&serial.to_asn1_integer().unwrap())
-        .unwrap();
-
-    let basic_constraints = BasicConstraints::new().critical().build().unwrap();
-    builder.append_extension(basic_constraints).unwrap();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(pkey)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    builder.set_not_before(&not_before)?;
+    let not_after = Asn1Time::days_from_now(opts.days)?;
+    builder.set_not_after(&not_after)?;
+
+    let mut serial = BigNum::new()?;
+    serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+
+    let basic_constraints = BasicConstraints::new().critical().build()?;
+    builder.append_extension(basic_constraints)?;
 
     // Subject Alternative Name
-    let subject_alternative_name = SubjectAlternativeName::new()
-        .dns("localhost")
-        .dns("*.localhost")
-        .ip("127.0.0.1")
-        .ip("::1")
-        .ip(ip_address) // Add the dynamically selected IP here.
-        .build(&builder.x509v3_context(None, None))
-        .unwrap();
-    builder.append_extension(subject_alternative_name).unwrap();
-
-    builder.sign(&pkey, MessageDigest::sha256()).unwrap();
-    let cert = builder.build();
-
-    let mut cert_file = File::create(cert_path).unwrap();
-    cert_file.write_all(&cert.to_pem().unwrap()).unwrap();
-
-    let mut key_file = File::create(key_path).unwrap();
-    key_file
-        .write_all(&pkey.private_key_to_pem_pkcs8().unwrap())
-        .unwrap();
+    if !opts.dns_names.is_empty() || !opts.ip_addresses.is_empty() {
+        let mut san = SubjectAlternativeName::new();
+        for dns_name in &opts.dns_names {
+            san.dns(dns_name);
+        }
+        for ip in &opts.ip_addresses {
+            san.ip(&ip.to_string());
+        }
+        let san = san.build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(san)?;
+    }
+
+    builder.sign(pkey, signing_digest(pkey))?;
+    builder.build().to_pem()
+}
+
+/// Writes a PKCS#8 key readable by the owner only; an existing file is kept unless `force`.
+pub fn write_private_key(path: &Path, key: &PKey<Private>, force: bool) -> Result<(), String> {
+    let pem = key.private_key_to_pem_pkcs8().map_err(ssl_error)?;
+    let mut options = OpenOptions::new();
+    options.write(true).mode(0o600);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let write = || -> std::io::Result<()> {
+        let mut file = options.open(path)?;
+        // mode() only applies to new files.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(&pem)
+    };
+    write().map_err(|e| format!("Cannot write '{}': {}", path.display(), e))
+}
+
+fn ssl_error(e: ErrorStack) -> String {
+    format!("OpenSSL error: {}", e)
 }
@@ -1,9 +1,16 @@
 /* src/setup/check.rs */
 
-use super::config::{Config, RfsConfig};
+use super::config::{Config, RfsConfig, UserConfig, VolumeKind};
+use crate::quic::acl;
+use crate::quic::auth::TokenHash;
+use crate::quic::tls;
+use crate::rfs::volume;
+use ipnetwork::IpNetwork;
 use regex::Regex;
 use std::collections::HashSet;
 use std::fs;
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use uuid::Uuid;
 
@@ -11,6 +18,12 @@ use uuid::Uuid;
 pub fn validate_server_config(config: &Config) -> Result<(), String> {
     println!("> Performing server configuration checks...");
 
+    validate_auth(config)?;
+    validate_client_certs(config)?;
+    validate_access_lists(config)?;
+    validate_audit(config)?;
+    validate_users(config)?;
+
     if let Some(rfs_list) = &config.rfs {
         if rfs_list.is_empty() {
             return Err(
@@ -19,7 +32,13 @@ pub fn validate_server_config(config: &Config) -> Result<(), String> {
             );
         }
         validate_rfs_dev_names(rfs_list)?;
-        validate_rfs_bind_paths(rfs_list)?;
+        let (dir_volumes, block_volumes): (Vec<RfsConfig>, Vec<RfsConfig>) = rfs_list
+            .iter()
+            .cloned()
+            .partition(|r| r.kind == VolumeKind::Directory);
+        validate_rfs_bindings(&dir_volumes)?;
+        validate_rfs_bind_paths(&dir_volumes)?;
+        validate_rfs_block_devices(&block_volumes)?;
     } else {
         return Err(
             "Configuration error: The 'rfs' table is missing, which is required for server mode."
@@ -31,6 +50,179 @@ pub fn validate_server_config(config: &Config) -> Result<(), String> {
     Ok(())
 }
 
+// Client entry point: the server address must resolve and the TLS setup must load
+pub fn validate_client_config(config: &Config) -> Result<(), String> {
+    println!("> Performing client configuration checks...");
+
+    let address = format!("{}:{}", config.network.address, config.network.port);
+    if !address.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()) {
+        return Err(format!(
+            "Configuration error: Server address '{}' does not resolve.",
+            address
+        ));
+    }
+    tls::build_client_config(config)
+        .map_err(|e| format!("Configuration error: TLS setup failed: {}", e))?;
+
+    println!("+ Configuration checks passed successfully.");
+    Ok(())
+}
+
+// The server needs the token or its hash, and a hash must be usable
+fn validate_auth(config: &Config) -> Result<(), String> {
+    match &config.setup.auth_token_hash {
+        Some(encoded) => {
+            if !config.setup.auth_token.is_empty() {
+                println!("! Both auth_token and auth_token_hash are set; auth_token is ignored.");
+            }
+            TokenHash::parse(encoded)
+                .map(|_| ())
+                .map_err(|e| format!("Configuration error: Invalid auth_token_hash: {}", e))
+        }
+        None if config.setup.auth_token.is_empty() && config.users.is_empty() => Err(
+            "Configuration error: Set auth_token, auth_token_hash or at least one [[users]] entry for server mode."
+                .to_string(),
+        ),
+        None => Ok(()),
+    }
+}
+
+// Client certificates need a loadable CA, and turning token auth off needs certificates
+fn validate_client_certs(config: &Config) -> Result<(), String> {
+    let setup = &config.setup;
+    match &setup.client_ca {
+        Some(ca_path) => {
+            tls::load_certs(ca_path).map_err(|e| format!("Configuration error: client_ca: {}", e))?;
+        }
+        None if setup.require_client_cert => {
+            return Err(
+                "Configuration error: require_client_cert needs client_ca to be set.".to_string(),
+            );
+        }
+        None if !setup.token_auth => {
+            return Err(
+                "Configuration error: token_auth = false needs client_ca to be set.".to_string(),
+            );
+        }
+        None => {}
+    }
+    if let Some(crl_path) = &setup.client_crl {
+        if setup.client_ca.is_none() {
+            return Err("Configuration error: client_crl needs client_ca to be set.".to_string());
+        }
+        tls::load_crls(crl_path).map_err(|e| format!("Configuration error: client_crl: {}", e))?;
+    }
+    if setup.client_ca.is_some() && !config.users.iter().any(has_cert_mapping) {
+        println!("! client_ca is set but no [[users]] entry has cert_subject or cert_fingerprint.");
+    }
+    Ok(())
+}
+
+// allow/deny entries must parse, and the auth limits must leave room to connect
+fn validate_access_lists(config: &Config) -> Result<(), String> {
+    let network = &config.network;
+    for (list, entries) in [("allow", &network.allow), ("deny", &network.deny)] {
+        for entry in entries {
+            entry.parse::<IpNetwork>().map_err(|e| {
+                format!("Configuration error: Invalid {} entry '{}': {}", list, entry, e)
+            })?;
+        }
+    }
+    if network.max_unauthenticated == 0 || network.auth_timeout_secs == 0 {
+        return Err(
+            "Configuration error: max_unauthenticated and auth_timeout_secs must be at least 1."
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+fn validate_audit(config: &Config) -> Result<(), String> {
+    let audit = &config.audit;
+    if !audit.enabled {
+        println!("! The audit log is disabled; operations are not recorded.");
+        return Ok(());
+    }
+    if audit.path.is_empty() || audit.max_size_mib == 0 {
+        return Err(
+            "Configuration error: audit.path must be set and audit.max_size_mib at least 1."
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+fn has_cert_mapping(user: &UserConfig) -> bool {
+    user.cert_subject.is_some() || user.cert_fingerprint.is_some()
+}
+
+// Users need a unique name, a usable credential and permissions on existing volumes
+fn validate_users(config: &Config) -> Result<(), String> {
+    let re = Regex::new(r"^[a-zA-Z0-9_.-]+$").unwrap();
+    let dev_names: HashSet<&str> = config
+        .rfs
+        .iter()
+        .flatten()
+        .map(|r| r.dev_name.as_str())
+        .collect();
+    let mut seen = HashSet::new();
+    for user in &config.users {
+        if !re.is_match(&user.name) {
+            return Err(format!(
+                "Configuration error: User name '{}' contains invalid characters. Only a-z, A-Z, 0-9, _, ., - are allowed.",
+                user.name
+            ));
+        }
+        if user.name == acl::DEFAULT_USER {
+            return Err(format!(
+                "Configuration error: User name '{}' is reserved for the [setup] auth_token.",
+                acl::DEFAULT_USER
+            ));
+        }
+        if !seen.insert(user.name.as_str()) {
+            return Err(format!(
+                "Configuration error: User '{}' is defined more than once.",
+                user.name
+            ));
+        }
+        match &user.auth_token_hash {
+            Some(encoded) => {
+                TokenHash::parse(encoded).map_err(|e| {
+                    format!(
+                        "Configuration error: Invalid auth_token_hash for user '{}': {}",
+                        user.name, e
+                    )
+                })?;
+            }
+            None if user.auth_token.is_empty() && !has_cert_mapping(user) => {
+                return Err(format!(
+                    "Configuration error: User '{}' needs an auth_token, auth_token_hash or certificate mapping.",
+                    user.name
+                ));
+            }
+            None => {}
+        }
+        if let Some(fingerprint) = &user.cert_fingerprint {
+            let normalized = acl::normalize_fingerprint(fingerprint);
+            if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!(
+                    "Configuration error: cert_fingerprint of user '{}' must be a SHA-256 hex digest.",
+                    user.name
+                ));
+            }
+        }
+        for dev_name in user.volumes.keys() {
+            if dev_name != acl::ALL_VOLUMES && !dev_names.contains(dev_name.as_str()) {
+                return Err(format!(
+                    "Configuration error: User '{}' has permissions on unknown dev_name '{}'.",
+                    user.name, dev_name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 // dev_name must be valid format
 fn validate_rfs_dev_names(rfs_list: &[RfsConfig]) -> Result<(), String> {
     let re = Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap();
@@ -45,6 +237,65 @@ fn validate_rfs_dev_names(rfs_list: &[RfsConfig]) -> Result<(), String> {
     Ok(())
 }
 
+// uuid/label-bound volumes must have their device mounted at bind_path
+fn validate_rfs_bindings(rfs_list: &[RfsConfig]) -> Result<(), String> {
+    let bound: Vec<&RfsConfig> = rfs_list
+        .iter()
+        .filter(|r| r.uuid.is_some() || r.label.is_some())
+        .collect();
+    if bound.is_empty() {
+        return Ok(());
+    }
+    let mounts = volume::read_mountinfo().map_err(|e| format!("Configuration error: {}", e))?;
+    for rfs_config in bound {
+        if rfs_config.uuid.is_some() && rfs_config.label.is_some() {
+            return Err(format!(
+                "Configuration error: dev_name '{}' sets both uuid and label. Use only one.",
+                rfs_config.dev_name
+            ));
+        }
+        volume::verify_binding(rfs_config, &mounts).map_err(|e| {
+            format!(
+                "Configuration error: Refusing to serve dev_name '{}': {}",
+                rfs_config.dev_name, e
+            )
+        })?;
+        println!(
+            "+ dev_name '{}' is backed by its expected device.",
+            rfs_config.dev_name
+        );
+    }
+    Ok(())
+}
+
+// block volumes must point at an existing block device or image file
+fn validate_rfs_block_devices(rfs_list: &[RfsConfig]) -> Result<(), String> {
+    for rfs_config in rfs_list {
+        let device = rfs_config.device.as_deref().ok_or_else(|| {
+            format!(
+                "Configuration error: block volume '{}' is missing 'device'.",
+                rfs_config.dev_name
+            )
+        })?;
+        match fs::metadata(device) {
+            Ok(meta) if meta.file_type().is_block_device() || meta.is_file() => {}
+            Ok(_) => {
+                return Err(format!(
+                    "Configuration error: device '{}' for dev_name '{}' is not a block device or image file.",
+                    device, rfs_config.dev_name
+                ));
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Configuration error: device '{}' for dev_name '{}' is not accessible: {}",
+                    device, rfs_config.dev_name, e
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 // bind_path must be unique and writable
 fn validate_rfs_bind_paths(rfs_list: &[RfsConfig]) -> Result<(), String> {
     let mut seen_paths = HashSet::new();
@@ -1,21 +1,147 @@
 /* src/setup/config.rs */
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeKind {
+    #[default]
+    Directory,
+    // Raw block device or image file, only reachable through privileged image/NBD access.
+    Block,
+}
+
+/// What a user may do on a volume. `admin` implies every other permission and adds
+/// raw block access, server-side paths and scrub status.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    List,
+    Read,
+    Write,
+    Delete,
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Permission::List => "list",
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Delete => "delete",
+            Permission::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    #[serde(default)]
+    pub auth_token: String,
+    #[serde(default)]
+    pub auth_token_hash: Option<String>,
+    // Client certificates that authenticate as this user, by subject common name
+    // or by SHA-256 fingerprint of the DER certificate.
+    #[serde(default)]
+    pub cert_subject: Option<String>,
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
+    // Permissions per dev_name; "*" applies to every volume.
+    #[serde(default)]
+    pub volumes: HashMap<String, Vec<Permission>>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RfsConfig {
     pub dev_name: String,
+    #[serde(default)]
+    pub kind: VolumeKind,
+    #[serde(default)]
     pub bind_path: String,
+    // Filesystem UUID or label expected to be mounted at bind_path.
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    // Device node or image file backing a `block` volume.
+    pub device: Option<String>,
+    // Refuse raw writes (image push, NBD writes) to a `block` volume.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_unauthenticated() -> usize {
+    64
+}
+
+fn default_auth_timeout_secs() -> u64 {
+    10
+}
+
+fn default_resume_secs() -> u64 {
+    300
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SetupConfig {
     pub mode: String,
+    // Server: its certificate. Client: trust root for the server, optional when the
+    // server is pinned by server_cert_sha256 or trusted on first use.
+    #[serde(default)]
     pub certificate: String,
+    #[serde(default)]
     pub private_key: String,
+    // Shared secret; clients prove they know it without sending it. On the server it
+    // authenticates the "default" user, who may do everything on every volume.
+    #[serde(default)]
     pub auth_token: String,
+    // Server only: salted hash from `anchr hash-token`, stored instead of auth_token.
+    #[serde(default)]
+    pub auth_token_hash: Option<String>,
     pub log_level: String,
+    // Grants the default user admin rights: server-side paths in `rfs list` and raw image access.
+    #[serde(default)]
+    pub admin: bool,
+    // Client only: user to authenticate as; unset means the server's default user.
+    #[serde(default)]
+    pub user: Option<String>,
+    // Server only: CA whose client certificates are verified and mapped to [[users]].
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    // Server only: CRL of client_ca (`anchr ca` keeps one); revoked certificates are refused.
+    #[serde(default)]
+    pub client_crl: Option<String>,
+    // Server only: refuse TLS handshakes without a client certificate signed by client_ca.
+    #[serde(default)]
+    pub require_client_cert: bool,
+    // Server only: allow token challenge-response; off leaves client certificates as the only way.
+    #[serde(default = "default_true")]
+    pub token_auth: bool,
+    // Client only: name the server certificate is issued for, sent as SNI; defaults to localhost.
+    #[serde(default)]
+    pub server_name: Option<String>,
+    // Client only: SHA-256 fingerprint of the server certificate, checked instead of `certificate`.
+    #[serde(default)]
+    pub server_cert_sha256: Option<String>,
+    // Client only: file of fingerprints trusted on first use when neither of the above is set.
+    #[serde(default)]
+    pub known_hosts: Option<String>,
+    // Client only: certificate and key presented to servers that verify clients.
+    #[serde(default)]
+    pub client_certificate: Option<String>,
+    #[serde(default)]
+    pub client_key: Option<String>,
+    // Client only: preferred encoding for structured payloads (json, bincode, msgpack, base64).
+    #[serde(default)]
+    pub encoding: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,6 +149,70 @@ pub struct NetworkConfig {
     pub listen: String,
     pub address: String,
     pub port: u16,
+    // Server only: source addresses or CIDR ranges that may connect; empty allows everyone.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    // Server only: source addresses or CIDR ranges that are refused, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // Server only: connections that may be handshaking or authenticating at once.
+    #[serde(default = "default_max_unauthenticated")]
+    pub max_unauthenticated: usize,
+    // Server only: seconds a client has to authenticate after connecting.
+    #[serde(default = "default_auth_timeout_secs")]
+    pub auth_timeout_secs: u64,
+    // Server only: seconds a dropped client may resume its session; 0 disables it.
+    #[serde(default = "default_resume_secs")]
+    pub resume_secs: u64,
+    // Accept (server) or send (client) 0-RTT data on resumed TLS sessions.
+    #[serde(default = "default_true")]
+    pub zero_rtt: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScrubConfig {
+    pub enabled: bool,
+    // Minimum time between two scrub passes over the same volume.
+    pub interval_secs: u64,
+    // Read throughput cap while re-hashing, in MiB/s.
+    pub rate_mib: u64,
+    // Flag mismatching files in the checksum index so `rfs list` reports them.
+    pub mark_corrupted: bool,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 7 * 24 * 3600,
+            rate_mib: 16,
+            mark_corrupted: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    // JSON-lines file connections, authentication and file operations are appended to.
+    pub path: String,
+    // Size at which the log is rotated, in MiB.
+    pub max_size_mib: u64,
+    // Rotated logs kept as `<path>.1` (newest) to `<path>.<keep>`.
+    pub keep: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "anchr-audit.log".to_string(),
+            max_size_mib: 64,
+            keep: 5,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -30,11 +220,19 @@ pub struct Config {
     pub setup: SetupConfig,
     pub network: NetworkConfig,
     pub rfs: Option<Vec<RfsConfig>>,
+    #[serde(default)]
+    pub scrub: ScrubConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    // Server only: named users with their own tokens and per-volume permissions.
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
 }
 
 impl Config {
-    pub fn from_file(path: &str) -> Self {
-        let content = fs::read_to_string(path).expect("Failed to read config file");
-        toml::from_str(&content).expect("Failed to parse config file")
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file '{}': {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format!("Cannot parse config file '{}': {}", path, e))
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 /* src/setup/gen_conf.rs */
 
-use super::cert::generate_certificate;
+use super::cert::{CertOptions, generate_certificate};
 use pnet::datalink;
 use std::fs::File;
 use std::io::{self, Write};
@@ -60,17 +60,31 @@ fn select_ip_address() -> String {
 }
 
 // Generates a default configuration file after prompting the user to select an IP address.
-pub fn generate_default_config<P: AsRef<Path>>(path: P) {
+// An existing config, certificate or key is only replaced with `force`.
+pub fn generate_default_config<P: AsRef<Path>>(path: P, force: bool) -> Result<(), String> {
+    let path = path.as_ref();
+    if path.exists() && !force {
+        return Err(format!(
+            "'{}' already exists. Use 'anchr init --force' to replace it.",
+            path.display()
+        ));
+    }
     let selected_ip = select_ip_address();
-    let cert_path = "cert.crt";
-    let key_path = "cert.key";
+    let mut cert_opts = CertOptions {
+        force,
+        ..CertOptions::default()
+    };
+    cert_opts.ip_addresses.push(selected_ip.parse().unwrap());
+    let (cert_path, key_path) = (cert_opts.cert_path.clone(), cert_opts.key_path.clone());
 
     // Generate the certificate and key using the selected IP.
     println!(
         "> Generating certificate '{}' and key '{}' for IP address {}...",
         cert_path, key_path, selected_ip
     );
-    generate_certificate(cert_path, key_path, &selected_ip);
+    generate_certificate(&cert_opts).map_err(|e| {
+        format!("{}\n! Use 'anchr init --force' to replace the existing key.", e)
+    })?;
     println!("+ Certificate and key generated successfully.");
 
     let uuid = Uuid::new_v4();
@@ -80,12 +94,32 @@ mode = "server"
 certificate = "{}"
 private_key = "{}"
 auth_token = "{}"
+# Servers may store only a salted hash: run `anchr hash-token <token>`,
+# paste the printed auth_token_hash line here and remove auth_token.
 log_level = "info"
+admin = false
+# Mutual TLS: accept client certificates signed by this CA and map them to [[users]]
+# by cert_subject (common name) or cert_fingerprint (SHA-256). Clients present theirs
+# with client_certificate and client_key.
+# client_ca = "/etc/anchr/client-ca.pem"
+# client_crl = "/etc/anchr/crl.pem"
+# require_client_cert = false
+# token_auth = true
 
 [network]
 listen = "0.0.0.0"
 address = "{}"
 port = 33321
+# Refuse or restrict clients by source address or CIDR range; deny wins over allow.
+# allow = ["192.168.0.0/16", "fd00::/8"]
+# deny = ["192.168.1.13"]
+# Connections still authenticating at once, and seconds each has to authenticate.
+# max_unauthenticated = 64
+# auth_timeout_secs = 10
+# Seconds a dropped client may resume its session without authenticating again (0 disables),
+# and whether resumed TLS sessions may carry 0-RTT data.
+# resume_secs = 300
+# zero_rtt = true
 
 [[rfs]]
 dev_name = "ipel_disk_1"
@@ -94,11 +128,51 @@ bind_path = "/path/to/your/volume/folder1"
 [[rfs]]
 dev_name = "ipel_disk_2"
 bind_path = "/path/to/your/volume/folder2"
+# Optionally bind to a partition; the volume is refused unless it is mounted at bind_path.
+# uuid = "0a1b2c3d-..."
+# label = "data"
+
+# Raw partitions or image files for `rfs image` and `rfs nbd`; requires admin permission.
+# [[rfs]]
+# dev_name = "ipel_raw_1"
+# kind = "block"
+# device = "/dev/sdb1"
+# read_only = false
+
+# Background re-hashing of uploaded files to detect bit rot.
+# [scrub]
+# enabled = true
+# interval_secs = 604800
+# rate_mib = 16
+# mark_corrupted = false
+
+# Append-only JSON-lines record of connections, logins and file operations.
+# Query it with 'anchr audit'.
+# [audit]
+# enabled = true
+# path = "anchr-audit.log"
+# max_size_mib = 64
+# keep = 5
+
+# Named users with their own token and per-volume permissions: list, read, write,
+# delete, admin. Clients pick one with `user = "..."` in [setup]; "*" means every volume.
+# The [setup] auth_token authenticates the "default" user with full access.
+# [[users]]
+# name = "backup"
+# auth_token_hash = "scram-sha256$..."
+# cert_subject = "backup.example.org"
+# cert_fingerprint = "3f:a9:..."
+# [users.volumes]
+# ipel_disk_1 = ["list", "read", "write"]
+# "*" = ["list"]
 "#,
         cert_path, key_path, uuid, selected_ip
     );
 
-    let mut file = File::create(path).unwrap();
-    file.write_all(content.as_bytes()).unwrap();
+    let mut file =
+        File::create(path).map_err(|e| format!("Cannot create '{}': {}", path.display(), e))?;
+    file.write_all(content.as_bytes())
+        .map_err(|e| format!("Cannot write '{}': {}", path.display(), e))?;
     println!("+ Default configuration file created successfully.");
+    Ok(())
 }
\ No newline at end of file
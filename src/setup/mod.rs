@@ -1,5 +1,7 @@
 /* src/setup/mod.rs */
 
+pub mod args;
+pub mod ca;
 pub mod cert;
 pub mod config;
 pub mod gen_conf;
@@ -0,0 +1,399 @@
+/* src/wsm/codec.rs */
+
+use crate::wsm::header::{
+    HEADER_V1_LEN, HEADER_V2_LEN, HEADER_V2_MARKER, PayloadType, RESERVED_FINAL_FLAG,
+    RESERVED_MORE_FLAG, WsmHeader,
+};
+use crate::wsm::hello::MAX_CHUNK_SIZE;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::payload;
+use bytes::{Buf, BufMut, BytesMut};
+use quinn::{RecvStream, SendStream};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+/// Largest single frame accepted on the control stream. Bigger replies are streamed.
+pub const MAX_CONTROL_PAYLOAD: u32 = 256 * 1024;
+/// Payload size of each frame when a reply is streamed.
+pub const STREAM_FRAME_SIZE: usize = 64 * 1024;
+/// Largest reply a client reassembles from streamed frames.
+pub const MAX_STREAMED_PAYLOAD: usize = 64 * 1024 * 1024;
+/// Largest payload accepted on transfer streams: one chunk plus its ID and hash.
+pub const MAX_TRANSFER_PAYLOAD: u32 = MAX_CHUNK_SIZE as u32 + 64;
+
+pub type WsmReader = FramedRead<RecvStream, WsmCodec>;
+pub type WsmWriter = FramedWrite<SendStream, WsmCodec>;
+
+/// One complete, validated WSM message: the decoded header plus its whole payload.
+#[derive(Debug, Clone)]
+pub struct WsmMessage {
+    pub opcode: Opcode,
+    pub message_id: u32,
+    pub payload_type: PayloadType,
+    pub reserved: u8,
+    /// Framing used on the wire, see `WsmHeader::version`.
+    pub version: u8,
+    pub payload: Vec<u8>,
+}
+
+impl WsmMessage {
+    pub fn new(
+        opcode: Opcode,
+        message_id: u32,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Self {
+        WsmMessage {
+            opcode,
+            message_id,
+            payload_type,
+            reserved: 0,
+            version: 2,
+            payload,
+        }
+    }
+
+    /// A message without payload.
+    pub fn empty(opcode: Opcode, message_id: u32) -> Self {
+        Self::new(opcode, message_id, PayloadType::Raw, Vec::new())
+    }
+
+    /// A message carrying `value` in the given structured encoding. Fails if the encoding
+    /// cannot represent it, e.g. non-string map keys in MsgPack or NaN in JSON.
+    pub fn encoded<T: Serialize>(
+        opcode: Opcode,
+        message_id: u32,
+        payload_type: PayloadType,
+        value: &T,
+    ) -> Result<Self, String> {
+        let payload = payload::encode(payload_type, value)
+            .map_err(|e| format!("Failed to encode {} payload: {}", opcode, e))?;
+        Ok(Self::new(opcode, message_id, payload_type, payload))
+    }
+
+    pub fn with_reserved(mut self, reserved: u8) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    /// Marks this message as the last one for its message ID.
+    pub fn with_final(self) -> Self {
+        self.with_reserved(RESERVED_FINAL_FLAG)
+    }
+
+    /// Switches to v1 framing, used for the handshake so older peers can still parse it.
+    /// The message ID is truncated to 8 bits.
+    pub fn v1(mut self) -> Self {
+        self.version = 1;
+        self
+    }
+
+    pub fn is_final(&self) -> bool {
+        self.reserved == RESERVED_FINAL_FLAG
+    }
+
+    /// Whether further frames of this message follow.
+    pub fn has_more(&self) -> bool {
+        self.reserved == RESERVED_MORE_FLAG
+    }
+
+    /// Splits the payload into frames of at most `frame_size` bytes. Every frame but
+    /// the last carries the continuation flag; the last keeps this message's flags.
+    pub fn into_frames(self, frame_size: usize) -> Vec<WsmMessage> {
+        if self.payload.len() <= frame_size {
+            return vec![self];
+        }
+        let chunks: Vec<&[u8]> = self.payload.chunks(frame_size).collect();
+        let last = chunks.len() - 1;
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| WsmMessage {
+                reserved: if i == last { self.reserved } else { RESERVED_MORE_FLAG },
+                payload: chunk.to_vec(),
+                ..self.without_payload()
+            })
+            .collect()
+    }
+
+    /// A copy of this message's header fields with an empty payload.
+    pub fn without_payload(&self) -> WsmMessage {
+        WsmMessage {
+            payload: Vec::new(),
+            ..*self
+        }
+    }
+
+    pub fn header(&self) -> WsmHeader {
+        WsmHeader {
+            opcode: self.opcode as u8,
+            message_id: self.message_id,
+            payload_type: self.payload_type as u8,
+            reserved: self.reserved,
+            payload_len: self.payload.len() as u32,
+            version: self.version,
+        }
+    }
+
+    /// Size of the encoded message, header included.
+    pub fn wire_len(&self) -> usize {
+        self.header().wire_len() + self.payload.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = self.header().to_bytes();
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// Decodes the payload according to its declared payload type.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, String> {
+        payload::decode(self.payload_type, &self.payload)
+    }
+
+    /// The payload as text, for error reasons and other human-readable replies.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.payload).to_string()
+    }
+}
+
+/// Frames WSM messages in either header version and refuses anything this build
+/// cannot handle before its payload is buffered.
+#[derive(Debug, Clone, Copy)]
+pub struct WsmCodec {
+    max_payload: u32,
+}
+
+impl WsmCodec {
+    pub fn control() -> Self {
+        WsmCodec {
+            max_payload: MAX_CONTROL_PAYLOAD,
+        }
+    }
+
+    pub fn transfer() -> Self {
+        WsmCodec {
+            max_payload: MAX_TRANSFER_PAYLOAD,
+        }
+    }
+
+    fn validate(&self, header: &WsmHeader) -> std::io::Result<(Opcode, PayloadType)> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let opcode = Opcode::try_from(header.opcode)
+            .map_err(|_| invalid(format!("unknown opcode {:#04X}", header.opcode)))?;
+        let payload_type = PayloadType::try_from(header.payload_type)
+            .map_err(|_| invalid(format!("unknown payload type {:#04X}", header.payload_type)))?;
+        if header.payload_len > self.max_payload {
+            return Err(invalid(format!(
+                "{} payload of {} bytes exceeds the {} byte limit",
+                opcode, header.payload_len, self.max_payload
+            )));
+        }
+        Ok((opcode, payload_type))
+    }
+
+    fn assemble(&self, header: WsmHeader, payload: Vec<u8>) -> std::io::Result<WsmMessage> {
+        let (opcode, payload_type) = self.validate(&header)?;
+        Ok(WsmMessage {
+            opcode,
+            message_id: header.message_id,
+            payload_type,
+            reserved: header.reserved,
+            version: header.version,
+            payload,
+        })
+    }
+
+    /// Reads exactly one message without buffering past it, for streams that
+    /// leave WSM framing after their first message.
+    pub async fn read_one<R: AsyncRead + Unpin>(
+        &self,
+        recv: &mut R,
+    ) -> std::io::Result<WsmMessage> {
+        let header = WsmHeader::read_from(recv).await?;
+        self.validate(&header)?;
+        let mut payload = vec![0; header.payload_len as usize];
+        recv.read_exact(&mut payload).await?;
+        self.assemble(header, payload)
+    }
+}
+
+impl Decoder for WsmCodec {
+    type Item = WsmMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<WsmMessage>, Self::Error> {
+        if src.len() < HEADER_V1_LEN {
+            return Ok(None);
+        }
+        let header = if src[0] == HEADER_V2_MARKER {
+            if src.len() < HEADER_V2_LEN {
+                return Ok(None);
+            }
+            WsmHeader::from_v2_bytes(src[..HEADER_V2_LEN].try_into().unwrap())
+        } else {
+            WsmHeader::from_bytes(src[..HEADER_V1_LEN].try_into().unwrap())
+        };
+        self.validate(&header)?;
+
+        let total = header.wire_len() + header.payload_len as usize;
+        if src.len() < total {
+            src.reserve(total - src.len());
+            return Ok(None);
+        }
+        src.advance(header.wire_len());
+        let payload = src.split_to(header.payload_len as usize).to_vec();
+        self.assemble(header, payload).map(Some)
+    }
+}
+
+impl Encoder<WsmMessage> for WsmCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, msg: WsmMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if msg.payload.len() > self.max_payload as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} payload of {} bytes exceeds the {} byte limit",
+                    msg.opcode,
+                    msg.payload.len(),
+                    self.max_payload
+                ),
+            ));
+        }
+        dst.reserve(msg.wire_len());
+        dst.put_slice(&msg.header().to_bytes());
+        dst.put_slice(&msg.payload);
+        Ok(())
+    }
+}
+
+/// Queues a reply for the sender task, streaming it in frames when it is large.
+/// Each frame waits for room in the channel, so a slow peer throttles the producer.
+pub async fn send_streamed(tx: &mpsc::Sender<WsmMessage>, msg: WsmMessage) -> bool {
+    for frame in msg.into_frames(STREAM_FRAME_SIZE) {
+        if tx.send(frame).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Wraps both halves of a stream in the given codec.
+pub fn framed(send: SendStream, recv: RecvStream, codec: WsmCodec) -> (WsmWriter, WsmReader) {
+    (FramedWrite::new(send, codec), FramedRead::new(recv, codec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn message(len: usize) -> WsmMessage {
+        WsmMessage::new(Opcode::Ack, 0x0102_0304, PayloadType::Raw, vec![0xAB; len])
+    }
+
+    fn encode(codec: &mut WsmCodec, msg: WsmMessage) -> std::io::Result<BytesMut> {
+        let mut buf = BytesMut::new();
+        codec.encode(msg, &mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn control_limit_is_inclusive() {
+        let limit = MAX_CONTROL_PAYLOAD as usize;
+        for len in [limit - 1, limit] {
+            let mut codec = WsmCodec::control();
+            let mut buf = encode(&mut codec, message(len)).unwrap();
+            let decoded = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(decoded.payload.len(), len);
+            assert!(buf.is_empty());
+        }
+        let err = encode(&mut WsmCodec::control(), message(limit + 1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_from_the_header() {
+        for (mut codec, limit) in [
+            (WsmCodec::control(), MAX_CONTROL_PAYLOAD),
+            (WsmCodec::transfer(), MAX_TRANSFER_PAYLOAD),
+        ] {
+            let mut header = message(0).header();
+            header.payload_len = limit + 1;
+            // Refused before any of the payload arrives.
+            let mut buf = BytesMut::from(&header.to_bytes()[..]);
+            assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+
+            header.payload_len = limit;
+            let mut buf = BytesMut::from(&header.to_bytes()[..]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn transfer_limit_fits_a_chunk_with_its_id_and_hash() {
+        let limit = MAX_TRANSFER_PAYLOAD as usize;
+        assert!(limit as u64 >= MAX_CHUNK_SIZE + 40);
+        for len in [limit - 1, limit] {
+            assert!(encode(&mut WsmCodec::transfer(), message(len)).is_ok());
+        }
+        assert!(encode(&mut WsmCodec::transfer(), message(limit + 1)).is_err());
+    }
+
+    #[test]
+    fn partial_frames_wait_for_the_rest() {
+        for msg in [message(100), message(100).v1()] {
+            let mut codec = WsmCodec::control();
+            let bytes = encode(&mut codec, msg.clone()).unwrap();
+            for len in 0..bytes.len() {
+                let mut partial = BytesMut::from(&bytes[..len]);
+                assert!(codec.decode(&mut partial).unwrap().is_none(), "cut at {}", len);
+                assert_eq!(partial.len(), len);
+            }
+            let mut whole = bytes.clone();
+            let decoded = codec.decode(&mut whole).unwrap().unwrap();
+            assert_eq!(decoded.version, msg.version);
+            assert_eq!(decoded.payload, msg.payload);
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_and_payload_types_are_refused() {
+        let mut bytes = message(0).header().to_bytes();
+        bytes[1] = 0xEE;
+        let err = WsmCodec::control().decode(&mut BytesMut::from(&bytes[..])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut bytes = message(0).header().to_bytes();
+        bytes[2] = 0x00;
+        assert!(WsmCodec::control().decode(&mut BytesMut::from(&bytes[..])).is_err());
+    }
+
+    #[test]
+    fn into_frames_splits_at_the_frame_size() {
+        let frames = message(STREAM_FRAME_SIZE * 2 + 1).with_final().into_frames(STREAM_FRAME_SIZE);
+        let sizes: Vec<usize> = frames.iter().map(|f| f.payload.len()).collect();
+        assert_eq!(sizes, [STREAM_FRAME_SIZE, STREAM_FRAME_SIZE, 1]);
+        assert!(frames[0].has_more() && frames[1].has_more());
+        assert!(frames[2].is_final());
+
+        let single = message(STREAM_FRAME_SIZE).with_final().into_frames(STREAM_FRAME_SIZE);
+        assert_eq!(single.len(), 1);
+        assert!(single[0].is_final());
+    }
+
+    #[test]
+    fn unencodable_values_are_errors() {
+        let map = BTreeMap::from([(vec![1u8], 1u8)]);
+        assert!(WsmMessage::encoded(Opcode::Ack, 1, PayloadType::Json, &map).is_err());
+        assert!(WsmMessage::encoded(Opcode::Ack, 1, PayloadType::Raw, &"text").is_err());
+        let ok = WsmMessage::encoded(Opcode::Ack, 1, PayloadType::MsgPack, &map).unwrap();
+        assert_eq!(ok.decode::<BTreeMap<Vec<u8>, u8>>().unwrap(), map);
+    }
+}
@@ -1,19 +1,22 @@
 /* src/wsm/endpoints.rs */
 
-use crate::console::app::Stats;
-use crate::quic::{auth, keepalive};
-use crate::quic::service::OngoingUploads;
-use crate::rfs::{self, SharedUploadContext, UploadState};
+use crate::console::app::Notifications;
+use crate::events::{self, ServerEvent};
+use crate::quic::client::ClientSession;
+use crate::quic::{auth, cert_monitor, keepalive, resume};
+use crate::quic::service::{ConnectionState, OngoingUploads};
+use crate::rfs;
 use crate::setup::config::Config;
-use crate::wsm::header::{WsmHeader, OPCODE_ERROR_FATAL};
-use crate::wsm::msg_id;
-use quinn::{Connection, RecvStream};
-use std::collections::HashMap;
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::error::{self, ErrorCode, ErrorReply};
+use crate::wsm::header::PayloadType;
+use crate::wsm::hello;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::payload;
 use std::ops::ControlFlow;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::mpsc;
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,39 +24,153 @@ pub enum AuthState {
     Unauthenticated,
     Authenticated,
 }
-pub type InFlightPings = Arc<Mutex<HashMap<u8, Instant>>>;
 
 
 // Server-side dispatcher
 pub async fn dispatch_server(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
-    tx: mpsc::Sender<Vec<u8>>,
-    auth_state: Arc<Mutex<AuthState>>,
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    conn: &ConnectionState,
     cfg: &Config,
     ongoing_uploads: OngoingUploads,
 ) -> ControlFlow<()> {
-    let state = *auth_state.lock().await;
-    if state == AuthState::Unauthenticated && !matches!(header.opcode, 0x01 | 0x03) {
-        eprintln!("! WSM-Server: Denying opcode {:#04X} for unauthenticated client.", header.opcode);
-        auth::send_unauthorized_response(header.message_id, tx).await;
+    let state = *conn.auth_state.lock().await;
+    if state == AuthState::Unauthenticated
+        && !matches!(msg.opcode, Opcode::Ping | Opcode::Auth | Opcode::Resume | Opcode::Hello)
+    {
+        eprintln!("! WSM-Server: Denying opcode {} for unauthenticated client.", msg.opcode);
+        auth::send_unauthorized_response(msg.message_id, tx).await;
         return ControlFlow::Break(());
     }
 
-    match header.opcode {
-        0x01 => keepalive::handle_ping_request(header.message_id, tx, cfg).await,
-        0x03 => {
-            if !auth::handle_auth_request(header, recv, tx, auth_state, cfg).await {
+    if msg.opcode != Opcode::Hello {
+        let caps = conn.negotiated.lock().await.clone();
+        match caps {
+            None if matches!(msg.opcode, Opcode::Auth | Opcode::Resume) => {
+                hello::reject_missing_hello(msg.message_id, tx).await;
+                return ControlFlow::Break(());
+            }
+            Some(caps) if !caps.accepts(msg) => {
+                eprintln!(
+                    "! WSM-Server: Ignoring opcode {} (payload type {:?}), which was not negotiated.",
+                    msg.opcode, msg.payload_type
+                );
+                error::send_error(
+                    &tx,
+                    msg.message_id,
+                    ErrorCode::Unsupported,
+                    format!("Opcode {} was not negotiated", msg.opcode),
+                )
+                .await;
+                return ControlFlow::Continue(());
+            }
+            _ => {}
+        }
+    }
+
+    if !payload::accepts(msg.opcode, msg.payload_type) {
+        eprintln!(
+            "! WSM-Server: Rejecting opcode {} with undecodable payload type {:?}.",
+            msg.opcode, msg.payload_type
+        );
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::MalformedRequest,
+            format!("Opcode {} cannot carry a {:?} payload", msg.opcode, msg.payload_type),
+        )
+        .await;
+        return ControlFlow::Continue(());
+    }
+    // Only replies are streamed; every request must fit in one frame.
+    if msg.has_more() {
+        error::send_error(
+            &tx,
+            msg.message_id,
+            ErrorCode::MalformedRequest,
+            "Requests cannot be split across frames",
+        )
+        .await;
+        return ControlFlow::Continue(());
+    }
+    // Structured replies use the encoding the client asked for during negotiation.
+    let encoding = conn
+        .negotiated
+        .lock()
+        .await
+        .as_ref()
+        .map_or(PayloadType::Json, |caps| caps.encoding());
+
+    match msg.opcode {
+        Opcode::Ping => keepalive::handle_ping_request(msg.message_id, tx, cfg).await,
+        Opcode::Auth => {
+            if !auth::handle_auth_request(msg, tx, conn, cfg, encoding).await {
+                return ControlFlow::Break(());
+            }
+        }
+        Opcode::Resume => resume::handle_resume_request(msg, tx, conn, cfg).await,
+        Opcode::Hello => {
+            if !hello::handle_hello_request(msg, tx, conn.negotiated.clone()).await {
                 return ControlFlow::Break(());
             }
         }
+        _ => return dispatch_authenticated(msg, tx, conn, cfg, ongoing_uploads, encoding).await,
+    }
+    ControlFlow::Continue(())
+}
+
+// Requests that act on behalf of the authenticated user and are checked against their permissions.
+async fn dispatch_authenticated(
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    conn: &ConnectionState,
+    cfg: &Config,
+    ongoing_uploads: OngoingUploads,
+    encoding: PayloadType,
+) -> ControlFlow<()> {
+    let Some(identity) = conn.identity.lock().await.clone() else {
+        eprintln!("! WSM-Server: Denying opcode {} without an identity.", msg.opcode);
+        auth::send_unauthorized_response(msg.message_id, tx).await;
+        return ControlFlow::Break(());
+    };
+    match msg.opcode {
         // Delegate RFS logic to the rfs module
-        0x05 => rfs::list::handle_request(header.message_id, tx, cfg).await,
-        0x06 => rfs::upload::handle_init_request(header, recv, tx, cfg, ongoing_uploads).await,
-        0x07 => rfs::upload::handle_worker_request(header, recv, tx, cfg).await,
-        0x10 => rfs::upload::handle_finalize_request(header, recv, tx, cfg, ongoing_uploads).await,
+        Opcode::ListRequest => {
+            rfs::list::handle_request(msg.message_id, tx, cfg, ongoing_uploads, encoding, &identity)
+                .await
+        }
+        Opcode::UploadInit => {
+            rfs::upload::handle_init_request(msg, tx, cfg, ongoing_uploads, &identity).await
+        }
+        Opcode::WorkerRequest => rfs::upload::handle_worker_request(msg, tx, cfg).await,
+        Opcode::Finalize => {
+            rfs::upload::handle_finalize_request(msg, tx, cfg, ongoing_uploads, &identity).await
+        }
+        Opcode::Subscribe => {
+            events::handle_subscribe_request(msg, tx, conn.topics.clone()).await
+        }
+        Opcode::Watch => {
+            let watches = conn.watches.clone();
+            rfs::watch::handle_watch_request(msg, tx, cfg, watches, encoding, &identity).await
+        }
+        Opcode::Unwatch => {
+            rfs::watch::handle_unwatch_request(msg, tx, conn.watches.clone()).await
+        }
+        Opcode::ScrubStatusRequest => {
+            rfs::scrub::handle_status_request(msg.message_id, tx, encoding, &identity).await
+        }
+        Opcode::CertStatusRequest => {
+            cert_monitor::handle_status_request(msg.message_id, tx, encoding, &identity).await
+        }
         _ => {
-            eprintln!("! WSM-Server: Received unknown opcode: {:#04X}", header.opcode);
+            eprintln!("! WSM-Server: Received unexpected opcode on control stream: {}", msg.opcode);
+            error::send_error(
+                &tx,
+                msg.message_id,
+                ErrorCode::Unsupported,
+                format!("Opcode {} is not a request", msg.opcode),
+            )
+            .await;
         }
     }
     ControlFlow::Continue(())
@@ -61,97 +178,64 @@ pub async fn dispatch_server(
 
 // Client-side dispatcher
 pub async fn dispatch_client(
-    header: &WsmHeader,
-    recv: &mut RecvStream,
-    in_flight_pings: InFlightPings,
-    auth_state: Arc<Mutex<AuthState>>,
+    msg: WsmMessage,
     stop_reconnecting: Arc<AtomicBool>,
-    cfg: &Config,
-    stats: Stats,
-    context: SharedUploadContext,
-    tx: mpsc::Sender<Vec<u8>>,
-    connection: Arc<Connection>,
+    session: &ClientSession,
+    notifications: &Notifications,
 ) -> ControlFlow<()> {
-    stats.rx_bytes.fetch_add(header.payload_len as u64, Ordering::Relaxed);
+    if !session.caps.accepts(&msg) {
+        log::warn!(
+            "! WSM-Client: Ignoring opcode {} (payload type {:?}), which was not negotiated.",
+            msg.opcode, msg.payload_type
+        );
+        return ControlFlow::Continue(());
+    }
 
-    match header.opcode {
-        0x00 => { // Generic ACK/Reply
-            let mut context_lock = context.lock().await;
-            if let Some(ctx) = context_lock.as_ref() {
-                if ctx.message_id == header.message_id {
-                    let state = ctx.state; // Copy state to avoid borrowing issues
-                    match state {
-                        UploadState::Initiated => {
-                            drop(context_lock); // Release lock before async calls
-                            let mut payload_buf = [0; 1];
-                            if header.payload_len == 1 && recv.read_exact(&mut payload_buf).await.is_ok() {
-                                match payload_buf[0] {
-                                    1 => log::info!("> Server acknowledged NEW upload request."),
-                                    2 => log::info!("> Server acknowledged RESUMABLE upload."),
-                                    _ => log::warn!("> Server sent unknown ACK code."),
-                                }
-                                // FIXED: Removed extra argument to match function definition
-                                rfs::upload::handle_init_ack(context.clone(), tx).await;
-                            } else {
-                                log::error!("> Server sent invalid ACK for upload initiation.");
-                                *context.lock().await = None; // Clear context on error
-                            }
-                        }
-                        UploadState::WorkersOpening => {
-                            drop(context_lock); // Release lock before async calls
-                            // FIXED: Removed extra argument to match function definition
-                            rfs::upload::handle_worker_ack(context.clone(), connection, tx.clone()).await;
-                        }
-                        UploadState::Finishing => {
-                            if header.payload_len == 1 {
-                                let mut payload = [0; 1];
-                                if recv.read_exact(&mut payload).await.is_ok() {
-                                    if payload[0] == 1 { // 1 = Success
-                                        rfs::stats::log_completion_stats(&ctx);
-                                    } else {
-                                        log::error!("! Upload failed during server-side finalization.");
-                                    }
-                                }
-                            } else {
-                                log::error!("! Received invalid finalization response from server.");
-                            }
-                            *context_lock = None; // Re-lock to modify and clear context
-                        }
-                        _ => {}
-                    }
-                    msg_id::remove_msg_id(header.message_id).await;
-                    return ControlFlow::Continue(());
-                }
-            }
+    if !payload::accepts(msg.opcode, msg.payload_type) {
+        log::warn!(
+            "! WSM-Client: Dropping {} with undecodable payload type {:?}.",
+            msg.opcode, msg.payload_type
+        );
+        return ControlFlow::Continue(());
+    }
 
-            drop(context_lock);
-            if *auth_state.lock().await == AuthState::Unauthenticated {
-                if !auth::handle_auth_response(header, recv, auth_state, stop_reconnecting).await {
-                    return ControlFlow::Break(());
-                }
-            }
+    // A fatal error ends the connection even when it answers a pending request.
+    if msg.opcode == Opcode::ErrorFatal {
+        log::error!("! WSM-Client: Received fatal error from server.");
+        if !msg.payload.is_empty() {
+            log::error!("! Server reason: {}", msg.text());
         }
-        0x02 => keepalive::handle_pong_response(header.message_id, in_flight_pings, cfg).await,
-        0x04 => rfs::list::handle_response(header, recv).await,
-        OPCODE_ERROR_FATAL => {
-            log::error!("! WSM-Client: Received fatal error from server.");
-            if header.payload_len > 0 {
-                let mut reason_buf = vec![0; header.payload_len as usize];
-                if recv.read_exact(&mut reason_buf).await.is_ok() {
-                    log::error!("! Server reason: {}", String::from_utf8_lossy(&reason_buf));
-                }
+        stop_reconnecting.store(true, Ordering::SeqCst);
+        return ControlFlow::Break(());
+    }
+
+    // Events are pushed unprompted and answer nothing.
+    if msg.opcode == Opcode::Event {
+        match msg.decode::<ServerEvent>() {
+            Ok(event) => {
+                log::info!("* Server event: {}", event);
+                notifications.push(event.to_string());
             }
-            stop_reconnecting.store(true, Ordering::SeqCst);
-            return ControlFlow::Break(());
-        }
-        _ => {
-            log::warn!("! WSM-Client: Received unknown opcode: {:#04X}", header.opcode);
+            Err(e) => log::warn!("! WSM-Client: Failed to decode server event: {}", e),
         }
+        return ControlFlow::Continue(());
     }
 
-    if header.is_final() {
-        msg_id::remove_msg_id(header.message_id).await;
+    // Everything else the server sends answers a request; hand it to whoever is waiting.
+    let Some(msg) = session.requests.resolve(msg).await else {
+        return ControlFlow::Continue(());
+    };
+    if msg.opcode == Opcode::Error {
+        log::error!(
+            "! WSM-Client: Server error for msg_id {}: {}",
+            msg.message_id,
+            ErrorReply::from_message(&msg)
+        );
+    } else {
+        log::warn!(
+            "! WSM-Client: Dropping {} for msg_id {}, which no request is waiting for.",
+            msg.opcode, msg.message_id
+        );
     }
-
     ControlFlow::Continue(())
-}
\ No newline at end of file
+}
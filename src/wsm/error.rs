@@ -0,0 +1,121 @@
+/* src/wsm/error.rs */
+
+use crate::wsm::codec::WsmMessage;
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::sync::mpsc;
+
+/// Why a request failed. The hundreds digit is the category, so codes added by
+/// newer servers still land in the right bucket on older clients.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    MalformedRequest = 100,
+    Unsupported = 101,
+    AuthFailed = 200,
+    PermissionDenied = 201,
+    NotFound = 300,
+    AlreadyExists = 301,
+    InvalidPath = 302,
+    ReadOnly = 303,
+    Unavailable = 304,
+    StorageFailure = 400,
+    VerificationFailed = 401,
+    Internal = 500,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCategory {
+    /// The request itself was malformed or not understood.
+    Request,
+    /// The client is not allowed to do this.
+    Permission,
+    /// The volume, device or file named by the request is missing or unusable.
+    Resource,
+    /// The server failed to read or write its storage.
+    Storage,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn category(self) -> ErrorCategory {
+        match self as u16 / 100 {
+            1 => ErrorCategory::Request,
+            2 => ErrorCategory::Permission,
+            3 => ErrorCategory::Resource,
+            4 => ErrorCategory::Storage,
+            _ => ErrorCategory::Internal,
+        }
+    }
+}
+
+/// Payload of a non-fatal error reply (0x1C). Sent with the ID of the failed
+/// request; the ID is repeated in the body for logs and worker streams, which use ID 0.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorReply {
+    pub code: u16,
+    pub category: ErrorCategory,
+    pub message: String,
+    pub request_id: u32,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorReply {
+            code: code as u16,
+            category: code.category(),
+            message: message.into(),
+            request_id: 0,
+        }
+    }
+
+    /// Encodes this error as the reply to `request_id`. Error replies are always
+    /// JSON so they stay readable when the failure happened before negotiation; should
+    /// that fail, the message goes out as plain text, which `from_message` also reads.
+    pub fn to_message(&self, request_id: u32) -> WsmMessage {
+        let reply = ErrorReply {
+            request_id,
+            ..self.clone()
+        };
+        WsmMessage::encoded(Opcode::Error, request_id, PayloadType::Json, &reply)
+            .unwrap_or_else(|_| {
+                let text = self.message.clone().into_bytes();
+                WsmMessage::new(Opcode::Error, request_id, PayloadType::Raw, text)
+            })
+            .with_final()
+    }
+
+    /// Decodes an error reply, keeping the raw text if the body is not a valid `ErrorReply`.
+    pub fn from_message(msg: &WsmMessage) -> Self {
+        msg.decode().unwrap_or_else(|_| ErrorReply {
+            request_id: msg.message_id,
+            ..ErrorReply::new(ErrorCode::Internal, msg.text())
+        })
+    }
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:?} error {})",
+            self.message, self.category, self.code
+        )
+    }
+}
+
+// [SERVER-SIDE] Answers a control-stream request with an error reply.
+pub async fn send_error(
+    tx: &mpsc::Sender<WsmMessage>,
+    request_id: u32,
+    code: ErrorCode,
+    message: impl Into<String>,
+) {
+    let reply = ErrorReply::new(code, message);
+    if tx.send(reply.to_message(request_id)).await.is_err() {
+        eprintln!("! WSM-Server: Failed to send error reply: {}", reply);
+    }
+}
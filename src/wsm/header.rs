@@ -1,12 +1,17 @@
 /* src/wsm/header.rs */
 
 use std::convert::TryFrom;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 // Final message flag for reserved field
 pub const RESERVED_FINAL_FLAG: u8 = 0xFF;
-// Opcode for fatal, unrecoverable errors
-pub const OPCODE_ERROR_FATAL: u8 = 0xFF;
-
+// Continuation flag: more frames of the same message ID follow. The last frame carries
+// the final flag, and the receiver concatenates all payloads in order.
+pub const RESERVED_MORE_FLAG: u8 = 0x01;
+// First byte of a v2 header. It is never a valid opcode, so both framings can be told apart.
+pub const HEADER_V2_MARKER: u8 = 0xFE;
+pub const HEADER_V1_LEN: usize = 8;
+pub const HEADER_V2_LEN: usize = 12;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -37,61 +42,145 @@ impl TryFrom<u8> for PayloadType {
 #[derive(Debug, Clone, Copy)]
 pub struct WsmHeader {
     pub opcode: u8,
-    pub message_id: u8,
+    pub message_id: u32,
     pub payload_type: u8,
     pub reserved: u8,
     pub payload_len: u32,
+    /// Framing used on the wire: 1 (8 bytes, u8 ID) or 2 (12 bytes, u32 ID).
+    pub version: u8,
 }
 
 impl WsmHeader {
-    pub fn new(opcode: u8, message_id: u8, payload_type: PayloadType, payload_len: u32) -> Self {
+    /// Size of this header on the wire.
+    pub fn wire_len(&self) -> usize {
+        if self.version == 1 { HEADER_V1_LEN } else { HEADER_V2_LEN }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.wire_len() + self.payload_len as usize);
+        if self.version == 1 {
+            buf.push(self.opcode);
+            buf.push(self.message_id as u8);
+            buf.push(self.payload_type);
+            buf.push(self.reserved);
+        } else {
+            buf.push(HEADER_V2_MARKER);
+            buf.push(self.opcode);
+            buf.push(self.payload_type);
+            buf.push(self.reserved);
+            buf.extend_from_slice(&self.message_id.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.payload_len.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; 8]) -> Self {
+        let payload_len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
         WsmHeader {
-            opcode,
-            message_id,
-            payload_type: payload_type as u8,
-            reserved: 0,
+            opcode: buf[0],
+            message_id: buf[1] as u32,
+            payload_type: buf[2],
+            reserved: buf[3],
             payload_len,
+            version: 1,
         }
     }
 
-    pub fn with_reserved(
-        opcode: u8,
-        message_id: u8,
-        payload_type: PayloadType,
-        payload_len: u32,
-        reserved: u8,
-    ) -> Self {
+    pub fn from_v2_bytes(buf: &[u8; 12]) -> Self {
         WsmHeader {
-            opcode,
+            opcode: buf[1],
+            message_id: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            payload_type: buf[2],
+            reserved: buf[3],
+            payload_len: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            version: 2,
+        }
+    }
+
+    /// Reads one header in either framing.
+    pub async fn read_from<R: AsyncRead + Unpin>(recv: &mut R) -> std::io::Result<Self> {
+        let mut buf = [0u8; HEADER_V2_LEN];
+        recv.read_exact(&mut buf[..HEADER_V1_LEN]).await?;
+        if buf[0] != HEADER_V2_MARKER {
+            return Ok(Self::from_bytes(buf[..HEADER_V1_LEN].try_into().unwrap()));
+        }
+        recv.read_exact(&mut buf[HEADER_V1_LEN..]).await?;
+        Ok(Self::from_v2_bytes(&buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    fn header(version: u8, message_id: u32) -> WsmHeader {
+        WsmHeader {
+            opcode: 0x03,
             message_id,
-            payload_type: payload_type as u8,
-            reserved,
-            payload_len,
+            payload_type: PayloadType::Json as u8,
+            reserved: RESERVED_FINAL_FLAG,
+            payload_len: 0x0102_0304,
+            version,
         }
     }
 
-    pub fn is_final(&self) -> bool {
-        self.reserved == RESERVED_FINAL_FLAG
+    fn assert_same(a: WsmHeader, b: WsmHeader) {
+        assert_eq!(
+            (a.opcode, a.message_id, a.payload_type, a.reserved, a.payload_len, a.version),
+            (b.opcode, b.message_id, b.payload_type, b.reserved, b.payload_len, b.version)
+        );
     }
 
-    pub fn to_bytes(&self) -> [u8; 8] {
-        let mut buf = [0u8; 8];
-        buf[0] = self.opcode;
-        buf[1] = self.message_id;
-        buf[2] = self.payload_type;
-        buf[3] = self.reserved;
-        buf[4..8].copy_from_slice(&self.payload_len.to_le_bytes());
-        buf
+    #[tokio::test]
+    async fn v1_round_trip() {
+        let sent = header(1, 0x42);
+        let bytes = sent.to_bytes();
+        assert_eq!(bytes.len(), HEADER_V1_LEN);
+        assert_same(WsmHeader::from_bytes(bytes[..].try_into().unwrap()), sent);
+        assert_same(WsmHeader::read_from(&mut &bytes[..]).await.unwrap(), sent);
     }
 
-    pub fn from_bytes(buf: &[u8; 8]) -> Self {
-        let payload_len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
-        WsmHeader {
-            opcode: buf[0],
-            message_id: buf[1],
-            payload_type: buf[2],
-            reserved: buf[3],
-            payload_len,
+    #[tokio::test]
+    async fn v1_truncates_the_message_id() {
+        let bytes = header(1, 0x1234_5678).to_bytes();
+        let read = WsmHeader::read_from(&mut &bytes[..]).await.unwrap();
+        assert_eq!(read.message_id, 0x78);
+    }
+
+    #[tokio::test]
+    async fn v2_round_trip() {
+        let sent = header(2, 0xDEAD_BEEF);
+        let bytes = sent.to_bytes();
+        assert_eq!(bytes.len(), HEADER_V2_LEN);
+        assert_eq!(bytes[0], HEADER_V2_MARKER);
+        assert_same(WsmHeader::from_v2_bytes(bytes[..].try_into().unwrap()), sent);
+        assert_same(WsmHeader::read_from(&mut &bytes[..]).await.unwrap(), sent);
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_rejected() {
+        for version in [1, 2] {
+            let bytes = header(version, 7).to_bytes();
+            for len in 0..bytes.len() {
+                let err = WsmHeader::read_from(&mut &bytes[..len]).await.unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "v{} cut at {}", version, len);
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn payload_types_round_trip() {
+        for kind in [
+            PayloadType::Json,
+            PayloadType::Bincode,
+            PayloadType::Raw,
+            PayloadType::Base64,
+            PayloadType::MsgPack,
+            PayloadType::Custom,
+        ] {
+            assert_eq!(PayloadType::try_from(kind as u8), Ok(kind));
+        }
+        assert_eq!(PayloadType::try_from(0x00), Err(()));
+    }
+}
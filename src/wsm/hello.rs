@@ -0,0 +1,376 @@
+/* src/wsm/hello.rs */
+
+use crate::rfs::{image, worker};
+use crate::wsm::codec::{WsmMessage, WsmReader};
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use crate::wsm::payload;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{Mutex, mpsc};
+
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest peer version this build still interoperates with.
+/// v2 introduced 12-byte headers; v1 peers are only told, in v1 framing, that they are too old.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+
+/// Payload encodings this build can decode.
+pub const SUPPORTED_ENCODINGS: &[PayloadType] = &[
+    PayloadType::Json,
+    PayloadType::Bincode,
+    PayloadType::MsgPack,
+    PayloadType::Base64,
+    PayloadType::Raw,
+];
+
+/// Compression schemes in order of preference; "none" is always understood.
+pub const SUPPORTED_COMPRESSION: &[&str] = &["none"];
+
+/// Largest chunk any transfer in this build sends in one message.
+pub const MAX_CHUNK_SIZE: u64 = if image::IMAGE_CHUNK_SIZE > worker::CHUNK_SIZE {
+    image::IMAGE_CHUNK_SIZE
+} else {
+    worker::CHUNK_SIZE
+};
+
+/// Sent by the client (0x1A) as the first message on the control stream.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub min_protocol_version: u16,
+    pub opcodes: Vec<u8>,
+    /// In order of preference; the first structured one both sides know is used.
+    pub encodings: Vec<u8>,
+    pub compression: Vec<String>,
+    pub max_chunk_size: u64,
+    pub software: String,
+}
+
+/// The feature set both peers agreed on, returned by the server (0x1B).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    pub protocol_version: u16,
+    pub opcodes: Vec<u8>,
+    pub encodings: Vec<u8>,
+    /// Encoding of structured payloads after the handshake.
+    #[serde(default = "default_encoding")]
+    pub preferred_encoding: u8,
+    pub compression: String,
+    pub max_chunk_size: u64,
+}
+
+fn default_encoding() -> u8 {
+    PayloadType::Json as u8
+}
+
+/// Per-connection negotiated set on the server; `None` until the Hello arrives.
+pub type NegotiatedCaps = Arc<Mutex<Option<Capabilities>>>;
+
+impl Hello {
+    /// Our Hello, listing `preferred` ahead of the other encodings.
+    pub fn local(preferred: PayloadType) -> Self {
+        let mut encodings = vec![preferred as u8];
+        encodings.extend(
+            SUPPORTED_ENCODINGS
+                .iter()
+                .filter(|p| **p != preferred)
+                .map(|p| *p as u8),
+        );
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            opcodes: Opcode::ALL.iter().map(|op| *op as u8).collect(),
+            encodings,
+            compression: SUPPORTED_COMPRESSION
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            max_chunk_size: MAX_CHUNK_SIZE,
+            software: format!("anchr {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn supports(&self, opcode: Opcode) -> bool {
+        self.opcodes.contains(&(opcode as u8))
+    }
+
+    pub fn supports_encoding(&self, payload_type: PayloadType) -> bool {
+        self.encodings.contains(&(payload_type as u8))
+    }
+
+    /// The agreed encoding for structured payloads.
+    pub fn encoding(&self) -> PayloadType {
+        PayloadType::try_from(self.preferred_encoding).unwrap_or(PayloadType::Json)
+    }
+
+    /// Whether a received message only uses negotiated features.
+    pub fn accepts(&self, msg: &WsmMessage) -> bool {
+        self.supports(msg.opcode) && self.supports_encoding(msg.payload_type)
+    }
+}
+
+/// Intersects a peer's Hello with our own, or explains why the two cannot talk.
+pub fn negotiate(peer: &Hello) -> Result<Capabilities, String> {
+    if peer.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Client protocol v{} ({}) is too old; this server requires v{} or newer. Please upgrade the client.",
+            peer.protocol_version, peer.software, MIN_PROTOCOL_VERSION
+        ));
+    }
+    if peer.min_protocol_version > PROTOCOL_VERSION {
+        return Err(format!(
+            "Client ({}) requires protocol v{} or newer, but this server only speaks v{}. Please upgrade the server.",
+            peer.software, peer.min_protocol_version, PROTOCOL_VERSION
+        ));
+    }
+    let encodings: Vec<u8> = peer
+        .encodings
+        .iter()
+        .copied()
+        .filter(|e| SUPPORTED_ENCODINGS.iter().any(|p| *p as u8 == *e))
+        .collect();
+    if !encodings.contains(&(PayloadType::Json as u8))
+        || !encodings.contains(&(PayloadType::Raw as u8))
+    {
+        return Err("Client does not support the JSON and raw payload encodings.".to_string());
+    }
+    let preferred_encoding = encodings
+        .iter()
+        .copied()
+        .find(|e| PayloadType::try_from(*e).is_ok_and(payload::is_structured))
+        .unwrap_or(PayloadType::Json as u8);
+    let compression = SUPPORTED_COMPRESSION
+        .iter()
+        .find(|c| peer.compression.iter().any(|p| p == *c))
+        .unwrap_or(&"none")
+        .to_string();
+    Ok(Capabilities {
+        protocol_version: peer.protocol_version.min(PROTOCOL_VERSION),
+        opcodes: peer
+            .opcodes
+            .iter()
+            .copied()
+            .filter(|op| Opcode::try_from(*op).is_ok())
+            .collect(),
+        encodings,
+        preferred_encoding,
+        compression,
+        max_chunk_size: peer.max_chunk_size.min(MAX_CHUNK_SIZE),
+    })
+}
+
+// Handshake messages and their rejections always use v1 framing so peers of any version can read them.
+async fn send_fatal(message_id: u32, tx: &mpsc::Sender<WsmMessage>, reason: &str) {
+    let response = WsmMessage::new(
+        Opcode::ErrorFatal,
+        message_id,
+        PayloadType::Raw,
+        reason.as_bytes().to_vec(),
+    )
+    .with_final()
+    .v1();
+    let _ = tx.send(response).await;
+}
+
+// [SERVER-SIDE] Handles the client Hello (0x1A). Returns false if the connection must be closed.
+pub async fn handle_hello_request(
+    msg: &WsmMessage,
+    tx: mpsc::Sender<WsmMessage>,
+    negotiated: NegotiatedCaps,
+) -> bool {
+    let mut current = negotiated.lock().await;
+    if current.is_some() {
+        eprintln!("! WSM-Server: Client sent a second Hello. Closing.");
+        send_fatal(msg.message_id, &tx, "Protocol already negotiated.").await;
+        return false;
+    }
+    let result = serde_json::from_slice::<Hello>(&msg.payload)
+        .map_err(|e| format!("Malformed Hello: {}", e))
+        .and_then(|hello| negotiate(&hello).map(|caps| (hello, caps)));
+    match result {
+        Ok((hello, caps)) => {
+            println!(
+                "  -> WSM: Negotiated protocol v{} with {} ({} opcodes, {:?} payloads, max chunk {} bytes).",
+                caps.protocol_version,
+                hello.software,
+                caps.opcodes.len(),
+                caps.encoding(),
+                caps.max_chunk_size
+            );
+            let ack = WsmMessage::encoded(
+                Opcode::HelloAck,
+                msg.message_id,
+                PayloadType::Json,
+                &caps,
+            );
+            let response = match ack {
+                Ok(response) => response.with_final().v1(),
+                Err(e) => {
+                    eprintln!("! WSM-Server: {}", e);
+                    send_fatal(msg.message_id, &tx, "Failed to encode the hello reply.").await;
+                    return false;
+                }
+            };
+            *current = Some(caps);
+            tx.send(response).await.is_ok()
+        }
+        Err(reason) => {
+            println!("  -> WSM: Rejecting client: {}", reason);
+            send_fatal(msg.message_id, &tx, &reason).await;
+            false
+        }
+    }
+}
+
+// [SERVER-SIDE] Rejects clients that skip the Hello and go straight to auth.
+pub async fn reject_missing_hello(message_id: u32, tx: mpsc::Sender<WsmMessage>) {
+    println!("  -> WSM: Rejecting client that did not negotiate a protocol version.");
+    send_fatal(
+        message_id,
+        &tx,
+        &format!(
+            "This server requires protocol negotiation (v{}+). Please upgrade the client.",
+            MIN_PROTOCOL_VERSION
+        ),
+    )
+    .await;
+}
+
+/// Outcome of the client side of the handshake.
+pub enum HelloOutcome {
+    Accepted(Capabilities),
+    /// The server refused us for a reason retrying will not fix.
+    Rejected(String),
+}
+
+// [CLIENT-SIDE] Sends our Hello and waits for the server's answer.
+pub async fn client_handshake(
+    message_id: u32,
+    tx: &mpsc::Sender<WsmMessage>,
+    reader: &mut WsmReader,
+    preferred: PayloadType,
+) -> Result<HelloOutcome, String> {
+    let hello = Hello::local(preferred);
+    let request = WsmMessage::encoded(Opcode::Hello, message_id, PayloadType::Json, &hello)?.v1();
+    tx.send(request).await.map_err(|e| e.to_string())?;
+
+    let reply = match reader.next().await {
+        Some(Ok(reply)) => reply,
+        Some(Err(e)) => return Err(format!("Connection lost during hello: {}", e)),
+        None => return Err("Connection closed during hello.".to_string()),
+    };
+    match reply.opcode {
+        Opcode::HelloAck => serde_json::from_slice::<Capabilities>(&reply.payload)
+            .map(HelloOutcome::Accepted)
+            .map_err(|e| format!("Invalid hello reply: {}", e)),
+        Opcode::ErrorFatal => {
+            let reason = reply.text();
+            // Servers predating negotiation treat the Hello as an unauthenticated request.
+            if reason == "Unauthenticated" {
+                Ok(HelloOutcome::Rejected(
+                    "Server does not support protocol negotiation; it is older than this client."
+                        .to_string(),
+                ))
+            } else {
+                Ok(HelloOutcome::Rejected(reason))
+            }
+        }
+        op => Err(format!("Unexpected reply {} to hello.", op)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> Hello {
+        Hello::local(PayloadType::Json)
+    }
+
+    #[test]
+    fn matching_peers_agree_on_everything() {
+        let caps = negotiate(&peer()).unwrap();
+        assert_eq!(caps.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(caps.opcodes.len(), Opcode::ALL.len());
+        assert_eq!(caps.encodings.len(), SUPPORTED_ENCODINGS.len());
+        assert_eq!(caps.encoding(), PayloadType::Json);
+        assert_eq!(caps.compression, "none");
+        assert_eq!(caps.max_chunk_size, MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn too_old_peers_are_refused() {
+        let mut hello = peer();
+        hello.protocol_version = MIN_PROTOCOL_VERSION - 1;
+        let reason = negotiate(&hello).unwrap_err();
+        assert!(reason.contains("too old"), "{}", reason);
+    }
+
+    #[test]
+    fn peers_requiring_a_newer_version_are_refused() {
+        let mut hello = peer();
+        hello.protocol_version = PROTOCOL_VERSION + 1;
+        hello.min_protocol_version = PROTOCOL_VERSION + 1;
+        let reason = negotiate(&hello).unwrap_err();
+        assert!(reason.contains("upgrade the server"), "{}", reason);
+    }
+
+    #[test]
+    fn newer_peers_fall_back_to_our_version() {
+        let mut hello = peer();
+        hello.protocol_version = PROTOCOL_VERSION + 1;
+        hello.max_chunk_size = MAX_CHUNK_SIZE * 2;
+        let caps = negotiate(&hello).unwrap();
+        assert_eq!(caps.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(caps.max_chunk_size, MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn unknown_opcodes_are_dropped() {
+        let mut hello = peer();
+        hello.opcodes = vec![Opcode::Ping as u8, 0x7E, Opcode::Auth as u8];
+        let caps = negotiate(&hello).unwrap();
+        assert_eq!(caps.opcodes, vec![Opcode::Ping as u8, Opcode::Auth as u8]);
+        assert!(caps.supports(Opcode::Auth));
+        assert!(!caps.supports(Opcode::Resume));
+    }
+
+    #[test]
+    fn preferred_encoding_is_the_first_structured_one() {
+        let hello = Hello::local(PayloadType::MsgPack);
+        assert_eq!(negotiate(&hello).unwrap().encoding(), PayloadType::MsgPack);
+
+        // Raw is not structured and 0xEE is unknown, so the next in the peer's order wins.
+        let mut hello = peer();
+        hello.encodings = vec![
+            PayloadType::Raw as u8,
+            0xEE,
+            PayloadType::Bincode as u8,
+            PayloadType::Json as u8,
+        ];
+        assert_eq!(negotiate(&hello).unwrap().encoding(), PayloadType::Bincode);
+    }
+
+    #[test]
+    fn json_and_raw_are_required() {
+        let mut hello = peer();
+        hello.encodings = vec![PayloadType::Json as u8, PayloadType::MsgPack as u8];
+        assert!(negotiate(&hello).is_err());
+        hello.encodings = vec![PayloadType::Raw as u8, PayloadType::MsgPack as u8];
+        assert!(negotiate(&hello).is_err());
+    }
+
+    #[test]
+    fn unknown_encodings_and_compression_are_ignored() {
+        let mut hello = peer();
+        hello.encodings.push(PayloadType::Custom as u8);
+        hello.compression = vec!["zstd".to_string()];
+        let caps = negotiate(&hello).unwrap();
+        assert!(!caps.supports_encoding(PayloadType::Custom));
+        assert_eq!(caps.compression, "none");
+    }
+}
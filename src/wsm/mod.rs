@@ -1,5 +1,11 @@
 /* src/wsm/mod.rs */
 
+pub mod codec;
+pub mod error;
 pub mod header;
+pub mod hello;
 pub mod msg_id;
-pub mod endpoints;
\ No newline at end of file
+pub mod opcode;
+pub mod payload;
+pub mod requests;
+pub mod endpoints;
@@ -1,43 +1,67 @@
 /* src/wsm/msg_id.rs */
 
-use lazy_static::lazy_static;
 use rand; // Make sure rand is in your Cargo.toml
 use std::collections::HashSet;
-use tokio::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-lazy_static! {
-    static ref MSG_ID_POOL: Mutex<HashSet<u8>> = Mutex::new(HashSet::new());
+// Upper bound on requests outstanding on one connection.
+const MAX_IN_FLIGHT: usize = 4096;
+
+/// Message IDs in use on one connection. ID 0 is never handed out; it is used by
+/// messages that are not tracked as requests (worker streams, the handshake and auth).
+/// The lock is never held across an await, so IDs can be released from `Drop`.
+#[derive(Clone, Default)]
+pub struct MsgIdPool {
+    ids: Arc<Mutex<HashSet<u32>>>,
 }
 
-pub async fn create_new_msg_id() -> Option<u8> {
-    let mut pool = MSG_ID_POOL.lock().await;
-    // u8::MAX is 255. The length can go from 0 to 256.
-    if pool.len() >= (u8::MAX as usize) + 1 {
-        return None;
-    }
-    loop {
-        let new_id = rand::random::<u8>();
-        if pool.insert(new_id) {
-            return Some(new_id);
+impl MsgIdPool {
+    pub fn create_new_msg_id(&self) -> Option<u32> {
+        let mut pool = self.ids.lock().unwrap();
+        if pool.len() >= MAX_IN_FLIGHT {
+            return None;
+        }
+        loop {
+            let new_id = rand::random::<u32>();
+            if new_id != 0 && pool.insert(new_id) {
+                return Some(new_id);
+            }
         }
     }
-}
 
-pub async fn remove_msg_id(id: u8) -> bool {
-    let mut pool = MSG_ID_POOL.lock().await;
-    pool.remove(&id)
-}
+    pub fn remove_msg_id(&self, id: u32) -> bool {
+        self.ids.lock().unwrap().remove(&id)
+    }
 
-pub async fn clear_msg_id_pool() {
-    let mut pool = MSG_ID_POOL.lock().await;
-    if !pool.is_empty() {
-        pool.clear();
-        // Use log instead of println to integrate with the TUI logger
-        log::info!("- Cleared message ID pool due to new session or extended disconnection.");
+    /// Returns the number of message IDs currently in use.
+    pub fn size(&self) -> usize {
+        self.ids.lock().unwrap().len()
     }
 }
 
-/// Returns the number of message IDs currently in use.
-pub async fn get_pool_size() -> usize {
-    MSG_ID_POOL.lock().await.len()
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_unique_and_never_zero() {
+        let pool = MsgIdPool::default();
+        let ids: HashSet<u32> = (0..100).map(|_| pool.create_new_msg_id().unwrap()).collect();
+        assert_eq!(ids.len(), 100);
+        assert!(!ids.contains(&0));
+        assert_eq!(pool.size(), 100);
+    }
+
+    #[test]
+    fn full_pool_refuses_until_an_id_is_released() {
+        let pool = MsgIdPool::default();
+        let first = pool.create_new_msg_id().unwrap();
+        for _ in 1..MAX_IN_FLIGHT {
+            pool.create_new_msg_id().unwrap();
+        }
+        assert_eq!(pool.create_new_msg_id(), None);
+        assert!(pool.remove_msg_id(first));
+        assert!(!pool.remove_msg_id(first));
+        assert!(pool.create_new_msg_id().is_some());
+    }
+}
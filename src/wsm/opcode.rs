@@ -0,0 +1,124 @@
+/* src/wsm/opcode.rs */
+
+use std::convert::TryFrom;
+
+/// Every message type of the WSM protocol. Requests and their replies share
+/// `Ack` (0x00) unless a dedicated response opcode exists. `Pong`, `Event` and
+/// `SessionToken` are the only messages the server sends unprompted.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Ack = 0x00,
+    Ping = 0x01,
+    Pong = 0x02,
+    Auth = 0x03,
+    ListResponse = 0x04,
+    ListRequest = 0x05,
+    UploadInit = 0x06,
+    WorkerRequest = 0x07,
+    ChunkInquiry = 0x08,
+    ChunkData = 0x09,
+    Finalize = 0x10,
+    WorkerHello = 0x11,
+    ImageHello = 0x12,
+    ImageInfo = 0x13,
+    ImageChunkInquiry = 0x14,
+    ImageChunkData = 0x15,
+    NbdHello = 0x16,
+    NbdExportInfo = 0x17,
+    ScrubStatusRequest = 0x18,
+    ScrubStatusResponse = 0x19,
+    Hello = 0x1A,
+    HelloAck = 0x1B,
+    /// Non-fatal failure of one request, see `wsm::error::ErrorReply`.
+    Error = 0x1C,
+    Subscribe = 0x1D,
+    /// Server-pushed event, see `events::ServerEvent`.
+    Event = 0x1E,
+    /// Start or stop streaming changes below a directory, see `rfs::watch`.
+    Watch = 0x1F,
+    Unwatch = 0x20,
+    /// Server nonce answering an empty `Auth`, see `quic::auth`.
+    AuthChallenge = 0x21,
+    /// Validity of the served certificate, see `quic::cert_monitor`.
+    CertStatusRequest = 0x22,
+    CertStatusResponse = 0x23,
+    /// Takes over a session by its token instead of authenticating, see `quic::resume`.
+    Resume = 0x24,
+    /// Server-pushed token for resuming the session after a reconnect.
+    SessionToken = 0x25,
+    /// Unrecoverable failure; the peer closes the connection.
+    ErrorFatal = 0xFF,
+}
+
+impl Opcode {
+    /// Every opcode this build understands, in either direction.
+    pub const ALL: &'static [Opcode] = &[
+        Opcode::Ack,
+        Opcode::Ping,
+        Opcode::Pong,
+        Opcode::Auth,
+        Opcode::ListResponse,
+        Opcode::ListRequest,
+        Opcode::UploadInit,
+        Opcode::WorkerRequest,
+        Opcode::ChunkInquiry,
+        Opcode::ChunkData,
+        Opcode::Finalize,
+        Opcode::WorkerHello,
+        Opcode::ImageHello,
+        Opcode::ImageInfo,
+        Opcode::ImageChunkInquiry,
+        Opcode::ImageChunkData,
+        Opcode::NbdHello,
+        Opcode::NbdExportInfo,
+        Opcode::ScrubStatusRequest,
+        Opcode::ScrubStatusResponse,
+        Opcode::Hello,
+        Opcode::HelloAck,
+        Opcode::Error,
+        Opcode::Subscribe,
+        Opcode::Event,
+        Opcode::Watch,
+        Opcode::Unwatch,
+        Opcode::AuthChallenge,
+        Opcode::CertStatusRequest,
+        Opcode::CertStatusResponse,
+        Opcode::Resume,
+        Opcode::SessionToken,
+        Opcode::ErrorFatal,
+    ];
+
+    /// Whether receiving the message twice has no further effect. Only these may be sent
+    /// before the handshake completes: 0-RTT data can be replayed by anyone who recorded it.
+    pub fn is_idempotent(self) -> bool {
+        matches!(
+            self,
+            Opcode::Ping
+                | Opcode::Hello
+                | Opcode::ListRequest
+                | Opcode::ScrubStatusRequest
+                | Opcode::CertStatusRequest
+                | Opcode::Subscribe
+                | Opcode::Watch
+                | Opcode::Unwatch
+        )
+    }
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = ();
+    fn try_from(v: u8) -> Result<Self, ()> {
+        Opcode::ALL
+            .iter()
+            .copied()
+            .find(|op| *op as u8 == v)
+            .ok_or(())
+    }
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({:#04X})", self, *self as u8)
+    }
+}
@@ -0,0 +1,80 @@
+/* src/wsm/payload.rs */
+
+use crate::wsm::header::PayloadType;
+use crate::wsm::opcode::Opcode;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Encodings that carry a serialized value rather than opaque bytes.
+/// Base64 wraps the JSON encoding for peers that need a text-safe payload.
+pub const STRUCTURED_ENCODINGS: &[PayloadType] = &[
+    PayloadType::Json,
+    PayloadType::Bincode,
+    PayloadType::MsgPack,
+    PayloadType::Base64,
+];
+
+pub fn is_structured(payload_type: PayloadType) -> bool {
+    STRUCTURED_ENCODINGS.contains(&payload_type)
+}
+
+/// Parses an encoding name as used in the config file.
+pub fn parse_name(name: &str) -> Option<PayloadType> {
+    match name.to_ascii_lowercase().as_str() {
+        "json" => Some(PayloadType::Json),
+        "bincode" => Some(PayloadType::Bincode),
+        "msgpack" => Some(PayloadType::MsgPack),
+        "base64" => Some(PayloadType::Base64),
+        _ => None,
+    }
+}
+
+pub fn encode<T: Serialize>(payload_type: PayloadType, value: &T) -> Result<Vec<u8>, String> {
+    match payload_type {
+        PayloadType::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+        PayloadType::Bincode => bincode::serialize(value).map_err(|e| e.to_string()),
+        PayloadType::MsgPack => rmp_serde::to_vec(value).map_err(|e| e.to_string()),
+        PayloadType::Base64 => serde_json::to_vec(value)
+            .map(|json| BASE64.encode(json).into_bytes())
+            .map_err(|e| e.to_string()),
+        other => Err(format!("{:?} payloads cannot carry structured data", other)),
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(payload_type: PayloadType, payload: &[u8]) -> Result<T, String> {
+    match payload_type {
+        PayloadType::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+        PayloadType::Bincode => bincode::deserialize(payload).map_err(|e| e.to_string()),
+        PayloadType::MsgPack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        PayloadType::Base64 => {
+            let json = BASE64.decode(payload).map_err(|e| e.to_string())?;
+            serde_json::from_slice(&json).map_err(|e| e.to_string())
+        }
+        other => Err(format!("{:?} payloads cannot carry structured data", other)),
+    }
+}
+
+/// Whether a handler for `opcode` can decode a payload of the declared type.
+/// Opcodes with a structured body take any structured encoding; all others carry raw bytes.
+pub fn accepts(opcode: Opcode, payload_type: PayloadType) -> bool {
+    match opcode {
+        Opcode::Hello
+        | Opcode::HelloAck
+        | Opcode::UploadInit
+        | Opcode::Finalize
+        | Opcode::ListResponse
+        | Opcode::ScrubStatusResponse
+        | Opcode::ImageHello
+        | Opcode::ImageInfo
+        | Opcode::NbdHello
+        | Opcode::NbdExportInfo
+        | Opcode::Subscribe
+        | Opcode::Event
+        | Opcode::AuthChallenge
+        | Opcode::CertStatusResponse
+        | Opcode::Error => is_structured(payload_type),
+        _ => payload_type == PayloadType::Raw,
+    }
+}
@@ -0,0 +1,331 @@
+/* src/wsm/requests.rs */
+
+use crate::wsm::codec::{MAX_STREAMED_PAYLOAD, WsmMessage};
+use crate::wsm::error::ErrorReply;
+use crate::wsm::msg_id::MsgIdPool;
+use crate::wsm::opcode::Opcode;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::time;
+
+/// How long a control request waits for its reply unless the caller says otherwise.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// Every message ID on this connection is in use.
+    PoolFull,
+    /// The connection went away before the reply arrived.
+    Disconnected,
+    TimedOut(Duration),
+    /// A streamed reply grew past `MAX_STREAMED_PAYLOAD` and was discarded.
+    TooLarge,
+    /// The server answered with an error reply (0x1C).
+    Server(ErrorReply),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::PoolFull => write!(f, "message ID pool is full"),
+            RequestError::Disconnected => write!(f, "connection lost before the server replied"),
+            RequestError::TimedOut(after) => write!(f, "no reply from server within {:?}", after),
+            RequestError::TooLarge => write!(
+                f,
+                "streamed reply exceeds the {} byte limit",
+                MAX_STREAMED_PAYLOAD
+            ),
+            RequestError::Server(reply) => write!(f, "server error: {}", reply),
+        }
+    }
+}
+
+type ReplySender = oneshot::Sender<Result<WsmMessage, RequestError>>;
+type PendingReplies = Arc<std::sync::Mutex<HashMap<u32, ReplySender>>>;
+
+// A request's message ID and reply slot, released however the request ends: with a reply,
+// an error, or the caller's future being dropped mid-await.
+struct InFlight {
+    id: u32,
+    pool: MsgIdPool,
+    pending: PendingReplies,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+        self.pool.remove_msg_id(self.id);
+    }
+}
+
+/// A streamed reply whose final frame has not arrived yet.
+enum Partial {
+    Collecting(WsmMessage),
+    /// Over the size limit; remaining frames are dropped until the final one.
+    Discarding,
+}
+
+/// [CLIENT-SIDE] Correlates control-stream requests with their replies.
+/// Each request owns a message ID from the connection's pool until its reply,
+/// timeout or disconnect, so IDs can no longer leak.
+#[derive(Clone)]
+pub struct RequestTracker {
+    pool: MsgIdPool,
+    pending: PendingReplies,
+    partial: Arc<Mutex<HashMap<u32, Partial>>>,
+    tx: mpsc::Sender<WsmMessage>,
+}
+
+impl RequestTracker {
+    pub fn new(tx: mpsc::Sender<WsmMessage>) -> Self {
+        RequestTracker {
+            pool: MsgIdPool::default(),
+            pending: Arc::default(),
+            partial: Arc::default(),
+            tx,
+        }
+    }
+
+    /// Sends the message built for a fresh message ID and waits for the reply carrying that ID.
+    /// Error replies from the server are returned as `RequestError::Server`.
+    pub async fn request<F>(&self, build: F, timeout: Duration) -> Result<WsmMessage, RequestError>
+    where
+        F: FnOnce(u32) -> WsmMessage,
+    {
+        let id = self.pool.create_new_msg_id().ok_or(RequestError::PoolFull)?;
+        let in_flight = InFlight {
+            id,
+            pool: self.pool.clone(),
+            pending: self.pending.clone(),
+        };
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, reply_tx);
+
+        let result = match self.tx.send(build(id)).await {
+            Err(_) => Err(RequestError::Disconnected),
+            Ok(()) => match time::timeout(timeout, reply_rx).await {
+                Ok(Ok(Ok(reply))) if reply.opcode == Opcode::Error => {
+                    Err(RequestError::Server(ErrorReply::from_message(&reply)))
+                }
+                Ok(Ok(reply)) => reply,
+                Ok(Err(_)) => Err(RequestError::Disconnected),
+                Err(_) => Err(RequestError::TimedOut(timeout)),
+            },
+        };
+        drop(in_flight);
+        result
+    }
+
+    /// Hands a reply to the request waiting for it. Messages nobody waits for are returned.
+    /// Streamed replies are reassembled first; the reader only pulls the next frame once
+    /// this returns, so QUIC flow control holds back a server that streams too fast.
+    pub async fn resolve(&self, msg: WsmMessage) -> Option<WsmMessage> {
+        let msg = self.reassemble(msg).await?;
+        let waiter = self.pending.lock().unwrap().remove(&msg.message_id);
+        match waiter {
+            Some(waiter) => {
+                // The requester may have given up in the meantime; that is fine.
+                let _ = waiter.send(Ok(msg));
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    // Returns the complete message once its last frame is in, None while frames are outstanding.
+    async fn reassemble(&self, msg: WsmMessage) -> Option<WsmMessage> {
+        let mut partial = self.partial.lock().await;
+        let id = msg.message_id;
+        let mut combined = match partial.remove(&id) {
+            None if !msg.has_more() => return Some(msg),
+            None => msg.without_payload(),
+            Some(Partial::Collecting(combined)) => combined,
+            Some(Partial::Discarding) if msg.has_more() => {
+                partial.insert(id, Partial::Discarding);
+                return None;
+            }
+            Some(Partial::Discarding) => return None,
+        };
+
+        if combined.payload.len() + msg.payload.len() > MAX_STREAMED_PAYLOAD {
+            let waiter = self.pending.lock().unwrap().remove(&id);
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(Err(RequestError::TooLarge));
+            }
+            if msg.has_more() {
+                partial.insert(id, Partial::Discarding);
+            }
+            return None;
+        }
+        combined.payload.extend_from_slice(&msg.payload);
+        if msg.has_more() {
+            partial.insert(id, Partial::Collecting(combined));
+            return None;
+        }
+        combined.reserved = msg.reserved;
+        Some(combined)
+    }
+
+    /// Fails every outstanding request, used when the connection is lost.
+    pub async fn fail_all(&self) {
+        self.pending.lock().unwrap().clear();
+        self.partial.lock().await.clear();
+    }
+
+    /// Number of requests currently awaiting a reply.
+    pub fn in_flight(&self) -> usize {
+        self.pool.size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wsm::error::ErrorCode;
+    use crate::wsm::header::{PayloadType, RESERVED_MORE_FLAG};
+    use tokio::task::JoinHandle;
+
+    fn frame(message_id: u32, len: usize, more: bool) -> WsmMessage {
+        let msg = WsmMessage::new(
+            Opcode::ListResponse,
+            message_id,
+            PayloadType::Raw,
+            vec![7; len],
+        );
+        match more {
+            true => msg.with_reserved(RESERVED_MORE_FLAG),
+            false => msg.with_final(),
+        }
+    }
+
+    // Starts a request and returns it with the message ID it was sent under.
+    async fn start(
+        tracker: &RequestTracker,
+        rx: &mut mpsc::Receiver<WsmMessage>,
+        timeout: Duration,
+    ) -> (JoinHandle<Result<WsmMessage, RequestError>>, u32) {
+        let requester = tracker.clone();
+        let handle = tokio::spawn(async move {
+            requester
+                .request(|id| WsmMessage::empty(Opcode::ListRequest, id), timeout)
+                .await
+        });
+        let sent = rx.recv().await.expect("request is sent");
+        (handle, sent.message_id)
+    }
+
+    #[tokio::test]
+    async fn replies_reach_their_request_and_release_the_id() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let tracker = RequestTracker::new(tx);
+        let (handle, id) = start(&tracker, &mut rx, REQUEST_TIMEOUT).await;
+        assert_eq!(tracker.in_flight(), 1);
+
+        assert!(tracker.resolve(frame(id, 3, false)).await.is_none());
+        let reply = handle.await.unwrap().unwrap();
+        assert_eq!(reply.payload.len(), 3);
+        assert_eq!(tracker.in_flight(), 0);
+        // Nobody waits for this ID any more, so the message is handed back.
+        assert!(tracker.resolve(frame(id, 3, false)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn error_replies_become_server_errors() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let tracker = RequestTracker::new(tx);
+        let (handle, id) = start(&tracker, &mut rx, REQUEST_TIMEOUT).await;
+
+        let reply = ErrorReply::new(ErrorCode::NotFound, "no such volume");
+        tracker
+            .resolve(reply.to_message(id))
+            .await;
+        match handle.await.unwrap() {
+            Err(RequestError::Server(reply)) => assert_eq!(reply.code, ErrorCode::NotFound as u16),
+            other => panic!("expected a server error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_and_dropped_requests_release_the_id() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let tracker = RequestTracker::new(tx);
+        let (handle, _) = start(&tracker, &mut rx, Duration::from_millis(10)).await;
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(RequestError::TimedOut(_))
+        ));
+        assert_eq!(tracker.in_flight(), 0);
+
+        let (handle, id) = start(&tracker, &mut rx, REQUEST_TIMEOUT).await;
+        handle.abort();
+        let _ = handle.await;
+        assert_eq!(tracker.in_flight(), 0);
+        assert!(tracker.pending.lock().unwrap().is_empty());
+        assert!(tracker.resolve(frame(id, 1, false)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn continuation_frames_are_reassembled() {
+        let (tx, _rx) = mpsc::channel(4);
+        let tracker = RequestTracker::new(tx);
+        let frames = frame(5, 10, false).into_frames(4);
+        assert_eq!(frames.len(), 3);
+        let mut frames = frames.into_iter();
+        assert!(tracker.resolve(frames.next().unwrap()).await.is_none());
+        // Frames of other messages are not mixed in.
+        assert_eq!(
+            tracker
+                .resolve(frame(6, 2, false))
+                .await
+                .unwrap()
+                .payload
+                .len(),
+            2
+        );
+        assert!(tracker.resolve(frames.next().unwrap()).await.is_none());
+
+        let combined = tracker.resolve(frames.next().unwrap()).await.unwrap();
+        assert_eq!(combined.payload, vec![7; 10]);
+        assert!(combined.is_final());
+        assert!(tracker.partial.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_up_to_the_cap_are_delivered() {
+        let (tx, _rx) = mpsc::channel(4);
+        let tracker = RequestTracker::new(tx);
+        let half = MAX_STREAMED_PAYLOAD / 2;
+        assert!(tracker.resolve(frame(1, half, true)).await.is_none());
+        let combined = tracker.resolve(frame(1, half, false)).await.unwrap();
+        assert_eq!(combined.payload.len(), MAX_STREAMED_PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_past_the_cap_fail_and_are_discarded() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let tracker = RequestTracker::new(tx);
+        let (handle, id) = start(&tracker, &mut rx, REQUEST_TIMEOUT).await;
+
+        assert!(
+            tracker
+                .resolve(frame(id, MAX_STREAMED_PAYLOAD, true))
+                .await
+                .is_none()
+        );
+        assert!(tracker.resolve(frame(id, 1, true)).await.is_none());
+        assert!(matches!(handle.await.unwrap(), Err(RequestError::TooLarge)));
+        assert!(matches!(
+            tracker.partial.lock().await.get(&id),
+            Some(Partial::Discarding)
+        ));
+
+        // The rest of the stream is dropped, up to and including its final frame.
+        assert!(tracker.resolve(frame(id, 1, true)).await.is_none());
+        assert!(tracker.resolve(frame(id, 1, false)).await.is_none());
+        assert!(tracker.partial.lock().await.is_empty());
+        assert_eq!(tracker.in_flight(), 0);
+    }
+}